@@ -1,6 +1,6 @@
 use buffers::ByteBuf;
 use serde::de::Error as DeError;
-use sha1w::{ISha1, Sha1};
+use sha1w::{ISha1, ISha256, Sha1, Sha256};
 
 pub struct BencodeDeserializer<'de> {
     buf: &'de [u8],
@@ -10,6 +10,8 @@ pub struct BencodeDeserializer<'de> {
     // This is a f**ing hack
     pub is_torrent_info: bool,
     pub torrent_info_digest: Option<[u8; 20]>,
+    // SHA-256 of the same bytes, which is the info hash of BitTorrent v2 torrents.
+    pub torrent_info_digest_v2: Option<[u8; 32]>,
 }
 
 impl<'de> BencodeDeserializer<'de> {
@@ -20,6 +22,7 @@ impl<'de> BencodeDeserializer<'de> {
             parsing_key: false,
             is_torrent_info: false,
             torrent_info_digest: None,
+            torrent_info_digest_v2: None,
         }
     }
     pub fn into_remaining(self) -> &'de [u8] {
@@ -544,7 +547,11 @@ impl<'a, 'de> serde::de::MapAccess<'de> for MapAccess<'a, 'de> {
             let mut hash = Sha1::new();
             hash.update(&buf_before[..len]);
             let digest = hash.finish();
-            self.de.torrent_info_digest = Some(digest)
+            self.de.torrent_info_digest = Some(digest);
+
+            let mut hash = Sha256::new();
+            hash.update(&buf_before[..len]);
+            self.de.torrent_info_digest_v2 = Some(hash.finish());
         }
         self.de.field_context.pop();
         Ok(value)
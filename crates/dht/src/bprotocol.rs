@@ -1,7 +1,7 @@
 use std::{
     io::Write,
     marker::PhantomData,
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
 
 use bencode::{ByteBuf, ByteBufOwned};
@@ -165,7 +165,7 @@ struct RawMessage<BufT, Args = IgnoredAny, Resp = IgnoredAny> {
 
 pub struct Node {
     pub id: Id20,
-    pub addr: SocketAddrV4,
+    pub addr: SocketAddr,
 }
 
 impl core::fmt::Debug for Node {
@@ -174,6 +174,93 @@ impl core::fmt::Debug for Node {
     }
 }
 
+fn write_compact_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => buf.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => buf.extend_from_slice(&ip.octets()),
+    }
+    // BE encoding for port.
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+// Parses 6 bytes as IPv4 + port, or 18 bytes as IPv6 + port.
+fn read_compact_addr(b: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = match b.len() {
+        6 => (IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3])), &b[4..]),
+        18 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&b[..16]);
+            (IpAddr::V6(Ipv6Addr::from(octets)), &b[16..])
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+}
+
+fn serialize_compact_nodes<S: serde::Serializer>(
+    nodes: &[Node],
+    ipv6: bool,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let entry_len = if ipv6 { 38 } else { 26 };
+    let mut buf = Vec::<u8>::with_capacity(nodes.len() * entry_len);
+    // Nodes of the other address family can't be represented here, they are skipped.
+    for node in nodes.iter().filter(|n| n.addr.is_ipv6() == ipv6) {
+        buf.extend_from_slice(&node.id.0);
+        write_compact_addr(&mut buf, node.addr);
+    }
+    serializer.serialize_bytes(&buf)
+}
+
+struct CompactNodesVisitor {
+    ipv6: bool,
+}
+
+impl CompactNodesVisitor {
+    fn entry_len(&self) -> usize {
+        if self.ipv6 {
+            38
+        } else {
+            26
+        }
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for CompactNodesVisitor {
+    type Value = Vec<Node>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            formatter,
+            "compact node info with length multiple of {}",
+            self.entry_len()
+        )
+    }
+    fn visit_borrowed_bytes<E>(self, v: &'de [u8]) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        let entry_len = self.entry_len();
+        let chunks = v.chunks_exact(entry_len);
+        if !chunks.remainder().is_empty() {
+            return Err(E::invalid_length(v.len(), &self));
+        }
+        let mut buf = Vec::<Node>::with_capacity(v.len() / entry_len);
+        for chunk in chunks {
+            let mut node_id = [0u8; 20];
+            node_id.copy_from_slice(&chunk[..20]);
+            let addr =
+                read_compact_addr(&chunk[20..]).ok_or_else(|| E::invalid_length(v.len(), &self))?;
+            buf.push(Node {
+                id: Id20::new(node_id),
+                addr,
+            })
+        }
+        Ok(buf)
+    }
+}
+
+// The "nodes" key: IPv4 nodes, 26 bytes each.
 pub struct CompactNodeInfo {
     pub nodes: Vec<Node>,
 }
@@ -189,17 +276,7 @@ impl Serialize for CompactNodeInfo {
     where
         S: serde::Serializer,
     {
-        let mut buf = Vec::<u8>::with_capacity(self.nodes.len() * 26);
-        for node in self.nodes.iter() {
-            buf.extend_from_slice(&node.id.0);
-            let ip_octets = node.addr.ip().octets();
-            let port = node.addr.port();
-            buf.extend_from_slice(&ip_octets);
-            // BE encoding for port.
-            buf.push((port >> 8) as u8);
-            buf.push((port & 0xff) as u8);
-        }
-        serializer.serialize_bytes(&buf)
+        serialize_compact_nodes(&self.nodes, false, serializer)
     }
 }
 
@@ -208,40 +285,46 @@ impl<'de> Deserialize<'de> for CompactNodeInfo {
     where
         D: Deserializer<'de>,
     {
-        struct Visitor;
-        impl<'de> serde::de::Visitor<'de> for Visitor {
-            type Value = CompactNodeInfo;
+        Ok(CompactNodeInfo {
+            nodes: deserializer.deserialize_bytes(CompactNodesVisitor { ipv6: false })?,
+        })
+    }
+}
 
-            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                write!(formatter, "compact node info with length multiple of 26")
-            }
-            fn visit_borrowed_bytes<E>(self, v: &'de [u8]) -> Result<Self::Value, E>
-            where
-                E: serde::de::Error,
-            {
-                if v.len() % 26 != 0 {
-                    return Err(E::invalid_length(v.len(), &self));
-                }
-                let mut buf = Vec::<Node>::with_capacity(v.len() / 26);
-                for chunk in v.chunks_exact(26) {
-                    let mut node_id = [0u8; 20];
-                    node_id.copy_from_slice(&chunk[..20]);
-                    let ip = Ipv4Addr::new(chunk[20], chunk[21], chunk[22], chunk[23]);
-                    let port = ((chunk[24] as u16) << 8) + chunk[25] as u16;
-                    buf.push(Node {
-                        id: Id20::new(node_id),
-                        addr: SocketAddrV4::new(ip, port),
-                    })
-                }
-                Ok(CompactNodeInfo { nodes: buf })
-            }
-        }
-        deserializer.deserialize_bytes(Visitor)
+// The "nodes6" key (BEP 32): IPv6 nodes, 38 bytes each.
+pub struct CompactNodeInfo6 {
+    pub nodes: Vec<Node>,
+}
+
+impl core::fmt::Debug for CompactNodeInfo6 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.nodes)
+    }
+}
+
+impl Serialize for CompactNodeInfo6 {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serialize_compact_nodes(&self.nodes, true, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CompactNodeInfo6 {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(CompactNodeInfo6 {
+            nodes: deserializer.deserialize_bytes(CompactNodesVisitor { ipv6: true })?,
+        })
     }
 }
 
+// 6 bytes for IPv4 peers, 18 bytes for IPv6 peers.
 pub struct CompactPeerInfo {
-    pub addr: SocketAddrV4,
+    pub addr: SocketAddr,
 }
 
 impl core::fmt::Debug for CompactPeerInfo {
@@ -255,16 +338,8 @@ impl Serialize for CompactPeerInfo {
     where
         S: serde::Serializer,
     {
-        let octets = self.addr.ip().octets();
-        let port = self.addr.port();
-        let buf = [
-            octets[0],
-            octets[1],
-            octets[2],
-            octets[3],
-            (port >> 8) as u8,
-            (port & 0xff) as u8,
-        ];
+        let mut buf = Vec::with_capacity(18);
+        write_compact_addr(&mut buf, self.addr);
         serializer.serialize_bytes(&buf)
     }
 }
@@ -279,30 +354,174 @@ impl<'de> Deserialize<'de> for CompactPeerInfo {
             type Value = CompactPeerInfo;
 
             fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                write!(formatter, "6 bytes of peer info")
+                write!(formatter, "6 or 18 bytes of peer info")
             }
             fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
             where
                 E: serde::de::Error,
             {
-                if v.len() != 6 {
-                    return Err(E::invalid_length(6, &self));
+                match read_compact_addr(v) {
+                    Some(addr) => Ok(CompactPeerInfo { addr }),
+                    None => Err(E::invalid_length(v.len(), &self)),
                 }
-                let ip = Ipv4Addr::new(v[0], v[1], v[2], v[3]);
-                let port = ((v[4] as u16) << 8) + v[5] as u16;
-                Ok(CompactPeerInfo {
-                    addr: SocketAddrV4::new(ip, port),
-                })
             }
         }
         deserializer.deserialize_bytes(Visitor {})
     }
 }
 
+// BEP 32: which address families the requester wants nodes for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Want {
+    N4,
+    N6,
+}
+
+impl Serialize for Want {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            Want::N4 => serializer.serialize_bytes(b"n4"),
+            Want::N6 => serializer.serialize_bytes(b"n6"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Want {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct Visitor;
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Want;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(formatter, r#""n4" or "n6""#)
+            }
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                match v {
+                    b"n4" => Ok(Want::N4),
+                    b"n6" => Ok(Want::N6),
+                    _ => Err(E::invalid_value(Unexpected::Bytes(v), &self)),
+                }
+            }
+        }
+        deserializer.deserialize_bytes(Visitor)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FindNodeRequest {
     pub id: Id20,
     pub target: Id20,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub want: Option<Vec<Want>>,
+}
+
+// BEP 44: a stored item can be any bencoded value. It's kept bencoded, as that's what the
+// target hash and the signature are computed from.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct BencodedValue(Vec<u8>);
+
+impl BencodedValue {
+    /// BEP 44 limits the bencoded value to 1000 bytes.
+    pub const MAX_LEN: usize = 1000;
+
+    pub fn new<T: Serialize>(value: &T) -> anyhow::Result<Self> {
+        let mut buf = Vec::new();
+        bencode::bencode_serialize_to_writer(value, &mut buf)?;
+        Self::from_bencoded(buf)
+    }
+
+    pub fn from_bencoded(buf: Vec<u8>) -> anyhow::Result<Self> {
+        if buf.len() > Self::MAX_LEN {
+            anyhow::bail!(
+                "value is {} bytes bencoded, at most {} are allowed",
+                buf.len(),
+                Self::MAX_LEN
+            );
+        }
+        bencode::dyn_from_bytes::<ByteBuf>(&buf)?;
+        Ok(Self(buf))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn decode<'a, T: Deserialize<'a>>(&'a self) -> anyhow::Result<T> {
+        bencode::from_bytes(&self.0)
+    }
+}
+
+impl core::fmt::Debug for BencodedValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", ByteBuf(&self.0))
+    }
+}
+
+// The bytes are valid bencode, and re-encoding valid bencode gives the same bytes, as dictionary
+// keys are sorted.
+impl Serialize for BencodedValue {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::Error;
+        bencode::dyn_from_bytes::<ByteBuf>(&self.0)
+            .map_err(|e| S::Error::custom(format!("{e:#}")))?
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BencodedValue {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let value = bencode::BencodeValue::<ByteBuf<'de>>::deserialize(deserializer)?;
+        let mut buf = Vec::new();
+        bencode::bencode_serialize_to_writer(&value, &mut buf)
+            .map_err(|e| D::Error::custom(format!("{e:#}")))?;
+        Ok(Self(buf))
+    }
+}
+
+// BEP 44
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetRequest {
+    pub id: Id20,
+    pub target: Id20,
+    /// Only return the value if it's newer than this.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub want: Option<Vec<Want>>,
+}
+
+// BEP 44. Immutable items only have "v", mutable ones also "k", "sig" and "seq".
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PutRequest<BufT> {
+    pub id: Id20,
+    pub token: BufT,
+    pub v: BencodedValue,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k: Option<BufT>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sig: Option<BufT>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub salt: Option<BufT>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cas: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -313,13 +532,26 @@ pub struct Response<BufT> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub nodes: Option<CompactNodeInfo>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub nodes6: Option<CompactNodeInfo6>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub token: Option<BufT>,
+    // BEP 44 "get" responses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<BencodedValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k: Option<BufT>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sig: Option<BufT>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GetPeersRequest {
     pub id: Id20,
     pub info_hash: Id20,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub want: Option<Vec<Want>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -346,6 +578,8 @@ pub struct GetPeersResponse<BufT> {
     pub values: Option<Vec<CompactPeerInfo>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub nodes: Option<CompactNodeInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nodes6: Option<CompactNodeInfo6>,
 }
 
 #[derive(Debug)]
@@ -353,7 +587,7 @@ pub struct Message<BufT> {
     pub kind: MessageKind<BufT>,
     pub transaction_id: BufT,
     pub version: Option<BufT>,
-    pub ip: Option<SocketAddrV4>,
+    pub ip: Option<SocketAddr>,
 }
 
 impl Message<ByteBufOwned> {
@@ -374,6 +608,8 @@ pub enum MessageKind<BufT> {
     Response(Response<BufT>),
     PingRequest(PingRequest),
     AnnouncePeer(AnnouncePeer<BufT>),
+    GetRequest(GetRequest),
+    PutRequest(PutRequest<BufT>),
 }
 
 impl<BufT: core::fmt::Debug> core::fmt::Debug for MessageKind<BufT> {
@@ -385,6 +621,8 @@ impl<BufT: core::fmt::Debug> core::fmt::Debug for MessageKind<BufT> {
             Self::Response(r) => write!(f, "{r:?}"),
             Self::PingRequest(r) => write!(f, "{r:?}"),
             Self::AnnouncePeer(r) => write!(f, "{r:?}"),
+            Self::GetRequest(r) => write!(f, "{r:?}"),
+            Self::PutRequest(r) => write!(f, "{r:?}"),
         }
     }
 }
@@ -393,7 +631,7 @@ pub fn serialize_message<'a, W: Write, BufT: Serialize + From<&'a [u8]>>(
     writer: &mut W,
     transaction_id: BufT,
     version: Option<BufT>,
-    ip: Option<SocketAddrV4>,
+    ip: Option<SocketAddr>,
     kind: MessageKind<BufT>,
 ) -> anyhow::Result<()> {
     let ip = ip.map(|ip| CompactPeerInfo { addr: ip });
@@ -476,6 +714,32 @@ pub fn serialize_message<'a, W: Write, BufT: Serialize + From<&'a [u8]>>(
             };
             Ok(bencode::bencode_serialize_to_writer(msg, writer)?)
         }
+        MessageKind::GetRequest(req) => {
+            let msg: RawMessage<BufT, _, ()> = RawMessage {
+                message_type: MessageType::Request,
+                transaction_id,
+                error: None,
+                response: None,
+                method_name: Some(BufT::from(b"get")),
+                arguments: Some(req),
+                ip,
+                version,
+            };
+            Ok(bencode::bencode_serialize_to_writer(msg, writer)?)
+        }
+        MessageKind::PutRequest(req) => {
+            let msg: RawMessage<BufT, _, ()> = RawMessage {
+                message_type: MessageType::Request,
+                transaction_id,
+                error: None,
+                response: None,
+                method_name: Some(BufT::from(b"put")),
+                arguments: Some(req),
+                ip,
+                version,
+            };
+            Ok(bencode::bencode_serialize_to_writer(msg, writer)?)
+        }
     }
 }
 
@@ -523,6 +787,24 @@ where
                         kind: MessageKind::AnnouncePeer(de.arguments.unwrap())
                     })
                 }
+                b"get" => {
+                    let de: RawMessage<BufT, GetRequest> = bencode::from_bytes(buf)?;
+                    Ok(Message {
+                        transaction_id: de.transaction_id,
+                        version: de.version,
+                        ip: de.ip.map(|c| c.addr),
+                        kind: MessageKind::GetRequest(de.arguments.unwrap()),
+                    })
+                }
+                b"put" => {
+                    let de: RawMessage<BufT, PutRequest<BufT>> = bencode::from_bytes(buf)?;
+                    Ok(Message {
+                        transaction_id: de.transaction_id,
+                        version: de.version,
+                        ip: de.ip.map(|c| c.addr),
+                        kind: MessageKind::PutRequest(de.arguments.unwrap()),
+                    })
+                }
                 other => anyhow::bail!("unsupported method {:?}", ByteBuf(other)),
             },
             _ => anyhow::bail!(
@@ -702,6 +984,112 @@ mod tests {
         assert_eq!(ann[..], buf[..]);
     }
 
+    #[test]
+    fn test_response_ipv6_roundtrip() {
+        let id = librqbit_core::hash_id::Id20::new([1u8; 20]);
+        let v6: std::net::SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let v4: std::net::SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let mut buf = Vec::new();
+        bprotocol::serialize_message(
+            &mut buf,
+            ByteBuf(b"aa"),
+            None,
+            Some(v6),
+            bprotocol::MessageKind::Response(bprotocol::Response {
+                id,
+                values: Some(vec![
+                    bprotocol::CompactPeerInfo { addr: v4 },
+                    bprotocol::CompactPeerInfo { addr: v6 },
+                ]),
+                nodes: Some(bprotocol::CompactNodeInfo {
+                    nodes: vec![bprotocol::Node { id, addr: v4 }],
+                }),
+                nodes6: Some(bprotocol::CompactNodeInfo6 {
+                    nodes: vec![bprotocol::Node { id, addr: v6 }],
+                }),
+                token: None,
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+
+        let msg = bprotocol::deserialize_message::<ByteBuf>(&buf).unwrap();
+        assert_eq!(msg.ip, Some(v6));
+        let resp = match msg.kind {
+            bprotocol::MessageKind::Response(r) => r,
+            _ => panic!("wrong kind"),
+        };
+        let values = resp.values.unwrap();
+        assert_eq!(values.iter().map(|p| p.addr).collect::<Vec<_>>(), [v4, v6]);
+        let nodes = resp.nodes.unwrap().nodes;
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].addr, v4);
+        let nodes6 = resp.nodes6.unwrap().nodes;
+        assert_eq!(nodes6.len(), 1);
+        assert_eq!(nodes6[0].addr, v6);
+    }
+
+    #[test]
+    fn test_get_peers_want() {
+        let req = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234564:wantl2:n42:n6ee1:q9:get_peers1:t2:aa1:y1:qe";
+        let msg = bprotocol::deserialize_message::<ByteBuf>(req).unwrap();
+        match &msg.kind {
+            bprotocol::MessageKind::GetPeersRequest(r) => assert_eq!(
+                r.want.as_deref(),
+                Some(&[bprotocol::Want::N4, bprotocol::Want::N6][..])
+            ),
+            _ => panic!("wrong kind"),
+        }
+        let mut buf = Vec::new();
+        bprotocol::serialize_message(&mut buf, msg.transaction_id, msg.version, msg.ip, msg.kind)
+            .unwrap();
+        assert_eq!(req[..], buf[..]);
+    }
+
+    #[test]
+    fn test_put_roundtrip() {
+        let req = b"d1:ad3:casi1e2:id20:abcdefghij01234567891:k32:0123456789abcdef0123456789abcdef4:salt6:foobar3:seqi4e3:sig64:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef5:token8:aoeusnth1:v12:Hello World!e1:q3:put1:t2:aa1:y1:qe";
+        let msg = bprotocol::deserialize_message::<ByteBuf>(req).unwrap();
+        match &msg.kind {
+            bprotocol::MessageKind::PutRequest(put) => {
+                assert_eq!(put.v.as_bytes(), b"12:Hello World!");
+                assert_eq!(put.seq, Some(4));
+                assert_eq!(put.cas, Some(1));
+                assert_eq!(put.salt.as_deref(), Some(&b"foobar"[..]));
+            }
+            _ => panic!("wrong kind"),
+        }
+        let mut buf = Vec::new();
+        bprotocol::serialize_message(&mut buf, msg.transaction_id, msg.version, msg.ip, msg.kind)
+            .unwrap();
+        assert_eq!(req[..], buf[..]);
+    }
+
+    #[test]
+    fn test_get_response_roundtrip() {
+        let resp = b"d1:rd2:id20:0123456789abcdefghij1:k32:0123456789abcdef0123456789abcdef5:nodes0:3:seqi4e3:sig64:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef5:token8:aoeusnth1:vd1:ai1e1:bl1:xeee1:t2:aa1:y1:re";
+        let msg = bprotocol::deserialize_message::<ByteBuf>(resp).unwrap();
+        match &msg.kind {
+            bprotocol::MessageKind::Response(r) => {
+                assert_eq!(r.v.as_ref().unwrap().as_bytes(), b"d1:ai1e1:bl1:xee");
+                assert_eq!(r.seq, Some(4));
+            }
+            _ => panic!("wrong kind"),
+        }
+        let mut buf = Vec::new();
+        bprotocol::serialize_message(&mut buf, msg.transaction_id, msg.version, msg.ip, msg.kind)
+            .unwrap();
+        assert_eq!(resp[..], buf[..]);
+    }
+
+    #[test]
+    fn test_bencoded_value() {
+        let v = bprotocol::BencodedValue::new(&ByteBuf(b"Hello World!")).unwrap();
+        assert_eq!(v.as_bytes(), b"12:Hello World!");
+        assert!(bprotocol::BencodedValue::from_bencoded(b"12:Hello".to_vec()).is_err());
+        assert!(bprotocol::BencodedValue::new(&ByteBuf(&[0u8; 1000])).is_err());
+    }
+
     #[test]
     fn deserialize_bencode_packets_captured_from_wireshark() {
         debug_hex_bencode("req: find_node", FIND_NODE_REQUEST);
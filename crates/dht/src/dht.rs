@@ -12,9 +12,11 @@ use std::{
 
 use crate::{
     bprotocol::{
-        self, AnnouncePeer, CompactNodeInfo, ErrorDescription, FindNodeRequest, GetPeersRequest,
-        Message, MessageKind, Node, PingRequest, Response,
+        self, AnnouncePeer, BencodedValue, CompactNodeInfo, CompactNodeInfo6, ErrorDescription,
+        FindNodeRequest, GetPeersRequest, GetRequest, Message, MessageKind, Node, PingRequest,
+        PutRequest, Response, Want,
     },
+    item_store::{immutable_target, mutable_target, Item, ItemStore, MutableItem, PutError},
     peer_store::PeerStore,
     routing_table::{InsertResult, NodeStatus, RoutingTable},
     INACTIVITY_TIMEOUT, REQUERY_INTERVAL, RESPONSE_TIMEOUT,
@@ -33,7 +35,7 @@ use librqbit_core::{
     peer_id::generate_peer_id,
     spawn_utils::{spawn, spawn_with_cancel},
 };
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
 use serde::Serialize;
 use tokio::{
@@ -50,6 +52,7 @@ pub struct DhtStats {
     pub id: Id20,
     pub outstanding_requests: usize,
     pub routing_table_size: usize,
+    pub routing_table_size_v6: usize,
 }
 
 struct OutstandingRequest {
@@ -154,7 +157,7 @@ impl RecursiveRequestCallbacks for RecursiveRequestCallbacksGetPeers {
 struct RecursiveRequestCallbacksFindNodes {}
 impl RecursiveRequestCallbacks for RecursiveRequestCallbacksFindNodes {
     fn on_request_start(&self, req: &RecursiveRequest<Self>, target_node: Id20, addr: SocketAddr) {
-        let mut rt = req.dht.routing_table.write();
+        let mut rt = req.dht.routing_table_for(addr).write();
         match rt.add_node(target_node, addr) {
             InsertResult::WasExisting | InsertResult::ReplacedBad(_) | InsertResult::Added => {
                 rt.mark_outgoing_request(&target_node);
@@ -167,10 +170,10 @@ impl RecursiveRequestCallbacks for RecursiveRequestCallbacksFindNodes {
         &self,
         req: &RecursiveRequest<Self>,
         target_node: Id20,
-        _addr: SocketAddr,
+        addr: SocketAddr,
         resp: &anyhow::Result<ResponseOrError>,
     ) {
-        let mut table = req.dht.routing_table.write();
+        let mut table = req.dht.routing_table_for(addr).write();
         if resp.is_ok() {
             table.mark_response(&target_node);
         } else {
@@ -179,6 +182,78 @@ impl RecursiveRequestCallbacks for RecursiveRequestCallbacksFindNodes {
     }
 }
 
+// A "get" response from a node, with the token to "put" on it.
+struct GetResponse {
+    id: Id20,
+    addr: SocketAddr,
+    token: ByteBufOwned,
+    // Only set if it matches the target.
+    item: Option<Item>,
+}
+
+struct RecursiveRequestCallbacksGet {
+    // The salt of mutable items, which is needed to check them against the target. None for
+    // immutable items.
+    salt: Option<Vec<u8>>,
+    responses: Mutex<Vec<GetResponse>>,
+}
+
+impl RecursiveRequestCallbacksGet {
+    fn parse_item(&self, target: Id20, resp: &Response<ByteBufOwned>) -> anyhow::Result<Item> {
+        let value = resp.v.clone().context("no value")?;
+        let item = match &self.salt {
+            None => Item::Immutable(value),
+            Some(salt) => {
+                let (k, sig, seq) = match (&resp.k, &resp.sig, resp.seq) {
+                    (Some(k), Some(sig), Some(seq)) => (k, sig, seq),
+                    _ => bail!("mutable item without k, sig or seq"),
+                };
+                let item = MutableItem::from_parts(k, Some(salt), seq, value, sig)?;
+                item.verify()?;
+                Item::Mutable(item)
+            }
+        };
+        if item.target() != target {
+            bail!("item doesn't match the target");
+        }
+        Ok(item)
+    }
+}
+
+impl RecursiveRequestCallbacks for RecursiveRequestCallbacksGet {
+    fn on_request_start(&self, _: &RecursiveRequest<Self>, _: Id20, _: SocketAddr) {}
+
+    fn on_request_end(
+        &self,
+        req: &RecursiveRequest<Self>,
+        target_node: Id20,
+        addr: SocketAddr,
+        resp: &anyhow::Result<ResponseOrError>,
+    ) {
+        let resp = match resp {
+            Ok(ResponseOrError::Response(resp)) => resp,
+            _ => return,
+        };
+        let token = match &resp.token {
+            Some(token) => token.clone(),
+            None => return,
+        };
+        let item = match resp.v {
+            Some(_) => self
+                .parse_item(req.info_hash, resp)
+                .map_err(|e| debug!("{addr}: ignoring item: {e:#}"))
+                .ok(),
+            None => None,
+        };
+        self.responses.lock().push(GetResponse {
+            id: target_node,
+            addr,
+            token,
+            item,
+        });
+    }
+}
+
 struct RecursiveRequest<C: RecursiveRequestCallbacks> {
     max_depth: usize,
     useful_nodes_limit: usize,
@@ -278,7 +353,7 @@ impl RecursiveRequest<RecursiveRequestCallbacksFindNodes> {
         let mut futs = FuturesUnordered::new();
 
         let mut initial_addrs = 0;
-        for addr in addrs {
+        for addr in addrs.filter(|a| req.dht.can_send_to(*a)) {
             futs.push(request_one(None, addr, 0));
             initial_addrs += 1;
         }
@@ -320,6 +395,73 @@ impl RecursiveRequest<RecursiveRequestCallbacksFindNodes> {
     }
 }
 
+impl RecursiveRequest<RecursiveRequestCallbacksGet> {
+    // Look up the nodes closest to the target, asking them for the item. Returns the responses
+    // sorted by distance to the target.
+    async fn get_item(
+        dht: Arc<DhtState>,
+        target: Id20,
+        seq: Option<i64>,
+        salt: Option<Vec<u8>>,
+    ) -> anyhow::Result<Vec<GetResponse>> {
+        let (node_tx, mut node_rx) = unbounded_channel();
+        let req = RecursiveRequest {
+            max_depth: 4,
+            info_hash: target,
+            request: Request::Get { target, seq },
+            dht,
+            useful_nodes_limit: 32,
+            useful_nodes: RwLock::new(Vec::new()),
+            peer_tx: unbounded_channel().0,
+            node_tx,
+            callbacks: RecursiveRequestCallbacksGet {
+                salt,
+                responses: Default::default(),
+            },
+        };
+
+        let request_one = |id, addr, depth| {
+            req.request_one(Some(id), addr, depth)
+                .map_err(|e| debug!("error: {e:?}"))
+                .instrument(error_span!("get", addr = addr.to_string()))
+        };
+
+        let mut futs = FuturesUnordered::new();
+        for table in req.dht.routing_tables() {
+            for node in table.read().sorted_by_distance_from(target).iter().take(8) {
+                futs.push(request_one(node.id(), node.addr(), 0));
+            }
+        }
+        if futs.is_empty() {
+            bail!("no nodes in the routing table");
+        }
+
+        loop {
+            tokio::select! {
+                biased;
+
+                r = node_rx.recv() => {
+                    let (id, addr, depth) = r.unwrap();
+                    // Nodes from responses always have ids.
+                    if let Some(id) = id {
+                        futs.push(request_one(id, addr, depth));
+                    }
+                },
+                f = futs.next() => {
+                    if f.is_none() {
+                        break;
+                    }
+                }
+            }
+        }
+        drop(futs);
+
+        let mut responses = req.callbacks.responses.into_inner();
+        responses.sort_by_key(|r| r.id.distance(&target));
+        Ok(responses)
+    }
+}
+
 impl RecursiveRequest<RecursiveRequestCallbacksGetPeers> {
     fn request_peers_forever(
         self: &Arc<Self>,
@@ -375,17 +517,17 @@ impl RecursiveRequest<RecursiveRequestCallbacksGetPeers> {
 
     fn get_peers_root(&self) -> anyhow::Result<usize> {
         let mut count = 0;
-        for (id, addr) in self
-            .dht
-            .routing_table
-            .read()
-            .sorted_by_distance_from(self.info_hash)
-            .iter()
-            .map(|n| (n.id(), n.addr()))
-            .take(8)
-        {
-            count += 1;
-            self.node_tx.send((Some(id), addr, 0))?;
+        for table in self.dht.routing_tables() {
+            for (id, addr) in table
+                .read()
+                .sorted_by_distance_from(self.info_hash)
+                .iter()
+                .map(|n| (n.id(), n.addr()))
+                .take(8)
+            {
+                count += 1;
+                self.node_tx.send((Some(id), addr, 0))?;
+            }
         }
         Ok(count)
     }
@@ -410,7 +552,7 @@ impl<C: RecursiveRequestCallbacks> RecursiveRequest<C> {
             self.callbacks.on_request_end(self, id, addr, &response);
         }
 
-        let response = match self.dht.request(self.request.clone(), addr).await {
+        let response = match response {
             Ok(ResponseOrError::Response(r)) => r,
             Ok(ResponseOrError::Error(e)) => bail!("error response: {:?}", e),
             Err(e) => {
@@ -421,25 +563,31 @@ impl<C: RecursiveRequestCallbacks> RecursiveRequest<C> {
 
         if let Some(peers) = response.values {
             for peer in peers {
-                self.peer_tx.send(SocketAddr::V4(peer.addr))?;
+                self.peer_tx.send(peer.addr)?;
             }
         }
 
-        if let Some(nodes) = response.nodes {
-            for node in nodes.nodes {
-                let addr = SocketAddr::V4(node.addr);
-                let should_request = self.should_request_node(node.id, addr, depth);
-                trace!(
-                    "should_request={}, id={:?}, addr={}, depth={}/{}",
-                    should_request,
-                    node.id,
-                    addr,
-                    depth,
-                    self.max_depth
-                );
-                if should_request {
-                    self.node_tx.send((Some(node.id), addr, depth + 1))?;
-                }
+        let nodes = response
+            .nodes
+            .into_iter()
+            .flat_map(|n| n.nodes)
+            .chain(response.nodes6.into_iter().flat_map(|n| n.nodes));
+        for node in nodes {
+            let addr = node.addr;
+            if !self.dht.can_send_to(addr) {
+                continue;
+            }
+            let should_request = self.should_request_node(node.id, addr, depth);
+            trace!(
+                "should_request={}, id={:?}, addr={}, depth={}/{}",
+                should_request,
+                node.id,
+                addr,
+                depth,
+                self.max_depth
+            );
+            if should_request {
+                self.node_tx.send((Some(node.id), addr, depth + 1))?;
             }
         }
         Ok(())
@@ -536,7 +684,11 @@ pub struct DhtState {
     inflight_by_transaction_id: DashMap<(u16, SocketAddr), OutstandingRequest>,
 
     routing_table: RwLock<RoutingTable>,
+    // BEP 32: IPv6 nodes live in a separate routing table.
+    routing_table_v6: RwLock<RoutingTable>,
     listen_addr: SocketAddr,
+    ipv4_enabled: bool,
+    ipv6_enabled: bool,
 
     // Sending requests to the worker.
     rate_limiter: RateLimiter,
@@ -546,31 +698,72 @@ pub struct DhtState {
     cancellation_token: CancellationToken,
 
     pub(crate) peer_store: PeerStore,
+    item_store: ItemStore,
+}
+
+struct DhtSockets {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
 }
 
 impl DhtState {
+    #[allow(clippy::too_many_arguments)]
     fn new_internal(
         id: Id20,
         sender: UnboundedSender<WorkerSendRequest>,
         routing_table: Option<RoutingTable>,
+        routing_table_v6: Option<RoutingTable>,
         listen_addr: SocketAddr,
+        sockets: &DhtSockets,
         peer_store: PeerStore,
         cancellation_token: CancellationToken,
     ) -> Self {
         let routing_table = routing_table.unwrap_or_else(|| RoutingTable::new(id, None));
+        let routing_table_v6 = routing_table_v6.unwrap_or_else(|| RoutingTable::new(id, None));
         Self {
             id,
             next_transaction_id: AtomicU16::new(0),
             inflight_by_transaction_id: Default::default(),
             routing_table: RwLock::new(routing_table),
+            routing_table_v6: RwLock::new(routing_table_v6),
             worker_sender: sender,
             listen_addr,
+            ipv4_enabled: sockets.v4.is_some(),
+            ipv6_enabled: sockets.v6.is_some(),
             rate_limiter: make_rate_limiter(),
             peer_store,
+            item_store: ItemStore::new(),
             cancellation_token,
         }
     }
 
+    fn routing_table_for(&self, addr: SocketAddr) -> &RwLock<RoutingTable> {
+        match addr {
+            SocketAddr::V4(_) => &self.routing_table,
+            SocketAddr::V6(_) => &self.routing_table_v6,
+        }
+    }
+
+    fn routing_tables(&self) -> [&RwLock<RoutingTable>; 2] {
+        [&self.routing_table, &self.routing_table_v6]
+    }
+
+    fn can_send_to(&self, addr: SocketAddr) -> bool {
+        match addr {
+            SocketAddr::V4(_) => self.ipv4_enabled,
+            SocketAddr::V6(_) => self.ipv6_enabled,
+        }
+    }
+
+    // If we can talk both IPv4 and IPv6, ask for nodes of both families (BEP 32).
+    fn want(&self) -> Option<Vec<Want>> {
+        if self.ipv4_enabled && self.ipv6_enabled {
+            Some(vec![Want::N4, Want::N6])
+        } else {
+            None
+        }
+    }
+
     async fn request(&self, request: Request, addr: SocketAddr) -> anyhow::Result<ResponseOrError> {
         self.rate_limiter.acquire_one().await;
         let (tid, message) = self.create_request(request);
@@ -619,6 +812,7 @@ impl DhtState {
                 kind: MessageKind::GetPeersRequest(GetPeersRequest {
                     id: self.id,
                     info_hash,
+                    want: self.want(),
                 }),
             },
             Request::FindNode(target) => Message {
@@ -628,6 +822,7 @@ impl DhtState {
                 kind: MessageKind::FindNodeRequest(FindNodeRequest {
                     id: self.id,
                     target,
+                    want: self.want(),
                 }),
             },
             Request::Ping => Message {
@@ -652,6 +847,47 @@ impl DhtState {
                 version: None,
                 ip: None,
             },
+            Request::Get { target, seq } => Message {
+                transaction_id: ByteBufOwned::from(transaction_id_buf.as_ref()),
+                version: None,
+                ip: None,
+                kind: MessageKind::GetRequest(GetRequest {
+                    id: self.id,
+                    target,
+                    seq,
+                    want: self.want(),
+                }),
+            },
+            Request::Put { token, item, cas } => {
+                let put = match item {
+                    Item::Immutable(v) => PutRequest {
+                        id: self.id,
+                        token,
+                        v,
+                        k: None,
+                        sig: None,
+                        seq: None,
+                        salt: None,
+                        cas: None,
+                    },
+                    Item::Mutable(m) => PutRequest {
+                        id: self.id,
+                        token,
+                        k: Some(ByteBufOwned::from(&m.public_key[..])),
+                        sig: Some(ByteBufOwned::from(&m.signature[..])),
+                        seq: Some(m.seq),
+                        salt: (!m.salt.is_empty()).then(|| ByteBufOwned::from(m.salt)),
+                        v: m.value,
+                        cas,
+                    },
+                };
+                Message {
+                    transaction_id: ByteBufOwned::from(transaction_id_buf.as_ref()),
+                    version: None,
+                    ip: None,
+                    kind: MessageKind::PutRequest(put),
+                }
+            }
         };
         (transaction_id, message)
     }
@@ -661,24 +897,31 @@ impl DhtState {
         msg: Message<ByteBufOwned>,
         addr: SocketAddr,
     ) -> anyhow::Result<()> {
-        let generate_compact_nodes = |target| {
-            let nodes = self
-                .routing_table
+        let closest_nodes = |table: &RwLock<RoutingTable>, target| {
+            table
                 .read()
                 .sorted_by_distance_from(target)
                 .into_iter()
-                .filter_map(|r| {
-                    Some(Node {
-                        id: r.id(),
-                        addr: match r.addr() {
-                            SocketAddr::V4(v4) => v4,
-                            SocketAddr::V6(_) => return None,
-                        },
-                    })
+                .map(|r| Node {
+                    id: r.id(),
+                    addr: r.addr(),
                 })
                 .take(8)
-                .collect::<Vec<_>>();
-            CompactNodeInfo { nodes }
+                .collect::<Vec<_>>()
+        };
+        // Without "want", respond with nodes of the same address family as the requester.
+        let generate_compact_nodes = |target, want: &Option<Vec<Want>>| {
+            let (n4, n6) = match want {
+                Some(want) => (want.contains(&Want::N4), want.contains(&Want::N6)),
+                None => (addr.is_ipv4(), addr.is_ipv6()),
+            };
+            let nodes = n4.then(|| CompactNodeInfo {
+                nodes: closest_nodes(&self.routing_table, target),
+            });
+            let nodes6 = n6.then(|| CompactNodeInfo6 {
+                nodes: closest_nodes(&self.routing_table_v6, target),
+            });
+            (nodes, nodes6)
         };
 
         match &msg.kind {
@@ -730,7 +973,9 @@ impl DhtState {
                         ..Default::default()
                     }),
                 };
-                self.routing_table.write().mark_last_query(&req.id);
+                self.routing_table_for(addr)
+                    .write()
+                    .mark_last_query(&req.id);
                 self.worker_sender.send(WorkerSendRequest {
                     our_tid: None,
                     message,
@@ -739,7 +984,9 @@ impl DhtState {
                 Ok(())
             }
             MessageKind::AnnouncePeer(ann) => {
-                self.routing_table.write().mark_last_query(&ann.id);
+                self.routing_table_for(addr)
+                    .write()
+                    .mark_last_query(&ann.id);
                 let added = self.peer_store.store_peer(ann, addr);
                 trace!("{addr}: added_peer={added}, announce={ann:?}");
                 let message = Message {
@@ -759,20 +1006,26 @@ impl DhtState {
                 Ok(())
             }
             MessageKind::GetPeersRequest(req) => {
-                let compact_node_info = generate_compact_nodes(req.info_hash);
-                let compact_peer_info = self.peer_store.get_for_info_hash(req.info_hash);
-                self.routing_table.write().mark_last_query(&req.id);
+                let (nodes, nodes6) = generate_compact_nodes(req.info_hash, &req.want);
+                let compact_peer_info = self
+                    .peer_store
+                    .get_for_info_hash(req.info_hash, addr.is_ipv6());
+                self.routing_table_for(addr)
+                    .write()
+                    .mark_last_query(&req.id);
                 let message = Message {
                     transaction_id: msg.transaction_id,
                     version: None,
                     ip: None,
                     kind: MessageKind::Response(bprotocol::Response {
                         id: self.id,
-                        nodes: Some(compact_node_info),
+                        nodes,
+                        nodes6,
                         values: Some(compact_peer_info),
                         token: Some(ByteBufOwned::from(
                             &self.peer_store.gen_token_for(req.id, addr)[..],
                         )),
+                        ..Default::default()
                     }),
                 };
                 self.worker_sender.send(WorkerSendRequest {
@@ -783,15 +1036,18 @@ impl DhtState {
                 Ok(())
             }
             MessageKind::FindNodeRequest(req) => {
-                let compact_node_info = generate_compact_nodes(req.target);
-                self.routing_table.write().mark_last_query(&req.id);
+                let (nodes, nodes6) = generate_compact_nodes(req.target, &req.want);
+                self.routing_table_for(addr)
+                    .write()
+                    .mark_last_query(&req.id);
                 let message = Message {
                     transaction_id: msg.transaction_id,
                     version: None,
                     ip: None,
                     kind: MessageKind::Response(bprotocol::Response {
                         id: self.id,
-                        nodes: Some(compact_node_info),
+                        nodes,
+                        nodes6,
                         ..Default::default()
                     }),
                 };
@@ -802,15 +1058,100 @@ impl DhtState {
                 })?;
                 Ok(())
             }
+            MessageKind::GetRequest(req) => {
+                let (nodes, nodes6) = generate_compact_nodes(req.target, &req.want);
+                self.routing_table_for(addr)
+                    .write()
+                    .mark_last_query(&req.id);
+                let mut response = bprotocol::Response {
+                    id: self.id,
+                    nodes,
+                    nodes6,
+                    token: Some(ByteBufOwned::from(
+                        &self.peer_store.gen_token_for(req.id, addr)[..],
+                    )),
+                    ..Default::default()
+                };
+                match self.item_store.get(&req.target) {
+                    Some(Item::Immutable(v)) => response.v = Some(v),
+                    Some(Item::Mutable(m)) => {
+                        response.seq = Some(m.seq);
+                        // The requester already has this one.
+                        if req.seq.map_or(true, |seq| m.seq > seq) {
+                            response.k = Some(ByteBufOwned::from(&m.public_key[..]));
+                            response.sig = Some(ByteBufOwned::from(&m.signature[..]));
+                            response.v = Some(m.value);
+                        }
+                    }
+                    None => {}
+                }
+                let message = Message {
+                    transaction_id: msg.transaction_id,
+                    version: None,
+                    ip: None,
+                    kind: MessageKind::Response(response),
+                };
+                self.worker_sender.send(WorkerSendRequest {
+                    our_tid: None,
+                    message,
+                    addr,
+                })?;
+                Ok(())
+            }
+            MessageKind::PutRequest(put) => {
+                self.routing_table_for(addr)
+                    .write()
+                    .mark_last_query(&put.id);
+                let result = self.store_item(put, addr);
+                trace!("{addr}: put result={result:?}");
+                let kind = match result {
+                    Ok(()) => MessageKind::Response(bprotocol::Response {
+                        id: self.id,
+                        ..Default::default()
+                    }),
+                    Err(e) => MessageKind::Error(ErrorDescription {
+                        code: e.code(),
+                        description: ByteBufOwned::from(e.description().as_bytes()),
+                    }),
+                };
+                let message = Message {
+                    transaction_id: msg.transaction_id,
+                    version: None,
+                    ip: None,
+                    kind,
+                };
+                self.worker_sender.send(WorkerSendRequest {
+                    our_tid: None,
+                    message,
+                    addr,
+                })?;
+                Ok(())
+            }
             _ => unreachable!(),
         }
     }
 
+    fn store_item(&self, put: &PutRequest<ByteBufOwned>, addr: SocketAddr) -> Result<(), PutError> {
+        if !self.peer_store.is_valid_token(&put.token, put.id, addr) {
+            return Err(PutError::InvalidToken);
+        }
+        let item = match (&put.k, &put.sig, put.seq) {
+            (None, None, None) => Item::Immutable(put.v.clone()),
+            (Some(k), Some(sig), Some(seq)) => Item::Mutable(
+                MutableItem::from_parts(k, put.salt.as_deref(), seq, put.v.clone(), sig)
+                    .map_err(|_| PutError::MalformedItem)?,
+            ),
+            _ => return Err(PutError::MalformedItem),
+        };
+        self.item_store.put(item, put.cas)
+    }
+
     pub fn get_stats(&self) -> DhtStats {
         DhtStats {
             id: self.id,
             outstanding_requests: self.inflight_by_transaction_id.len(),
             routing_table_size: self.routing_table.read().len(),
+            routing_table_size_v6: self.routing_table_v6.read().len(),
         }
     }
 }
@@ -825,6 +1166,15 @@ enum Request {
         port: u16,
     },
     Ping,
+    Get {
+        target: Id20,
+        seq: Option<i64>,
+    },
+    Put {
+        token: ByteBufOwned,
+        item: Item,
+        cas: Option<i64>,
+    },
 }
 
 enum ResponseOrError {
@@ -842,7 +1192,7 @@ impl core::fmt::Debug for ResponseOrError {
 }
 
 struct DhtWorker {
-    socket: UdpSocket,
+    sockets: DhtSockets,
     dht: Arc<DhtState>,
 }
 
@@ -919,13 +1269,15 @@ impl DhtWorker {
             loop {
                 interval.tick().await;
                 let mut found = 0;
-                for bucket in self.dht.routing_table.read().iter_buckets() {
-                    if bucket.leaf.last_refreshed.elapsed() < INACTIVITY_TIMEOUT {
-                        continue;
+                for table in self.dht.routing_tables() {
+                    for bucket in table.read().iter_buckets() {
+                        if bucket.leaf.last_refreshed.elapsed() < INACTIVITY_TIMEOUT {
+                            continue;
+                        }
+                        found += 1;
+                        let random_id = bucket.random_within();
+                        tx.send((random_id, table)).unwrap();
                     }
-                    found += 1;
-                    let random_id = bucket.random_within();
-                    tx.send(random_id).unwrap();
                 }
                 trace!("iteration {}, refreshing {} buckets", iteration, found);
                 iteration += 1;
@@ -937,11 +1289,9 @@ impl DhtWorker {
         loop {
             tokio::select! {
                 _ = &mut filler => {},
-                random_id = rx.recv() => {
-                    let random_id = random_id.unwrap();
-                    let addrs = self
-                        .dht
-                        .routing_table
+                r = rx.recv() => {
+                    let (random_id, table) = r.unwrap();
+                    let addrs = table
                         .read()
                         .sorted_by_distance_from(random_id)
                         .iter()
@@ -967,13 +1317,15 @@ impl DhtWorker {
             loop {
                 interval.tick().await;
                 let mut found = 0;
-                for node in self.dht.routing_table.read().iter() {
-                    if matches!(
-                        node.status(),
-                        NodeStatus::Questionable | NodeStatus::Unknown
-                    ) {
-                        found += 1;
-                        tx.send((node.id(), node.addr())).unwrap();
+                for table in self.dht.routing_tables() {
+                    for node in table.read().iter() {
+                        if matches!(
+                            node.status(),
+                            NodeStatus::Questionable | NodeStatus::Unknown
+                        ) {
+                            found += 1;
+                            tx.send((node.id(), node.addr())).unwrap();
+                        }
                     }
                 }
                 trace!("iteration {}, pinging {} nodes", iteration, found);
@@ -989,13 +1341,14 @@ impl DhtWorker {
                 r = rx.recv() => {
                     let (id, addr) = r.unwrap();
                     futs.push(async move {
-                        self.dht.routing_table.write().mark_outgoing_request(&id);
+                        let table = self.dht.routing_table_for(addr);
+                        table.write().mark_outgoing_request(&id);
                         match self.dht.request(Request::Ping, addr).await {
                             Ok(_) => {
-                                self.dht.routing_table.write().mark_response(&id);
+                                table.write().mark_response(&id);
                             },
                             Err(e) => {
-                                self.dht.routing_table.write().mark_error(&id);
+                                table.write().mark_error(&id);
                                 debug!("error: {e:?}");
                             }
                         }
@@ -1008,7 +1361,7 @@ impl DhtWorker {
 
     async fn framer(
         &self,
-        socket: &UdpSocket,
+        sockets: &DhtSockets,
         mut input_rx: UnboundedReceiver<WorkerSendRequest>,
         output_tx: Sender<(Message<ByteBufOwned>, SocketAddr)>,
     ) -> anyhow::Result<()> {
@@ -1032,10 +1385,20 @@ impl DhtWorker {
                     message.kind,
                 )
                 .unwrap();
-                if let Err(e) = socket.send_to(&buf, addr).await {
+                let socket = match addr {
+                    SocketAddr::V4(_) => sockets.v4.as_ref(),
+                    SocketAddr::V6(_) => sockets.v6.as_ref(),
+                };
+                let res = match socket {
+                    Some(socket) => socket.send_to(&buf, addr).await.map_err(|e| e.into()),
+                    None => Err(anyhow::anyhow!(
+                        "no socket for the address family of {addr}"
+                    )),
+                };
+                if let Err(e) = res {
                     debug!("error sending to {addr}: {e:?}");
                     if let Some(tid) = our_tid {
-                        self.on_send_error(tid, addr, e.into());
+                        self.on_send_error(tid, addr, e);
                     }
                 }
             }
@@ -1043,32 +1406,41 @@ impl DhtWorker {
                 "DHT UDP socket writer over, nowhere to read messages from"
             ))
         };
-        let reader = async {
-            let mut buf = vec![0u8; 16384];
-            loop {
-                let (size, addr) = socket
-                    .recv_from(&mut buf)
-                    .await
-                    .context("error reading from UDP socket")?;
-                match bprotocol::deserialize_message::<ByteBufOwned>(&buf[..size]) {
-                    Ok(msg) => match output_tx.send((msg, addr)).await {
-                        Ok(_) => {}
-                        Err(_) => break,
-                    },
-                    Err(e) => debug!("{}: error deserializing incoming message: {}", addr, e),
-                }
-            }
-            Err::<(), _>(anyhow::anyhow!(
-                "DHT UDP socket reader over, nowhere to send responses to"
-            ))
-        };
         let result = tokio::select! {
             err = writer => err,
-            err = reader => err,
+            err = Self::reader(sockets.v4.as_ref(), &output_tx) => err,
+            err = Self::reader(sockets.v6.as_ref(), &output_tx) => err,
         };
         result.context("DHT UDP framer closed")
     }
 
+    async fn reader(
+        socket: Option<&UdpSocket>,
+        output_tx: &Sender<(Message<ByteBufOwned>, SocketAddr)>,
+    ) -> anyhow::Result<()> {
+        let socket = match socket {
+            Some(socket) => socket,
+            None => return futures::future::pending().await,
+        };
+        let mut buf = vec![0u8; 16384];
+        loop {
+            let (size, addr) = socket
+                .recv_from(&mut buf)
+                .await
+                .context("error reading from UDP socket")?;
+            match bprotocol::deserialize_message::<ByteBufOwned>(&buf[..size]) {
+                Ok(msg) => match output_tx.send((msg, addr)).await {
+                    Ok(_) => {}
+                    Err(_) => break,
+                },
+                Err(e) => debug!("{}: error deserializing incoming message: {}", addr, e),
+            }
+        }
+        Err::<(), _>(anyhow::anyhow!(
+            "DHT UDP socket reader over, nowhere to send responses to"
+        ))
+    }
+
     async fn start(
         self,
         in_rx: UnboundedReceiver<WorkerSendRequest>,
@@ -1076,7 +1448,7 @@ impl DhtWorker {
     ) -> anyhow::Result<()> {
         let (out_tx, mut out_rx) = channel(1);
         let framer = self
-            .framer(&self.socket, in_rx, out_tx)
+            .framer(&self.sockets, in_rx, out_tx)
             .instrument(debug_span!("dht_framer"));
 
         let bootstrap = self.bootstrap(bootstrap_addrs);
@@ -1134,6 +1506,7 @@ pub struct DhtConfig {
     pub peer_id: Option<Id20>,
     pub bootstrap_addrs: Option<Vec<String>>,
     pub routing_table: Option<RoutingTable>,
+    pub routing_table_v6: Option<RoutingTable>,
     pub listen_addr: Option<SocketAddr>,
     pub peer_store: Option<PeerStore>,
     pub cancellation_token: Option<CancellationToken>,
@@ -1164,6 +1537,28 @@ impl DhtState {
                 .context("cannot determine UDP listen addr")?;
             info!("DHT listening on {:?}", listen_addr);
 
+            // If listening on IPv4, also listen on IPv6 on the same port. Not all hosts have
+            // IPv6, so failing to do so isn't fatal.
+            let sockets = match listen_addr {
+                SocketAddr::V4(v4) => {
+                    let v6 = match bind_udp_v6_only(v4.port()) {
+                        Ok(socket) => Some(socket),
+                        Err(e) => {
+                            warn!("DHT: error listening on IPv6, continuing with IPv4 only: {e:#}");
+                            None
+                        }
+                    };
+                    DhtSockets {
+                        v4: Some(socket),
+                        v6,
+                    }
+                }
+                SocketAddr::V6(_) => DhtSockets {
+                    v4: None,
+                    v6: Some(socket),
+                },
+            };
+
             let peer_id = config.peer_id.unwrap_or_else(generate_peer_id);
             info!("starting up DHT with peer id {:?}", peer_id);
             let bootstrap_addrs = config
@@ -1177,7 +1572,9 @@ impl DhtState {
                 peer_id,
                 in_tx,
                 config.routing_table,
+                config.routing_table_v6,
                 listen_addr,
+                &sockets,
                 config.peer_store.unwrap_or_else(|| PeerStore::new(peer_id)),
                 token,
             ));
@@ -1185,7 +1582,10 @@ impl DhtState {
             spawn_with_cancel(error_span!("dht"), state.cancellation_token.clone(), {
                 let state = state.clone();
                 async move {
-                    let worker = DhtWorker { socket, dht: state };
+                    let worker = DhtWorker {
+                        sockets,
+                        dht: state,
+                    };
                     worker.start(in_rx, &bootstrap_addrs).await
                 }
             });
@@ -1207,6 +1607,89 @@ impl DhtState {
         ))
     }
 
+    /// Get an immutable item (BEP 44), stored under the SHA-1 of its bencoded value.
+    pub async fn get_immutable(
+        self: &Arc<Self>,
+        target: Id20,
+    ) -> anyhow::Result<Option<BencodedValue>> {
+        let responses = RecursiveRequest::get_item(self.clone(), target, None, None).await?;
+        Ok(responses.into_iter().find_map(|r| match r.item {
+            Some(Item::Immutable(v)) => Some(v),
+            _ => None,
+        }))
+    }
+
+    /// Get the newest version of a mutable item (BEP 44). If seq is passed, only a newer item is
+    /// returned.
+    pub async fn get_mutable(
+        self: &Arc<Self>,
+        public_key: [u8; 32],
+        salt: &[u8],
+        seq: Option<i64>,
+    ) -> anyhow::Result<Option<MutableItem>> {
+        let target = mutable_target(&public_key, salt);
+        let responses =
+            RecursiveRequest::get_item(self.clone(), target, seq, Some(salt.to_vec())).await?;
+        Ok(responses
+            .into_iter()
+            .filter_map(|r| match r.item {
+                Some(Item::Mutable(m)) => Some(m),
+                _ => None,
+            })
+            .filter(|m| seq.map_or(true, |seq| m.seq > seq))
+            .max_by_key(|m| m.seq))
+    }
+
+    /// Store an immutable item (BEP 44) on the nodes closest to it. Returns its target.
+    pub async fn put_immutable(self: &Arc<Self>, value: BencodedValue) -> anyhow::Result<Id20> {
+        let target = immutable_target(&value);
+        self.put_item(Item::Immutable(value), None).await?;
+        Ok(target)
+    }
+
+    /// Store a mutable item (BEP 44) on the nodes closest to it. With cas, nodes only replace the
+    /// item if the seq of the one they have is cas. Returns its target.
+    pub async fn put_mutable(
+        self: &Arc<Self>,
+        item: MutableItem,
+        cas: Option<i64>,
+    ) -> anyhow::Result<Id20> {
+        item.verify()?;
+        let target = item.target();
+        self.put_item(Item::Mutable(item), cas).await?;
+        Ok(target)
+    }
+
+    async fn put_item(self: &Arc<Self>, item: Item, cas: Option<i64>) -> anyhow::Result<()> {
+        let target = item.target();
+        let salt = match &item {
+            Item::Immutable(_) => None,
+            Item::Mutable(m) => Some(m.salt.clone()),
+        };
+        let responses = RecursiveRequest::get_item(self.clone(), target, None, salt).await?;
+        let puts = responses.into_iter().take(8).map(|r| {
+            let request = Request::Put {
+                token: r.token,
+                item: item.clone(),
+                cas,
+            };
+            self.request(request, r.addr).map(move |res| (r.addr, res))
+        });
+        let mut stored = 0;
+        for (addr, res) in futures::future::join_all(puts).await {
+            match res {
+                Ok(ResponseOrError::Response(_)) => stored += 1,
+                Ok(ResponseOrError::Error(e)) => debug!("{addr}: error storing item: {e:?}"),
+                Err(e) => debug!("{addr}: error storing item: {e:#}"),
+            }
+        }
+        if stored == 0 {
+            bail!("no node stored the item");
+        }
+        debug!(?target, stored, "stored item");
+        Ok(())
+    }
+
     pub fn listen_addr(&self) -> SocketAddr {
         self.listen_addr
     }
@@ -1222,4 +1705,204 @@ impl DhtState {
     pub fn clone_routing_table(&self) -> RoutingTable {
         self.routing_table.read().clone()
     }
+
+    pub fn with_routing_table_v6<R, F: FnOnce(&RoutingTable) -> R>(&self, f: F) -> R {
+        f(&self.routing_table_v6.read())
+    }
+
+    pub fn clone_routing_table_v6(&self) -> RoutingTable {
+        self.routing_table_v6.read().clone()
+    }
+}
+
+fn bind_udp_v6_only(port: u16) -> anyhow::Result<UdpSocket> {
+    let addr = SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, port));
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV6,
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    // Otherwise on some systems this would conflict with the IPv4 socket on the same port.
+    socket.set_only_v6(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("error binding socket, address {addr}"))?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use bencode::ByteBufOwned;
+    use librqbit_core::hash_id::Id20;
+    use tokio::net::UdpSocket;
+
+    use ed25519_dalek::SigningKey;
+
+    use super::{DhtConfig, DhtState, RecursiveRequest, Request, ResponseOrError};
+    use crate::{
+        bprotocol::{self, BencodedValue, MessageKind, Response},
+        item_store::{Item, MutableItem, PutError},
+        routing_table::RoutingTable,
+    };
+
+    // A node that answers every query with its id and a token, counting "get_peers" queries.
+    async fn spawn_counting_node() -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let get_peers = Arc::new(AtomicUsize::new(0));
+        let counter = get_peers.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 16384];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let msg = bprotocol::deserialize_message::<ByteBufOwned>(&buf[..len]).unwrap();
+                if let MessageKind::GetPeersRequest(_) = msg.kind {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+                let response = Response {
+                    id: Id20::new([1; 20]),
+                    token: Some(ByteBufOwned::from(&b"token"[..])),
+                    ..Default::default()
+                };
+                let mut out = Vec::new();
+                bprotocol::serialize_message(
+                    &mut out,
+                    msg.transaction_id,
+                    None,
+                    None,
+                    MessageKind::Response(response),
+                )
+                .unwrap();
+                socket.send_to(&out, from).await.unwrap();
+            }
+        });
+        (addr, get_peers)
+    }
+
+    #[tokio::test]
+    async fn test_recursive_request_queries_node_once() {
+        let (node, get_peers) = spawn_counting_node().await;
+        let id = Id20::new([3; 20]);
+        let mut routing_table = RoutingTable::new(id, None);
+        routing_table.add_node(Id20::new([1; 20]), node);
+        let dht = DhtState::with_config(DhtConfig {
+            peer_id: Some(id),
+            bootstrap_addrs: Some(vec![node.to_string()]),
+            routing_table: Some(routing_table),
+            listen_addr: Some("127.0.0.1:0".parse().unwrap()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let _peers = dht.get_peers(Id20::new([2; 20]), None).unwrap();
+        // The next round only starts after a few seconds, as there's just one node.
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(get_peers.load(Ordering::Relaxed), 1);
+    }
+
+    async fn spawn_dht(id: Id20, node: (Id20, SocketAddr)) -> Arc<DhtState> {
+        let mut routing_table = RoutingTable::new(id, None);
+        routing_table.add_node(node.0, node.1);
+        DhtState::with_config(DhtConfig {
+            peer_id: Some(id),
+            bootstrap_addrs: Some(vec![node.1.to_string()]),
+            routing_table: Some(routing_table),
+            listen_addr: Some("127.0.0.1:0".parse().unwrap()),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    // Put the item on the node at addr only, returning the BEP 44 error code if it refused it.
+    async fn put_on(
+        dht: &Arc<DhtState>,
+        addr: SocketAddr,
+        item: Item,
+        salt: Option<Vec<u8>>,
+        cas: Option<i64>,
+    ) -> Result<(), i32> {
+        let responses = RecursiveRequest::get_item(dht.clone(), item.target(), None, salt)
+            .await
+            .unwrap();
+        let token = responses
+            .into_iter()
+            .find(|r| r.addr == addr)
+            .expect("no response from the node")
+            .token;
+        let request = Request::Put { token, item, cas };
+        match dht.request(request, addr).await.unwrap() {
+            ResponseOrError::Response(_) => Ok(()),
+            ResponseOrError::Error(e) => Err(e.code),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_get_items_between_nodes() {
+        // "a" only knows a stub node, "b" knows "a", so items put by "b" are stored on "a".
+        let (stub, _) = spawn_counting_node().await;
+        let a_id = Id20::new([4; 20]);
+        let a = spawn_dht(a_id, (Id20::new([1; 20]), stub)).await;
+        let b = spawn_dht(Id20::new([5; 20]), (a_id, a.listen_addr())).await;
+        let a_addr = a.listen_addr();
+
+        let value = BencodedValue::new(&"hello").unwrap();
+        let target = b.put_immutable(value.clone()).await.unwrap();
+        assert_eq!(b.get_immutable(target).await.unwrap(), Some(value));
+
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let salt = b"salt".to_vec();
+        let item = |seq: i64, v: &str| {
+            MutableItem::new_signed(&key, salt.clone(), seq, BencodedValue::new(&v).unwrap())
+                .unwrap()
+        };
+        let put = |item: MutableItem, cas| {
+            put_on(&b, a_addr, Item::Mutable(item), Some(salt.clone()), cas)
+        };
+
+        b.put_mutable(item(2, "two"), None).await.unwrap();
+        let got = b
+            .get_mutable(key.verifying_key().to_bytes(), &salt, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (got.seq, got.value),
+            (2, BencodedValue::new(&"two").unwrap())
+        );
+
+        assert_eq!(
+            put(item(3, "three"), Some(1)).await,
+            Err(PutError::CasMismatch.code())
+        );
+        assert_eq!(
+            put(item(1, "one"), None).await,
+            Err(PutError::SeqTooLow.code())
+        );
+        let mut forged = item(4, "four");
+        forged.value = BencodedValue::new(&"forged").unwrap();
+        assert_eq!(
+            put(forged, None).await,
+            Err(PutError::InvalidSignature.code())
+        );
+        assert_eq!(put(item(3, "three"), Some(2)).await, Ok(()));
+
+        let got = b
+            .get_mutable(key.verifying_key().to_bytes(), &salt, Some(2))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(got.seq, 3);
+    }
 }
@@ -0,0 +1,338 @@
+// BEP 44: storing arbitrary data in the DHT. Immutable items are stored under the SHA-1 of their
+// value, mutable ones under the SHA-1 of their ed25519 public key and salt, and are signed so that
+// only the key owner can update them.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use librqbit_core::hash_id::Id20;
+use parking_lot::RwLock;
+use sha1w::{ISha1, Sha1};
+use tracing::trace;
+
+use crate::bprotocol::BencodedValue;
+
+pub const MAX_SALT_LEN: usize = 64;
+
+// BEP 44 suggests storing items for at least 2 hours, publishers re-put them more often.
+const ITEM_EXPIRY: Duration = Duration::from_secs(2 * 60 * 60);
+
+pub fn immutable_target(value: &BencodedValue) -> Id20 {
+    let mut sha1 = Sha1::new();
+    sha1.update(value.as_bytes());
+    Id20::new(sha1.finish())
+}
+
+pub fn mutable_target(public_key: &[u8; 32], salt: &[u8]) -> Id20 {
+    let mut sha1 = Sha1::new();
+    sha1.update(public_key);
+    sha1.update(salt);
+    Id20::new(sha1.finish())
+}
+
+// What's signed: the bencoded "salt", "seq" and "v" keys, without the surrounding dictionary.
+fn signature_payload(salt: &[u8], seq: i64, value: &BencodedValue) -> Vec<u8> {
+    let mut buf = Vec::new();
+    if !salt.is_empty() {
+        buf.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+        buf.extend_from_slice(salt);
+    }
+    buf.extend_from_slice(format!("3:seqi{seq}e1:v").as_bytes());
+    buf.extend_from_slice(value.as_bytes());
+    buf
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MutableItem {
+    pub public_key: [u8; 32],
+    pub salt: Vec<u8>,
+    pub seq: i64,
+    pub value: BencodedValue,
+    pub signature: [u8; 64],
+}
+
+impl MutableItem {
+    /// To update an item, sign a new value with a higher seq.
+    pub fn new_signed(
+        key: &SigningKey,
+        salt: Vec<u8>,
+        seq: i64,
+        value: BencodedValue,
+    ) -> anyhow::Result<Self> {
+        if salt.len() > MAX_SALT_LEN {
+            bail!(
+                "salt is {} bytes, at most {MAX_SALT_LEN} are allowed",
+                salt.len()
+            );
+        }
+        let signature = key.sign(&signature_payload(&salt, seq, &value));
+        Ok(Self {
+            public_key: key.verifying_key().to_bytes(),
+            salt,
+            seq,
+            value,
+            signature: signature.to_bytes(),
+        })
+    }
+
+    pub(crate) fn from_parts(
+        public_key: &[u8],
+        salt: Option<&[u8]>,
+        seq: i64,
+        value: BencodedValue,
+        signature: &[u8],
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            public_key: public_key
+                .try_into()
+                .context("public key must be 32 bytes")?,
+            salt: salt.unwrap_or_default().to_vec(),
+            seq,
+            value,
+            signature: signature.try_into().context("signature must be 64 bytes")?,
+        })
+    }
+
+    pub fn target(&self) -> Id20 {
+        mutable_target(&self.public_key, &self.salt)
+    }
+
+    pub fn verify(&self) -> anyhow::Result<()> {
+        if self.salt.len() > MAX_SALT_LEN {
+            bail!("salt too big");
+        }
+        let key = VerifyingKey::from_bytes(&self.public_key).context("invalid public key")?;
+        key.verify(
+            &signature_payload(&self.salt, self.seq, &self.value),
+            &Signature::from_bytes(&self.signature),
+        )
+        .context("invalid signature")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Item {
+    Immutable(BencodedValue),
+    Mutable(MutableItem),
+}
+
+impl Item {
+    pub fn target(&self) -> Id20 {
+        match self {
+            Item::Immutable(v) => immutable_target(v),
+            Item::Mutable(m) => m.target(),
+        }
+    }
+}
+
+// Errors for "put" requests, as defined in BEP 44.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PutError {
+    InvalidToken,
+    MalformedItem,
+    ValueTooBig,
+    InvalidSignature,
+    SaltTooBig,
+    CasMismatch,
+    SeqTooLow,
+}
+
+impl PutError {
+    pub fn code(&self) -> i32 {
+        match self {
+            PutError::InvalidToken | PutError::MalformedItem => 203,
+            PutError::ValueTooBig => 205,
+            PutError::InvalidSignature => 206,
+            PutError::SaltTooBig => 207,
+            PutError::CasMismatch => 301,
+            PutError::SeqTooLow => 302,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            PutError::InvalidToken => "invalid token",
+            PutError::MalformedItem => "malformed item",
+            PutError::ValueTooBig => "message (v field) too big",
+            PutError::InvalidSignature => "invalid signature",
+            PutError::SaltTooBig => "salt (salt field) too big",
+            PutError::CasMismatch => "the CAS hash mismatched, re-read value and try again",
+            PutError::SeqTooLow => "sequence number less than current",
+        }
+    }
+}
+
+struct StoredItem {
+    item: Item,
+    time: Instant,
+}
+
+// Items other nodes put on us.
+pub(crate) struct ItemStore {
+    max_items: usize,
+    items: RwLock<HashMap<Id20, StoredItem>>,
+}
+
+impl ItemStore {
+    pub fn new() -> Self {
+        Self {
+            max_items: 1000,
+            items: Default::default(),
+        }
+    }
+
+    pub fn get(&self, target: &Id20) -> Option<Item> {
+        self.items
+            .read()
+            .get(target)
+            .filter(|s| s.time.elapsed() < ITEM_EXPIRY)
+            .map(|s| s.item.clone())
+    }
+
+    pub fn put(&self, item: Item, cas: Option<i64>) -> Result<(), PutError> {
+        let value = match &item {
+            Item::Immutable(v) => v,
+            Item::Mutable(m) => &m.value,
+        };
+        if value.as_bytes().len() > BencodedValue::MAX_LEN {
+            return Err(PutError::ValueTooBig);
+        }
+        if let Item::Mutable(m) = &item {
+            if m.salt.len() > MAX_SALT_LEN {
+                return Err(PutError::SaltTooBig);
+            }
+            if m.verify().is_err() {
+                return Err(PutError::InvalidSignature);
+            }
+        }
+        let target = item.target();
+        let mut items = self.items.write();
+        items.retain(|_, s| s.time.elapsed() < ITEM_EXPIRY);
+        if let (
+            Item::Mutable(new),
+            Some(StoredItem {
+                item: Item::Mutable(old),
+                ..
+            }),
+        ) = (&item, items.get(&target))
+        {
+            if cas.is_some_and(|cas| cas != old.seq) {
+                return Err(PutError::CasMismatch);
+            }
+            if new.seq < old.seq {
+                return Err(PutError::SeqTooLow);
+            }
+        }
+        if !items.contains_key(&target) && items.len() >= self.max_items {
+            // Make room by forgetting the item that was put the longest ago.
+            if let Some(oldest) = items.iter().min_by_key(|(_, s)| s.time).map(|(t, _)| *t) {
+                trace!(item = ?oldest, "item store: out of capacity, forgetting oldest");
+                items.remove(&oldest);
+            }
+        }
+        items.insert(
+            target,
+            StoredItem {
+                item,
+                time: Instant::now(),
+            },
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+    use librqbit_core::hash_id::Id20;
+
+    use super::{immutable_target, Item, ItemStore, MutableItem, PutError};
+    use crate::bprotocol::BencodedValue;
+
+    fn hello() -> BencodedValue {
+        BencodedValue::from_bencoded(b"12:Hello World!".to_vec()).unwrap()
+    }
+
+    fn from_hex<const N: usize>(s: &str) -> [u8; N] {
+        hex::decode(s).unwrap().try_into().unwrap()
+    }
+
+    // Test vectors from BEP 44.
+    #[test]
+    fn test_bep44_vectors() {
+        assert_eq!(
+            immutable_target(&hello()),
+            Id20::new(from_hex("e5f96f6f38320f0f33959cb4d3d656452117aadb"))
+        );
+
+        let public_key =
+            from_hex("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548");
+        let item = MutableItem {
+            public_key,
+            salt: Vec::new(),
+            seq: 1,
+            value: hello(),
+            signature: from_hex("305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01"),
+        };
+        item.verify().unwrap();
+        assert_eq!(
+            item.target(),
+            Id20::new(from_hex("4a533d47ec9c7d95b1ad75f576cffc641853b750"))
+        );
+
+        let item = MutableItem {
+            salt: b"foobar".to_vec(),
+            signature: from_hex("6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17ddf9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08"),
+            ..item
+        };
+        item.verify().unwrap();
+        assert_eq!(
+            item.target(),
+            Id20::new(from_hex("411eba73b6f087ca51a3795d9c8c938d365e32c1"))
+        );
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let item = MutableItem::new_signed(&key, b"salt".to_vec(), 3, hello()).unwrap();
+        item.verify().unwrap();
+
+        let tampered = MutableItem { seq: 4, ..item };
+        assert!(tampered.verify().is_err());
+    }
+
+    #[test]
+    fn test_item_store() {
+        let store = ItemStore::new();
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let item =
+            |seq| Item::Mutable(MutableItem::new_signed(&key, Vec::new(), seq, hello()).unwrap());
+
+        store.put(item(2), None).unwrap();
+        assert_eq!(store.put(item(1), None), Err(PutError::SeqTooLow));
+        assert_eq!(store.put(item(3), Some(1)), Err(PutError::CasMismatch));
+        store.put(item(3), Some(2)).unwrap();
+        match store.get(&item(0).target()) {
+            Some(Item::Mutable(m)) => assert_eq!(m.seq, 3),
+            other => panic!("unexpected {other:?}"),
+        }
+
+        let mut bad = match item(4) {
+            Item::Mutable(m) => m,
+            _ => unreachable!(),
+        };
+        bad.signature[0] ^= 1;
+        assert_eq!(
+            store.put(Item::Mutable(bad), None),
+            Err(PutError::InvalidSignature)
+        );
+
+        store.put(Item::Immutable(hello()), None).unwrap();
+        assert!(store.get(&immutable_target(&hello())).is_some());
+    }
+}
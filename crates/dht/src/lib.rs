@@ -1,5 +1,6 @@
 mod bprotocol;
 mod dht;
+mod item_store;
 mod peer_store;
 mod persistence;
 mod routing_table;
@@ -8,8 +9,11 @@ mod utils;
 use std::sync::Arc;
 use std::time::Duration;
 
+pub use crate::bprotocol::BencodedValue;
 pub use crate::dht::DhtStats;
 pub use crate::dht::{DhtConfig, DhtState, RequestPeersStream};
+pub use ed25519_dalek::SigningKey;
+pub use item_store::{immutable_target, mutable_target, MutableItem, MAX_SALT_LEN};
 pub use librqbit_core::hash_id::Id20;
 pub use persistence::{PersistentDht, PersistentDhtConfig};
 
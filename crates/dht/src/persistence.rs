@@ -29,6 +29,7 @@ pub struct PersistentDhtConfig {
 struct DhtSerialize<Table, PeerStore> {
     addr: SocketAddr,
     table: Table,
+    table_v6: Option<Table>,
     peer_store: Option<PeerStore>,
 }
 
@@ -47,14 +48,17 @@ fn dump_dht(dht: &Dht, filename: &Path, tempfile_name: &Path) -> anyhow::Result<
 
     let addr = dht.listen_addr();
     match dht.with_routing_table(|r| {
-        serde_json::to_writer(
-            &mut file,
-            &DhtSerialize {
-                addr,
-                table: r,
-                peer_store: Some(&dht.peer_store),
-            },
-        )
+        dht.with_routing_table_v6(|r6| {
+            serde_json::to_writer(
+                &mut file,
+                &DhtSerialize {
+                    addr,
+                    table: r,
+                    table_v6: Some(r6),
+                    peer_store: Some(&dht.peer_store),
+                },
+            )
+        })
     }) {
         Ok(_) => {
             trace!("dumped DHT to {:?}", &tempfile_name);
@@ -126,14 +130,15 @@ impl PersistentDht {
                     }
                 },
             };
-            let (listen_addr, routing_table, peer_store) = de
-                .map(|de| (Some(de.addr), Some(de.table), de.peer_store))
-                .unwrap_or((None, None, None));
+            let (listen_addr, routing_table, routing_table_v6, peer_store) = de
+                .map(|de| (Some(de.addr), Some(de.table), de.table_v6, de.peer_store))
+                .unwrap_or((None, None, None, None));
             let peer_id = routing_table.as_ref().map(|r| r.id());
 
             let dht_config = DhtConfig {
                 peer_id,
                 routing_table,
+                routing_table_v6,
                 listen_addr,
                 peer_store,
                 cancellation_token,
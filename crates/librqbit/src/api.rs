@@ -1,4 +1,9 @@
-use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+};
 
 use anyhow::Context;
 use buffers::ByteBufOwned;
@@ -8,20 +13,28 @@ use http::StatusCode;
 use librqbit_core::torrent_metainfo::TorrentMetaV1Info;
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::UnboundedSender;
-use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
-use tracing::warn;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamExt,
+};
+use tracing::{debug, warn};
 
 use crate::{
     api_error::{ApiError, ApiErrorExt},
+    events::TorrentEvent,
+    file_info::FilePriority,
+    limits::RateLimitsConfig,
+    playlist::{make_playlist, PlaylistFormat},
     session::{
         AddTorrent, AddTorrentOptions, AddTorrentResponse, ListOnlyResponse, Session, TorrentId,
     },
     torrent_state::{
         peer::stats::snapshot::{PeerStatsFilter, PeerStatsSnapshot},
-        FileStream, ManagedTorrentHandle,
+        FileStream, ManagedTorrentHandle, StreamOptions,
     },
     tracing_subscriber_config_utils::LineBroadcast,
 };
+use tracker_comms::{TrackerComms, TrackerScrape, TrackerStatus};
 
 pub use crate::torrent_state::stats::{LiveStats, TorrentStats};
 
@@ -122,6 +135,19 @@ impl Api {
         Ok(Default::default())
     }
 
+    pub async fn api_torrent_action_move_storage(
+        &self,
+        idx: TorrentId,
+        output_folder: PathBuf,
+    ) -> Result<EmptyJsonResponse> {
+        self.session
+            .move_storage(idx, output_folder)
+            .await
+            .context("error moving torrent")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        Ok(Default::default())
+    }
+
     pub fn api_torrent_action_update_only_files(
         &self,
         idx: TorrentId,
@@ -134,6 +160,47 @@ impl Api {
         Ok(Default::default())
     }
 
+    pub fn api_torrent_get_file_priorities(&self, idx: TorrentId) -> Result<Vec<FilePriority>> {
+        let handle = self.mgr_handle(idx)?;
+        Ok(handle.file_priorities())
+    }
+
+    pub fn api_torrent_action_update_file_priorities(
+        &self,
+        idx: TorrentId,
+        file_priorities: Vec<FilePriority>,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        self.session
+            .update_file_priorities(&handle, file_priorities)
+            .context("error updating file priorities")?;
+        Ok(Default::default())
+    }
+
+    pub fn api_torrent_get_limits(&self, idx: TorrentId) -> Result<RateLimitsConfig> {
+        let handle = self.mgr_handle(idx)?;
+        Ok(handle.ratelimits())
+    }
+
+    pub fn api_torrent_action_update_limits(
+        &self,
+        idx: TorrentId,
+        config: RateLimitsConfig,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        handle.set_ratelimits(config);
+        Ok(Default::default())
+    }
+
+    pub fn api_get_limits(&self) -> RateLimitsConfig {
+        self.session.ratelimits()
+    }
+
+    pub fn api_update_limits(&self, config: RateLimitsConfig) -> EmptyJsonResponse {
+        self.session.set_ratelimits(config);
+        Default::default()
+    }
+
     pub fn api_set_rust_log(&self, new_value: String) -> Result<EmptyJsonResponse> {
         let tx = self
             .rust_log_reload_tx
@@ -159,6 +226,18 @@ impl Api {
             .context("line_rx wasn't set")?)
     }
 
+    /// Lifecycle events of all torrents, as they happen. Events missed by falling behind are
+    /// skipped.
+    pub fn api_events_stream(&self) -> impl Stream<Item = TorrentEvent> + Send + Sync + 'static {
+        BroadcastStream::new(self.session.subscribe_events()).filter_map(|r| match r {
+            Ok(event) => Some(event),
+            Err(BroadcastStreamRecvError::Lagged(n)) => {
+                debug!(missed = n, "events subscriber is lagging");
+                None
+            }
+        })
+    }
+
     pub async fn api_add_torrent(
         &self,
         add: AddTorrent<'_>,
@@ -203,12 +282,7 @@ impl Api {
                     id: Some(id),
                     details,
                     seen_peers: None,
-                    output_folder: handle
-                        .info()
-                        .options
-                        .output_folder
-                        .to_string_lossy()
-                        .into_owned(),
+                    output_folder: handle.info().output_folder().to_string_lossy().into_owned(),
                 }
             }
         };
@@ -239,14 +313,114 @@ impl Api {
         Ok(mgr.stats())
     }
 
+    pub fn api_torrent_trackers(&self, idx: TorrentId) -> Result<Vec<TrackerStatus>> {
+        let handle = self.mgr_handle(idx)?;
+        Ok(handle.info().trackers.status())
+    }
+
+    /// Without a tier, the tracker is added in a new last tier.
+    pub fn api_torrent_action_add_tracker(
+        &self,
+        idx: TorrentId,
+        url: String,
+        tier: Option<usize>,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        let added = handle
+            .info()
+            .trackers
+            .add(url, tier)
+            .context("error adding tracker")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        if !added {
+            return Err(ApiError::new_from_text(
+                StatusCode::CONFLICT,
+                "tracker already added",
+            ));
+        }
+        Ok(Default::default())
+    }
+
+    pub fn api_torrent_action_remove_tracker(
+        &self,
+        idx: TorrentId,
+        url: &str,
+    ) -> Result<EmptyJsonResponse> {
+        let handle = self.mgr_handle(idx)?;
+        if !handle.info().trackers.remove(url) {
+            return Err(ApiError::new_from_text(
+                StatusCode::NOT_FOUND,
+                "no such tracker",
+            ));
+        }
+        Ok(Default::default())
+    }
+
+    /// Like "list_only", but only asks the trackers about the torrent's swarm. Magnet links
+    /// don't need to be resolved first.
+    pub async fn api_scrape(&self, add: AddTorrent<'_>) -> Result<ApiScrapeResponse> {
+        let (info_hash, scrapes) = self
+            .session
+            .scrape(add)
+            .await
+            .context("error scraping")
+            .with_error_status_code(StatusCode::BAD_REQUEST)?;
+        Ok(make_scrape_response(&info_hash, scrapes))
+    }
+
+    pub async fn api_torrent_scrape(&self, idx: TorrentId) -> Result<ApiScrapeResponse> {
+        let handle = self.mgr_handle(idx)?;
+        let info_hash = handle.info_hash();
+        let trackers = handle.info().trackers.urls();
+        if trackers.is_empty() {
+            return Err(anyhow::anyhow!("torrent has no trackers"))
+                .with_error_status_code(StatusCode::BAD_REQUEST);
+        }
+        let scrapes = TrackerComms::scrape(trackers, &[info_hash]).await;
+        Ok(make_scrape_response(&info_hash, scrapes))
+    }
+
+    /// A tracker announce, given its query string. Returns the bencoded tracker response.
+    pub fn api_tracker_announce(&self, query: &str, remote_ip: IpAddr) -> Result<Vec<u8>> {
+        let tracker = self.session.tracker().ok_or(ApiError::tracker_disabled())?;
+        Ok(tracker.http_announce(query, remote_ip))
+    }
+
+    pub fn api_tracker_scrape(&self, query: &str) -> Result<Vec<u8>> {
+        let tracker = self.session.tracker().ok_or(ApiError::tracker_disabled())?;
+        Ok(tracker.http_scrape(query))
+    }
+
     pub fn api_dump_haves(&self, idx: usize) -> Result<String> {
         let mgr = self.mgr_handle(idx)?;
         Ok(mgr.with_chunk_tracker(|chunks| format!("{:?}", chunks.get_have_pieces()))?)
     }
 
-    pub fn api_stream(&self, idx: TorrentId, file_id: usize) -> Result<FileStream> {
+    /// A playlist of the media files of a torrent, or of all torrents if idx is None. Stream
+    /// URLs start with base_url, e.g. "http://192.168.1.2:3030".
+    pub fn api_playlist(
+        &self,
+        idx: Option<TorrentId>,
+        format: PlaylistFormat,
+        base_url: &str,
+    ) -> Result<String> {
+        let torrents = match idx {
+            Some(idx) => vec![(idx, self.mgr_handle(idx)?)],
+            None => self
+                .session
+                .with_torrents(|torrents| torrents.map(|(id, mgr)| (id, mgr.clone())).collect()),
+        };
+        Ok(make_playlist(format, torrents, base_url))
+    }
+
+    pub fn api_stream(
+        &self,
+        idx: TorrentId,
+        file_id: usize,
+        opts: StreamOptions,
+    ) -> Result<FileStream> {
         let mgr = self.mgr_handle(idx)?;
-        Ok(mgr.stream(file_id)?)
+        Ok(mgr.stream_with_options(file_id, opts)?)
     }
 }
 
@@ -287,6 +461,45 @@ pub struct ApiAddTorrentResponse {
     pub seen_peers: Option<Vec<SocketAddr>>,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct TrackerScrapeResponse {
+    pub url: String,
+    // These are missing if the tracker doesn't know the torrent.
+    pub complete: Option<u64>,
+    pub incomplete: Option<u64>,
+    pub downloaded: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ApiScrapeResponse {
+    pub info_hash: String,
+    pub trackers: Vec<TrackerScrapeResponse>,
+}
+
+fn make_scrape_response(info_hash: &Id20, scrapes: Vec<TrackerScrape>) -> ApiScrapeResponse {
+    let trackers = scrapes
+        .into_iter()
+        .map(|scrape| {
+            let (stats, error) = match scrape.result {
+                Ok(files) => (files.get(info_hash).copied(), None),
+                Err(e) => (None, Some(format!("{e:#}"))),
+            };
+            TrackerScrapeResponse {
+                url: scrape.url,
+                complete: stats.map(|s| s.complete),
+                incomplete: stats.map(|s| s.incomplete),
+                downloaded: stats.map(|s| s.downloaded),
+                error,
+            }
+        })
+        .collect();
+    ApiScrapeResponse {
+        info_hash: info_hash.as_string(),
+        trackers,
+    }
+}
+
 fn make_torrent_details(
     info_hash: &Id20,
     info: &TorrentMetaV1Info<ByteBufOwned>,
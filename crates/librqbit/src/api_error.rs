@@ -52,6 +52,10 @@ impl ApiError {
         }
     }
 
+    pub const fn tracker_disabled() -> Self {
+        Self::new_from_text(StatusCode::NOT_FOUND, "tracker is disabled")
+    }
+
     pub fn status(&self) -> StatusCode {
         self.status.unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
     }
@@ -6,8 +6,8 @@ use peer_binary_protocol::Piece;
 use tracing::{debug, trace};
 
 use crate::{
-    file_info::FileInfo,
-    type_aliases::{FileInfos, FilePriorities, BF},
+    file_info::{FileInfo, FilePriority},
+    type_aliases::{FileInfos, FileOrder, BF},
 };
 
 pub struct ChunkTracker {
@@ -32,6 +32,9 @@ pub struct ChunkTracker {
     // was called.
     selected: BF,
 
+    // Per-file priorities. Files with FilePriority::Skip are the ones that aren't selected.
+    file_priorities: Vec<FilePriority>,
+
     // How many bytes do we have per each file.
     per_file_bytes: Vec<u64>,
 
@@ -136,6 +139,7 @@ impl ChunkTracker {
         selected_pieces: BF,
         lengths: Lengths,
         file_infos: &FileInfos,
+        file_priorities: Vec<FilePriority>,
     ) -> anyhow::Result<Self> {
         let needed_pieces = compute_queued_pieces(&have_pieces, &selected_pieces)
             .context("error computing needed pieces")?;
@@ -148,6 +152,7 @@ impl ChunkTracker {
                 .context("error computing chunk status")?,
             queue_pieces: needed_pieces,
             selected: selected_pieces,
+            file_priorities,
             lengths,
             have: have_pieces,
             hns: HaveNeededSelected::default(),
@@ -203,19 +208,50 @@ impl ChunkTracker {
         hns
     }
 
+    // Pieces that need to be downloaded, from files with higher priority first. Files of the same
+    // priority are visited in "file_order".
     pub(crate) fn iter_queued_pieces<'a>(
         &'a self,
-        file_priorities: &'a FilePriorities,
+        file_order: &'a FileOrder,
         file_infos: &'a FileInfos,
     ) -> impl Iterator<Item = ValidPieceIndex> + 'a {
-        file_priorities
-            .iter()
-            .filter_map(|p| Some((*p, file_infos.get(*p)?)))
-            .filter(|(id, f)| self.per_file_bytes[*id] != f.len)
-            .flat_map(|(_id, f)| f.iter_piece_priorities())
-            .filter(|id| self.queue_pieces[*id])
-            .filter_map(|id| id.try_into().ok())
-            .filter_map(|id| self.lengths.validate_piece_index(id))
+        self.iter_queued_pieces_with_priority(file_order, file_infos)
+            .map(|(_, id)| id)
+    }
+
+    // Same as iter_queued_pieces(), but also yields the priority of the file each piece is from.
+    pub(crate) fn iter_queued_pieces_with_priority<'a>(
+        &'a self,
+        file_order: &'a FileOrder,
+        file_infos: &'a FileInfos,
+    ) -> impl Iterator<Item = (FilePriority, ValidPieceIndex)> + 'a {
+        FilePriority::DOWNLOAD_ORDER
+            .into_iter()
+            .flat_map(move |priority| {
+                file_order
+                    .iter()
+                    .filter(move |id| self.file_priorities.get(**id) == Some(&priority))
+                    .map(move |id| (priority, *id))
+            })
+            .filter_map(|(priority, id)| Some((priority, id, file_infos.get(id)?)))
+            .filter(|(_, id, f)| self.per_file_bytes[*id] != f.len)
+            .flat_map(|(priority, _, f)| f.iter_piece_priorities().map(move |id| (priority, id)))
+            .filter(|(_, id)| self.queue_pieces[*id])
+            .filter_map(|(priority, id)| {
+                let id = self.lengths.validate_piece_index(id.try_into().ok()?)?;
+                Some((priority, id))
+            })
+    }
+
+    pub(crate) fn has_queued_pieces(&self) -> bool {
+        self.queue_pieces.any()
+    }
+
+    pub(crate) fn is_chunk_downloaded(&self, chunk: &ChunkInfo) -> bool {
+        self.chunk_status
+            .get(chunk.absolute_index as usize)
+            .map(|b| *b)
+            .unwrap_or(false)
     }
 
     pub(crate) fn is_piece_have(&self, id: ValidPieceIndex) -> bool {
@@ -307,6 +343,11 @@ impl ChunkTracker {
         );
 
         if chunk_range.all() {
+            // The piece might have been queued again while someone was still downloading it,
+            // e.g. when a peer that previously owned it died. Nobody must reserve it from now on,
+            // otherwise they would overwrite it. If the checksum fails it's queued again.
+            self.queue_pieces
+                .set(chunk_info.piece_index.get() as usize, false);
             return Some(ChunkMarkingResult::Completed);
         }
         Some(ChunkMarkingResult::NotCompleted)
@@ -317,10 +358,13 @@ impl ChunkTracker {
     // sense, they will be ignored.
     pub fn update_only_files(
         &mut self,
-        file_lengths_iterator: impl IntoIterator<Item = u64>,
+        // (offset, length) of each file.
+        files: impl IntoIterator<Item = (u64, u64)>,
         // TODO: maybe make this a BF
         new_only_files: &HashSet<usize>,
     ) -> anyhow::Result<HaveNeededSelected> {
+        FilePriority::apply_only_files(&mut self.file_priorities, new_only_files);
+
         let mut piece_it = self.lengths.iter_piece_infos();
         let mut current_piece = piece_it
             .next()
@@ -331,9 +375,21 @@ impl ChunkTracker {
         let mut selected_bytes = 0u64;
         let mut needed_bytes = 0u64;
 
-        for (idx, len) in file_lengths_iterator.into_iter().enumerate() {
-            let file_required = new_only_files.contains(&idx);
+        // Padding between aligned files (v2 torrents) doesn't belong to any file.
+        let mut segments = Vec::new();
+        let mut pos = 0u64;
+        for (idx, (offset, len)) in files.into_iter().enumerate() {
+            if offset > pos {
+                segments.push((false, offset - pos));
+            }
+            segments.push((new_only_files.contains(&idx), len));
+            pos = pos.max(offset + len);
+        }
+        if self.lengths.total_length() > pos {
+            segments.push((false, self.lengths.total_length() - pos));
+        }
 
+        for (file_required, len) in segments {
             let mut remaining_file_len = len;
 
             while remaining_file_len > 0 {
@@ -393,6 +449,21 @@ impl ChunkTracker {
         Ok(res)
     }
 
+    // NOTE: this doesn't validate the length of new_priorities.
+    pub fn update_file_priorities(
+        &mut self,
+        // (offset, length) of each file.
+        files: impl IntoIterator<Item = (u64, u64)>,
+        new_priorities: Vec<FilePriority>,
+    ) -> anyhow::Result<HaveNeededSelected> {
+        let only_files = FilePriority::only_files(&new_priorities)
+            .into_iter()
+            .collect();
+        let hns = self.update_only_files(files, &only_files)?;
+        self.file_priorities = new_priorities;
+        Ok(hns)
+    }
+
     pub(crate) fn get_selected_pieces(&self) -> &BF {
         &self.selected
     }
@@ -434,8 +505,13 @@ mod tests {
     use std::collections::HashSet;
 
     use librqbit_core::{constants::CHUNK_SIZE, lengths::Lengths};
+    use peer_binary_protocol::Piece;
 
-    use crate::{chunk_tracker::HaveNeededSelected, type_aliases::BF};
+    use crate::{
+        chunk_tracker::{ChunkMarkingResult, HaveNeededSelected},
+        file_info::{FileInfo, FilePriority},
+        type_aliases::BF,
+    };
 
     use super::{compute_chunk_have_status, ChunkTracker};
 
@@ -537,6 +613,17 @@ mod tests {
         }
     }
 
+    fn with_offsets(lengths: &[u64]) -> Vec<(u64, u64)> {
+        lengths
+            .iter()
+            .scan(0, |offset, len| {
+                let file = (*offset, *len);
+                *offset += len;
+                Some(file)
+            })
+            .collect()
+    }
+
     #[test]
     fn test_update_only_files() {
         let piece_len = CHUNK_SIZE * 2 + 1;
@@ -545,12 +632,12 @@ mod tests {
         assert_eq!(l.total_pieces(), 3);
         assert_eq!(l.total_chunks(), 7);
 
-        let all_files = [
+        let all_files = with_offsets(&[
             piece_len as u64, // piece 0 and boundary
             1,                // piece 1
             0,                // piece 1 (or none)
             piece_len as u64, // piece 1 and 2
-        ];
+        ]);
 
         let bf_len = l.piece_bitfield_bytes();
         let initial_have = BF::from_boxed_slice(vec![0u8; bf_len].into_boxed_slice());
@@ -562,12 +649,13 @@ mod tests {
             initial_selected.clone(),
             l,
             &Default::default(),
+            Vec::new(),
         )
         .unwrap();
 
         // Select all file, no changes.
         assert_eq!(
-            ct.update_only_files(all_files.into_iter(), &HashSet::from_iter([0, 1, 2, 3]))
+            ct.update_only_files(all_files.iter().copied(), &HashSet::from_iter([0, 1, 2, 3]))
                 .unwrap(),
             HaveNeededSelected {
                 have_bytes: 0,
@@ -581,12 +669,12 @@ mod tests {
         // Select only the first file.
         println!("Select only the first file.");
         assert_eq!(
-            ct.update_only_files(all_files, &HashSet::from_iter([0]))
+            ct.update_only_files(all_files.iter().copied(), &HashSet::from_iter([0]))
                 .unwrap(),
             HaveNeededSelected {
                 have_bytes: 0,
-                selected_bytes: all_files[0],
-                needed_bytes: all_files[0],
+                selected_bytes: all_files[0].1,
+                needed_bytes: all_files[0].1,
             }
         );
         assert!(ct.queue_pieces[0]);
@@ -595,7 +683,7 @@ mod tests {
 
         // Select only the second file.
         assert_eq!(
-            ct.update_only_files(all_files, &HashSet::from_iter([1]))
+            ct.update_only_files(all_files.iter().copied(), &HashSet::from_iter([1]))
                 .unwrap(),
             HaveNeededSelected {
                 have_bytes: 0,
@@ -609,7 +697,7 @@ mod tests {
 
         // Select only the third file (zero sized one!).
         assert_eq!(
-            ct.update_only_files(all_files, &HashSet::from_iter([2]))
+            ct.update_only_files(all_files.iter().copied(), &HashSet::from_iter([2]))
                 .unwrap(),
             HaveNeededSelected {
                 have_bytes: 0,
@@ -623,7 +711,7 @@ mod tests {
 
         // Select only the fourth file.
         assert_eq!(
-            ct.update_only_files(all_files, &HashSet::from_iter([3]))
+            ct.update_only_files(all_files.iter().copied(), &HashSet::from_iter([3]))
                 .unwrap(),
             HaveNeededSelected {
                 have_bytes: 0,
@@ -637,12 +725,12 @@ mod tests {
 
         // Select first and last file
         assert_eq!(
-            ct.update_only_files(all_files, &HashSet::from_iter([0, 3]))
+            ct.update_only_files(all_files.iter().copied(), &HashSet::from_iter([0, 3]))
                 .unwrap(),
             HaveNeededSelected {
                 have_bytes: 0,
-                selected_bytes: all_files[0] + all_files[3] + 1,
-                needed_bytes: all_files[0] + all_files[3] + 1,
+                selected_bytes: all_files[0].1 + all_files[3].1 + 1,
+                needed_bytes: all_files[0].1 + all_files[3].1 + 1,
             }
         );
         assert!(ct.queue_pieces[0]);
@@ -651,7 +739,7 @@ mod tests {
 
         // Select all files
         assert_eq!(
-            ct.update_only_files(all_files, &HashSet::from_iter([0, 1, 2, 3]))
+            ct.update_only_files(all_files.iter().copied(), &HashSet::from_iter([0, 1, 2, 3]))
                 .unwrap(),
             HaveNeededSelected {
                 have_bytes: 0,
@@ -663,4 +751,139 @@ mod tests {
         assert!(ct.queue_pieces[1]);
         assert!(ct.queue_pieces[2]);
     }
+
+    #[test]
+    fn test_update_only_files_with_padding() {
+        // Two files aligned to piece boundaries, the padding doesn't select anything.
+        let piece_len = CHUNK_SIZE;
+        let l = Lengths::new(piece_len as u64 * 3, piece_len).unwrap();
+        let files = [(0, 10), (piece_len as u64, piece_len as u64 + 1)];
+
+        let bf_len = l.piece_bitfield_bytes();
+        let mut ct = ChunkTracker::new(
+            BF::from_boxed_slice(vec![0u8; bf_len].into_boxed_slice()),
+            BF::from_boxed_slice(vec![u8::MAX; bf_len].into_boxed_slice()),
+            l,
+            &Default::default(),
+            Vec::new(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            ct.update_only_files(files, &HashSet::from_iter([0]))
+                .unwrap(),
+            HaveNeededSelected {
+                have_bytes: 0,
+                selected_bytes: piece_len as u64,
+                needed_bytes: piece_len as u64,
+            }
+        );
+        assert!(ct.queue_pieces[0]);
+        assert!(!ct.queue_pieces[1]);
+        assert!(!ct.queue_pieces[2]);
+
+        assert_eq!(
+            ct.update_only_files(files, &HashSet::from_iter([1]))
+                .unwrap()
+                .selected_bytes,
+            piece_len as u64 * 2
+        );
+        assert!(!ct.queue_pieces[0]);
+        assert!(ct.queue_pieces[1]);
+        assert!(ct.queue_pieces[2]);
+    }
+
+    #[test]
+    fn test_completed_piece_is_not_queued() {
+        let piece_len = CHUNK_SIZE * 2;
+        let l = Lengths::new(piece_len as u64, piece_len).unwrap();
+        let bf_len = l.piece_bitfield_bytes();
+        let mut ct = ChunkTracker::new(
+            BF::from_boxed_slice(vec![0u8; bf_len].into_boxed_slice()),
+            BF::from_boxed_slice(vec![u8::MAX; bf_len].into_boxed_slice()),
+            l,
+            &Default::default(),
+            Vec::new(),
+        )
+        .unwrap();
+        let index = l.validate_piece_index(0).unwrap();
+        let block = vec![0u8; CHUNK_SIZE as usize];
+        let chunk = |begin| Piece {
+            index: 0,
+            begin,
+            block: &block[..],
+        };
+
+        ct.reserve_needed_piece(index);
+        assert!(matches!(
+            ct.mark_chunk_downloaded(&chunk(0)),
+            Some(ChunkMarkingResult::NotCompleted)
+        ));
+
+        // A request for the piece was cancelled while the piece is still being downloaded.
+        assert_eq!(ct.mark_chunk_request_cancelled(index, 1), Some(true));
+        assert!(ct.queue_pieces[0]);
+
+        assert!(matches!(
+            ct.mark_chunk_downloaded(&chunk(CHUNK_SIZE)),
+            Some(ChunkMarkingResult::Completed)
+        ));
+        assert!(!ct.queue_pieces[0]);
+
+        // Checksum failed.
+        ct.mark_piece_broken_if_not_have(index);
+        assert!(ct.queue_pieces[0]);
+    }
+
+    #[test]
+    fn test_iter_queued_pieces_by_priority() {
+        use FilePriority::*;
+
+        // 4 files, 2 pieces each.
+        let piece_len = CHUNK_SIZE;
+        let l = Lengths::new(piece_len as u64 * 8, piece_len).unwrap();
+        let file_infos = (0u32..4)
+            .map(|i| FileInfo {
+                relative_filename: format!("{i}").into(),
+                offset_in_torrent: (piece_len * 2 * i) as u64,
+                piece_range: i * 2..i * 2 + 2,
+                len: piece_len as u64 * 2,
+            })
+            .collect::<Vec<_>>();
+        let files = file_infos
+            .iter()
+            .map(|f| (f.offset_in_torrent, f.len))
+            .collect::<Vec<_>>();
+
+        let bf_len = l.piece_bitfield_bytes();
+        let mut ct = ChunkTracker::new(
+            BF::from_boxed_slice(vec![0u8; bf_len].into_boxed_slice()),
+            BF::from_boxed_slice(vec![u8::MAX; bf_len].into_boxed_slice()),
+            l,
+            &file_infos,
+            vec![Normal; 4],
+        )
+        .unwrap();
+
+        let order = vec![0, 1, 2, 3];
+        let queued = |ct: &ChunkTracker| {
+            ct.iter_queued_pieces(&order, &file_infos)
+                .map(|p| p.get())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(queued(&ct), vec![0, 1, 2, 3, 4, 5, 6, 7]);
+
+        let hns = ct
+            .update_file_priorities(files.iter().copied(), vec![Low, Skip, High, Normal])
+            .unwrap();
+        assert_eq!(hns.selected_bytes, piece_len as u64 * 6);
+        assert_eq!(queued(&ct), vec![4, 5, 6, 7, 0, 1]);
+        assert!(!ct.queue_pieces[2]);
+
+        // Deselecting keeps the priority of the files that are still selected.
+        ct.update_only_files(files.iter().copied(), &HashSet::from_iter([0, 2]))
+            .unwrap();
+        assert_eq!(ct.file_priorities, vec![Low, Skip, High, Skip]);
+        assert_eq!(queued(&ct), vec![4, 5, 0, 1]);
+    }
 }
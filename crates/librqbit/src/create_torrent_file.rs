@@ -138,7 +138,11 @@ async fn create_torrent_raw<'a>(
                     .components()
                     .map(|c| osstr_to_bytes(c.as_os_str()).into())
                     .collect();
-                output_files.push(TorrentMetaV1File { length, path });
+                output_files.push(TorrentMetaV1File {
+                    length,
+                    path,
+                    attr: None,
+                });
                 continue 'outer;
             }
 
@@ -159,7 +163,7 @@ async fn create_torrent_raw<'a>(
     }
     Ok(TorrentMetaV1Info {
         name: Some(name),
-        pieces: piece_hashes.into(),
+        pieces: Some(piece_hashes.into()),
         piece_length,
         length: if single_file_mode { Some(length) } else { None },
         md5sum: None,
@@ -168,6 +172,9 @@ async fn create_torrent_raw<'a>(
         } else {
             Some(output_files)
         },
+        private: None,
+        meta_version: None,
+        file_tree: None,
     })
 }
 
@@ -209,7 +216,10 @@ pub async fn create_torrent<'a>(
             publisher: None,
             publisher_url: None,
             creation_date: None,
+            url_list: Vec::new(),
+            piece_layers: None,
             info_hash,
+            info_hash_v2: None,
         },
     })
 }
@@ -0,0 +1,45 @@
+// A tracker served by the session, so that torrents created and seeded with rqbit don't depend
+// on a third-party tracker. HTTP announces go through the HTTP API, UDP ones through a separate
+// socket.
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Weak},
+};
+
+use tracker_comms::{InfoHashFilter, TrackerServer, TrackerServerOptions};
+
+use crate::Session;
+
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddedTrackerOptions {
+    /// Serve UDP tracker requests (BEP 15) on this address. HTTP requests are served by the HTTP
+    /// API on /announce and /scrape.
+    pub udp_listen_addr: Option<SocketAddr>,
+    /// Only track torrents that are in the session. Otherwise anyone can use the tracker for any
+    /// torrent.
+    pub only_session_torrents: bool,
+    pub server: TrackerServerOptions,
+}
+
+pub(crate) fn session_torrents_filter(session: Weak<Session>) -> InfoHashFilter {
+    Box::new(move |info_hash| {
+        let session = match session.upgrade() {
+            Some(s) => s,
+            None => return false,
+        };
+        session.with_torrents(|torrents| {
+            torrents
+                .flat_map(|(_, t)| t.swarm_info_hashes())
+                .any(|h| h == *info_hash)
+        })
+    })
+}
+
+pub(crate) async fn expire_peers(tracker: Arc<TrackerServer>) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(tracker.interval());
+    loop {
+        interval.tick().await;
+        tracker.expire();
+    }
+}
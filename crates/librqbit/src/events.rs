@@ -0,0 +1,114 @@
+// Torrent lifecycle events of a session, so that clients don't have to poll for changes.
+
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::session::TorrentId;
+
+// Piece events are frequent, slow subscribers should still see most of them.
+pub(crate) const EVENTS_CHANNEL_CAPACITY: usize = 4096;
+
+/// Where the events of all torrents go: a broadcast that slow subscribers may miss events of, and
+/// a lossless channel for the hooks, which only gets the events hooks run on.
+#[derive(Clone)]
+pub(crate) struct SessionEventSenders {
+    pub broadcast: tokio::sync::broadcast::Sender<TorrentEvent>,
+    pub hooks: Option<UnboundedSender<TorrentEvent>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TorrentEventKind {
+    Added,
+    /// Files were checked, the torrent is about to be paused or started.
+    Initialized,
+    Paused,
+    Started,
+    Errored {
+        error: String,
+    },
+    /// All selected files were downloaded.
+    Completed,
+    Removed,
+    FileCompleted {
+        file_id: usize,
+    },
+    PieceCompleted {
+        piece: u32,
+    },
+}
+
+impl TorrentEventKind {
+    pub(crate) fn runs_hooks(&self) -> bool {
+        matches!(
+            self,
+            TorrentEventKind::Added
+                | TorrentEventKind::Completed
+                | TorrentEventKind::Errored { .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentEvent {
+    pub torrent_id: TorrentId,
+    pub info_hash: String,
+    #[serde(flatten)]
+    pub kind: TorrentEventKind,
+}
+
+/// Sends the events of one torrent to the session.
+#[derive(Clone)]
+pub(crate) struct TorrentEventSender {
+    torrent_id: TorrentId,
+    info_hash: String,
+    tx: SessionEventSenders,
+}
+
+impl TorrentEventSender {
+    pub fn new(torrent_id: TorrentId, info_hash: String, tx: SessionEventSenders) -> Self {
+        Self {
+            torrent_id,
+            info_hash,
+            tx,
+        }
+    }
+
+    pub fn send(&self, kind: TorrentEventKind) {
+        let event = TorrentEvent {
+            torrent_id: self.torrent_id,
+            info_hash: self.info_hash.clone(),
+            kind,
+        };
+        if let Some(hooks) = &self.tx.hooks {
+            if event.kind.runs_hooks() {
+                // Errors only mean that the session is shutting down.
+                let _ = hooks.send(event.clone());
+            }
+        }
+        // Errors only mean that nobody is subscribed.
+        let _ = self.tx.broadcast.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TorrentEvent, TorrentEventKind};
+
+    #[test]
+    fn test_serialize() {
+        let event = |kind| TorrentEvent {
+            torrent_id: 1,
+            info_hash: "abcd".to_owned(),
+            kind,
+        };
+        assert_eq!(
+            serde_json::to_string(&event(TorrentEventKind::Added)).unwrap(),
+            r#"{"torrent_id":1,"info_hash":"abcd","type":"added"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&event(TorrentEventKind::FileCompleted { file_id: 2 })).unwrap(),
+            r#"{"torrent_id":1,"info_hash":"abcd","type":"file_completed","file_id":2}"#
+        );
+    }
+}
@@ -0,0 +1,195 @@
+// Fast-resume: remembering which pieces we have across restarts, so that we don't need
+// to re-hash all the data on startup.
+//
+// The have-bitfield is only trusted for files whose on-disk metadata (length and mtime)
+// did not change since it was stored. Pieces touching any other file are re-hashed.
+
+use std::{
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::debug;
+
+use librqbit_core::lengths::Lengths;
+
+use crate::type_aliases::{FileInfos, BF};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FileMetadata {
+    pub len: u64,
+    // Modification time since UNIX epoch. If the filesystem can't tell it, the file will
+    // always be re-checked.
+    pub mtime: Option<Duration>,
+}
+
+impl FileMetadata {
+    fn read(path: &Path) -> Option<Self> {
+        let meta = std::fs::metadata(path).ok()?;
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+        Some(Self {
+            len: meta.len(),
+            mtime,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct FastResumeData {
+    #[serde(
+        serialize_with = "serialize_bitfield",
+        deserialize_with = "deserialize_bitfield"
+    )]
+    pub have_pieces: Vec<u8>,
+    // Per-file metadata, indexed the same way as the torrent's files.
+    // None if the file didn't exist when the data was stored.
+    pub files: Vec<Option<FileMetadata>>,
+}
+
+// The result of validating fast-resume data against what's currently on disk.
+pub(crate) struct TrustedPieces {
+    // The stored have-bitfield.
+    pub have: BF,
+    // Pieces that touch files that changed since the data was stored. These must be re-hashed.
+    pub recheck: BF,
+}
+
+impl FastResumeData {
+    pub fn collect(have_pieces: &BF, output_folder: &Path, file_infos: &FileInfos) -> Self {
+        Self {
+            have_pieces: have_pieces.as_raw_slice().to_owned(),
+            files: file_infos
+                .iter()
+                .map(|fi| FileMetadata::read(&output_folder.join(&fi.relative_filename)))
+                .collect(),
+        }
+    }
+
+    // Returns None if the data doesn't match the torrent at all, in which case the
+    // full check needs to run.
+    pub fn validate(
+        &self,
+        lengths: &Lengths,
+        file_infos: &FileInfos,
+        output_folder: &Path,
+    ) -> Option<TrustedPieces> {
+        if self.have_pieces.len() != lengths.piece_bitfield_bytes() {
+            debug!(
+                "fast-resume bitfield has wrong length: {} != {}",
+                self.have_pieces.len(),
+                lengths.piece_bitfield_bytes()
+            );
+            return None;
+        }
+        if self.files.len() != file_infos.len() {
+            debug!(
+                "fast-resume file count mismatch: {} != {}",
+                self.files.len(),
+                file_infos.len()
+            );
+            return None;
+        }
+
+        let have = BF::from_boxed_slice(self.have_pieces.clone().into_boxed_slice());
+        let mut recheck = BF::from_boxed_slice(vec![0u8; lengths.piece_bitfield_bytes()].into());
+
+        for (fi, stored) in file_infos.iter().zip(self.files.iter()) {
+            let current = FileMetadata::read(&output_folder.join(&fi.relative_filename));
+            let unchanged = match (stored, current) {
+                (Some(stored), Some(current)) => stored.mtime.is_some() && *stored == current,
+                _ => false,
+            };
+            if unchanged {
+                continue;
+            }
+            debug!(filename=?fi.relative_filename, "file changed since fast-resume data was stored, will re-check");
+            if let Some(r) = recheck.get_mut(fi.piece_range_usize()) {
+                r.fill(true);
+            }
+        }
+
+        Some(TrustedPieces { have, recheck })
+    }
+}
+
+fn serialize_bitfield<S>(bf: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    use base64::{engine::general_purpose, Engine as _};
+    general_purpose::STANDARD_NO_PAD
+        .encode(bf)
+        .serialize(serializer)
+}
+
+fn deserialize_bitfield<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use base64::{engine::general_purpose, Engine as _};
+    use serde::de::Error;
+    let s = String::deserialize(deserializer)?;
+    general_purpose::STANDARD_NO_PAD
+        .decode(s)
+        .map_err(D::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use librqbit_core::lengths::Lengths;
+
+    use crate::{file_info::FileInfo, type_aliases::BF};
+
+    use super::FastResumeData;
+
+    #[test]
+    fn test_validate_rechecks_only_changed_files() {
+        let dir = tempfile::TempDir::with_prefix("rqbit_fastresume").unwrap();
+        let lengths = Lengths::new(200, 50).unwrap();
+        let file_infos = vec![
+            FileInfo {
+                relative_filename: PathBuf::from("0.data"),
+                offset_in_torrent: 0,
+                piece_range: 0..2,
+                len: 100,
+            },
+            FileInfo {
+                relative_filename: PathBuf::from("1.data"),
+                offset_in_torrent: 100,
+                piece_range: 2..4,
+                len: 100,
+            },
+        ];
+        for fi in file_infos.iter() {
+            std::fs::write(dir.path().join(&fi.relative_filename), [0u8; 100]).unwrap();
+        }
+
+        let mut have = BF::from_boxed_slice(vec![0u8; lengths.piece_bitfield_bytes()].into());
+        have.set(0, true);
+        have.set(3, true);
+        let data = FastResumeData::collect(&have, dir.path(), &file_infos);
+
+        let trusted = data.validate(&lengths, &file_infos, dir.path()).unwrap();
+        assert_eq!(trusted.have, have);
+        assert!(trusted.recheck.not_any());
+
+        std::fs::write(dir.path().join("1.data"), [1u8; 50]).unwrap();
+        let trusted = data.validate(&lengths, &file_infos, dir.path()).unwrap();
+        assert_eq!(
+            trusted.recheck.iter().by_vals().collect::<Vec<_>>()[..4],
+            [false, false, true, true]
+        );
+
+        // Data for a different torrent layout must not be used.
+        let other_lengths = Lengths::new(1000, 50).unwrap();
+        assert!(data
+            .validate(&other_lengths, &file_infos, dir.path())
+            .is_none());
+    }
+}
@@ -1,4 +1,6 @@
-use std::path::PathBuf;
+use std::{collections::HashSet, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone)]
 pub struct FileInfo {
@@ -22,6 +24,56 @@ fn iter_piece_priorities(range: std::ops::Range<usize>) -> impl Iterator<Item =
     first.chain(last).chain(mid).take(r.len())
 }
 
+// How eager we are to download a file. Pieces of higher priority files are requested first,
+// "skip" files are not downloaded at all (same as not being in "only_files").
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FilePriority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FilePriority {
+    // The order in which files are picked when looking for pieces to download.
+    pub(crate) const DOWNLOAD_ORDER: [FilePriority; 3] =
+        [FilePriority::High, FilePriority::Normal, FilePriority::Low];
+
+    pub(crate) fn from_only_files(file_count: usize, only_files: Option<&[usize]>) -> Vec<Self> {
+        (0..file_count)
+            .map(|id| match only_files {
+                Some(only_files) if !only_files.contains(&id) => FilePriority::Skip,
+                _ => FilePriority::Normal,
+            })
+            .collect()
+    }
+
+    // Skip the files that aren't selected, and un-skip the ones that are, keeping other
+    // priorities as is.
+    pub(crate) fn apply_only_files(priorities: &mut [Self], only_files: &HashSet<usize>) {
+        for (id, p) in priorities.iter_mut().enumerate() {
+            match (only_files.contains(&id), *p) {
+                (false, _) => *p = FilePriority::Skip,
+                (true, FilePriority::Skip) => *p = FilePriority::Normal,
+                (true, _) => {}
+            }
+        }
+    }
+
+    pub(crate) fn only_files(priorities: &[Self]) -> Vec<usize> {
+        priorities
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| **p != FilePriority::Skip)
+            .map(|(id, _)| id)
+            .collect()
+    }
+}
+
 impl FileInfo {
     pub fn piece_range_usize(&self) -> std::ops::Range<usize> {
         self.piece_range.start as usize..self.piece_range.end as usize
@@ -34,7 +86,9 @@ impl FileInfo {
 
 #[cfg(test)]
 mod tests {
-    use super::iter_piece_priorities;
+    use std::collections::HashSet;
+
+    use super::{iter_piece_priorities, FilePriority};
 
     #[test]
     fn test_iter_piece_priorities() {
@@ -46,4 +100,18 @@ mod tests {
         assert_eq!(it(0..3), vec![0, 2, 1]);
         assert_eq!(it(0..4), vec![0, 3, 1, 2]);
     }
+
+    #[test]
+    fn test_file_priorities_only_files() {
+        use FilePriority::*;
+
+        let mut p = FilePriority::from_only_files(4, Some(&[1, 2]));
+        assert_eq!(p, vec![Skip, Normal, Normal, Skip]);
+        assert_eq!(FilePriority::only_files(&p), vec![1, 2]);
+
+        p[1] = High;
+        FilePriority::apply_only_files(&mut p, &HashSet::from_iter([0, 1]));
+        assert_eq!(p, vec![Normal, High, Skip, Skip]);
+        assert_eq!(FilePriority::from_only_files(2, None), vec![Normal, Normal]);
+    }
 }
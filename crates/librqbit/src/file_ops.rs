@@ -1,19 +1,18 @@
-use std::{
-    marker::PhantomData,
-    sync::atomic::{AtomicU64, Ordering},
-};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use anyhow::Context;
 use buffers::ByteBufOwned;
 use librqbit_core::{
     lengths::{ChunkInfo, Lengths, ValidPieceIndex},
-    torrent_metainfo::TorrentMetaV1Info,
+    merkle::MerkleHasher,
+    torrent_metainfo::{PieceHashV2, TorrentMetaV1Info},
 };
 use peer_binary_protocol::Piece;
 use sha1w::{ISha1, Sha1};
 use tracing::{debug, trace, warn};
 
 use crate::{
+    fastresume::TrustedPieces,
     file_info::FileInfo,
     storage::TorrentStorage,
     type_aliases::{FileInfos, PeerHandle, BF},
@@ -42,11 +41,97 @@ pub(crate) struct InitialCheckResults {
     pub selected_bytes: u64,
 }
 
-pub fn update_hash_from_file<Sha1: ISha1>(
+// A contiguous range of the torrent's piece space. Either a part of a file, or padding
+// between files (v2 and BEP 47 alignment), which is all zeros and never stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Segment {
+    pub file_id: Option<usize>,
+    pub offset_in_file: u64,
+    pub len: u64,
+}
+
+// Splits "len" bytes of the piece space starting at "absolute_offset" into segments.
+pub(crate) fn iter_segments(
+    file_infos: &[FileInfo],
+    absolute_offset: u64,
+    len: u64,
+) -> Vec<Segment> {
+    let end = absolute_offset + len;
+    let mut segments = Vec::new();
+    let mut pos = absolute_offset;
+    // Files are sorted by offset and don't overlap, so their ends are sorted too. Skip those
+    // that end before "pos" without scanning them.
+    let first = file_infos.partition_point(|fi| fi.offset_in_torrent + fi.len <= pos);
+    for (file_id, fi) in file_infos.iter().enumerate().skip(first) {
+        if pos >= end {
+            break;
+        }
+        let file_end = fi.offset_in_torrent + fi.len;
+        if fi.len == 0 || file_end <= pos {
+            continue;
+        }
+        if fi.offset_in_torrent > pos {
+            let gap_end = fi.offset_in_torrent.min(end);
+            segments.push(Segment {
+                file_id: None,
+                offset_in_file: 0,
+                len: gap_end - pos,
+            });
+            pos = gap_end;
+            if pos >= end {
+                break;
+            }
+        }
+        let segment_end = file_end.min(end);
+        segments.push(Segment {
+            file_id: Some(file_id),
+            offset_in_file: pos - fi.offset_in_torrent,
+            len: segment_end - pos,
+        });
+        pos = segment_end;
+    }
+    if pos < end {
+        segments.push(Segment {
+            file_id: None,
+            offset_in_file: 0,
+            len: end - pos,
+        });
+    }
+    segments
+}
+
+// v1 pieces are hashed with SHA1 including padding, v2 pieces are merkle roots over the
+// 16KiB blocks of one file.
+pub enum PieceHasher {
+    V1(Sha1),
+    V2(MerkleHasher),
+}
+
+impl PieceHasher {
+    pub fn update(&mut self, buf: &[u8]) {
+        match self {
+            PieceHasher::V1(h) => h.update(buf),
+            PieceHasher::V2(h) => h.update(buf),
+        }
+    }
+
+    fn update_padding(&mut self, mut len: u64) {
+        if let PieceHasher::V1(h) = self {
+            const ZEROES: [u8; 4096] = [0u8; 4096];
+            while len > 0 {
+                let chunk = usize::try_from(len).unwrap_or(usize::MAX).min(ZEROES.len());
+                h.update(&ZEROES[..chunk]);
+                len -= chunk as u64;
+            }
+        }
+    }
+}
+
+pub fn update_hash_from_file(
     file_id: usize,
     mut pos: u64,
     files: &dyn TorrentStorage,
-    hash: &mut Sha1,
+    hash: &mut PieceHasher,
     buf: &mut [u8],
     mut bytes_to_read: usize,
 ) -> anyhow::Result<()> {
@@ -69,7 +154,8 @@ pub(crate) struct FileOps<'a> {
     files: &'a dyn TorrentStorage,
     file_infos: &'a FileInfos,
     lengths: &'a Lengths,
-    phantom_data: PhantomData<Sha1>,
+    // Set for v2-only torrents. Hybrid torrents are checked with the v1 hashes.
+    v2_hashes: Option<&'a [PieceHashV2]>,
 }
 
 impl<'a> FileOps<'a> {
@@ -78,20 +164,42 @@ impl<'a> FileOps<'a> {
         files: &'a dyn TorrentStorage,
         file_infos: &'a FileInfos,
         lengths: &'a Lengths,
+        v2_hashes: Option<&'a [PieceHashV2]>,
     ) -> Self {
         Self {
             torrent,
             files,
             file_infos,
             lengths,
-            phantom_data: PhantomData,
+            v2_hashes,
         }
     }
 
+    fn new_hasher(&self) -> PieceHasher {
+        match self.v2_hashes {
+            Some(_) => PieceHasher::V2(MerkleHasher::new()),
+            None => PieceHasher::V1(Sha1::new()),
+        }
+    }
+
+    fn compare_hash(&self, piece_index: u32, hasher: PieceHasher) -> Option<bool> {
+        match (hasher, self.v2_hashes) {
+            (PieceHasher::V2(h), Some(hashes)) => {
+                let expected = hashes.get(piece_index as usize)?;
+                Some(h.finish(expected.leaves) == expected.hash)
+            }
+            (PieceHasher::V1(h), _) => self.torrent.compare_hash(piece_index, h.finish()),
+            (PieceHasher::V2(_), None) => None,
+        }
+    }
+
+    // If "trusted" is passed, pieces that don't need re-checking will not be read from disk,
+    // and their have-status will be taken from it instead.
     pub fn initial_check(
         &self,
         only_files: Option<&[usize]>,
         progress: &AtomicU64,
+        trusted: Option<&TrustedPieces>,
     ) -> anyhow::Result<InitialCheckResults> {
         let mut needed_pieces =
             BF::from_boxed_slice(vec![0u8; self.lengths.piece_bitfield_bytes()].into());
@@ -101,94 +209,61 @@ impl<'a> FileOps<'a> {
         let mut have_bytes = 0u64;
         let mut needed_bytes = 0u64;
         let mut total_selected_bytes = 0u64;
-        let mut piece_files = Vec::<usize>::new();
-
-        #[derive(Debug)]
-        struct CurrentFile<'a> {
-            index: usize,
-            fi: &'a FileInfo,
-            full_file_required: bool,
-            processed_bytes: u64,
-            is_broken: bool,
-        }
-        impl<'a> CurrentFile<'a> {
-            fn remaining(&self) -> u64 {
-                self.fi.len - self.processed_bytes
-            }
-            fn mark_processed_bytes(&mut self, bytes: u64) {
-                self.processed_bytes += bytes
-            }
-        }
-        let mut file_iterator = self.file_infos.iter().enumerate().map(|(idx, fi)| {
-            let full_file_required = if let Some(only_files) = only_files {
-                only_files.contains(&idx)
-            } else {
-                true
-            };
-            CurrentFile {
-                index: idx,
-                fi,
-                full_file_required,
-                processed_bytes: 0,
-                is_broken: false,
-            }
-        });
 
-        let mut current_file = file_iterator
-            .next()
-            .ok_or_else(|| anyhow::anyhow!("empty input file list"))?;
+        if self.file_infos.is_empty() {
+            anyhow::bail!("empty input file list");
+        }
+        // Once a file fails to read, don't try reading it again.
+        let mut broken_files = vec![false; self.file_infos.len()];
+        let is_file_required =
+            |file_id: usize| only_files.map(|f| f.contains(&file_id)).unwrap_or(true);
 
         let mut read_buffer = vec![0u8; 65536];
 
         for piece_info in self.lengths.iter_piece_infos() {
-            piece_files.clear();
-            let mut computed_hash = Sha1::new();
-            let mut piece_remaining = piece_info.len as usize;
+            let mut computed_hash = self.new_hasher();
             let mut some_files_broken = false;
-            let mut piece_selected = current_file.full_file_required;
+            let mut piece_selected = false;
+            let piece_trusted =
+                trusted.filter(|t| !t.recheck[piece_info.piece_index.get() as usize]);
             progress.fetch_add(piece_info.len as u64, Ordering::Relaxed);
 
-            while piece_remaining > 0 {
-                let mut to_read_in_file: usize =
-                    std::cmp::min(current_file.remaining(), piece_remaining as u64).try_into()?;
-
-                // Keep changing the current file to next until we find a file that has greater than 0 length.
-                while to_read_in_file == 0 {
-                    current_file = file_iterator
-                        .next()
-                        .ok_or_else(|| anyhow::anyhow!("broken torrent metadata"))?;
-
-                    piece_selected |= current_file.full_file_required;
-
-                    to_read_in_file =
-                        std::cmp::min(current_file.remaining(), piece_remaining as u64)
-                            .try_into()?;
-                }
-
-                piece_files.push(current_file.index);
-
-                let pos = current_file.processed_bytes;
-                piece_remaining -= to_read_in_file;
-                current_file.mark_processed_bytes(to_read_in_file as u64);
-
-                if current_file.is_broken {
+            let segments = iter_segments(
+                self.file_infos,
+                self.lengths.piece_offset(piece_info.piece_index),
+                piece_info.len as u64,
+            );
+            for segment in segments {
+                let file_id = match segment.file_id {
+                    Some(file_id) => file_id,
+                    None => {
+                        computed_hash.update_padding(segment.len);
+                        continue;
+                    }
+                };
+                piece_selected |= is_file_required(file_id);
+
+                if broken_files[file_id] || piece_trusted.is_some() {
                     // no need to read.
                     continue;
                 }
 
                 if let Err(err) = update_hash_from_file(
-                    current_file.index,
-                    pos,
+                    file_id,
+                    segment.offset_in_file,
                     self.files,
                     &mut computed_hash,
                     &mut read_buffer,
-                    to_read_in_file,
+                    segment.len.try_into()?,
                 ) {
                     debug!(
                         "error reading from file {} ({:?}) at {}: {:#}",
-                        current_file.index, current_file.fi.relative_filename, pos, &err
+                        file_id,
+                        self.file_infos[file_id].relative_filename,
+                        segment.offset_in_file,
+                        &err
                     );
-                    current_file.is_broken = true;
+                    broken_files[file_id] = true;
                     some_files_broken = true;
                 }
             }
@@ -198,6 +273,18 @@ impl<'a> FileOps<'a> {
                 selected_pieces.set(piece_info.piece_index.get() as usize, true);
             }
 
+            if let Some(trusted) = piece_trusted {
+                let id = piece_info.piece_index.get() as usize;
+                if trusted.have[id] {
+                    have_bytes += piece_info.len as u64;
+                    have_pieces.set(id, true);
+                } else if piece_selected {
+                    needed_bytes += piece_info.len as u64;
+                    needed_pieces.set(id, true);
+                }
+                continue;
+            }
+
             if piece_selected && some_files_broken {
                 trace!(
                     "piece {} had errors, marking as needed",
@@ -209,8 +296,7 @@ impl<'a> FileOps<'a> {
             }
 
             if self
-                .torrent
-                .compare_hash(piece_info.piece_index.get(), computed_hash.finish())
+                .compare_hash(piece_info.piece_index.get(), computed_hash)
                 .context("bug: either torrent info broken or we have a bug - piece index invalid")?
             {
                 trace!(
@@ -245,56 +331,49 @@ impl<'a> FileOps<'a> {
 
     pub fn check_piece(
         &self,
-        who_sent: PeerHandle,
+        who_sent: impl std::fmt::Display,
         piece_index: ValidPieceIndex,
         last_received_chunk: &ChunkInfo,
     ) -> anyhow::Result<bool> {
-        let mut h = Sha1::new();
+        let mut h = self.new_hasher();
         let piece_length = self.lengths.piece_length(piece_index);
-        let mut absolute_offset = self.lengths.piece_offset(piece_index);
+        let absolute_offset = self.lengths.piece_offset(piece_index);
         let mut buf = vec![0u8; std::cmp::min(65536, piece_length as usize)];
 
-        let mut piece_remaining_bytes = piece_length as usize;
-
-        for (file_idx, (name, file_len)) in self.torrent.iter_filenames_and_lengths()?.enumerate() {
-            if absolute_offset > file_len {
-                absolute_offset -= file_len;
-                continue;
-            }
-            let file_remaining_len = file_len - absolute_offset;
-
-            let to_read_in_file: usize =
-                std::cmp::min(file_remaining_len, piece_remaining_bytes as u64).try_into()?;
+        for segment in iter_segments(self.file_infos, absolute_offset, piece_length as u64) {
+            let file_idx = match segment.file_id {
+                Some(file_idx) => file_idx,
+                None => {
+                    h.update_padding(segment.len);
+                    continue;
+                }
+            };
+            let to_read_in_file: usize = segment.len.try_into()?;
             trace!(
                 "piece={}, handle={}, file_idx={}, seeking to {}. Last received chunk: {:?}",
                 piece_index,
                 who_sent,
                 file_idx,
-                absolute_offset,
+                segment.offset_in_file,
                 &last_received_chunk
             );
             update_hash_from_file(
                 file_idx,
-                absolute_offset,
+                segment.offset_in_file,
                 self.files,
                 &mut h,
                 &mut buf,
                 to_read_in_file,
             )
             .with_context(|| {
-                format!("error reading {to_read_in_file} bytes, file_id: {file_idx} (\"{name:?}\")")
+                format!(
+                    "error reading {to_read_in_file} bytes, file_id: {file_idx} (\"{:?}\")",
+                    self.file_infos[file_idx].relative_filename
+                )
             })?;
-
-            piece_remaining_bytes -= to_read_in_file;
-
-            if piece_remaining_bytes == 0 {
-                break;
-            }
-
-            absolute_offset = 0;
         }
 
-        match self.torrent.compare_hash(piece_index.get(), h.finish()) {
+        match self.compare_hash(piece_index.get(), h) {
             Some(true) => {
                 trace!("piece={} hash matches", piece_index);
                 Ok(true)
@@ -320,38 +399,33 @@ impl<'a> FileOps<'a> {
         if result_buf.len() < chunk_info.size as usize {
             anyhow::bail!("read_chunk(): not enough capacity in the provided buffer")
         }
-        let mut absolute_offset = self.lengths.chunk_absolute_offset(chunk_info);
+        let absolute_offset = self.lengths.chunk_absolute_offset(chunk_info);
         let mut buf = result_buf;
 
-        for (file_idx, file_len) in self.torrent.iter_file_lengths()?.enumerate() {
-            if absolute_offset > file_len {
-                absolute_offset -= file_len;
-                continue;
-            }
-            let file_remaining_len = file_len - absolute_offset;
-            let to_read_in_file = std::cmp::min(file_remaining_len, buf.len() as u64).try_into()?;
+        for segment in iter_segments(self.file_infos, absolute_offset, chunk_info.size as u64) {
+            let (current, rest) = std::mem::take(&mut buf).split_at_mut(segment.len.try_into()?);
+            buf = rest;
+            let file_idx = match segment.file_id {
+                Some(file_idx) => file_idx,
+                None => {
+                    current.fill(0);
+                    continue;
+                }
+            };
 
             trace!(
                 "piece={}, handle={}, file_idx={}, seeking to {}. To read chunk: {:?}",
                 chunk_info.piece_index,
                 who_sent,
                 file_idx,
-                absolute_offset,
+                segment.offset_in_file,
                 &chunk_info
             );
             self.files
-                .pread_exact(file_idx, absolute_offset, &mut buf[..to_read_in_file])
+                .pread_exact(file_idx, segment.offset_in_file, current)
                 .with_context(|| {
-                    format!("error reading {file_idx} bytes, file_id: {to_read_in_file}")
+                    format!("error reading {} bytes, file_id: {file_idx}", current.len())
                 })?;
-
-            buf = &mut buf[to_read_in_file..];
-
-            if buf.is_empty() {
-                break;
-            }
-
-            absolute_offset = 0;
         }
 
         Ok(())
@@ -359,7 +433,7 @@ impl<'a> FileOps<'a> {
 
     pub fn write_chunk<ByteBuf>(
         &self,
-        who_sent: PeerHandle,
+        who_sent: impl std::fmt::Display,
         data: &Piece<ByteBuf>,
         chunk_info: &ChunkInfo,
     ) -> anyhow::Result<()>
@@ -367,16 +441,15 @@ impl<'a> FileOps<'a> {
         ByteBuf: AsRef<[u8]>,
     {
         let mut buf = data.block.as_ref();
-        let mut absolute_offset = self.lengths.chunk_absolute_offset(chunk_info);
+        let absolute_offset = self.lengths.chunk_absolute_offset(chunk_info);
 
-        for (file_idx, (name, file_len)) in self.torrent.iter_filenames_and_lengths()?.enumerate() {
-            if absolute_offset > file_len {
-                absolute_offset -= file_len;
+        for segment in iter_segments(self.file_infos, absolute_offset, buf.len() as u64) {
+            let (current, rest) = buf.split_at(segment.len.try_into()?);
+            buf = rest;
+            // Padding isn't stored.
+            let Some(file_idx) = segment.file_id else {
                 continue;
-            }
-
-            let remaining_len = file_len - absolute_offset;
-            let to_write = std::cmp::min(buf.len() as u64, remaining_len).try_into()?;
+            };
 
             trace!(
                 "piece={}, chunk={:?}, handle={}, begin={}, file={}, writing {} bytes at {}",
@@ -385,20 +458,70 @@ impl<'a> FileOps<'a> {
                 who_sent,
                 chunk_info.offset,
                 file_idx,
-                to_write,
-                absolute_offset
+                current.len(),
+                segment.offset_in_file
             );
             self.files
-                .pwrite_all(file_idx, absolute_offset, &buf[..to_write])
-                .with_context(|| format!("error writing to file {file_idx} (\"{name:?}\")"))?;
-            buf = &buf[to_write..];
-            if buf.is_empty() {
-                break;
-            }
-
-            absolute_offset = 0;
+                .pwrite_all(file_idx, segment.offset_in_file, current)
+                .with_context(|| {
+                    format!(
+                        "error writing to file {file_idx} (\"{:?}\")",
+                        self.file_infos[file_idx].relative_filename
+                    )
+                })?;
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::file_info::FileInfo;
+
+    use super::{iter_segments, Segment};
+
+    fn file(offset_in_torrent: u64, len: u64) -> FileInfo {
+        FileInfo {
+            relative_filename: PathBuf::from("f"),
+            offset_in_torrent,
+            piece_range: 0..0,
+            len,
+        }
+    }
+
+    #[test]
+    fn test_iter_segments_with_padding() {
+        // Files aligned to 100 bytes, with an empty file in the middle.
+        let files = vec![file(0, 30), file(100, 0), file(100, 150), file(300, 10)];
+        let seg = |file_id, offset_in_file, len| Segment {
+            file_id,
+            offset_in_file,
+            len,
+        };
+
+        assert_eq!(
+            iter_segments(&files, 0, 400),
+            vec![
+                seg(Some(0), 0, 30),
+                seg(None, 0, 70),
+                seg(Some(2), 0, 150),
+                seg(None, 0, 50),
+                seg(Some(3), 0, 10),
+                seg(None, 0, 90),
+            ]
+        );
+        assert_eq!(
+            iter_segments(&files, 20, 100),
+            vec![seg(Some(0), 20, 10), seg(None, 0, 70), seg(Some(2), 0, 20)]
+        );
+        assert_eq!(iter_segments(&files, 40, 10), vec![seg(None, 0, 10)]);
+        assert_eq!(iter_segments(&files, 120, 10), vec![seg(Some(2), 20, 10)]);
+        assert_eq!(
+            iter_segments(&files, 260, 60),
+            vec![seg(None, 0, 40), seg(Some(3), 0, 10), seg(None, 0, 10)]
+        );
+    }
+}
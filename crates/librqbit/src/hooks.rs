@@ -0,0 +1,268 @@
+// Hooks run when torrents are added, completed or fail, e.g. to unpack or move downloads, or to
+// notify someone. They follow the session events, so they never block the torrents themselves.
+
+use std::{
+    process::Stdio,
+    str::FromStr,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use serde::Serialize;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tracing::{debug, error_span, warn};
+
+use crate::{
+    events::{TorrentEvent, TorrentEventKind},
+    session::TorrentId,
+    Session,
+};
+
+const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hook {
+    /// A shell command. The torrent is passed in RQBIT_* environment variables.
+    Command(String),
+    /// A URL to POST the torrent to as JSON.
+    Webhook(String),
+}
+
+/// http:// and https:// URLs are webhooks, anything else is a command.
+impl FromStr for Hook {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().is_empty() {
+            bail!("hook can't be empty");
+        }
+        if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(Hook::Webhook(s.to_owned()))
+        } else {
+            Ok(Hook::Command(s.to_owned()))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SessionHooks {
+    /// Run when a torrent is added, including when it's restored from persistence on startup.
+    pub on_added: Vec<Hook>,
+    /// Run when all selected files of a torrent were downloaded.
+    pub on_completed: Vec<Hook>,
+    pub on_error: Vec<Hook>,
+    /// How long each hook may run before it's killed. Defaults to 60 seconds.
+    pub timeout: Option<Duration>,
+}
+
+impl SessionHooks {
+    pub fn is_empty(&self) -> bool {
+        self.on_added.is_empty() && self.on_completed.is_empty() && self.on_error.is_empty()
+    }
+
+    fn hooks_for(&self, kind: &TorrentEventKind) -> &[Hook] {
+        match kind {
+            TorrentEventKind::Added => &self.on_added,
+            TorrentEventKind::Completed => &self.on_completed,
+            TorrentEventKind::Errored { .. } => &self.on_error,
+            _ => &[],
+        }
+    }
+}
+
+/// What hooks get to know about the torrent.
+#[derive(Debug, Serialize)]
+struct HookPayload {
+    event: &'static str,
+    torrent_id: TorrentId,
+    info_hash: String,
+    name: Option<String>,
+    output_folder: String,
+    total_bytes: u64,
+    error: Option<String>,
+}
+
+impl HookPayload {
+    fn new(session: &Session, event: &TorrentEvent) -> Option<Self> {
+        let (name, error) = match &event.kind {
+            TorrentEventKind::Added => ("added", None),
+            TorrentEventKind::Completed => ("completed", None),
+            TorrentEventKind::Errored { error } => ("error", Some(error.clone())),
+            _ => return None,
+        };
+        let handle = session.get(event.torrent_id)?;
+        let info = handle.info();
+        Some(Self {
+            event: name,
+            torrent_id: event.torrent_id,
+            info_hash: event.info_hash.clone(),
+            name: info.info.name.as_ref().map(|n| n.to_string()),
+            output_folder: info.output_folder().to_string_lossy().into_owned(),
+            total_bytes: info.lengths.total_length(),
+            error,
+        })
+    }
+
+    fn env_vars(&self) -> Vec<(&'static str, String)> {
+        let mut vars = vec![
+            ("RQBIT_EVENT", self.event.to_owned()),
+            ("RQBIT_TORRENT_ID", self.torrent_id.to_string()),
+            ("RQBIT_INFO_HASH", self.info_hash.clone()),
+            ("RQBIT_OUTPUT_FOLDER", self.output_folder.clone()),
+            ("RQBIT_TOTAL_BYTES", self.total_bytes.to_string()),
+        ];
+        if let Some(name) = &self.name {
+            vars.push(("RQBIT_TORRENT_NAME", name.clone()));
+        }
+        if let Some(error) = &self.error {
+            vars.push(("RQBIT_ERROR", error.clone()));
+        }
+        vars
+    }
+}
+
+async fn run_command(
+    command: &str,
+    payload: &HookPayload,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = tokio::process::Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = tokio::process::Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+    cmd.arg(command)
+        .envs(payload.env_vars())
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    let status = tokio::time::timeout(timeout, cmd.status())
+        .await
+        .with_context(|| format!("timed out after {timeout:?}"))?
+        .context("error running command")?;
+    if !status.success() {
+        bail!("command failed with {status}");
+    }
+    Ok(())
+}
+
+async fn post_webhook(
+    client: &reqwest::Client,
+    url: &str,
+    payload: &HookPayload,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    client
+        .post(url)
+        .json(payload)
+        .timeout(timeout)
+        .send()
+        .await
+        .context("error sending request")?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn run_hook(
+    client: reqwest::Client,
+    hook: Hook,
+    payload: Arc<HookPayload>,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let result = match &hook {
+        Hook::Command(command) => run_command(command, &payload, timeout).await,
+        Hook::Webhook(url) => post_webhook(&client, url, &payload, timeout).await,
+    };
+    match result {
+        Ok(()) => debug!(?hook, "hook finished"),
+        Err(e) => warn!(?hook, "hook failed: {e:#}"),
+    }
+    Ok(())
+}
+
+/// Runs the hooks for session events until the session is dropped. Each hook runs in its own
+/// task, so that slow ones don't hold back the others.
+pub(crate) async fn run_hooks(
+    session: Weak<Session>,
+    hooks: SessionHooks,
+    mut events: UnboundedReceiver<TorrentEvent>,
+) -> anyhow::Result<()> {
+    let client = reqwest::Client::new();
+    let timeout = hooks.timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT);
+    while let Some(event) = events.recv().await {
+        let to_run = hooks.hooks_for(&event.kind);
+        if to_run.is_empty() {
+            continue;
+        }
+        let session = match session.upgrade() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        let payload = match HookPayload::new(&session, &event) {
+            Some(p) => Arc::new(p),
+            None => continue,
+        };
+        for hook in to_run {
+            session.spawn(
+                error_span!("hook", torrent_id = event.torrent_id, event = payload.event),
+                run_hook(client.clone(), hook.clone(), payload.clone(), timeout),
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{run_command, Hook, HookPayload};
+
+    fn payload() -> HookPayload {
+        HookPayload {
+            event: "completed",
+            torrent_id: 3,
+            info_hash: "abcd".to_owned(),
+            name: Some("name with spaces".to_owned()),
+            output_folder: "/downloads".to_owned(),
+            total_bytes: 100,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_hook() {
+        assert_eq!(
+            "https://example.com/hook".parse::<Hook>().unwrap(),
+            Hook::Webhook("https://example.com/hook".to_owned())
+        );
+        assert_eq!(
+            "unrar x \"$RQBIT_OUTPUT_FOLDER\"".parse::<Hook>().unwrap(),
+            Hook::Command("unrar x \"$RQBIT_OUTPUT_FOLDER\"".to_owned())
+        );
+        assert!(" ".parse::<Hook>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_command() {
+        let t = Duration::from_secs(5);
+        run_command(
+            "test \"$RQBIT_TORRENT_NAME\" = \"name with spaces\" && test \"$RQBIT_TORRENT_ID\" = 3",
+            &payload(),
+            t,
+        )
+        .await
+        .unwrap();
+        assert!(run_command("exit 3", &payload(), t).await.is_err());
+        assert!(
+            run_command("sleep 5", &payload(), Duration::from_millis(100))
+                .await
+                .is_err()
+        );
+    }
+}
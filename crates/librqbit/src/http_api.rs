@@ -1,27 +1,38 @@
 use anyhow::Context;
 use axum::body::Bytes;
-use axum::extract::{Path, Query, State};
+use axum::extract::{ConnectInfo, Path, Query, RawQuery, State};
+use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::IntoResponse;
 use axum::routing::{get, post};
 use futures::future::BoxFuture;
-use futures::{FutureExt, TryStreamExt};
+use futures::stream::BoxStream;
+use futures::{FutureExt, StreamExt, TryStreamExt};
 use http::{HeaderMap, HeaderValue, StatusCode};
 use itertools::Itertools;
 
 use serde::{Deserialize, Serialize};
 use std::io::SeekFrom;
 use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::time::Duration;
-use tokio::io::AsyncSeekExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tracing::{debug, info, trace};
 
 use axum::Router;
 
 use crate::api::Api;
+use crate::file_info::FilePriority;
+use crate::http_range::{parse_range_header, RangeRequest};
+use crate::limits::RateLimitsConfig;
+use crate::mime::guess_mime_type;
 use crate::peer_connection::PeerConnectionOptions;
-use crate::session::{AddTorrent, AddTorrentOptions, SUPPORTED_SCHEMES};
+use crate::playlist::PlaylistFormat;
+use crate::session::{AddTorrent, AddTorrentOptions, TorrentId, SUPPORTED_SCHEMES};
 use crate::torrent_state::peer::stats::snapshot::PeerStatsFilter;
+use crate::torrent_state::StreamOptions;
+use crate::upnp_server::{UpnpServer, UpnpServerOptions, UPNP_HTTP_PREFIX};
 
 type ApiState = Api;
 
@@ -36,6 +47,8 @@ pub struct HttpApi {
 #[derive(Debug, Default)]
 pub struct HttpApiOptions {
     pub read_only: bool,
+    /// If set, torrents are also served as a UPnP MediaServer, advertised on the LAN.
+    pub upnp_server: Option<UpnpServerOptions>,
 }
 
 impl HttpApi {
@@ -56,6 +69,7 @@ impl HttpApi {
             axum::Json(serde_json::json!({
                 "apis": {
                     "GET /": "list all available APIs",
+                    "GET /events": "Server-sent events of torrents being added, initialized, paused, started, errored, completed, removed, and of files and pieces being completed",
                     "GET /dht/stats": "DHT stats",
                     "GET /dht/table": "DHT routing table",
                     "GET /torrents": "List torrents (default torrent is 0)",
@@ -63,12 +77,29 @@ impl HttpApi {
                     "GET /torrents/{index}/haves": "The bitfield of have pieces",
                     "GET /torrents/{index}/stats/v1": "Torrent stats",
                     "GET /torrents/{index}/peer_stats": "Per peer stats",
+                    "GET /torrents/{index}/trackers": "Trackers with their tiers and the result of the last announce",
+                    "GET /torrents/{index}/scrape": "Ask the trackers for the number of seeders, leechers and completed downloads",
+                    "POST /torrents/{index}/trackers/add": "Add a tracker. You need to POST json of the following form {\"url\": \"udp://tracker:6969\", \"tier\": 0}, without a tier it's added in a new last tier",
+                    "POST /torrents/{index}/trackers/remove": "Remove a tracker. You need to POST json of the following form {\"url\": \"udp://tracker:6969\"}",
                     "POST /torrents/{index}/pause": "Pause torrent",
                     "POST /torrents/{index}/start": "Resume torrent",
                     "POST /torrents/{index}/forget": "Forget about the torrent, keep the files",
                     "POST /torrents/{index}/delete": "Forget about the torrent, remove the files",
+                    "POST /torrents/{index}/move_storage": "Move the files to another folder without re-checking them. You need to POST json of the following form {\"output_folder\": \"/new/folder\"}",
                     "POST /torrents/{index}/update_only_files": "Change the selection of files to download. You need to POST json of the following form {\"only_files\": [0, 1, 2]}",
+                    "GET /torrents/{index}/file_priorities": "Download priority of each file",
+                    "POST /torrents/{index}/file_priorities": "Change file priorities. You need to POST json of the following form {\"file_priorities\": [\"high\", \"normal\", \"low\", \"skip\"]}, one per file",
+                    "GET /torrents/{index}/limits": "Torrent upload and download limits",
+                    "GET /torrents/{index}/stream/{file_index}": "Stream a file, supports Range requests. Optional query params for tuning: read_ahead_secs, min_read_ahead_bytes, max_read_ahead_bytes, urgent_pieces",
+                    "GET /torrents/{index}/playlist.m3u8": "M3U playlist of the media files in the torrent, also as playlist.xspf",
+                    "GET /torrents/playlist.m3u8": "M3U playlist of the media files in all torrents, also as playlist.xspf",
+                    "POST /torrents/{index}/limits": "Change torrent limits. You need to POST json of the following form {\"upload_bps\": 1048576, \"download_bps\": null}",
+                    "GET /limits": "Session-wide upload and download limits",
+                    "POST /limits": "Change session-wide limits, same format as for torrents",
                     "POST /torrents": "Add a torrent here. magnet: or http:// or a local file.",
+                    "POST /torrents/scrape": "Ask the trackers of a torrent for the number of seeders, leechers and completed downloads without adding it. Same body as for adding",
+                    "GET /announce": "BitTorrent tracker announce, if the tracker is enabled",
+                    "GET /scrape": "BitTorrent tracker scrape, if the tracker is enabled",
                     "POST /rust_log": "Set RUST_LOG to this post launch (for debugging)",
                     "GET /web/": "Web UI",
                 },
@@ -89,13 +120,7 @@ impl HttpApi {
             axum::Json(state.api_torrent_list())
         }
 
-        async fn torrents_post(
-            State(state): State<ApiState>,
-            Query(params): Query<TorrentAddQueryParams>,
-            data: Bytes,
-        ) -> Result<impl IntoResponse> {
-            let is_url = params.is_url;
-            let opts = params.into_add_torrent_options();
+        fn add_torrent_from_body(data: Bytes, is_url: Option<bool>) -> Result<AddTorrent<'static>> {
             let data = data.to_vec();
             let add = match is_url {
                 Some(true) => AddTorrent::Url(
@@ -118,9 +143,52 @@ impl HttpApi {
                 }
                 _ => AddTorrent::TorrentFileBytes(data.into()),
             };
+            Ok(add)
+        }
+
+        async fn torrents_post(
+            State(state): State<ApiState>,
+            Query(params): Query<TorrentAddQueryParams>,
+            data: Bytes,
+        ) -> Result<impl IntoResponse> {
+            let add = add_torrent_from_body(data, params.is_url)?;
+            let opts = params.into_add_torrent_options();
             state.api_add_torrent(add, Some(opts)).await.map(axum::Json)
         }
 
+        async fn torrents_scrape(
+            State(state): State<ApiState>,
+            Query(params): Query<TorrentAddQueryParams>,
+            data: Bytes,
+        ) -> Result<impl IntoResponse> {
+            let add = add_torrent_from_body(data, params.is_url)?;
+            state.api_scrape(add).await.map(axum::Json)
+        }
+
+        async fn torrent_scrape(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+        ) -> Result<impl IntoResponse> {
+            state.api_torrent_scrape(idx).await.map(axum::Json)
+        }
+
+        async fn tracker_announce(
+            State(state): State<ApiState>,
+            ConnectInfo(remote): ConnectInfo<SocketAddr>,
+            RawQuery(query): RawQuery,
+        ) -> Result<impl IntoResponse> {
+            let body = state.api_tracker_announce(&query.unwrap_or_default(), remote.ip())?;
+            Ok(([("Content-Type", "text/plain")], body))
+        }
+
+        async fn tracker_scrape(
+            State(state): State<ApiState>,
+            RawQuery(query): RawQuery,
+        ) -> Result<impl IntoResponse> {
+            let body = state.api_tracker_scrape(&query.unwrap_or_default())?;
+            Ok(([("Content-Type", "text/plain")], body))
+        }
+
         async fn torrent_details(
             State(state): State<ApiState>,
             Path(idx): Path<usize>,
@@ -157,58 +225,179 @@ impl HttpApi {
             state.api_peer_stats(idx, filter).map(axum::Json)
         }
 
+        async fn playlist(
+            state: &ApiState,
+            idx: Option<usize>,
+            format: PlaylistFormat,
+            headers: &HeaderMap,
+        ) -> Result<impl IntoResponse> {
+            let playlist = state.api_playlist(idx, format, &base_url(headers))?;
+            Ok(([("Content-Type", format.content_type())], playlist))
+        }
+
+        async fn torrent_playlist_m3u8(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            headers: HeaderMap,
+        ) -> Result<impl IntoResponse> {
+            playlist(&state, Some(idx), PlaylistFormat::M3u8, &headers).await
+        }
+
+        async fn torrent_playlist_xspf(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            headers: HeaderMap,
+        ) -> Result<impl IntoResponse> {
+            playlist(&state, Some(idx), PlaylistFormat::Xspf, &headers).await
+        }
+
+        async fn all_playlist_m3u8(
+            State(state): State<ApiState>,
+            headers: HeaderMap,
+        ) -> Result<impl IntoResponse> {
+            playlist(&state, None, PlaylistFormat::M3u8, &headers).await
+        }
+
+        async fn all_playlist_xspf(
+            State(state): State<ApiState>,
+            headers: HeaderMap,
+        ) -> Result<impl IntoResponse> {
+            playlist(&state, None, PlaylistFormat::Xspf, &headers).await
+        }
+
         async fn torrent_stream_file(
             State(state): State<ApiState>,
             Path((idx, file_id)): Path<(usize, usize)>,
+            Query(opts): Query<StreamOptions>,
             headers: http::HeaderMap,
         ) -> Result<impl IntoResponse> {
-            let mut stream = state.api_stream(idx, file_id)?;
-            let mut status = StatusCode::OK;
+            let mut stream = state.api_stream(idx, file_id, opts)?;
+            let len = stream.len();
+            let mgr = state.mgr_handle(idx)?;
+            let mime_type = mgr
+                .info()
+                .file_infos
+                .get(file_id)
+                .and_then(|fi| guess_mime_type(&fi.relative_filename));
+            // The contents of a file never change for a given info hash.
+            let etag = format!("\"{}-{}\"", mgr.info_hash().as_string(), file_id);
+
             let mut output_headers = HeaderMap::new();
             output_headers.insert("Accept-Ranges", HeaderValue::from_static("bytes"));
+            output_headers.insert(
+                http::header::ETAG,
+                HeaderValue::from_str(&etag).context("bug")?,
+            );
 
             let range_header = headers.get(http::header::RANGE);
             trace!(torrent_id=idx, file_id=file_id, range=?range_header, "request for HTTP stream");
 
-            if let Some(range) = headers.get(http::header::RANGE) {
-                let offset: Option<u64> = range
-                    .to_str()
-                    .ok()
-                    .and_then(|s| s.strip_prefix("bytes="))
-                    .and_then(|s| s.strip_suffix('-'))
-                    .and_then(|s| s.parse().ok());
-                if let Some(offset) = offset {
-                    status = StatusCode::PARTIAL_CONTENT;
+            // Only send parts if the client has the same file as we do. We don't send
+            // Last-Modified, so dates never match.
+            let if_range_matches = headers
+                .get(http::header::IF_RANGE)
+                .map_or(true, |v| v.as_bytes() == etag.as_bytes());
+            let range = range_header
+                .filter(|_| if_range_matches)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| parse_range_header(v, len))
+                .unwrap_or(RangeRequest::Full);
+
+            if let Some(mime_type) = mime_type {
+                output_headers.insert(
+                    http::header::CONTENT_TYPE,
+                    HeaderValue::from_static(mime_type),
+                );
+            }
+
+            // For HEAD requests axum drops the body, so nothing gets read.
+            let content_length = |l: u64| HeaderValue::from_str(&l.to_string()).context("bug");
+            let (status, body) = match range {
+                RangeRequest::Full => {
+                    output_headers.insert(http::header::CONTENT_LENGTH, content_length(len)?);
+                    let s = tokio_util::io::ReaderStream::new(stream);
+                    (StatusCode::OK, axum::body::Body::from_stream(s))
+                }
+                RangeRequest::Unsatisfiable => {
+                    output_headers.insert(
+                        http::header::CONTENT_RANGE,
+                        HeaderValue::from_str(&format!("bytes */{len}")).context("bug")?,
+                    );
+                    (StatusCode::RANGE_NOT_SATISFIABLE, axum::body::Body::empty())
+                }
+                RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+                    let range = ranges[0];
                     stream
-                        .seek(SeekFrom::Start(offset))
+                        .seek(SeekFrom::Start(range.start))
                         .await
                         .context("error seeking")?;
-
+                    output_headers
+                        .insert(http::header::CONTENT_LENGTH, content_length(range.len())?);
                     output_headers.insert(
-                        http::header::CONTENT_LENGTH,
-                        HeaderValue::from_str(&format!("{}", stream.len() - stream.position()))
-                            .context("bug")?,
+                        http::header::CONTENT_RANGE,
+                        HeaderValue::from_str(&range.content_range(len)).context("bug")?,
                     );
+                    let s = tokio_util::io::ReaderStream::new(stream.take(range.len()));
+                    (
+                        StatusCode::PARTIAL_CONTENT,
+                        axum::body::Body::from_stream(s),
+                    )
+                }
+                RangeRequest::Partial(ranges) => {
+                    let boundary = uuid::Uuid::new_v4().simple().to_string();
+                    let parts = ranges
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, r)| {
+                            let delimiter = if i == 0 { "" } else { "\r\n" };
+                            let content_type = mime_type
+                                .map(|m| format!("Content-Type: {m}\r\n"))
+                                .unwrap_or_default();
+                            let header = format!(
+                                "{delimiter}--{boundary}\r\n{content_type}Content-Range: {}\r\n\r\n",
+                                r.content_range(len)
+                            );
+                            (header, r)
+                        })
+                        .collect::<Vec<_>>();
+                    let trailer = format!("\r\n--{boundary}--\r\n");
+                    let total_len = parts
+                        .iter()
+                        .map(|(h, r)| h.len() as u64 + r.len())
+                        .sum::<u64>()
+                        + trailer.len() as u64;
+
                     output_headers.insert(
-                        http::header::CONTENT_RANGE,
+                        http::header::CONTENT_TYPE,
                         HeaderValue::from_str(&format!(
-                            "bytes {}-{}/{}",
-                            stream.position(),
-                            stream.len().saturating_sub(1),
-                            stream.len()
+                            "multipart/byteranges; boundary={boundary}"
                         ))
                         .context("bug")?,
                     );
-                } else {
-                    output_headers.insert(
-                        http::header::CONTENT_LENGTH,
-                        HeaderValue::from_str(&format!("{}", stream.len())).context("bug")?,
+                    output_headers.insert(http::header::CONTENT_LENGTH, content_length(total_len)?);
+
+                    let s: BoxStream<'static, std::io::Result<Bytes>> = Box::pin(
+                        async_stream::try_stream! {
+                            for (header, range) in parts {
+                                yield Bytes::from(header);
+                                stream.seek(SeekFrom::Start(range.start)).await?;
+                                let mut part =
+                                    tokio_util::io::ReaderStream::new((&mut stream).take(range.len()));
+                                while let Some(chunk) = part.next().await {
+                                    yield chunk?;
+                                }
+                            }
+                            yield Bytes::from(trailer);
+                        },
                     );
+                    (
+                        StatusCode::PARTIAL_CONTENT,
+                        axum::body::Body::from_stream(s),
+                    )
                 }
-            }
+            };
 
-            let s = tokio_util::io::ReaderStream::new(stream);
-            Ok((status, (output_headers, axum::body::Body::from_stream(s))))
+            Ok((status, (output_headers, body)))
         }
 
         async fn torrent_action_pause(
@@ -239,6 +428,50 @@ impl HttpApi {
             state.api_torrent_action_delete(idx).map(axum::Json)
         }
 
+        async fn torrent_action_move_storage(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            axum::Json(req): axum::Json<MoveStorageRequest>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_move_storage(idx, req.output_folder)
+                .await
+                .map(axum::Json)
+        }
+
+        async fn torrent_trackers(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+        ) -> Result<impl IntoResponse> {
+            state.api_torrent_trackers(idx).map(axum::Json)
+        }
+
+        #[derive(Deserialize)]
+        struct TrackerRequest {
+            url: String,
+            tier: Option<usize>,
+        }
+
+        async fn torrent_action_add_tracker(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            axum::Json(req): axum::Json<TrackerRequest>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_add_tracker(idx, req.url, req.tier)
+                .map(axum::Json)
+        }
+
+        async fn torrent_action_remove_tracker(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            axum::Json(req): axum::Json<TrackerRequest>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_remove_tracker(idx, &req.url)
+                .map(axum::Json)
+        }
+
         #[derive(Deserialize)]
         struct UpdateOnlyFilesRequest {
             only_files: Vec<usize>,
@@ -254,6 +487,56 @@ impl HttpApi {
                 .map(axum::Json)
         }
 
+        async fn torrent_file_priorities_get(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+        ) -> Result<impl IntoResponse> {
+            state.api_torrent_get_file_priorities(idx).map(axum::Json)
+        }
+
+        #[derive(Deserialize)]
+        struct UpdateFilePrioritiesRequest {
+            file_priorities: Vec<FilePriority>,
+        }
+
+        async fn torrent_action_update_file_priorities(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            axum::Json(req): axum::Json<UpdateFilePrioritiesRequest>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_update_file_priorities(idx, req.file_priorities)
+                .map(axum::Json)
+        }
+
+        async fn limits_get(State(state): State<ApiState>) -> impl IntoResponse {
+            axum::Json(state.api_get_limits())
+        }
+
+        async fn limits_update(
+            State(state): State<ApiState>,
+            axum::Json(req): axum::Json<RateLimitsConfig>,
+        ) -> impl IntoResponse {
+            axum::Json(state.api_update_limits(req))
+        }
+
+        async fn torrent_limits_get(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+        ) -> Result<impl IntoResponse> {
+            state.api_torrent_get_limits(idx).map(axum::Json)
+        }
+
+        async fn torrent_action_update_limits(
+            State(state): State<ApiState>,
+            Path(idx): Path<usize>,
+            axum::Json(req): axum::Json<RateLimitsConfig>,
+        ) -> Result<impl IntoResponse> {
+            state
+                .api_torrent_action_update_limits(idx, req)
+                .map(axum::Json)
+        }
+
         async fn set_rust_log(
             State(state): State<ApiState>,
             new_value: String,
@@ -269,18 +552,41 @@ impl HttpApi {
             Ok(axum::body::Body::from_stream(s))
         }
 
+        async fn events(State(state): State<ApiState>) -> impl IntoResponse {
+            let s = state
+                .api_events_stream()
+                .map(|event| Event::default().json_data(event));
+            Sse::new(s).keep_alive(KeepAlive::default())
+        }
+
         let mut app = Router::new()
             .route("/", get(api_root))
             .route("/stream_logs", get(stream_logs))
+            .route("/events", get(events))
             .route("/rust_log", post(set_rust_log))
             .route("/dht/stats", get(dht_stats))
             .route("/dht/table", get(dht_table))
+            .route("/announce", get(tracker_announce))
+            .route("/scrape", get(tracker_scrape))
             .route("/torrents", get(torrents_list))
             .route("/torrents/:id", get(torrent_details))
             .route("/torrents/:id/haves", get(torrent_haves))
             .route("/torrents/:id/stats", get(torrent_stats_v0))
             .route("/torrents/:id/stats/v1", get(torrent_stats_v1))
             .route("/torrents/:id/peer_stats", get(peer_stats))
+            .route("/torrents/:id/trackers", get(torrent_trackers))
+            .route("/torrents/:id/scrape", get(torrent_scrape))
+            .route("/torrents/scrape", post(torrents_scrape))
+            .route(
+                "/torrents/:id/file_priorities",
+                get(torrent_file_priorities_get),
+            )
+            .route("/torrents/:id/limits", get(torrent_limits_get))
+            .route("/limits", get(limits_get))
+            .route("/torrents/playlist.m3u8", get(all_playlist_m3u8))
+            .route("/torrents/playlist.xspf", get(all_playlist_xspf))
+            .route("/torrents/:id/playlist.m3u8", get(torrent_playlist_m3u8))
+            .route("/torrents/:id/playlist.xspf", get(torrent_playlist_xspf))
             .route("/torrents/:id/stream/:file_id", get(torrent_stream_file))
             .route(
                 "/torrents/:id/stream/:file_id/*filename",
@@ -294,10 +600,28 @@ impl HttpApi {
                 .route("/torrents/:id/start", post(torrent_action_start))
                 .route("/torrents/:id/forget", post(torrent_action_forget))
                 .route("/torrents/:id/delete", post(torrent_action_delete))
+                .route(
+                    "/torrents/:id/trackers/add",
+                    post(torrent_action_add_tracker),
+                )
+                .route(
+                    "/torrents/:id/trackers/remove",
+                    post(torrent_action_remove_tracker),
+                )
+                .route(
+                    "/torrents/:id/move_storage",
+                    post(torrent_action_move_storage),
+                )
                 .route(
                     "/torrents/:id/update_only_files",
                     post(torrent_action_update_only_files),
-                );
+                )
+                .route(
+                    "/torrents/:id/file_priorities",
+                    post(torrent_action_update_file_priorities),
+                )
+                .route("/torrents/:id/limits", post(torrent_action_update_limits))
+                .route("/limits", post(limits_update));
         }
 
         #[cfg(feature = "webui")]
@@ -363,11 +687,19 @@ impl HttpApi {
                 .allow_headers(AllowHeaders::any())
         };
 
+        let upnp_server = self
+            .opts
+            .upnp_server
+            .map(|opts| UpnpServer::new(state.session().clone(), opts));
+        if let Some(upnp_server) = upnp_server.as_ref() {
+            app = app.nest(UPNP_HTTP_PREFIX, upnp_server.router());
+        }
+
         let app = app
             .layer(cors_layer)
             .layer(tower_http::trace::TraceLayer::new_for_http())
             .with_state(state)
-            .into_make_service();
+            .into_make_service_with_connect_info::<SocketAddr>();
 
         info!(%addr, "starting HTTP server");
 
@@ -377,6 +709,12 @@ impl HttpApi {
             let listener = TcpListener::bind(&addr)
                 .await
                 .with_context(|| format!("error binding to {addr}"))?;
+            if let Some(upnp_server) = upnp_server {
+                upnp_server
+                    .spawn_ssdp(listener.local_addr()?.port())
+                    .await
+                    .context("error starting SSDP server")?;
+            }
             axum::serve(listener, app).await?;
             Ok(())
         }
@@ -384,9 +722,41 @@ impl HttpApi {
     }
 }
 
+/// The URL to reach this server at, from the Host header of a request.
+pub(crate) fn base_url(headers: &HeaderMap) -> String {
+    headers
+        .get(http::header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| format!("http://{h}"))
+        .unwrap_or_default()
+}
+
+/// The absolute URL of the stream endpoint for a file.
+pub(crate) fn stream_url(
+    base_url: &str,
+    torrent_id: TorrentId,
+    file_id: usize,
+    relative_filename: &std::path::Path,
+) -> String {
+    // The filename at the end helps players that look at the extension.
+    let filename = relative_filename
+        .file_name()
+        .map(|f| f.to_string_lossy())
+        .unwrap_or_default();
+    format!(
+        "{base_url}/torrents/{torrent_id}/stream/{file_id}/{}",
+        urlencoding::encode(&filename)
+    )
+}
+
 pub(crate) struct OnlyFiles(Vec<usize>);
 pub(crate) struct InitialPeers(pub Vec<SocketAddr>);
 
+#[derive(Serialize, Deserialize)]
+pub(crate) struct MoveStorageRequest {
+    pub output_folder: PathBuf,
+}
+
 #[derive(Serialize, Deserialize, Default)]
 pub(crate) struct TorrentAddQueryParams {
     pub overwrite: Option<bool>,
@@ -400,6 +770,10 @@ pub(crate) struct TorrentAddQueryParams {
     // Will force interpreting the content as a URL.
     pub is_url: Option<bool>,
     pub list_only: Option<bool>,
+    // Per-torrent limits in bytes per second.
+    pub ratelimit_upload: Option<NonZeroU32>,
+    pub ratelimit_download: Option<NonZeroU32>,
+    pub upload_slots: Option<usize>,
 }
 
 impl Serialize for OnlyFiles {
@@ -484,6 +858,11 @@ impl TorrentAddQueryParams {
                 read_write_timeout: self.peer_read_write_timeout.map(Duration::from_secs),
                 ..Default::default()
             }),
+            ratelimits: RateLimitsConfig {
+                upload_bps: self.ratelimit_upload,
+                download_bps: self.ratelimit_download,
+            },
+            upload_slots: self.upload_slots,
             ..Default::default()
         }
     }
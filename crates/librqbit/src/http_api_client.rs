@@ -1,10 +1,12 @@
+use std::path::PathBuf;
+
 use anyhow::Context;
 use futures::{future::BoxFuture, FutureExt};
 use serde::Deserialize;
 
 use crate::{
-    api::ApiAddTorrentResponse,
-    http_api::TorrentAddQueryParams,
+    api::{ApiAddTorrentResponse, ApiScrapeResponse},
+    http_api::{MoveStorageRequest, TorrentAddQueryParams},
     session::{AddTorrent, AddTorrentOptions},
 };
 
@@ -99,6 +101,9 @@ impl HttpApiClient {
                 output_folder: opts.output_folder,
                 sub_folder: opts.sub_folder,
                 list_only: Some(opts.list_only),
+                ratelimit_upload: opts.ratelimits.upload_bps,
+                ratelimit_download: opts.ratelimits.download_bps,
+                upload_slots: opts.upload_slots,
                 ..Default::default()
             };
             let qs = serde_urlencoded::to_string(&params).unwrap();
@@ -115,4 +120,43 @@ impl HttpApiClient {
         }
         .boxed()
     }
+
+    pub fn scrape<'a>(
+        &'a self,
+        torrent: AddTorrent<'a>,
+    ) -> BoxFuture<'a, anyhow::Result<ApiScrapeResponse>> {
+        async move {
+            let url = format!("{}torrents/scrape", &self.base_url);
+            let response = check_response(
+                self.client
+                    .post(&url)
+                    .body(torrent.into_bytes())
+                    .send()
+                    .await?,
+            )
+            .await?;
+            json_response(response).await
+        }
+        .boxed()
+    }
+
+    pub fn move_storage(
+        &self,
+        id: usize,
+        output_folder: PathBuf,
+    ) -> BoxFuture<'_, anyhow::Result<()>> {
+        async move {
+            let url = format!("{}torrents/{}/move_storage", &self.base_url, id);
+            check_response(
+                self.client
+                    .post(&url)
+                    .json(&MoveStorageRequest { output_folder })
+                    .send()
+                    .await?,
+            )
+            .await?;
+            Ok(())
+        }
+        .boxed()
+    }
 }
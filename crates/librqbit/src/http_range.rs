@@ -0,0 +1,156 @@
+// Parsing of the HTTP Range header (RFC 9110, section 14), for streaming files.
+//
+// Only "bytes" ranges are supported. Anything we don't understand makes us ignore the header and
+// respond with the full file, as the RFC allows.
+
+// Guard against requests for lots of tiny ranges.
+const MAX_RANGES: usize = 32;
+
+/// An inclusive range of bytes, as in the "Content-Range" header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn content_range(&self, total_len: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total_len)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RangeRequest {
+    Full,
+    Partial(Vec<ByteRange>),
+    Unsatisfiable,
+}
+
+fn parse_num(s: &str) -> Option<u64> {
+    if !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+// The outer None means the spec is invalid, the inner one that it's outside the file.
+fn parse_range_spec(spec: &str, len: u64) -> Option<Option<ByteRange>> {
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    if first.is_empty() {
+        // "-N": the last N bytes.
+        let suffix = parse_num(last)?;
+        if suffix == 0 || len == 0 {
+            return Some(None);
+        }
+        return Some(Some(ByteRange {
+            start: len.saturating_sub(suffix),
+            end: len - 1,
+        }));
+    }
+    let first = parse_num(first)?;
+    let last = if last.is_empty() {
+        u64::MAX
+    } else {
+        parse_num(last)?
+    };
+    if last < first {
+        return None;
+    }
+    if first >= len {
+        return Some(None);
+    }
+    Some(Some(ByteRange {
+        start: first,
+        end: last.min(len - 1),
+    }))
+}
+
+pub(crate) fn parse_range_header(value: &str, len: u64) -> RangeRequest {
+    let specs = match value.split_once('=') {
+        Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+        _ => return RangeRequest::Full,
+    };
+
+    let mut seen_specs = false;
+    let mut ranges = Vec::new();
+    // Empty list elements are allowed.
+    for spec in specs.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        seen_specs = true;
+        match parse_range_spec(spec, len) {
+            Some(Some(r)) => ranges.push(r),
+            Some(None) => {}
+            None => return RangeRequest::Full,
+        }
+    }
+
+    if !seen_specs || ranges.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Partial(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_range_header, ByteRange, RangeRequest};
+
+    fn partial(ranges: &[(u64, u64)]) -> RangeRequest {
+        RangeRequest::Partial(
+            ranges
+                .iter()
+                .map(|(start, end)| ByteRange {
+                    start: *start,
+                    end: *end,
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_parse_range_header() {
+        let p = |v: &str| parse_range_header(v, 1000);
+
+        assert_eq!(p("bytes=100-"), partial(&[(100, 999)]));
+        assert_eq!(p("bytes=100-199"), partial(&[(100, 199)]));
+        assert_eq!(p("bytes=900-5000"), partial(&[(900, 999)]));
+        assert_eq!(p("bytes=-500"), partial(&[(500, 999)]));
+        assert_eq!(p("bytes=-5000"), partial(&[(0, 999)]));
+        assert_eq!(
+            p("Bytes=0-0, -1,, 10-19"),
+            partial(&[(0, 0), (999, 999), (10, 19)])
+        );
+
+        // Unsatisfiable ones are skipped, unless there's nothing left.
+        assert_eq!(p("bytes=1000-,0-9"), partial(&[(0, 9)]));
+        assert_eq!(p("bytes=1000-"), RangeRequest::Unsatisfiable);
+        assert_eq!(p("bytes=-0"), RangeRequest::Unsatisfiable);
+        assert_eq!(
+            parse_range_header("bytes=0-", 0),
+            RangeRequest::Unsatisfiable
+        );
+
+        // Invalid headers are ignored.
+        for v in [
+            "items=0-1",
+            "bytes=",
+            "bytes=-",
+            "bytes=5-1",
+            "bytes=a-b",
+            "bytes=+1-2",
+            "bytes=0-1,x",
+            "bytes 0-1",
+        ] {
+            assert_eq!(p(v), RangeRequest::Full, "{v}");
+        }
+
+        let many = format!("bytes={}", vec!["0-0"; 33].join(","));
+        assert_eq!(p(&many), RangeRequest::Full);
+    }
+}
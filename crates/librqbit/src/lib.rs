@@ -30,13 +30,22 @@ mod api_error;
 mod chunk_tracker;
 mod create_torrent_file;
 mod dht_utils;
+mod embedded_tracker;
+mod events;
+mod fastresume;
 pub mod file_info;
 mod file_ops;
+mod hooks;
 pub mod http_api;
 pub mod http_api_client;
+mod http_range;
+mod limits;
 mod merge_streams;
+mod mime;
+mod mse;
 mod peer_connection;
 mod peer_info_reader;
+mod playlist;
 mod read_buf;
 mod session;
 mod spawn_utils;
@@ -44,21 +53,34 @@ pub mod storage;
 mod torrent_state;
 pub mod tracing_subscriber_config_utils;
 mod type_aliases;
+mod upnp_server;
+mod utp;
+mod watch_folder;
 
 pub use api::Api;
 pub use api_error::ApiError;
 pub use create_torrent_file::{create_torrent, CreateTorrentOptions};
 pub use dht;
+pub use embedded_tracker::EmbeddedTrackerOptions;
+pub use events::{TorrentEvent, TorrentEventKind};
+pub use file_info::FilePriority;
+pub use hooks::{Hook, SessionHooks};
+pub use limits::{RateLimits, RateLimitsConfig};
+pub use mse::EncryptionMode;
 pub use peer_connection::PeerConnectionOptions;
+pub use playlist::PlaylistFormat;
 pub use session::{
     AddTorrent, AddTorrentOptions, AddTorrentResponse, ListOnlyResponse, Session, SessionOptions,
     SUPPORTED_SCHEMES,
 };
 pub use spawn_utils::spawn as librqbit_spawn;
 pub use torrent_state::{
-    ManagedTorrent, ManagedTorrentInfo, ManagedTorrentState, TorrentStats, TorrentStatsState,
+    ManagedTorrent, ManagedTorrentInfo, ManagedTorrentState, StreamOptions, TorrentStats,
+    TorrentStatsState,
 };
 pub use type_aliases::FileInfos;
+pub use upnp_server::UpnpServerOptions;
+pub use watch_folder::WatchFolderOptions;
 
 pub use buffers::*;
 pub use clone_to_owned::CloneToOwned;
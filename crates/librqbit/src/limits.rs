@@ -0,0 +1,150 @@
+use std::{num::NonZeroU32, sync::Arc, time::Duration};
+
+use leaky_bucket::RateLimiter;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+/// Bandwidth limits in bytes per second. None means unlimited.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimitsConfig {
+    pub upload_bps: Option<NonZeroU32>,
+    pub download_bps: Option<NonZeroU32>,
+}
+
+// A token bucket that can be reconfigured at runtime.
+// The limiter is swapped as a whole, so that the waiters of the old one aren't blocked
+// forever when the limit is removed or changed.
+#[derive(Default)]
+struct RateLimit {
+    limiter: RwLock<Option<Arc<RateLimiter>>>,
+}
+
+impl RateLimit {
+    fn new(bps: Option<NonZeroU32>) -> Self {
+        let l = Self::default();
+        l.set(bps);
+        l
+    }
+
+    fn set(&self, bps: Option<NonZeroU32>) {
+        let limiter = bps.map(|bps| {
+            let bps = bps.get() as usize;
+            let per_100_ms = (bps / 10).max(1);
+            Arc::new(
+                RateLimiter::builder()
+                    .initial(per_100_ms)
+                    .max(bps)
+                    .interval(Duration::from_millis(100))
+                    .refill(per_100_ms)
+                    .build(),
+            )
+        });
+        *self.limiter.write() = limiter;
+    }
+
+    async fn acquire(&self, bytes: usize) {
+        let limiter = self.limiter.read().clone();
+        if let Some(limiter) = limiter {
+            limiter.acquire(bytes).await;
+        }
+    }
+}
+
+/// Upload and download limits. These are used both session-wide and per torrent.
+/// Per-torrent limits have the session ones as the parent, so both apply.
+pub struct RateLimits {
+    config: RwLock<RateLimitsConfig>,
+    upload: RateLimit,
+    download: RateLimit,
+    parent: Option<Arc<RateLimits>>,
+}
+
+impl RateLimits {
+    pub fn new(config: RateLimitsConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            upload: RateLimit::new(config.upload_bps),
+            download: RateLimit::new(config.download_bps),
+            parent: None,
+        }
+    }
+
+    pub(crate) fn new_child(parent: Arc<RateLimits>, config: RateLimitsConfig) -> Self {
+        Self {
+            parent: Some(parent),
+            ..Self::new(config)
+        }
+    }
+
+    pub fn get_config(&self) -> RateLimitsConfig {
+        *self.config.read()
+    }
+
+    pub fn set_config(&self, config: RateLimitsConfig) {
+        let mut g = self.config.write();
+        self.upload.set(config.upload_bps);
+        self.download.set(config.download_bps);
+        *g = config;
+    }
+
+    pub(crate) async fn prepare_for_upload(&self, bytes: usize) {
+        self.upload.acquire(bytes).await;
+        if let Some(parent) = &self.parent {
+            parent.upload.acquire(bytes).await;
+        }
+    }
+
+    pub(crate) async fn prepare_for_download(&self, bytes: usize) {
+        self.download.acquire(bytes).await;
+        if let Some(parent) = &self.parent {
+            parent.download.acquire(bytes).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        num::NonZeroU32,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use super::{RateLimits, RateLimitsConfig};
+
+    #[tokio::test]
+    async fn test_limits_apply_and_change_at_runtime() {
+        let session = Arc::new(RateLimits::new(Default::default()));
+        let torrent = RateLimits::new_child(
+            session.clone(),
+            RateLimitsConfig {
+                download_bps: NonZeroU32::new(100_000),
+                ..Default::default()
+            },
+        );
+
+        // 10_000 is available initially, the rest takes 4 refills of 100ms.
+        let start = Instant::now();
+        torrent.prepare_for_download(50_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        // Uploads aren't limited.
+        let start = Instant::now();
+        torrent.prepare_for_upload(1_000_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // Session limits apply to the torrent too.
+        session.set_config(RateLimitsConfig {
+            upload_bps: NonZeroU32::new(100_000),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        torrent.prepare_for_upload(50_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        torrent.set_config(Default::default());
+        let start = Instant::now();
+        torrent.prepare_for_download(1_000_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,60 @@
+// MIME types of files in torrents, guessed from their extensions. Tells players and media
+// renderers what they are about to stream.
+
+use std::path::Path;
+
+pub(crate) fn guess_mime_type(filename: &Path) -> Option<&'static str> {
+    let ext = filename.extension()?.to_str()?.to_ascii_lowercase();
+    let mime = match ext.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mov" => "video/quicktime",
+        "wmv" => "video/x-ms-wmv",
+        "flv" => "video/x-flv",
+        "mpg" | "mpeg" => "video/mpeg",
+        "ts" | "m2ts" => "video/mp2t",
+        "3gp" => "video/3gpp",
+        "ogv" => "video/ogg",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "m4a" => "audio/mp4",
+        "aac" => "audio/aac",
+        "ogg" | "oga" => "audio/ogg",
+        "opus" => "audio/opus",
+        "wav" => "audio/wav",
+        "wma" => "audio/x-ms-wma",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "srt" => "application/x-subrip",
+        "vtt" => "text/vtt",
+        _ => return None,
+    };
+    Some(mime)
+}
+
+pub(crate) fn is_media_file(filename: &Path) -> bool {
+    guess_mime_type(filename).is_some_and(|m| m.starts_with("video/") || m.starts_with("audio/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{guess_mime_type, is_media_file};
+
+    #[test]
+    fn test_guess_mime_type() {
+        assert_eq!(
+            guess_mime_type(Path::new("Show/S01E01.MKV")),
+            Some("video/x-matroska")
+        );
+        assert_eq!(guess_mime_type(Path::new("cover.jpg")), Some("image/jpeg"));
+        assert_eq!(guess_mime_type(Path::new("README")), None);
+        assert!(is_media_file(Path::new("Show/S01E01.mkv")));
+        assert!(!is_media_file(Path::new("Show/S01E01.srt")));
+    }
+}
@@ -0,0 +1,578 @@
+// Message Stream Encryption / Protocol Encryption (MSE/PE).
+//
+// Obfuscates peer connections so that they can't be recognized by the plaintext BitTorrent
+// handshake: a Diffie-Hellman key exchange, followed by RC4 with keys derived from the shared
+// secret and the info hash. After the handshake, MseStream is used in place of the raw socket.
+
+mod rc4;
+
+use std::{
+    io,
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+};
+
+use anyhow::{bail, Context as _};
+use byteorder::{ByteOrder, BE};
+use librqbit_core::hash_id::Id20;
+use num_bigint::BigUint;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha1w::{ISha1, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use self::rc4::Rc4;
+
+// The 768-bit MODP prime from the spec, with generator 2.
+const DH_PRIME_HEX: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6\
+F44C42E9A63A36210000000000090563";
+const DH_KEY_LEN: usize = 96;
+const MAX_PAD_LEN: usize = 512;
+const RC4_DISCARD: usize = 1024;
+const VC: [u8; 8] = [0; 8];
+
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+const PLAINTEXT_HANDSHAKE_PREFIX: &[u8] = b"\x13BitTorrent protocol";
+
+// Don't encrypt more than this per write speculatively, see MseStream::poll_write.
+const MAX_WRITE_CHUNK: usize = 64 * 1024;
+
+/// Whether to use Message Stream Encryption for peer connections.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionMode {
+    /// Connect with encryption first, falling back to plaintext. Accept both.
+    #[default]
+    Prefer,
+    /// Only use and accept encrypted connections.
+    Require,
+    /// Only use and accept plaintext connections.
+    Disable,
+}
+
+impl FromStr for EncryptionMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "prefer" => Self::Prefer,
+            "require" => Self::Require,
+            "disable" => Self::Disable,
+            _ => bail!("unknown encryption mode {s:?}, expected one of prefer, require, disable"),
+        })
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut h = Sha1::new();
+    for p in parts {
+        h.update(p);
+    }
+    h.finish()
+}
+
+fn xor20(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut out = [0u8; 20];
+    for (o, (a, b)) in out.iter_mut().zip(a.iter().zip(b.iter())) {
+        *o = a ^ b;
+    }
+    out
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0..=MAX_PAD_LEN);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+// Length-prefixed random padding.
+fn push_random_pad(out: &mut Vec<u8>) {
+    let pad = random_pad();
+    #[allow(clippy::cast_possible_truncation)]
+    out.extend_from_slice(&(pad.len() as u16).to_be_bytes());
+    out.extend_from_slice(&pad);
+}
+
+fn new_cipher(name: &[u8], secret: &[u8; DH_KEY_LEN], info_hash: &Id20) -> Rc4 {
+    let mut c = Rc4::new(&hash(&[name, secret, &info_hash.0]));
+    c.discard(RC4_DISCARD);
+    c
+}
+
+struct DhKeys {
+    private: BigUint,
+    public: [u8; DH_KEY_LEN],
+}
+
+impl DhKeys {
+    fn prime() -> BigUint {
+        BigUint::parse_bytes(DH_PRIME_HEX, 16).unwrap()
+    }
+
+    fn to_fixed_bytes(v: &BigUint) -> [u8; DH_KEY_LEN] {
+        let b = v.to_bytes_be();
+        let mut out = [0u8; DH_KEY_LEN];
+        out[DH_KEY_LEN - b.len()..].copy_from_slice(&b);
+        out
+    }
+
+    fn generate() -> Self {
+        let private = BigUint::from_bytes_be(&rand::thread_rng().gen::<[u8; 20]>());
+        let public = BigUint::from(2u32).modpow(&private, &Self::prime());
+        Self {
+            public: Self::to_fixed_bytes(&public),
+            private,
+        }
+    }
+
+    fn shared_secret(&self, remote_public: &[u8]) -> [u8; DH_KEY_LEN] {
+        let remote = BigUint::from_bytes_be(remote_public);
+        Self::to_fixed_bytes(&remote.modpow(&self.private, &Self::prime()))
+    }
+}
+
+// Buffers what was received during the handshake. Whatever is left over after it belongs
+// to the stream.
+struct HandshakeIo<'a, S> {
+    stream: &'a mut S,
+    buf: Vec<u8>,
+}
+
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> HandshakeIo<'a, S> {
+    fn new(stream: &'a mut S) -> Self {
+        Self {
+            stream,
+            buf: Vec::new(),
+        }
+    }
+
+    async fn read_more(&mut self) -> anyhow::Result<()> {
+        let mut chunk = [0u8; 4096];
+        let n = self.stream.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("peer disconnected during encryption handshake");
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    async fn fill(&mut self, len: usize) -> anyhow::Result<()> {
+        while self.buf.len() < len {
+            self.read_more().await?;
+        }
+        Ok(())
+    }
+
+    async fn take(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
+        self.fill(len).await?;
+        Ok(self.buf.drain(..len).collect())
+    }
+
+    // Skip past "pattern", which must start within the first "max_offset" bytes.
+    async fn sync_to(&mut self, pattern: &[u8], max_offset: usize) -> anyhow::Result<()> {
+        loop {
+            if let Some(pos) = self.buf.windows(pattern.len()).position(|w| w == pattern) {
+                if pos <= max_offset {
+                    self.buf.drain(..pos + pattern.len());
+                    return Ok(());
+                }
+            }
+            if self.buf.len() >= max_offset + pattern.len() {
+                bail!("synchronization pattern not found");
+            }
+            self.read_more().await?;
+        }
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        self.stream.write_all(buf).await?;
+        Ok(())
+    }
+
+    fn into_leftover(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Run the initiating side of the handshake, for an outgoing connection.
+pub(crate) async fn handshake_outgoing<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hash: Id20,
+    mode: EncryptionMode,
+) -> anyhow::Result<MseStream<S>> {
+    let crypto_provide = match mode {
+        EncryptionMode::Require => CRYPTO_RC4,
+        _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+    };
+
+    let keys = DhKeys::generate();
+    let mut io = HandshakeIo::new(&mut stream);
+    io.write_all(&[&keys.public[..], &random_pad()].concat())
+        .await?;
+
+    let yb = io.take(DH_KEY_LEN).await?;
+    let secret = keys.shared_secret(&yb);
+    let mut encrypt = new_cipher(b"keyA", &secret, &info_hash);
+    let mut decrypt = new_cipher(b"keyB", &secret, &info_hash);
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&hash(&[b"req1", &secret]));
+    out.extend_from_slice(&xor20(
+        &hash(&[b"req2", &info_hash.0]),
+        &hash(&[b"req3", &secret]),
+    ));
+    let encrypted_start = out.len();
+    out.extend_from_slice(&VC);
+    out.extend_from_slice(&crypto_provide.to_be_bytes());
+    push_random_pad(&mut out);
+    // No initial payload, the BitTorrent handshake is sent through the resulting stream.
+    out.extend_from_slice(&0u16.to_be_bytes());
+    encrypt.apply(&mut out[encrypted_start..]);
+    io.write_all(&out).await?;
+
+    // The reply starts with the encrypted VC, somewhere after the peer's padding.
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    io.sync_to(&vc, MAX_PAD_LEN)
+        .await
+        .context("error reading encryption handshake reply")?;
+    let mut reply = io.take(6).await?;
+    decrypt.apply(&mut reply);
+    let crypto_select = BE::read_u32(&reply[..4]);
+    let pad_d_len = BE::read_u16(&reply[4..6]) as usize;
+    if pad_d_len > MAX_PAD_LEN {
+        bail!("padding too long: {pad_d_len}");
+    }
+    let mut pad_d = io.take(pad_d_len).await?;
+    decrypt.apply(&mut pad_d);
+    let mut leftover = io.into_leftover();
+
+    if crypto_select.count_ones() != 1 || crypto_select & crypto_provide == 0 {
+        bail!("peer selected an unsupported crypto method {crypto_select:#x}");
+    }
+    if crypto_select == CRYPTO_RC4 {
+        decrypt.apply(&mut leftover);
+        Ok(MseStream::new(stream, leftover, Some((encrypt, decrypt))))
+    } else {
+        Ok(MseStream::new(stream, leftover, None))
+    }
+}
+
+/// Run the receiving side of the handshake, for an incoming connection. Each known torrent
+/// comes with its own encryption mode.
+///
+/// Plaintext BitTorrent handshakes are detected and passed through as is, unless all torrents
+/// require encryption. As the torrent isn't known until the BitTorrent handshake is read, the
+/// caller must then check that its mode allows plaintext.
+pub(crate) async fn handshake_incoming<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    known_info_hashes: &[(Id20, EncryptionMode)],
+) -> anyhow::Result<MseStream<S>> {
+    let mut io = HandshakeIo::new(&mut stream);
+    io.fill(PLAINTEXT_HANDSHAKE_PREFIX.len()).await?;
+    if io.buf.starts_with(PLAINTEXT_HANDSHAKE_PREFIX) {
+        if known_info_hashes
+            .iter()
+            .all(|(_, mode)| *mode == EncryptionMode::Require)
+        {
+            bail!("peer sent a plaintext handshake, but encryption is required");
+        }
+        let leftover = io.into_leftover();
+        return Ok(MseStream::new(stream, leftover, None));
+    }
+
+    let ya = io.take(DH_KEY_LEN).await?;
+    let keys = DhKeys::generate();
+    io.write_all(&[&keys.public[..], &random_pad()].concat())
+        .await?;
+    let secret = keys.shared_secret(&ya);
+
+    io.sync_to(&hash(&[b"req1", &secret]), MAX_PAD_LEN)
+        .await
+        .context("error reading encryption handshake")?;
+    let req2_xor_req3: [u8; 20] = io.take(20).await?.try_into().unwrap();
+    let req2 = xor20(&req2_xor_req3, &hash(&[b"req3", &secret]));
+    let (info_hash, mode) = known_info_hashes
+        .iter()
+        .find(|(ih, _)| hash(&[b"req2", &ih.0]) == req2)
+        .copied()
+        .context("encrypted handshake for an unknown torrent")?;
+    if mode == EncryptionMode::Disable {
+        bail!("peer sent an encrypted handshake, but encryption is disabled");
+    }
+
+    let mut decrypt = new_cipher(b"keyA", &secret, &info_hash);
+    let mut encrypt = new_cipher(b"keyB", &secret, &info_hash);
+
+    let mut req = io.take(14).await?;
+    decrypt.apply(&mut req);
+    if req[..8] != VC {
+        bail!("invalid verification constant");
+    }
+    let crypto_provide = BE::read_u32(&req[8..12]);
+    let pad_c_len = BE::read_u16(&req[12..14]) as usize;
+    if pad_c_len > MAX_PAD_LEN {
+        bail!("padding too long: {pad_c_len}");
+    }
+    let mut pad_c_and_ia_len = io.take(pad_c_len + 2).await?;
+    decrypt.apply(&mut pad_c_and_ia_len);
+    let ia_len = BE::read_u16(&pad_c_and_ia_len[pad_c_len..]) as usize;
+    // The initial payload is encrypted even if plaintext is selected.
+    let mut initial_payload = io.take(ia_len).await?;
+    decrypt.apply(&mut initial_payload);
+
+    let crypto_select = if crypto_provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if crypto_provide & CRYPTO_PLAINTEXT != 0 && mode == EncryptionMode::Prefer {
+        CRYPTO_PLAINTEXT
+    } else {
+        bail!("no acceptable crypto method, peer provided {crypto_provide:#x}");
+    };
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&VC);
+    out.extend_from_slice(&crypto_select.to_be_bytes());
+    push_random_pad(&mut out);
+    encrypt.apply(&mut out);
+    io.write_all(&out).await?;
+
+    let mut leftover = io.into_leftover();
+    if crypto_select == CRYPTO_RC4 {
+        decrypt.apply(&mut leftover);
+        initial_payload.extend_from_slice(&leftover);
+        Ok(MseStream::new(
+            stream,
+            initial_payload,
+            Some((encrypt, decrypt)),
+        ))
+    } else {
+        initial_payload.extend_from_slice(&leftover);
+        Ok(MseStream::new(stream, initial_payload, None))
+    }
+}
+
+/// A peer connection after the encryption handshake. If plaintext was negotiated (or the
+/// handshake was skipped), this passes everything through as is.
+pub(crate) struct MseStream<S> {
+    inner: S,
+    // Plaintext that was received during the handshake, to be read before anything else.
+    read_prefix: Vec<u8>,
+    read_prefix_pos: usize,
+    // (encrypt, decrypt)
+    ciphers: Option<(Rc4, Rc4)>,
+    write_buf: Vec<u8>,
+}
+
+impl<S> MseStream<S> {
+    fn new(inner: S, read_prefix: Vec<u8>, ciphers: Option<(Rc4, Rc4)>) -> Self {
+        Self {
+            inner,
+            read_prefix,
+            read_prefix_pos: 0,
+            ciphers,
+            write_buf: Vec::new(),
+        }
+    }
+
+    pub fn plaintext(inner: S) -> Self {
+        Self::new(inner, Vec::new(), None)
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.ciphers.is_some()
+    }
+}
+
+impl<S: AsyncRead + Unpin> MseStream<S> {
+    fn poll_read_inner(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled_before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if let Some((_, decrypt)) = &mut self.ciphers {
+            decrypt.apply(&mut buf.filled_mut()[filled_before..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.read_prefix_pos == this.read_prefix.len() {
+            return this.poll_read_inner(cx, buf);
+        }
+
+        let remaining = &this.read_prefix[this.read_prefix_pos..];
+        let len = remaining.len().min(buf.remaining());
+        buf.put_slice(&remaining[..len]);
+        this.read_prefix_pos += len;
+        if this.read_prefix_pos == this.read_prefix.len() {
+            this.read_prefix = Vec::new();
+            this.read_prefix_pos = 0;
+        }
+
+        // Readers like ReadBuf::read_handshake() expect the whole handshake from one read, so
+        // append whatever else is available. If nothing is, or there's an error, it will show
+        // up on the next read.
+        if buf.remaining() > 0 {
+            let _ = this.poll_read_inner(cx, buf);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let encrypt = match &mut this.ciphers {
+            Some((encrypt, _)) => encrypt,
+            None => return Pin::new(&mut this.inner).poll_write(cx, buf),
+        };
+
+        // The keystream can't be rewound, so encrypt with a copy of the cipher, and only
+        // advance the real one by what the inner stream accepted.
+        let buf = &buf[..buf.len().min(MAX_WRITE_CHUNK)];
+        let mut speculative = encrypt.clone();
+        this.write_buf.clear();
+        this.write_buf.extend_from_slice(buf);
+        speculative.apply(&mut this.write_buf);
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &this.write_buf))?;
+        if written == buf.len() {
+            *encrypt = speculative;
+        } else {
+            encrypt.discard(written);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use librqbit_core::hash_id::Id20;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::{handshake_incoming, handshake_outgoing, EncryptionMode};
+
+    fn info_hash() -> Id20 {
+        Id20::new([42; 20])
+    }
+
+    #[tokio::test]
+    async fn test_mse_handshake_and_transfer() {
+        let (a, b) = duplex(1024);
+        let known = [
+            (Id20::new([1; 20]), EncryptionMode::Disable),
+            (info_hash(), EncryptionMode::Prefer),
+        ];
+        let (a, b) = tokio::join!(
+            handshake_outgoing(a, info_hash(), EncryptionMode::Prefer),
+            handshake_incoming(b, &known),
+        );
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        assert!(a.is_encrypted());
+        assert!(b.is_encrypted());
+
+        // More than the duplex buffer, to exercise partial writes.
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let to_write = data.clone();
+        let writer = tokio::spawn(async move {
+            a.write_all(&to_write).await.unwrap();
+            a.shutdown().await.unwrap();
+            a
+        });
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).await.unwrap();
+        assert!(received == data);
+
+        let mut a = writer.await.unwrap();
+        b.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_mse_incoming_plaintext() {
+        let mut handshake = Vec::new();
+        peer_binary_protocol::Handshake::new(info_hash(), Id20::new([1; 20]))
+            .serialize(&mut handshake);
+
+        for (mode, ok) in [
+            (EncryptionMode::Prefer, true),
+            (EncryptionMode::Disable, true),
+            (EncryptionMode::Require, false),
+        ] {
+            let (mut a, b) = duplex(1024);
+            a.write_all(&handshake).await.unwrap();
+            let r = handshake_incoming(b, &[(info_hash(), mode)]).await;
+            assert_eq!(r.is_ok(), ok, "{mode:?}");
+            if let Ok(mut b) = r {
+                assert!(!b.is_encrypted());
+                // The whole handshake must come in one read.
+                let mut buf = [0u8; 1024];
+                let n = b.read(&mut buf).await.unwrap();
+                assert_eq!(&buf[..n], &handshake[..]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mse_handshake_failures() {
+        // Encryption disabled for the torrent on the receiving side.
+        let (a, b) = duplex(1024);
+        let (a, b) = tokio::join!(
+            handshake_outgoing(a, info_hash(), EncryptionMode::Require),
+            async move {
+                let known = [
+                    (info_hash(), EncryptionMode::Disable),
+                    (Id20::new([1; 20]), EncryptionMode::Require),
+                ];
+                let r = handshake_incoming(b, &known).await;
+                // Drop the stream, so that the other side sees the disconnect.
+                r.map(|_| ())
+            },
+        );
+        assert!(a.is_err());
+        assert!(b.is_err());
+
+        // Unknown torrent.
+        let (a, b) = duplex(1024);
+        let (a, b) = tokio::join!(
+            handshake_outgoing(a, info_hash(), EncryptionMode::Prefer),
+            async move {
+                handshake_incoming(b, &[(Id20::new([1; 20]), EncryptionMode::Prefer)])
+                    .await
+                    .map(|_| ())
+            },
+        );
+        assert!(a.is_err());
+        assert!(b.is_err());
+    }
+}
@@ -0,0 +1,59 @@
+// RC4, which MSE uses as its stream cipher. It's too small and too obsolete to pull a crate for.
+
+#[derive(Clone)]
+pub struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut s = [0u8; 256];
+        for (v, i) in s.iter_mut().zip(0..=255u8) {
+            *v = i;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Self { s, i: 0, j: 0 }
+    }
+
+    // Encrypts or decrypts in place.
+    pub fn apply(&mut self, buf: &mut [u8]) {
+        for b in buf {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+            *b ^= k;
+        }
+    }
+
+    pub fn discard(&mut self, mut len: usize) {
+        let mut buf = [0u8; 256];
+        while len > 0 {
+            let n = len.min(buf.len());
+            self.apply(&mut buf[..n]);
+            len -= n;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rc4;
+
+    #[test]
+    fn test_rc4_known_vectors() {
+        let mut buf = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut buf);
+        assert_eq!(hex::encode(buf), "bbf316e8d940af0ad3");
+
+        let mut buf = *b"Attack at dawn";
+        Rc4::new(b"Secret").apply(&mut buf);
+        assert_eq!(hex::encode(buf), "45a01f645fc35b383552544b9bf5");
+    }
+}
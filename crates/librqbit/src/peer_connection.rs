@@ -1,5 +1,9 @@
 use std::{
+    io,
     net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
     time::{Duration, Instant},
 };
 
@@ -18,20 +22,59 @@ use peer_binary_protocol::{
 };
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
-use tokio::time::timeout;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    time::timeout,
+};
 use tracing::{debug, trace};
 
-use crate::{read_buf::ReadBuf, spawn_utils::BlockingSpawner};
+use crate::{
+    limits::RateLimits,
+    mse::{self, EncryptionMode, MseStream},
+    read_buf::ReadBuf,
+    spawn_utils::BlockingSpawner,
+    utp::{UtpSocket, UtpStream},
+};
+
+// Protocol extensions negotiated through the reserved handshake bits. We always set
+// them ourselves, so each one is enabled if the peer set it too.
+#[derive(Clone, Copy)]
+struct PeerFeatures {
+    extended: bool,
+    // BEP 6
+    fast: bool,
+}
+
+impl PeerFeatures {
+    fn from_handshake<B>(h: &Handshake<B>) -> Self {
+        Self {
+            extended: h.supports_extended(),
+            fast: h.supports_fast(),
+        }
+    }
+}
 
 pub trait PeerConnectionHandler {
-    fn on_connected(&self, _connection_time: Duration) {}
-    fn get_have_bytes(&self) -> u64;
-    fn serialize_bitfield_message_to_buf(&self, buf: &mut Vec<u8>) -> anyhow::Result<usize>;
+    fn on_connected(&self, _connection_time: Duration, _over_utp: bool) {}
+    // Serializes the first message telling the peer which pieces we have. With the fast
+    // extension, this may be HaveAll or HaveNone. Returns 0 if there's nothing to send.
+    fn serialize_bitfield_message_to_buf(
+        &self,
+        buf: &mut Vec<u8>,
+        supports_fast: bool,
+    ) -> anyhow::Result<usize>;
     fn on_handshake<B>(&self, handshake: Handshake<B>) -> anyhow::Result<()>;
     fn on_extended_handshake(
         &self,
         extended_handshake: &ExtendedHandshake<ByteBuf>,
     ) -> anyhow::Result<()>;
+    fn update_my_extended_handshake(
+        &self,
+        _handshake: &mut ExtendedHandshake<ByteBuf<'static>>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
     async fn on_received_message(&self, msg: Message<ByteBuf<'_>>) -> anyhow::Result<()>;
     fn should_transmit_have(&self, id: ValidPieceIndex) -> bool;
     fn on_uploaded_bytes(&self, bytes: u32);
@@ -56,6 +99,69 @@ pub struct PeerConnectionOptions {
 
     #[serde_as(as = "Option<serde_with::DurationSeconds>")]
     pub keep_alive_interval: Option<Duration>,
+
+    #[serde(default)]
+    pub encryption: Option<EncryptionMode>,
+}
+
+// The transports to try for outgoing connections.
+#[derive(Clone, Default)]
+pub(crate) struct OutgoingTransports {
+    pub utp_socket: Option<Arc<UtpSocket>>,
+    // Try uTP first, and fall back to TCP. The default is the other way around.
+    pub prefer_utp: bool,
+}
+
+// A connected peer socket, either TCP or uTP.
+pub(crate) enum PeerSocket {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl PeerSocket {
+    pub fn is_utp(&self) -> bool {
+        matches!(self, PeerSocket::Utp(_))
+    }
+}
+
+impl AsyncRead for PeerSocket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerSocket::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            PeerSocket::Utp(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerSocket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PeerSocket::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            PeerSocket::Utp(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerSocket::Tcp(s) => Pin::new(s).poll_flush(cx),
+            PeerSocket::Utp(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerSocket::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            PeerSocket::Utp(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
 }
 
 pub(crate) struct PeerConnection<H> {
@@ -65,6 +171,7 @@ pub(crate) struct PeerConnection<H> {
     peer_id: Id20,
     options: PeerConnectionOptions,
     spawner: BlockingSpawner,
+    ratelimits: Option<Arc<RateLimits>>,
 }
 
 pub(crate) async fn with_timeout<T, E>(
@@ -88,6 +195,7 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
         handler: H,
         options: Option<PeerConnectionOptions>,
         spawner: BlockingSpawner,
+        ratelimits: Option<Arc<RateLimits>>,
     ) -> Self {
         PeerConnection {
             handler,
@@ -96,6 +204,7 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
             peer_id,
             spawner,
             options: options.unwrap_or_default(),
+            ratelimits,
         }
     }
 
@@ -106,7 +215,7 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
         outgoing_chan: tokio::sync::mpsc::UnboundedReceiver<WriterRequest>,
         read_buf: ReadBuf,
         handshake: Handshake<ByteBufOwned>,
-        mut conn: tokio::net::TcpStream,
+        mut conn: MseStream<PeerSocket>,
         have_broadcast: tokio::sync::broadcast::Receiver<ValidPieceIndex>,
     ) -> anyhow::Result<()> {
         use tokio::io::AsyncWriteExt;
@@ -130,19 +239,19 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
         );
 
         let mut write_buf = Vec::<u8>::with_capacity(PIECE_MESSAGE_DEFAULT_LEN);
-        let handshake = Handshake::new(self.info_hash, self.peer_id);
-        handshake.serialize(&mut write_buf);
+        let my_handshake = Handshake::new(self.info_hash, self.peer_id);
+        my_handshake.serialize(&mut write_buf);
         with_timeout(rwtimeout, conn.write_all(&write_buf))
             .await
             .context("error writing handshake")?;
         write_buf.clear();
 
-        let h_supports_extended = handshake.supports_extended();
+        let features = PeerFeatures::from_handshake(&handshake);
 
         self.handler.on_handshake(handshake)?;
 
         self.manage_peer(
-            h_supports_extended,
+            features,
             read_buf,
             write_buf,
             conn,
@@ -152,10 +261,57 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
         .await
     }
 
+    async fn connect_tcp(&self, connect_timeout: Duration) -> anyhow::Result<PeerSocket> {
+        let conn = with_timeout(connect_timeout, TcpStream::connect(self.addr))
+            .await
+            .context("error connecting over TCP")?;
+        Ok(PeerSocket::Tcp(conn))
+    }
+
+    async fn connect_utp(
+        &self,
+        utp_socket: &Arc<UtpSocket>,
+        connect_timeout: Duration,
+    ) -> anyhow::Result<PeerSocket> {
+        let conn = with_timeout(connect_timeout, utp_socket.connect(self.addr))
+            .await
+            .context("error connecting over uTP")?;
+        Ok(PeerSocket::Utp(conn))
+    }
+
+    async fn connect(
+        &self,
+        transports: &OutgoingTransports,
+        connect_timeout: Duration,
+    ) -> anyhow::Result<PeerSocket> {
+        // The uTP socket is IPv4 only, IPv6 peers are reached over TCP.
+        let utp_socket = match &transports.utp_socket {
+            Some(s) if self.addr.is_ipv4() => s,
+            _ => return self.connect_tcp(connect_timeout).await,
+        };
+        let first = if transports.prefer_utp {
+            self.connect_utp(utp_socket, connect_timeout).await
+        } else {
+            self.connect_tcp(connect_timeout).await
+        };
+        match first {
+            Ok(conn) => Ok(conn),
+            Err(e) => {
+                debug!("{e:#}, trying the other transport");
+                if transports.prefer_utp {
+                    self.connect_tcp(connect_timeout).await
+                } else {
+                    self.connect_utp(utp_socket, connect_timeout).await
+                }
+            }
+        }
+    }
+
     pub async fn manage_peer_outgoing(
         &self,
         outgoing_chan: tokio::sync::mpsc::UnboundedReceiver<WriterRequest>,
         have_broadcast: tokio::sync::broadcast::Receiver<ValidPieceIndex>,
+        transports: &OutgoingTransports,
     ) -> anyhow::Result<()> {
         use tokio::io::AsyncWriteExt;
         let rwtimeout = self
@@ -169,10 +325,35 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
             .unwrap_or_else(|| Duration::from_secs(10));
 
         let now = Instant::now();
-        let mut conn = with_timeout(connect_timeout, tokio::net::TcpStream::connect(self.addr))
+        let conn = self.connect(transports, connect_timeout).await?;
+        self.handler.on_connected(now.elapsed(), conn.is_utp());
+
+        let mode = self.options.encryption.unwrap_or_default();
+        let mut conn = match mode {
+            EncryptionMode::Disable => MseStream::plaintext(conn),
+            EncryptionMode::Require => with_timeout(
+                rwtimeout,
+                mse::handshake_outgoing(conn, self.info_hash, mode),
+            )
             .await
-            .context("error connecting")?;
-        self.handler.on_connected(now.elapsed());
+            .context("error in encryption handshake")?,
+            EncryptionMode::Prefer => {
+                match with_timeout(
+                    rwtimeout,
+                    mse::handshake_outgoing(conn, self.info_hash, mode),
+                )
+                .await
+                {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        // The peer has most likely dropped the connection, so start over.
+                        debug!("{e:#}, reconnecting without encryption");
+                        MseStream::plaintext(self.connect(transports, connect_timeout).await?)
+                    }
+                }
+            }
+        };
+        trace!(encrypted = conn.is_encrypted(), "connected");
 
         let mut write_buf = Vec::<u8>::with_capacity(PIECE_MESSAGE_DEFAULT_LEN);
         let handshake = Handshake::new(self.info_hash, self.peer_id);
@@ -187,7 +368,7 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
             .read_handshake(&mut conn, rwtimeout)
             .await
             .context("error reading handshake")?;
-        let h_supports_extended = h.supports_extended();
+        let features = PeerFeatures::from_handshake(&h);
         trace!(
             "connected: id={:?}",
             try_decode_peer_id(Id20::new(h.peer_id))
@@ -203,7 +384,7 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
         self.handler.on_handshake(h)?;
 
         self.manage_peer(
-            h_supports_extended,
+            features,
             read_buf,
             write_buf,
             conn,
@@ -215,10 +396,10 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
 
     async fn manage_peer(
         &self,
-        handshake_supports_extended: bool,
+        features: PeerFeatures,
         mut read_buf: ReadBuf,
         mut write_buf: Vec<u8>,
-        mut conn: tokio::net::TcpStream,
+        mut conn: MseStream<PeerSocket>,
         mut outgoing_chan: tokio::sync::mpsc::UnboundedReceiver<WriterRequest>,
         mut have_broadcast: tokio::sync::broadcast::Receiver<ValidPieceIndex>,
     ) -> anyhow::Result<()> {
@@ -231,13 +412,17 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
 
         let extended_handshake: RwLock<Option<ExtendedHandshake<ByteBufOwned>>> = RwLock::new(None);
         let extended_handshake_ref = &extended_handshake;
-        let supports_extended = handshake_supports_extended;
+        let supports_extended = features.extended;
 
         if supports_extended {
-            let my_extended =
-                Message::Extended(ExtendedMessage::Handshake(ExtendedHandshake::new()));
+            let mut my_extended = ExtendedHandshake::new();
+            self.handler
+                .update_my_extended_handshake(&mut my_extended)?;
+            let my_extended = Message::Extended(ExtendedMessage::Handshake(my_extended));
             trace!("sending extended handshake: {:?}", &my_extended);
-            my_extended.serialize(&mut write_buf, &|| None).unwrap();
+            my_extended
+                .serialize(&mut write_buf, &Default::default)
+                .unwrap();
             with_timeout(rwtimeout, conn.write_all(&write_buf))
                 .await
                 .context("error writing extended handshake")?;
@@ -252,13 +437,14 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
                 .keep_alive_interval
                 .unwrap_or_else(|| Duration::from_secs(120));
 
-            if self.handler.get_have_bytes() > 0 {
-                let len = self
-                    .handler
-                    .serialize_bitfield_message_to_buf(&mut write_buf)?;
+            let len = self
+                .handler
+                .serialize_bitfield_message_to_buf(&mut write_buf, features.fast)?;
+            if len > 0 {
                 with_timeout(rwtimeout, write_half.write_all(&write_buf[..len]))
                     .await
                     .context("error writing bitfield to peer")?;
+                write_buf.clear();
                 trace!("sent bitfield");
             }
 
@@ -300,9 +486,14 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
                         extended_handshake_ref
                             .read()
                             .as_ref()
-                            .and_then(|e| e.ut_metadata())
+                            .map(|e| e.peer_extended_messages())
+                            .unwrap_or_default()
                     })?,
                     WriterRequest::ReadChunkRequest(chunk) => {
+                        if let Some(ratelimits) = &self.ratelimits {
+                            ratelimits.prepare_for_upload(chunk.size as usize).await;
+                        }
+
                         #[allow(unused_mut)]
                         let mut skip_reading_for_e2e_tests = false;
 
@@ -376,6 +567,10 @@ impl<H: PeerConnectionHandler> PeerConnection<H> {
                     .context("error reading message")?;
                 trace!("received: {:?}", &message);
 
+                if let (Some(ratelimits), Message::Piece(piece)) = (&self.ratelimits, &message) {
+                    ratelimits.prepare_for_download(piece.block.len()).await;
+                }
+
                 if let Message::Extended(ExtendedMessage::Handshake(h)) = &message {
                     *extended_handshake_ref.write() = Some(h.clone_to_owned());
                     self.handler.on_extended_handshake(h)?;
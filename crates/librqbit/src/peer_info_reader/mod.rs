@@ -19,7 +19,8 @@ use tracing::trace;
 
 use crate::{
     peer_connection::{
-        PeerConnection, PeerConnectionHandler, PeerConnectionOptions, WriterRequest,
+        OutgoingTransports, PeerConnection, PeerConnectionHandler, PeerConnectionOptions,
+        WriterRequest,
     },
     spawn_utils::BlockingSpawner,
 };
@@ -30,6 +31,7 @@ pub(crate) async fn read_metainfo_from_peer(
     info_hash: Id20,
     peer_connection_options: Option<PeerConnectionOptions>,
     spawner: BlockingSpawner,
+    transports: &OutgoingTransports,
 ) -> anyhow::Result<TorrentMetaV1Info<ByteBufOwned>> {
     let (result_tx, result_rx) =
         tokio::sync::oneshot::channel::<anyhow::Result<TorrentMetaV1Info<ByteBufOwned>>>();
@@ -48,11 +50,16 @@ pub(crate) async fn read_metainfo_from_peer(
         handler,
         peer_connection_options,
         spawner,
+        None,
     );
 
     let result_reader = async move { result_rx.await? };
     let (_, brx) = tokio::sync::broadcast::channel(1);
-    let connection_runner = async move { connection.manage_peer_outgoing(writer_rx, brx).await };
+    let connection_runner = async move {
+        connection
+            .manage_peer_outgoing(writer_rx, brx, transports)
+            .await
+    };
 
     tokio::select! {
         result = result_reader => result,
@@ -143,11 +150,15 @@ struct Handler {
 }
 
 impl PeerConnectionHandler for Handler {
-    fn get_have_bytes(&self) -> u64 {
-        0
-    }
-
-    fn serialize_bitfield_message_to_buf(&self, _buf: &mut Vec<u8>) -> anyhow::Result<usize> {
+    fn serialize_bitfield_message_to_buf(
+        &self,
+        buf: &mut Vec<u8>,
+        supports_fast: bool,
+    ) -> anyhow::Result<usize> {
+        // The fast extension requires telling the peer we have nothing.
+        if supports_fast {
+            return Message::<ByteBuf>::HaveNone.serialize(buf, &Default::default);
+        }
         Ok(0)
     }
 
@@ -260,10 +271,15 @@ mod tests {
         let addr = SocketAddr::from_str("127.0.0.1:27311").unwrap();
         let peer_id = generate_peer_id();
         let info_hash = Id20::from_str("9905f844e5d8787ecd5e08fb46b2eb0a42c131d7").unwrap();
-        dbg!(
-            read_metainfo_from_peer(addr, peer_id, info_hash, None, BlockingSpawner::new(true))
-                .await
-                .unwrap()
-        );
+        dbg!(read_metainfo_from_peer(
+            addr,
+            peer_id,
+            info_hash,
+            None,
+            BlockingSpawner::new(true),
+            &Default::default()
+        )
+        .await
+        .unwrap());
     }
 }
@@ -0,0 +1,198 @@
+// M3U and XSPF playlists of the media files in torrents, pointing to the HTTP API stream
+// endpoint, so that players can open a whole season from one URL.
+
+use std::{cmp::Ordering, iter::Peekable, str::Chars};
+
+use librqbit_upnp::media_server::xml_escape;
+
+use crate::{mime::is_media_file, session::TorrentId, torrent_state::ManagedTorrentHandle};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    M3u8,
+    Xspf,
+}
+
+impl PlaylistFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u8 => "application/vnd.apple.mpegurl",
+            PlaylistFormat::Xspf => "application/xspf+xml",
+        }
+    }
+}
+
+struct PlaylistItem {
+    title: String,
+    url: String,
+}
+
+fn take_number(it: &mut Peekable<Chars>) -> String {
+    let mut n = String::new();
+    while let Some(c) = it.next_if(|c| c.is_ascii_digit()) {
+        n.push(c);
+    }
+    n
+}
+
+// Case-insensitive, with runs of digits compared as numbers, so that "E2" goes before "E10".
+pub(crate) fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut ia, mut ib) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        let (ca, cb) = match (ia.peek(), ib.peek()) {
+            (None, None) => break,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(ca), Some(cb)) => (*ca, *cb),
+        };
+        let ord = if ca.is_ascii_digit() && cb.is_ascii_digit() {
+            let (na, nb) = (take_number(&mut ia), take_number(&mut ib));
+            let (na, nb) = (na.trim_start_matches('0'), nb.trim_start_matches('0'));
+            na.len().cmp(&nb.len()).then_with(|| na.cmp(nb))
+        } else {
+            ia.next();
+            ib.next();
+            ca.to_lowercase().cmp(cb.to_lowercase())
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    // Only differ in case or zero padding, still need a stable order.
+    a.cmp(b)
+}
+
+fn torrent_name(mgr: &ManagedTorrentHandle) -> String {
+    let info = mgr.info();
+    info.info
+        .name
+        .as_ref()
+        .map(|n| n.to_string())
+        .unwrap_or_else(|| info.info_hash.as_string())
+}
+
+fn torrent_items(id: TorrentId, mgr: &ManagedTorrentHandle, base_url: &str) -> Vec<PlaylistItem> {
+    let mut files = mgr
+        .info()
+        .file_infos
+        .iter()
+        .enumerate()
+        .filter(|(_, fi)| is_media_file(&fi.relative_filename))
+        .map(|(file_id, fi)| {
+            (
+                fi.relative_filename.to_string_lossy().into_owned(),
+                file_id,
+                fi,
+            )
+        })
+        .collect::<Vec<_>>();
+    files.sort_by(|(a, ..), (b, ..)| natural_cmp(a, b));
+    files
+        .into_iter()
+        .map(|(path, file_id, fi)| PlaylistItem {
+            title: fi
+                .relative_filename
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or(path),
+            url: crate::http_api::stream_url(base_url, id, file_id, &fi.relative_filename),
+        })
+        .collect()
+}
+
+fn render(format: PlaylistFormat, items: &[PlaylistItem]) -> String {
+    let mut out = String::new();
+    match format {
+        PlaylistFormat::M3u8 => {
+            out.push_str("#EXTM3U\n");
+            for item in items {
+                // Titles can't span lines.
+                let title = item.title.replace(['\r', '\n'], " ");
+                out.push_str(&format!("#EXTINF:-1,{title}\n{}\n", item.url));
+            }
+        }
+        PlaylistFormat::Xspf => {
+            out.push_str(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                 <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n\
+                 <trackList>\n",
+            );
+            for item in items {
+                out.push_str(&format!(
+                    "<track><location>{}</location><title>{}</title></track>\n",
+                    xml_escape(&item.url),
+                    xml_escape(&item.title)
+                ));
+            }
+            out.push_str("</trackList>\n</playlist>\n");
+        }
+    }
+    out
+}
+
+/// A playlist of the media files of the given torrents. Torrents are sorted by name, and files
+/// of each torrent by path, both in natural order.
+pub(crate) fn make_playlist(
+    format: PlaylistFormat,
+    torrents: Vec<(TorrentId, ManagedTorrentHandle)>,
+    base_url: &str,
+) -> String {
+    let mut torrents = torrents
+        .into_iter()
+        .map(|(id, mgr)| (torrent_name(&mgr), id, mgr))
+        .collect::<Vec<_>>();
+    torrents.sort_by(|(a, a_id, _), (b, b_id, _)| natural_cmp(a, b).then(a_id.cmp(b_id)));
+    let items = torrents
+        .iter()
+        .flat_map(|(_, id, mgr)| torrent_items(*id, mgr, base_url))
+        .collect::<Vec<_>>();
+    render(format, &items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{natural_cmp, render, PlaylistFormat, PlaylistItem};
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = vec![
+            "Show S01E10.mkv",
+            "Show S01E2.mkv",
+            "show S01E01.mkv",
+            "Show S02E01.mkv",
+            "Show S01E1.mkv",
+            "Extras/a.mkv",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            names,
+            vec![
+                "Extras/a.mkv",
+                "Show S01E1.mkv",
+                "show S01E01.mkv",
+                "Show S01E2.mkv",
+                "Show S01E10.mkv",
+                "Show S02E01.mkv",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let items = vec![PlaylistItem {
+            title: "Tom & Jerry".to_owned(),
+            url: "http://host:3030/torrents/0/stream/1/Tom%20%26%20Jerry.mkv".to_owned(),
+        }];
+        assert_eq!(
+            render(PlaylistFormat::M3u8, &items),
+            "#EXTM3U\n\
+             #EXTINF:-1,Tom & Jerry\n\
+             http://host:3030/torrents/0/stream/1/Tom%20%26%20Jerry.mkv\n"
+        );
+        let xspf = render(PlaylistFormat::Xspf, &items);
+        assert!(xspf.contains(
+            "<track><location>http://host:3030/torrents/0/stream/1/Tom%20%26%20Jerry.mkv</location>\
+             <title>Tom &amp; Jerry</title></track>"
+        ));
+    }
+}
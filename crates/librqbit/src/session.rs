@@ -12,8 +12,18 @@ use std::{
 
 use crate::{
     dht_utils::{read_metainfo_from_peer_receiver, ReadMetainfoResult},
+    embedded_tracker::{expire_peers, session_torrents_filter, EmbeddedTrackerOptions},
+    events::{
+        SessionEventSenders, TorrentEvent, TorrentEventKind, TorrentEventSender,
+        EVENTS_CHANNEL_CAPACITY,
+    },
+    fastresume::FastResumeData,
+    file_info::FilePriority,
+    hooks::{run_hooks, SessionHooks},
+    limits::{RateLimits, RateLimitsConfig},
     merge_streams::merge_streams,
-    peer_connection::PeerConnectionOptions,
+    mse::{self, EncryptionMode, MseStream},
+    peer_connection::{with_timeout, OutgoingTransports, PeerConnectionOptions, PeerSocket},
     read_buf::ReadBuf,
     spawn_utils::BlockingSpawner,
     storage::{filesystem::FilesystemStorageFactory, BoxStorageFactory, StorageFactoryExt},
@@ -21,6 +31,8 @@ use crate::{
         ManagedTorrentBuilder, ManagedTorrentHandle, ManagedTorrentState, TorrentStateLive,
     },
     type_aliases::{DiskWorkQueueSender, PeerStream},
+    utp::UtpSocket,
+    watch_folder::{watch_folder, WatchFolderOptions},
 };
 use anyhow::{bail, Context};
 use bencode::{bencode_serialize_to_writer, BencodeDeserializer};
@@ -36,24 +48,29 @@ use itertools::Itertools;
 use librqbit_core::{
     constants::CHUNK_SIZE,
     directories::get_configuration_directory,
+    hash_id::Id32,
     magnet::Magnet,
     peer_id::generate_peer_id,
     spawn_utils::spawn_with_cancel,
     torrent_metainfo::{
-        torrent_from_bytes as bencode_torrent_from_bytes, TorrentMetaV1Info, TorrentMetaV1Owned,
+        torrent_from_bytes as bencode_torrent_from_bytes, PieceLayers, TorrentMetaV1Info,
+        TorrentMetaV1Owned,
     },
 };
 use parking_lot::RwLock;
 use peer_binary_protocol::Handshake;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
 use tokio_stream::StreamExt;
 use tokio_util::sync::{CancellationToken, DropGuard};
 use tracing::{debug, error, error_span, info, trace, warn, Instrument};
-use tracker_comms::TrackerComms;
+use tracker_comms::{TrackerComms, TrackerList, TrackerScrape, TrackerServer};
 
 pub const SUPPORTED_SCHEMES: [&str; 3] = ["http:", "https:", "magnet:"];
 
+// How long stopping the session waits for trackers to be told we stopped.
+const STOPPED_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(2);
+
 pub type TorrentId = usize;
 
 fn torrent_from_bytes(bytes: &[u8]) -> anyhow::Result<TorrentMetaV1Owned> {
@@ -71,24 +88,19 @@ pub struct SessionDatabase {
 }
 
 impl SessionDatabase {
-    fn add_torrent(
-        &mut self,
-        torrent: ManagedTorrentHandle,
-        preferred_id: Option<TorrentId>,
-    ) -> TorrentId {
+    // The id for the next torrent, which needs to be known before building it.
+    fn allocate_id(&mut self, preferred_id: Option<TorrentId>) -> TorrentId {
         match preferred_id {
             Some(id) if self.torrents.contains_key(&id) => {
                 warn!("id {id} already present in DB, ignoring \"preferred_id\" parameter");
             }
             Some(id) => {
-                self.torrents.insert(id, torrent);
                 self.next_id = id.max(self.next_id).wrapping_add(1);
                 return id;
             }
             _ => {}
         }
         let idx = self.next_id;
-        self.torrents.insert(idx, torrent);
         self.next_id += 1;
         idx
     }
@@ -105,21 +117,43 @@ impl SessionDatabase {
                         .is_type_id(TypeId::of::<FilesystemStorageFactory>())
                 })
                 .map(|(id, torrent)| {
+                    // Snapshot the bitfield first, and only then look at the files. If something is
+                    // written in between, the file will be re-checked on next start.
+                    let fastresume =
+                        match torrent.with_chunk_tracker(|ct| ct.get_have_pieces().clone()) {
+                            Ok(have) => Some(FastResumeData::collect(
+                                &have,
+                                &torrent.info().output_folder(),
+                                &torrent.info().file_infos,
+                            )),
+                            // Still checking, keep what we restored it from.
+                            Err(_) => torrent.with_state(|s| match s {
+                                ManagedTorrentState::Initializing(i) => i.fastresume.clone(),
+                                _ => None,
+                            }),
+                        };
                     (
                         *id,
                         SerializedTorrent {
-                            trackers: torrent
-                                .info()
-                                .trackers
-                                .iter()
-                                .map(|u| u.to_string())
-                                .collect(),
+                            trackers: torrent.info().trackers.tiers(),
+                            web_seeds: torrent.info().web_seeds.clone(),
                             info_hash: torrent.info_hash().as_string(),
+                            info_hash_v2: torrent.info().info_hash_v2.map(|h| h.as_string()),
                             info: torrent.info().info.clone(),
+                            piece_layers: torrent.info().piece_layers.clone(),
                             only_files: torrent.only_files().clone(),
+                            // Skip and normal are already covered by only_files.
+                            file_priorities: {
+                                let p = torrent.file_priorities();
+                                p.iter()
+                                    .any(|p| matches!(p, FilePriority::Low | FilePriority::High))
+                                    .then_some(p)
+                            },
                             is_paused: torrent
                                 .with_state(|s| matches!(s, ManagedTorrentState::Paused(_))),
-                            output_folder: torrent.info().options.output_folder.clone(),
+                            output_folder: torrent.info().output_folder(),
+                            fastresume,
+                            ratelimits: torrent.ratelimits(),
                         },
                     )
                 })
@@ -128,6 +162,20 @@ impl SessionDatabase {
     }
 }
 
+// A torrent whose info dict is known, either from a .torrent or resolved from a magnet, and where
+// to look for its peers.
+struct ResolvedTorrent {
+    info_hash: Id20,
+    info: TorrentMetaV1Info<ByteBufOwned>,
+    // BitTorrent v2 parts that aren't in the info dict.
+    info_hash_v2: Option<Id32>,
+    piece_layers: Option<PieceLayers<ByteBufOwned>>,
+    trackers: TrackerList,
+    web_seeds: Vec<String>,
+    peer_rx: Option<PeerStream>,
+    initial_peers: Vec<SocketAddr>,
+}
+
 #[derive(Serialize, Deserialize)]
 struct SerializedTorrent {
     info_hash: String,
@@ -136,19 +184,35 @@ struct SerializedTorrent {
         deserialize_with = "deserialize_torrent"
     )]
     info: TorrentMetaV1Info<ByteBufOwned>,
-    trackers: HashSet<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info_hash_v2: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_piece_layers",
+        deserialize_with = "deserialize_piece_layers"
+    )]
+    piece_layers: Option<PieceLayers<ByteBufOwned>>,
+    // BEP 12 tiers. Older versions stored a flat list.
+    #[serde(deserialize_with = "deserialize_tracker_tiers")]
+    trackers: Vec<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    web_seeds: Vec<String>,
     output_folder: PathBuf,
     only_files: Option<Vec<usize>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file_priorities: Option<Vec<FilePriority>>,
     is_paused: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fastresume: Option<FastResumeData>,
+    #[serde(default)]
+    ratelimits: RateLimitsConfig,
 }
 
-fn serialize_torrent<S>(
-    t: &TorrentMetaV1Info<ByteBufOwned>,
+fn serialize_bencode_base64<T: Serialize, S: Serializer>(
+    t: &T,
     serializer: S,
-) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
+) -> Result<S::Ok, S::Error> {
     use base64::{engine::general_purpose, Engine as _};
     use serde::ser::Error;
     let mut writer = Vec::new();
@@ -157,8 +221,9 @@ where
     s.serialize(serializer)
 }
 
-fn deserialize_torrent<'de, D>(deserializer: D) -> Result<TorrentMetaV1Info<ByteBufOwned>, D::Error>
+fn deserialize_bencode_base64<'de, T, D>(deserializer: D) -> Result<T, D::Error>
 where
+    T: for<'a> Deserialize<'a>,
     D: Deserializer<'de>,
 {
     use base64::{engine::general_purpose, Engine as _};
@@ -167,8 +232,63 @@ where
     let b = general_purpose::STANDARD_NO_PAD
         .decode(s)
         .map_err(D::Error::custom)?;
-    TorrentMetaV1Info::<ByteBufOwned>::deserialize(&mut BencodeDeserializer::new_from_buf(&b))
-        .map_err(D::Error::custom)
+    T::deserialize(&mut BencodeDeserializer::new_from_buf(&b)).map_err(D::Error::custom)
+}
+
+fn deserialize_tracker_tiers<'de, D>(deserializer: D) -> Result<Vec<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Trackers {
+        Tiers(Vec<Vec<String>>),
+        // All trackers were announced to, so each gets its own tier.
+        Flat(Vec<String>),
+    }
+    Ok(match Trackers::deserialize(deserializer)? {
+        Trackers::Tiers(tiers) => tiers,
+        Trackers::Flat(trackers) => trackers.into_iter().map(|t| vec![t]).collect(),
+    })
+}
+
+fn serialize_torrent<S>(
+    t: &TorrentMetaV1Info<ByteBufOwned>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serialize_bencode_base64(t, serializer)
+}
+
+fn deserialize_torrent<'de, D>(deserializer: D) -> Result<TorrentMetaV1Info<ByteBufOwned>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_bencode_base64(deserializer)
+}
+
+fn serialize_piece_layers<S>(
+    layers: &Option<PieceLayers<ByteBufOwned>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match layers {
+        Some(layers) => serialize_bencode_base64(layers, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn deserialize_piece_layers<'de, D>(
+    deserializer: D,
+) -> Result<Option<PieceLayers<ByteBufOwned>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_bencode_base64(deserializer).map(Some)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -193,6 +313,16 @@ pub struct Session {
 
     default_storage_factory: Option<BoxStorageFactory>,
 
+    ratelimits: Arc<RateLimits>,
+
+    transports: OutgoingTransports,
+
+    upload_slots: Option<usize>,
+
+    events_tx: SessionEventSenders,
+
+    tracker: Option<Arc<TrackerServer>>,
+
     // This is stored for all tasks to stop when session is dropped.
     _cancellation_token_drop_guard: DropGuard,
 }
@@ -211,6 +341,57 @@ async fn torrent_from_url(url: &str) -> anyhow::Result<TorrentMetaV1Owned> {
     torrent_from_bytes(&b).context("error decoding torrent")
 }
 
+// Everything but magnet links.
+async fn torrent_from_add(add: AddTorrent<'_>) -> anyhow::Result<TorrentMetaV1Owned> {
+    match add {
+        AddTorrent::Url(url) if url.starts_with("http://") || url.starts_with("https://") => {
+            torrent_from_url(&url).await
+        }
+        AddTorrent::Url(url) => {
+            bail!(
+                "unsupported URL {:?}. Supporting magnet:, http:, and https",
+                url
+            )
+        }
+        AddTorrent::TorrentFileBytes(bytes) => {
+            torrent_from_bytes(&bytes).context("error decoding torrent")
+        }
+        AddTorrent::TorrentInfo(t) => Ok(*t),
+    }
+}
+
+// The BTv1 infohash of a magnet link, which is what its metadata is resolved and verified with.
+// v2-only magnets aren't supported: without a .torrent, the piece layers needed to verify the data
+// would have to be requested from peers (BEP 52 hash requests), which isn't implemented.
+fn magnet_info_hash(magnet: &Magnet) -> anyhow::Result<Id20> {
+    match (magnet.as_id20(), magnet.as_id32()) {
+        (Some(info_hash), _) => Ok(info_hash),
+        (None, Some(_)) => bail!(
+            "v2-only magnet links are not supported, add the .torrent file or a hybrid magnet \
+             with a BTv1 (btih) infohash instead"
+        ),
+        (None, None) => bail!("magnet link didn't contain an infohash"),
+    }
+}
+
+fn tracker_tiers(torrent: &TorrentMetaV1Owned) -> Vec<Vec<String>> {
+    torrent
+        .announce_tiers()
+        .into_iter()
+        .map(|tier| {
+            tier.into_iter()
+                .filter_map(|tracker| match std::str::from_utf8(tracker.as_ref()) {
+                    Ok(url) => Some(url.to_owned()),
+                    Err(_) => {
+                        warn!("cannot parse tracker url as utf-8, ignoring");
+                        None
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
 fn compute_only_files_regex<ByteBuf: AsRef<[u8]>>(
     torrent: &TorrentMetaV1Info<ByteBuf>,
     filename_re: &str,
@@ -235,8 +416,23 @@ fn compute_only_files(
     info: &TorrentMetaV1Info<ByteBufOwned>,
     only_files: Option<Vec<usize>>,
     only_files_regex: Option<String>,
+    file_priorities: Option<&[FilePriority]>,
     list_only: bool,
 ) -> anyhow::Result<Option<Vec<usize>>> {
+    if let Some(file_priorities) = file_priorities {
+        if only_files.is_some() || only_files_regex.is_some() {
+            bail!("file_priorities can't be combined with only_files or only_files_regex");
+        }
+        let total_files = info.iter_file_lengths()?.count();
+        if file_priorities.len() != total_files {
+            bail!(
+                "expected {} file priorities, got {}",
+                total_files,
+                file_priorities.len()
+            );
+        }
+        return Ok(Some(FilePriority::only_files(file_priorities)));
+    }
     match (only_files, only_files_regex) {
         (Some(_), Some(_)) => {
             bail!("only_files and only_files_regex are mutually exclusive");
@@ -281,7 +477,7 @@ fn merge_two_optional_streams<T>(
 /// Options for adding new torrents to the session.
 //
 // Serialize/deserialize is for Tauri.
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct AddTorrentOptions {
     /// Start in paused state.
     pub paused: bool,
@@ -290,6 +486,9 @@ pub struct AddTorrentOptions {
     /// An explicit list of file IDs to download.
     /// To see the file indices, run with "list_only".
     pub only_files: Option<Vec<usize>>,
+    /// Download priority of each file, in file ID order. Files with "skip" priority aren't
+    /// downloaded. Can't be combined with "only_files" or "only_files_regex".
+    pub file_priorities: Option<Vec<FilePriority>>,
     /// Allow writing on top of existing files, including when resuming a torrent.
     /// You probably want to set it, however for safety it's not default.
     pub overwrite: bool,
@@ -320,6 +519,13 @@ pub struct AddTorrentOptions {
     // If true, will write to disk in separate threads. The downside is additional allocations.
     // May be useful if the disk is slow.
     pub defer_writes: Option<bool>,
+
+    /// Upload and download limits for this torrent. These apply on top of the session-wide ones.
+    #[serde(default)]
+    pub ratelimits: RateLimitsConfig,
+
+    /// How many peers to upload to at once. Overrides the session-wide setting. 0 means unlimited.
+    pub upload_slots: Option<usize>,
 }
 
 pub struct ListOnlyResponse {
@@ -428,30 +634,93 @@ pub struct SessionOptions {
     pub listen_port_range: Option<std::ops::Range<u16>>,
     pub enable_upnp_port_forwarding: bool,
 
+    /// Turn on to disable uTP. By default it listens on the same port as TCP.
+    pub disable_utp: bool,
+    /// Connect to peers over uTP first, and fall back to TCP. By default it's the other way around.
+    pub prefer_utp: bool,
+
     // If you set this to something, all writes to disk will happen in background and be
     // buffered in memory up to approximately the given number of megabytes.
     pub defer_writes_up_to: Option<usize>,
 
     pub default_storage_factory: Option<BoxStorageFactory>,
+
+    /// Session-wide upload and download limits, shared by all torrents.
+    pub ratelimits: RateLimitsConfig,
+
+    /// How many peers each torrent uploads to at once, unless set per torrent.
+    /// 0 means unlimited. Defaults to 4.
+    pub upload_slots: Option<usize>,
+
+    /// Commands and webhooks to run when torrents are added, completed or fail.
+    pub hooks: SessionHooks,
+
+    /// Add .torrent and .magnet files dropped into this folder.
+    pub watch_folder: Option<WatchFolderOptions>,
+
+    /// Run a BitTorrent tracker.
+    pub tracker: Option<EmbeddedTrackerOptions>,
+}
+
+struct TcpListeners {
+    v4: TcpListener,
+    // None if the host doesn't support IPv6.
+    v6: Option<TcpListener>,
 }
 
-async fn create_tcp_listener(
+fn bind_tcp_v6_only(port: u16) -> anyhow::Result<TcpListener> {
+    let addr = SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, port));
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV6,
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    // Otherwise on some systems this would conflict with the IPv4 listener on the same port.
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+// Listen for TCP (IPv4 and IPv6 if possible), and for uTP on the same port if enabled.
+async fn create_listeners(
     port_range: std::ops::Range<u16>,
-) -> anyhow::Result<(TcpListener, u16)> {
+    utp: bool,
+) -> anyhow::Result<(TcpListeners, Option<Arc<UtpSocket>>, u16)> {
     for port in port_range.clone() {
-        match TcpListener::bind(("0.0.0.0", port)).await {
-            Ok(l) => return Ok((l, port)),
+        let v4 = match TcpListener::bind(("0.0.0.0", port)).await {
+            Ok(l) => l,
             Err(e) => {
-                debug!("error listening on port {port}: {e:#}")
+                debug!("error listening on port {port}: {e:#}");
+                continue;
+            }
+        };
+        let v6 = match bind_tcp_v6_only(port) {
+            Ok(l) => Some(l),
+            Err(e) => {
+                debug!("error listening on [::]:{port}, continuing with IPv4 only: {e:#}");
+                None
+            }
+        };
+        let tcp = TcpListeners { v4, v6 };
+        if !utp {
+            return Ok((tcp, None, port));
+        }
+        match UtpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port)), true).await {
+            Ok(u) => return Ok((tcp, Some(u), port)),
+            Err(e) => {
+                debug!("error listening for uTP on port {port}: {e:#}")
             }
         }
     }
-    bail!("no free TCP ports in range {port_range:?}");
+    bail!("no free ports in range {port_range:?}");
 }
 
 pub(crate) struct CheckedIncomingConnection {
     pub addr: SocketAddr,
-    pub stream: tokio::net::TcpStream,
+    pub stream: MseStream<PeerSocket>,
     pub read_buf: ReadBuf,
     pub handshake: Handshake<ByteBufOwned>,
 }
@@ -484,14 +753,26 @@ impl Session {
             let peer_id = opts.peer_id.unwrap_or_else(generate_peer_id);
             let token = CancellationToken::new();
 
-            let (tcp_listener, tcp_listen_port) = if let Some(port_range) = opts.listen_port_range {
-                let (l, p) = create_tcp_listener(port_range)
+            let (tcp_listener, utp_socket, tcp_listen_port) = if let Some(port_range) =
+                opts.listen_port_range
+            {
+                let (l, u, p) = create_listeners(port_range, !opts.disable_utp)
+                    .await
+                    .context("error listening for incoming peer connections")?;
+                if l.v6.is_some() {
+                    info!("Listening on 0.0.0.0:{p} and [::]:{p} for incoming peer connections");
+                } else {
+                    info!("Listening on 0.0.0.0:{p} for incoming peer connections");
+                }
+                (Some(l), u, Some(p))
+            } else if !opts.disable_utp {
+                // Still need a socket for outgoing uTP connections.
+                let u = UtpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0)), false)
                     .await
-                    .context("error listening on TCP")?;
-                info!("Listening on 0.0.0.0:{p} for incoming peer connections");
-                (Some(l), Some(p))
+                    .context("error creating uTP socket")?;
+                (None, Some(u), None)
             } else {
-                (None, None)
+                (None, None, None)
             };
 
             let dht = if opts.disable_dht {
@@ -531,7 +812,25 @@ impl Session {
                 })
                 .unwrap_or_default();
 
-            let session = Arc::new(Self {
+            let tracker_udp_socket = match opts.tracker.as_ref().and_then(|t| t.udp_listen_addr) {
+                Some(addr) => {
+                    let sock = tokio::net::UdpSocket::bind(addr)
+                        .await
+                        .with_context(|| format!("error binding UDP tracker to {addr}"))?;
+                    info!("Listening on {addr} for UDP tracker requests");
+                    Some(sock)
+                }
+                None => None,
+            };
+
+            let (hooks_tx, hooks_rx) = if opts.hooks.is_empty() {
+                (None, None)
+            } else {
+                let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+                (Some(tx), Some(rx))
+            };
+
+            let session = Arc::new_cyclic(|session| Self {
                 persistence_filename,
                 peer_id,
                 dht,
@@ -544,6 +843,22 @@ impl Session {
                 tcp_listen_port,
                 disk_write_tx,
                 default_storage_factory: opts.default_storage_factory,
+                ratelimits: Arc::new(RateLimits::new(opts.ratelimits)),
+                upload_slots: opts.upload_slots,
+                events_tx: SessionEventSenders {
+                    broadcast: tokio::sync::broadcast::channel(EVENTS_CHANNEL_CAPACITY).0,
+                    hooks: hooks_tx,
+                },
+                transports: OutgoingTransports {
+                    utp_socket: utp_socket.clone(),
+                    prefer_utp: opts.prefer_utp,
+                },
+                tracker: opts.tracker.as_ref().map(|t| {
+                    let filter = t
+                        .only_session_torrents
+                        .then(|| session_torrents_filter(session.clone()));
+                    Arc::new(TrackerServer::new(t.server.clone(), filter))
+                }),
             });
 
             if let Some(mut disk_write_rx) = disk_write_rx {
@@ -556,10 +871,14 @@ impl Session {
                 });
             }
 
+            if let Some(utp_socket) = &utp_socket {
+                session.spawn(error_span!("utp"), utp_socket.clone().run_forever());
+            }
+
             if let Some(tcp_listener) = tcp_listener {
                 session.spawn(
-                    error_span!("tcp_listen", port = tcp_listen_port),
-                    session.clone().task_tcp_listener(tcp_listener),
+                    error_span!("listen", port = tcp_listen_port),
+                    session.clone().task_listener(tcp_listener, utp_socket),
                 );
             }
 
@@ -572,6 +891,23 @@ impl Session {
                 }
             }
 
+            if let Some(events) = hooks_rx {
+                session.spawn(
+                    error_span!("hooks"),
+                    run_hooks(Arc::downgrade(&session), opts.hooks, events),
+                );
+            }
+
+            if let Some(tracker) = &session.tracker {
+                session.spawn(error_span!("tracker_expiry"), expire_peers(tracker.clone()));
+                if let Some(sock) = tracker_udp_socket {
+                    let tracker = tracker.clone();
+                    session.spawn(error_span!("udp_tracker"), async move {
+                        tracker.run_udp(sock).await
+                    });
+                }
+            }
+
             if opts.persistence {
                 info!(
                     "will use {:?} for session persistence",
@@ -586,6 +922,17 @@ impl Session {
                 session.spawn(error_span!("session_persistence"), persistence_task);
             }
 
+            if let Some(watch_opts) = opts.watch_folder {
+                std::fs::create_dir_all(&watch_opts.folder).with_context(|| {
+                    format!("couldn't create watch folder {:?}", watch_opts.folder)
+                })?;
+                info!("will add torrents dropped into {:?}", watch_opts.folder);
+                session.spawn(
+                    error_span!("watch_folder"),
+                    watch_folder(Arc::downgrade(&session), watch_opts),
+                );
+            }
+
             Ok(session)
         }
         .boxed()
@@ -617,13 +964,27 @@ impl Session {
     async fn check_incoming_connection(
         &self,
         addr: SocketAddr,
-        mut stream: TcpStream,
+        stream: PeerSocket,
     ) -> anyhow::Result<(Arc<TorrentStateLive>, CheckedIncomingConnection)> {
         let rwtimeout = self
             .peer_opts
             .read_write_timeout
             .unwrap_or_else(|| Duration::from_secs(10));
 
+        let info_hashes = self
+            .db
+            .read()
+            .torrents
+            .values()
+            .flat_map(|t| {
+                let mode = t.info().options.peer_encryption.unwrap_or_default();
+                t.swarm_info_hashes().map(move |h| (h, mode))
+            })
+            .collect::<Vec<_>>();
+        let mut stream = with_timeout(rwtimeout, mse::handshake_incoming(stream, &info_hashes))
+            .await
+            .context("error in encryption handshake")?;
+
         let mut read_buf = ReadBuf::new();
         let h = read_buf
             .read_handshake(&mut stream, rwtimeout)
@@ -636,10 +997,16 @@ impl Session {
         }
 
         for (id, torrent) in self.db.read().torrents.iter() {
-            if torrent.info_hash().0 != h.info_hash {
+            if !torrent.swarm_info_hashes().any(|ih| ih.0 == h.info_hash) {
                 continue;
             }
 
+            if !stream.is_encrypted()
+                && torrent.info().options.peer_encryption == Some(EncryptionMode::Require)
+            {
+                bail!("torrent {id} requires encryption, ignoring plaintext connection");
+            }
+
             let live = match torrent.live() {
                 Some(live) => live,
                 None => {
@@ -666,23 +1033,28 @@ impl Session {
         )
     }
 
-    async fn task_tcp_listener(self: Arc<Self>, l: TcpListener) -> anyhow::Result<()> {
+    async fn task_listener(
+        self: Arc<Self>,
+        l: TcpListeners,
+        utp_socket: Option<Arc<UtpSocket>>,
+    ) -> anyhow::Result<()> {
         let mut futs = FuturesUnordered::new();
+        let check = |addr, stream| {
+            self.check_incoming_connection(addr, stream)
+                .map_err(|e| {
+                    debug!("error checking incoming connection: {e:#}");
+                    e
+                })
+                .instrument(error_span!("incoming", addr=%addr))
+        };
 
         loop {
             tokio::select! {
-                r = l.accept() => {
+                r = l.v4.accept() => {
                     match r {
                         Ok((stream, addr)) => {
                             trace!("accepted connection from {addr}");
-                            futs.push(
-                                self.check_incoming_connection(addr, stream)
-                                    .map_err(|e| {
-                                        debug!("error checking incoming connection: {e:#}");
-                                        e
-                                    })
-                                    .instrument(error_span!("incoming", addr=%addr))
-                            );
+                            futs.push(check(addr, PeerSocket::Tcp(stream)));
                         }
                         Err(e) => {
                             error!("error accepting: {e:#}");
@@ -690,6 +1062,31 @@ impl Session {
                         }
                     }
                 },
+                r = async { l.v6.as_ref().unwrap().accept().await }, if l.v6.is_some() => {
+                    match r {
+                        Ok((stream, addr)) => {
+                            trace!("accepted connection from {addr}");
+                            futs.push(check(addr, PeerSocket::Tcp(stream)));
+                        }
+                        Err(e) => {
+                            error!("error accepting: {e:#}");
+                            continue;
+                        }
+                    }
+                },
+                r = async { utp_socket.as_ref().unwrap().accept().await }, if utp_socket.is_some() => {
+                    match r {
+                        Ok(stream) => {
+                            let addr = stream.peer_addr();
+                            trace!("accepted uTP connection from {addr}");
+                            futs.push(check(addr, PeerSocket::Utp(stream)));
+                        }
+                        Err(e) => {
+                            error!("error accepting uTP: {e:#}");
+                            continue;
+                        }
+                    }
+                },
                 Some(Ok((live, checked))) = futs.next(), if !futs.is_empty() => {
                     if let Err(e) = live.add_incoming_peer(checked) {
                         warn!("error handing over incoming connection: {e:#}");
@@ -721,6 +1118,7 @@ impl Session {
             keep_alive_interval: other
                 .keep_alive_interval
                 .or(self.peer_opts.keep_alive_interval),
+            encryption: other.encryption.or(self.peer_opts.encryption),
         }
     }
 
@@ -742,11 +1140,23 @@ impl Session {
             .values()
             .cloned()
             .collect::<Vec<_>>();
+        let mut announces = Vec::new();
         for torrent in torrents {
-            if let Err(e) = torrent.pause() {
-                debug!("error pausing torrent: {e:#}");
+            match torrent.pause_inner() {
+                Ok(announce) => announces.push(announce),
+                Err(e) => debug!("error pausing torrent: {e:#}"),
             }
         }
+        // Best-effort, don't hold up stopping for unresponsive trackers.
+        if tokio::time::timeout(
+            STOPPED_ANNOUNCE_TIMEOUT,
+            futures::future::join_all(announces),
+        )
+        .await
+        .is_err()
+        {
+            debug!("timeout telling trackers we stopped");
+        }
         self.cancellation_token.cancel();
         // this sucks, but hopefully will be enough
         tokio::time::sleep(Duration::from_secs(1)).await;
@@ -767,14 +1177,18 @@ impl Session {
             serde_json::from_reader(&mut rdr).context("error deserializing session database")?;
         let mut futures = Vec::new();
         for (id, storrent) in db.torrents.into_iter() {
-            let trackers: Vec<ByteBufOwned> = storrent
+            let trackers: Vec<Vec<ByteBufOwned>> = storrent
                 .trackers
                 .into_iter()
-                .map(|t| ByteBufOwned::from(t.into_bytes()))
+                .map(|tier| {
+                    tier.into_iter()
+                        .map(|t| ByteBufOwned::from(t.into_bytes()))
+                        .collect()
+                })
                 .collect();
             let info = TorrentMetaV1Owned {
-                announce: trackers.first().cloned(),
-                announce_list: vec![trackers],
+                announce: trackers.iter().flatten().next().cloned(),
+                announce_list: trackers,
                 info: storrent.info,
                 comment: None,
                 created_by: None,
@@ -782,13 +1196,24 @@ impl Session {
                 publisher: None,
                 publisher_url: None,
                 creation_date: None,
+                url_list: storrent
+                    .web_seeds
+                    .into_iter()
+                    .map(|u| ByteBufOwned::from(u.into_bytes()))
+                    .collect(),
+                piece_layers: storrent.piece_layers,
                 info_hash: Id20::from_str(&storrent.info_hash)?,
+                info_hash_v2: storrent
+                    .info_hash_v2
+                    .as_deref()
+                    .map(Id32::from_str)
+                    .transpose()?,
             };
             futures.push({
                 let session = self.clone();
                 async move {
                     session
-                        .add_torrent(
+                        .add_torrent_with_fastresume(
                             AddTorrent::TorrentInfo(Box::new(info)),
                             Some(AddTorrentOptions {
                                 paused: storrent.is_paused,
@@ -799,11 +1224,17 @@ impl Session {
                                         .context("broken path")?
                                         .to_owned(),
                                 ),
-                                only_files: storrent.only_files,
+                                only_files: match storrent.file_priorities {
+                                    Some(_) => None,
+                                    None => storrent.only_files,
+                                },
+                                file_priorities: storrent.file_priorities,
                                 overwrite: true,
                                 preferred_id: Some(id),
+                                ratelimits: storrent.ratelimits,
                                 ..Default::default()
                             }),
+                            storrent.fastresume,
                         )
                         .await
                         .map_err(|e| {
@@ -851,6 +1282,41 @@ impl Session {
         self: &'a Arc<Self>,
         add: AddTorrent<'a>,
         opts: Option<AddTorrentOptions>,
+    ) -> BoxFuture<'a, anyhow::Result<AddTorrentResponse>> {
+        self.add_torrent_with_fastresume(add, opts, None)
+    }
+
+    /// Ask the trackers of a torrent about its swarm without adding it. Unlike with "list_only",
+    /// the metadata of magnet links doesn't need to be resolved.
+    pub async fn scrape(&self, add: AddTorrent<'_>) -> anyhow::Result<(Id20, Vec<TrackerScrape>)> {
+        let (info_hash, trackers) = match add {
+            AddTorrent::Url(magnet) if magnet.starts_with("magnet:") => {
+                let magnet =
+                    Magnet::parse(&magnet).context("provided path is not a valid magnet URL")?;
+                let info_hash = magnet_info_hash(&magnet)?;
+                (info_hash, magnet.trackers)
+            }
+            other => {
+                let torrent = torrent_from_add(other).await?;
+                let trackers = tracker_tiers(&torrent).into_iter().flatten().collect();
+                (torrent.info_hash, trackers)
+            }
+        };
+        let trackers = trackers.into_iter().unique().collect_vec();
+        if trackers.is_empty() {
+            bail!("torrent has no trackers");
+        }
+        Ok((
+            info_hash,
+            TrackerComms::scrape(trackers, &[info_hash]).await,
+        ))
+    }
+
+    fn add_torrent_with_fastresume<'a>(
+        self: &'a Arc<Self>,
+        add: AddTorrent<'a>,
+        opts: Option<AddTorrentOptions>,
+        fastresume: Option<FastResumeData>,
     ) -> BoxFuture<'a, anyhow::Result<AddTorrentResponse>> {
         async move {
             // Magnet links are different in that we first need to discover the metadata.
@@ -867,28 +1333,30 @@ impl Session {
             // into a torrent file by connecting to peers that support extended handshakes.
             // So we must discover at least one peer and connect to it to be able to proceed further.
 
-            let (info_hash, info, trackers, peer_rx, initial_peers) = match add {
+            let torrent = match add {
                 AddTorrent::Url(magnet) if magnet.starts_with("magnet:") => {
                     let magnet = Magnet::parse(&magnet)
                         .context("provided path is not a valid magnet URL")?;
-                    let info_hash = magnet
-                        .as_id20()
-                        .context("magnet link didn't contain a BTv1 infohash")?;
-
-                    let peer_rx = self.make_peer_rx(
-                        info_hash,
-                        if opts.disable_trackers {
-                            Default::default()
-                        } else {
-                            magnet.trackers.clone()
-                        },
-                        announce_port,
-                        opts.force_tracker_interval,
-                    )?;
-                    let peer_rx = match peer_rx {
-                        Some(peer_rx) => peer_rx,
-                        None => bail!("can't find peers: DHT disabled and no trackers in magnet"),
-                    };
+                    let info_hash = magnet_info_hash(&magnet)?;
+                    // Hybrid torrent magnets have both. Piece layers aren't in the info dict,
+                    // so the v1 hashes are used to verify the data.
+                    let info_hash_v2 = magnet.as_id32();
+
+                    // Magnets have no tiers, each tracker is announced to.
+                    let trackers =
+                        TrackerList::new(magnet.trackers.iter().map(|t| vec![t.clone()]).collect());
+                    if self.dht.is_none() && (opts.disable_trackers || trackers.is_empty()) {
+                        bail!("can't find peers: DHT disabled and no trackers in magnet");
+                    }
+                    let peer_rx = self
+                        .make_peer_rx(
+                            info_hash,
+                            info_hash_v2,
+                            (!opts.disable_trackers).then(|| trackers.clone()),
+                            announce_port,
+                            opts.force_tracker_interval,
+                        )?
+                        .context("bug: no peer sources")?;
 
                     debug!(?info_hash, "querying DHT");
                     let (info, peer_rx, initial_peers) = match read_metainfo_from_peer_receiver(
@@ -897,6 +1365,7 @@ impl Session {
                         opts.initial_peers.clone().unwrap_or_default(),
                         peer_rx,
                         Some(self.merge_peer_opts(opts.peer_opts)),
+                        &self.transports,
                     )
                     .await
                     {
@@ -906,40 +1375,30 @@ impl Session {
                         }
                     };
                     debug!(?info, "received result from DHT");
-                    (
+                    ResolvedTorrent {
                         info_hash,
                         info,
-                        magnet.trackers.into_iter().unique().collect(),
-                        Some(peer_rx),
-                        initial_peers,
-                    )
+                        info_hash_v2,
+                        piece_layers: None,
+                        trackers,
+                        web_seeds: Vec::new(),
+                        peer_rx: Some(peer_rx),
+                        initial_peers: initial_peers.into_iter().collect(),
+                    }
                 }
                 other => {
-                    let torrent = match other {
-                        AddTorrent::Url(url)
-                            if url.starts_with("http://") || url.starts_with("https://") =>
-                        {
-                            torrent_from_url(&url).await?
-                        }
-                        AddTorrent::Url(url) => {
-                            bail!(
-                                "unsupported URL {:?}. Supporting magnet:, http:, and https",
-                                url
-                            )
-                        }
-                        AddTorrent::TorrentFileBytes(bytes) => {
-                            torrent_from_bytes(&bytes).context("error decoding torrent")?
-                        }
-                        AddTorrent::TorrentInfo(t) => *t,
-                    };
+                    let torrent = torrent_from_add(other).await?;
+                    let trackers = TrackerList::new(tracker_tiers(&torrent));
 
-                    let trackers = torrent
-                        .iter_announce()
+                    let web_seeds = torrent
+                        .url_list
+                        .iter()
                         .unique()
-                        .filter_map(|tracker| match std::str::from_utf8(tracker.as_ref()) {
+                        .filter_map(|url| match std::str::from_utf8(url.as_ref()) {
+                            Ok("") => None,
                             Ok(url) => Some(url.to_owned()),
                             Err(_) => {
-                                warn!("cannot parse tracker url as utf-8, ignoring");
+                                warn!("cannot parse web seed url as utf-8, ignoring");
                                 None
                             }
                         })
@@ -950,39 +1409,27 @@ impl Session {
                     } else {
                         self.make_peer_rx(
                             torrent.info_hash,
-                            if opts.disable_trackers {
-                                Default::default()
-                            } else {
-                                trackers.clone()
-                            },
+                            torrent.info_hash_v2,
+                            (!opts.disable_trackers).then(|| trackers.clone()),
                             announce_port,
                             opts.force_tracker_interval,
                         )?
                     };
 
-                    (
-                        torrent.info_hash,
-                        torrent.info,
+                    ResolvedTorrent {
+                        info_hash: torrent.info_hash,
+                        info: torrent.info,
+                        info_hash_v2: torrent.info_hash_v2,
+                        piece_layers: torrent.piece_layers,
                         trackers,
+                        web_seeds,
                         peer_rx,
-                        opts.initial_peers
-                            .clone()
-                            .unwrap_or_default()
-                            .into_iter()
-                            .collect(),
-                    )
+                        initial_peers: opts.initial_peers.clone().unwrap_or_default(),
+                    }
                 }
             };
 
-            self.main_torrent_info(
-                info_hash,
-                info,
-                trackers,
-                peer_rx,
-                initial_peers.into_iter().collect(),
-                opts,
-            )
-            .await
+            self.main_torrent_info(torrent, opts, fastresume).await
         }
         .boxed()
     }
@@ -1016,19 +1463,27 @@ impl Session {
 
     async fn main_torrent_info(
         &self,
-        info_hash: Id20,
-        info: TorrentMetaV1Info<ByteBufOwned>,
-        trackers: Vec<String>,
-        peer_rx: Option<PeerStream>,
-        initial_peers: Vec<SocketAddr>,
+        torrent: ResolvedTorrent,
         mut opts: AddTorrentOptions,
+        fastresume: Option<FastResumeData>,
     ) -> anyhow::Result<AddTorrentResponse> {
+        let ResolvedTorrent {
+            info_hash,
+            info,
+            info_hash_v2,
+            piece_layers,
+            trackers,
+            web_seeds,
+            peer_rx,
+            initial_peers,
+        } = torrent;
         debug!("Torrent info: {:#?}", &info);
 
         let only_files = compute_only_files(
             &info,
             opts.only_files,
             opts.only_files_regex,
+            opts.file_priorities.as_deref(),
             opts.list_only,
         )?;
 
@@ -1066,6 +1521,7 @@ impl Session {
             .allow_overwrite(opts.overwrite)
             .spawner(self.spawner)
             .trackers(trackers)
+            .web_seeds(web_seeds)
             .peer_id(self.peer_id);
 
         if let Some(d) = self.disk_write_tx.clone() {
@@ -1075,10 +1531,31 @@ impl Session {
         if let Some(only_files) = only_files {
             builder.only_files(only_files);
         }
+        if let Some(file_priorities) = opts.file_priorities {
+            builder.file_priorities(file_priorities);
+        }
+        if let Some(fastresume) = fastresume {
+            builder.fastresume(fastresume);
+        }
+        if let Some(info_hash_v2) = info_hash_v2 {
+            builder.info_hash_v2(info_hash_v2);
+        }
+        if let Some(piece_layers) = piece_layers {
+            builder.piece_layers(piece_layers);
+        }
         if let Some(interval) = opts.force_tracker_interval {
             builder.force_tracker_interval(interval);
         }
 
+        builder.transports(self.transports.clone());
+        builder.ratelimits(Arc::new(RateLimits::new_child(
+            self.ratelimits.clone(),
+            opts.ratelimits,
+        )));
+        if let Some(slots) = opts.upload_slots.or(self.upload_slots) {
+            builder.upload_slots(slots);
+        }
+
         let peer_opts = self.merge_peer_opts(opts.peer_opts);
 
         if let Some(t) = peer_opts.connect_timeout {
@@ -1089,23 +1566,36 @@ impl Session {
             builder.peer_read_write_timeout(t);
         }
 
+        if let Some(e) = peer_opts.encryption {
+            builder.peer_encryption(e);
+        }
+
+        if let Some(port) = self.tcp_listen_port {
+            builder.listen_port(port);
+        }
+
         let (managed_torrent, id) = {
             let mut g = self.db.write();
             if let Some((id, handle)) = g.torrents.iter().find(|(_, t)| t.info_hash() == info_hash)
             {
                 return Ok(AddTorrentResponse::AlreadyManaged(*id, handle.clone()));
             }
-            let next_id = g.torrents.len();
-            let managed_torrent =
-                builder.build(error_span!(parent: None, "torrent", id = next_id))?;
-            let id = g.add_torrent(managed_torrent.clone(), opts.preferred_id);
+            let id = g.allocate_id(opts.preferred_id);
+            builder.events(TorrentEventSender::new(
+                id,
+                info_hash.as_string(),
+                self.events_tx.clone(),
+            ));
+            let managed_torrent = builder.build(error_span!(parent: None, "torrent", id = id))?;
+            g.torrents.insert(id, managed_torrent.clone());
             (managed_torrent, id)
         };
+        managed_torrent.info().send_event(TorrentEventKind::Added);
 
         // Merge "initial_peers" and "peer_rx" into one stream.
         let peer_rx = merge_two_optional_streams(
             if !initial_peers.is_empty() {
-                Some(futures::stream::iter(initial_peers.into_iter()))
+                Some(futures::stream::iter(initial_peers))
             } else {
                 None
             },
@@ -1124,6 +1614,11 @@ impl Session {
         Ok(AddTorrentResponse::Added(id, managed_torrent))
     }
 
+    /// Lifecycle events of all torrents. Subscribers that fall behind miss events.
+    pub fn subscribe_events(&self) -> tokio::sync::broadcast::Receiver<TorrentEvent> {
+        self.events_tx.broadcast.subscribe()
+    }
+
     pub fn get(&self, id: TorrentId) -> Option<ManagedTorrentHandle> {
         self.db.read().torrents.get(&id).cloned()
     }
@@ -1135,12 +1630,18 @@ impl Session {
             .torrents
             .remove(&id)
             .with_context(|| format!("torrent with id {} did not exist", id))?;
+        removed.info().send_event(TorrentEventKind::Removed);
 
+        let stats = removed.tracker_comms_stats();
         let paused = removed
             .with_state_mut(|s| {
                 let paused = match s.take() {
                     ManagedTorrentState::Paused(p) => p,
-                    ManagedTorrentState::Live(l) => l.pause()?,
+                    ManagedTorrentState::Live(l) => {
+                        let paused = l.pause()?;
+                        removed.info().spawn_announce_stopped(stats);
+                        paused
+                    }
                     _ => return Ok(None),
                 };
                 Ok::<_, anyhow::Error>(Some(paused))
@@ -1164,11 +1665,41 @@ impl Session {
         Ok(())
     }
 
-    // Get a peer stream from both DHT and trackers.
+    // Get a peer stream from both DHT and trackers. Hybrid torrents are in both the v1 and
+    // the v2 swarm. Trackers are None if disabled.
     fn make_peer_rx(
         self: &Arc<Self>,
         info_hash: Id20,
-        trackers: Vec<String>,
+        info_hash_v2: Option<Id32>,
+        trackers: Option<TrackerList>,
+        announce_port: Option<u16>,
+        force_tracker_interval: Option<Duration>,
+    ) -> anyhow::Result<Option<PeerStream>> {
+        let v1_rx = self.make_swarm_peer_rx(
+            info_hash,
+            info_hash,
+            trackers.clone(),
+            announce_port,
+            force_tracker_interval,
+        )?;
+        let v2_rx = match info_hash_v2.map(|h| h.truncate_for_dht()) {
+            Some(swarm_hash) if swarm_hash != info_hash => self.make_swarm_peer_rx(
+                swarm_hash,
+                info_hash,
+                trackers,
+                announce_port,
+                force_tracker_interval,
+            )?,
+            _ => None,
+        };
+        Ok(merge_two_optional_streams(v1_rx, v2_rx))
+    }
+
+    fn make_swarm_peer_rx(
+        self: &Arc<Self>,
+        swarm_hash: Id20,
+        info_hash: Id20,
+        trackers: Option<TrackerList>,
         announce_port: Option<u16>,
         force_tracker_interval: Option<Duration>,
     ) -> anyhow::Result<Option<PeerStream>> {
@@ -1176,21 +1707,23 @@ impl Session {
         let dht_rx = self
             .dht
             .as_ref()
-            .map(|dht| dht.get_peers(info_hash, announce_port))
+            .map(|dht| dht.get_peers(swarm_hash, announce_port))
             .transpose()?;
 
         let peer_rx_stats = PeerRxTorrentInfo {
             info_hash,
             session: self.clone(),
         };
-        let peer_rx = TrackerComms::start(
-            info_hash,
-            self.peer_id,
-            trackers,
-            Box::new(peer_rx_stats),
-            force_tracker_interval,
-            announce_port,
-        );
+        let peer_rx = trackers.map(|trackers| {
+            TrackerComms::start(
+                swarm_hash,
+                self.peer_id,
+                trackers,
+                Box::new(peer_rx_stats),
+                force_tracker_interval,
+                announce_port,
+            )
+        });
 
         Ok(merge_two_optional_streams(dht_rx, peer_rx))
     }
@@ -1198,7 +1731,8 @@ impl Session {
     pub fn unpause(self: &Arc<Self>, handle: &ManagedTorrentHandle) -> anyhow::Result<()> {
         let peer_rx = self.make_peer_rx(
             handle.info_hash(),
-            handle.info().trackers.clone().into_iter().collect(),
+            handle.info().info_hash_v2,
+            Some(handle.info().trackers.clone()),
             self.tcp_listen_port,
             handle.info().options.force_tracker_interval,
         )?;
@@ -1206,6 +1740,45 @@ impl Session {
         Ok(())
     }
 
+    /// Move the torrent's files to a new output folder. Live torrents are paused while moving, and
+    /// started again afterwards, even if moving failed. Pieces aren't re-checked.
+    ///
+    /// The move runs in its own task, so it's finished even if the caller stops waiting for it.
+    pub async fn move_storage(
+        self: &Arc<Self>,
+        id: TorrentId,
+        new_folder: PathBuf,
+    ) -> anyhow::Result<()> {
+        let handle = self
+            .get(id)
+            .with_context(|| format!("torrent with id {} did not exist", id))?;
+        let session = self.clone();
+        tokio::spawn(async move { session.move_storage_of(handle, new_folder).await })
+            .await
+            .context("bug: moving storage panicked")?
+    }
+
+    async fn move_storage_of(
+        self: &Arc<Self>,
+        handle: ManagedTorrentHandle,
+        new_folder: PathBuf,
+    ) -> anyhow::Result<()> {
+        let was_live = handle.live().is_some();
+        let result = handle.move_storage(new_folder).await;
+
+        // Don't wait for the next periodic dump, the old folder might be gone already.
+        if result.is_ok() && !self.persistence_filename.as_os_str().is_empty() {
+            if let Err(e) = self.dump_to_disk() {
+                error!("error dumping session to disk: {:?}", e);
+            }
+        }
+        if was_live && handle.with_state(|s| matches!(s, ManagedTorrentState::Paused(_))) {
+            self.unpause(&handle)
+                .context("error starting torrent after moving")?;
+        }
+        result
+    }
+
     pub fn update_only_files(
         self: &Arc<Self>,
         handle: &ManagedTorrentHandle,
@@ -1215,9 +1788,32 @@ impl Session {
         Ok(())
     }
 
+    pub fn update_file_priorities(
+        self: &Arc<Self>,
+        handle: &ManagedTorrentHandle,
+        file_priorities: Vec<FilePriority>,
+    ) -> anyhow::Result<()> {
+        handle.update_file_priorities(file_priorities)?;
+        Ok(())
+    }
+
     pub fn tcp_listen_port(&self) -> Option<u16> {
         self.tcp_listen_port
     }
+
+    /// The embedded tracker, if enabled.
+    pub fn tracker(&self) -> Option<&Arc<TrackerServer>> {
+        self.tracker.as_ref()
+    }
+
+    pub fn ratelimits(&self) -> RateLimitsConfig {
+        self.ratelimits.get_config()
+    }
+
+    /// Change the session-wide upload and download limits. Applies immediately to all torrents.
+    pub fn set_ratelimits(&self, config: RateLimitsConfig) {
+        self.ratelimits.set_config(config)
+    }
 }
 
 // Ad adapter for converting stats into the format that tracker_comms accepts.
@@ -1236,28 +1832,12 @@ impl tracker_comms::TorrentStatsProvider for PeerRxTorrentInfo {
             }
             None
         });
-        let mt = match mt {
-            Some(mt) => mt,
+        match mt {
+            Some(mt) => mt.tracker_comms_stats(),
             None => {
                 trace!(info_hash=?self.info_hash, "can't find torrent in the session, using default stats");
-                return Default::default();
+                Default::default()
             }
-        };
-        let stats = mt.stats();
-
-        use crate::torrent_state::stats::TorrentStatsState as TS;
-        use tracker_comms::TrackerCommsStatsState as S;
-
-        tracker_comms::TrackerCommsStats {
-            downloaded_bytes: stats.progress_bytes,
-            total_bytes: stats.total_bytes,
-            uploaded_bytes: stats.uploaded_bytes,
-            torrent_state: match stats.state {
-                TS::Initializing => S::Initializing,
-                TS::Live => S::Live,
-                TS::Paused => S::Paused,
-                TS::Error => S::None,
-            },
         }
     }
 }
@@ -18,8 +18,25 @@ impl StorageFactory for FilesystemStorageFactory {
     type Storage = FilesystemStorage;
 
     fn init_storage(&self, meta: &ManagedTorrentInfo) -> anyhow::Result<FilesystemStorage> {
+        FilesystemStorage::open(meta, meta.options.allow_overwrite)
+    }
+
+    fn clone_box(&self) -> crate::storage::BoxStorageFactory {
+        self.boxed()
+    }
+}
+
+pub struct FilesystemStorage {
+    pub(super) output_folder: PathBuf,
+    pub(super) opened_files: Vec<OpenedFile>,
+}
+
+impl FilesystemStorage {
+    /// Opens the files in the torrent's current output folder. Without allow_overwrite, all files
+    /// must be new.
+    pub(crate) fn open(meta: &ManagedTorrentInfo, allow_overwrite: bool) -> anyhow::Result<Self> {
         let mut files = Vec::<OpenedFile>::new();
-        let output_folder = &meta.options.output_folder;
+        let output_folder = meta.output_folder();
         for file_details in meta.info.iter_file_details(&meta.lengths)? {
             let mut full_path = output_folder.clone();
             let relative_path = file_details
@@ -29,7 +46,7 @@ impl StorageFactory for FilesystemStorageFactory {
             full_path.push(relative_path);
 
             std::fs::create_dir_all(full_path.parent().context("bug: no parent")?)?;
-            let file = if meta.options.allow_overwrite {
+            let file = if allow_overwrite {
                 OpenOptions::new()
                     .create(true)
                     .truncate(false)
@@ -54,22 +71,11 @@ impl StorageFactory for FilesystemStorageFactory {
             files.push(OpenedFile::new(file));
         }
         Ok(FilesystemStorage {
-            output_folder: output_folder.clone(),
+            output_folder,
             opened_files: files,
         })
     }
 
-    fn clone_box(&self) -> crate::storage::BoxStorageFactory {
-        self.boxed()
-    }
-}
-
-pub struct FilesystemStorage {
-    pub(super) output_folder: PathBuf,
-    pub(super) opened_files: Vec<OpenedFile>,
-}
-
-impl FilesystemStorage {
     pub(super) fn take_fs(&self) -> anyhow::Result<Self> {
         Ok(Self {
             opened_files: self
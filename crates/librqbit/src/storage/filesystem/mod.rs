@@ -1,6 +1,8 @@
 mod fs;
 mod mmap;
+mod move_files;
 mod opened_file;
 
 pub use fs::{FilesystemStorage, FilesystemStorageFactory};
 pub use mmap::{MmapFilesystemStorage, MmapFilesystemStorageFactory};
+pub(crate) use move_files::move_files;
@@ -0,0 +1,137 @@
+// Moves the files of a torrent to another folder. Files are renamed, and only copied when the new
+// folder is on another filesystem.
+
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use tracing::{debug, warn};
+
+// The OS error of renaming across filesystems: EXDEV on unix, ERROR_NOT_SAME_DEVICE on Windows.
+// io::ErrorKind::CrossesDevices needs a newer Rust than we support.
+#[cfg(unix)]
+const CROSSES_DEVICES: Option<i32> = Some(18);
+#[cfg(windows)]
+const CROSSES_DEVICES: Option<i32> = Some(17);
+#[cfg(not(any(unix, windows)))]
+const CROSSES_DEVICES: Option<i32> = None;
+
+fn move_file(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    match std::fs::rename(src, dst) {
+        Ok(()) => return Ok(()),
+        Err(e) if CROSSES_DEVICES.is_some() && e.raw_os_error() == CROSSES_DEVICES => {}
+        Err(e) => return Err(e).with_context(|| format!("error renaming {src:?} to {dst:?}")),
+    }
+    if let Err(e) = std::fs::copy(src, dst) {
+        let _ = std::fs::remove_file(dst);
+        return Err(e).with_context(|| format!("error copying {src:?} to {dst:?}"));
+    }
+    std::fs::remove_file(src).with_context(|| format!("error removing {src:?}"))
+}
+
+// Removes the folders of the file that became empty, but not the root folder itself.
+fn remove_empty_parents(root: &Path, file: &Path) {
+    let mut dir = file.parent();
+    while let Some(d) = dir.filter(|d| *d != root && d.starts_with(root)) {
+        if std::fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+/// Moves the given files, relative to "from", to the same paths relative to "to". Files that
+/// don't exist are skipped. If a file can't be moved, the ones moved before it are moved back.
+pub(crate) fn move_files<'a>(
+    from: &Path,
+    to: &Path,
+    files: impl IntoIterator<Item = &'a Path>,
+    allow_overwrite: bool,
+) -> anyhow::Result<()> {
+    let files = files
+        .into_iter()
+        .filter(|f| from.join(f).exists())
+        .collect::<Vec<_>>();
+    if !allow_overwrite {
+        if let Some(f) = files.iter().find(|f| to.join(f).exists()) {
+            bail!(
+                "{:?} already exists (because allow_overwrite = false)",
+                to.join(f)
+            );
+        }
+    }
+
+    let mut moved = Vec::new();
+    for f in files {
+        let (src, dst) = (from.join(f), to.join(f));
+        let result = dst
+            .parent()
+            .context("bug: no parent")
+            .and_then(|p| {
+                std::fs::create_dir_all(p).with_context(|| format!("error creating {p:?}"))
+            })
+            .and_then(|_| move_file(&src, &dst));
+        if let Err(e) = result {
+            for f in moved.into_iter().rev() {
+                if let Err(e) = move_file(&to.join(f), &from.join(f)) {
+                    warn!(file=?f, "error moving file back: {e:#}");
+                }
+            }
+            return Err(e);
+        }
+        debug!(?src, ?dst, "moved");
+        remove_empty_parents(from, &src);
+        moved.push(f);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{move_file, move_files};
+
+    #[test]
+    fn test_move_files() {
+        let from = tempfile::TempDir::with_prefix("test_move_files_from").unwrap();
+        let to = tempfile::TempDir::with_prefix("test_move_files_to").unwrap();
+        let files = [
+            Path::new("t/a.bin"),
+            Path::new("t/sub/b.bin"),
+            Path::new("t/missing.bin"),
+        ];
+        std::fs::create_dir_all(from.path().join("t/sub")).unwrap();
+        std::fs::write(from.path().join(files[0]), b"a").unwrap();
+        std::fs::write(from.path().join(files[1]), b"b").unwrap();
+
+        move_files(from.path(), to.path(), files, false).unwrap();
+        assert_eq!(std::fs::read(to.path().join(files[0])).unwrap(), b"a");
+        assert_eq!(std::fs::read(to.path().join(files[1])).unwrap(), b"b");
+        assert!(!to.path().join(files[2]).exists());
+        // The torrent's folder is gone, the output folder is kept.
+        assert!(!from.path().join("t").exists());
+        assert!(from.path().exists());
+
+        // Moving back onto existing files.
+        std::fs::create_dir_all(from.path().join("t")).unwrap();
+        std::fs::write(from.path().join(files[0]), b"old").unwrap();
+        assert!(move_files(to.path(), from.path(), files, false).is_err());
+        assert!(to.path().join(files[1]).exists());
+        move_files(to.path(), from.path(), files, true).unwrap();
+        assert_eq!(std::fs::read(from.path().join(files[0])).unwrap(), b"a");
+    }
+
+    #[test]
+    fn test_move_file_errors_are_not_copied_over() {
+        let dir = tempfile::TempDir::with_prefix("test_move_file_errors").unwrap();
+        let (src, dst) = (dir.path().join("a.bin"), dir.path().join("dir"));
+        std::fs::write(&src, b"a").unwrap();
+        std::fs::create_dir_all(dst.join("sub")).unwrap();
+
+        // Renaming onto a directory fails, and isn't retried as a copy.
+        let e = move_file(&src, &dst).unwrap_err();
+        assert!(format!("{e:#}").starts_with("error renaming"), "{e:#}");
+        assert_eq!(std::fs::read(&src).unwrap(), b"a");
+        assert!(dst.join("sub").is_dir());
+    }
+}
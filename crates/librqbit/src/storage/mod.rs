@@ -25,6 +25,12 @@ pub trait StorageFactory: Send + Sync + Any {
 
 pub type BoxStorageFactory = Box<dyn StorageFactory<Storage = Box<dyn TorrentStorage>>>;
 
+impl Clone for BoxStorageFactory {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
 pub trait StorageFactoryExt {
     fn boxed(self) -> BoxStorageFactory;
 }
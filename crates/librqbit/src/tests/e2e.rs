@@ -74,6 +74,13 @@ async fn test_e2e() {
                         enable_upnp_port_forwarding: false,
                         default_storage_factory: None,
                         defer_writes_up_to: None,
+                        disable_utp: false,
+                        prefer_utp: false,
+                        ratelimits: Default::default(),
+                        upload_slots: None,
+                        hooks: Default::default(),
+                        watch_folder: None,
+                        tracker: None,
                     },
                 )
                 .await
@@ -0,0 +1,134 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::Context;
+use tokio::{sync::broadcast::Receiver, time::timeout};
+
+use crate::{
+    create_torrent,
+    tests::test_util::{
+        add_torrent, create_default_random_dir_with_torrents, start_session, test_session_options,
+    },
+    AddTorrent, AddTorrentOptions, CreateTorrentOptions, TorrentEvent, TorrentEventKind,
+};
+
+async fn next_event(rx: &mut Receiver<TorrentEvent>) -> anyhow::Result<TorrentEvent> {
+    Ok(timeout(Duration::from_secs(10), rx.recv())
+        .await
+        .context("timeout waiting for event")??)
+}
+
+#[tokio::test]
+async fn test_e2e_events() -> anyhow::Result<()> {
+    let files = create_default_random_dir_with_torrents(2, 100_000, Some("test_e2e_events"));
+    let torrent = create_torrent(
+        files.path(),
+        CreateTorrentOptions {
+            name: None,
+            piece_length: Some(16384),
+        },
+    )
+    .await?;
+
+    let session = start_session(files.path(), test_session_options(None)).await?;
+    let mut rx = session.subscribe_events();
+
+    let (id, handle) = match session
+        .add_torrent(
+            AddTorrent::from_bytes(torrent.as_bytes()?),
+            Some(AddTorrentOptions {
+                output_folder: Some(files.path().to_str().unwrap().to_owned()),
+                overwrite: true,
+                ..Default::default()
+            }),
+        )
+        .await?
+    {
+        crate::AddTorrentResponse::Added(id, handle) => (id, handle),
+        _ => anyhow::bail!("expected the torrent to be added"),
+    };
+
+    for expected in [
+        TorrentEventKind::Added,
+        TorrentEventKind::Initialized,
+        TorrentEventKind::Started,
+    ] {
+        let event = next_event(&mut rx).await?;
+        assert_eq!(event.torrent_id, id);
+        assert_eq!(event.info_hash, handle.info_hash().as_string());
+        assert_eq!(event.kind, expected);
+    }
+
+    handle.pause()?;
+    assert_eq!(next_event(&mut rx).await?.kind, TorrentEventKind::Paused);
+    session.delete(id, false)?;
+    assert_eq!(next_event(&mut rx).await?.kind, TorrentEventKind::Removed);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_e2e_download_events() -> anyhow::Result<()> {
+    let files =
+        create_default_random_dir_with_torrents(3, 50_000, Some("test_e2e_download_events"));
+    let torrent = create_torrent(
+        files.path(),
+        CreateTorrentOptions {
+            name: None,
+            piece_length: Some(16384),
+        },
+    )
+    .await?;
+    let torrent_bytes = torrent.as_bytes()?;
+
+    let seeder = start_session(files.path(), test_session_options(Some(16800..16900))).await?;
+    let seeder_handle = add_torrent(
+        &seeder,
+        torrent_bytes.clone(),
+        AddTorrentOptions {
+            output_folder: Some(files.path().to_str().unwrap().to_owned()),
+            overwrite: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    timeout(
+        Duration::from_secs(10),
+        seeder_handle.wait_until_completed(),
+    )
+    .await
+    .context("timeout initializing seeder")??;
+    let seeder_addr = SocketAddr::new(
+        "127.0.0.1".parse().unwrap(),
+        seeder.tcp_listen_port().unwrap(),
+    );
+
+    let leecher_dir = tempfile::TempDir::with_prefix("test_e2e_download_events_leecher")?;
+    let leecher = start_session(leecher_dir.path(), test_session_options(None)).await?;
+    let mut rx = leecher.subscribe_events();
+    let handle = add_torrent(
+        &leecher,
+        torrent_bytes,
+        AddTorrentOptions {
+            initial_peers: Some(vec![seeder_addr]),
+            ..Default::default()
+        },
+    )
+    .await?;
+    let num_pieces = handle.info().lengths.total_pieces();
+
+    // Each piece and file completes once, and the torrent completes after all of them.
+    let mut pieces = Vec::new();
+    let mut completed_files = Vec::new();
+    loop {
+        match next_event(&mut rx).await?.kind {
+            TorrentEventKind::PieceCompleted { piece } => pieces.push(piece),
+            TorrentEventKind::FileCompleted { file_id } => completed_files.push(file_id),
+            TorrentEventKind::Completed => break,
+            _ => {}
+        }
+    }
+    pieces.sort_unstable();
+    completed_files.sort_unstable();
+    assert_eq!(pieces, (0..num_pieces).collect::<Vec<_>>());
+    assert_eq!(completed_files, vec![0, 1, 2]);
+    Ok(())
+}
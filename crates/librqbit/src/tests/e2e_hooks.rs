@@ -0,0 +1,130 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::Context;
+use axum::{extract::State, routing::post, Json, Router};
+use tokio::{sync::mpsc::UnboundedSender, time::timeout};
+
+use crate::{
+    create_torrent, AddTorrentOptions, CreateTorrentOptions, Hook, SessionHooks, SessionOptions,
+};
+
+use super::test_util::{
+    add_torrent, create_default_random_dir_with_torrents, start_session, test_session_options,
+};
+
+async fn receive_webhook(
+    State(tx): State<UnboundedSender<serde_json::Value>>,
+    Json(payload): Json<serde_json::Value>,
+) {
+    let _ = tx.send(payload);
+}
+
+async fn e2e_hooks() -> anyhow::Result<()> {
+    let files = create_default_random_dir_with_torrents(2, 100_000, Some("test_e2e_hooks"));
+    let torrent = create_torrent(
+        files.path(),
+        CreateTorrentOptions {
+            name: Some("hooks"),
+            piece_length: Some(16384),
+        },
+    )
+    .await?;
+    let torrent_bytes = torrent.as_bytes()?;
+
+    let seeder_dir = tempfile::TempDir::with_prefix("test_e2e_hooks_seeder")?;
+    let seeder = start_session(seeder_dir.path(), test_session_options(Some(16700..16800))).await?;
+    let seeder_handle = add_torrent(
+        &seeder,
+        torrent_bytes.clone(),
+        AddTorrentOptions {
+            output_folder: Some(files.path().to_str().unwrap().to_owned()),
+            overwrite: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    timeout(
+        Duration::from_secs(10),
+        seeder_handle.wait_until_completed(),
+    )
+    .await
+    .context("timeout initializing seeder")??;
+    let seeder_addr = SocketAddr::new(
+        "127.0.0.1".parse().unwrap(),
+        seeder.tcp_listen_port().unwrap(),
+    );
+
+    let (webhook_tx, mut webhook_rx) = tokio::sync::mpsc::unbounded_channel();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let app = Router::new()
+        .route("/hook", post(receive_webhook))
+        .with_state(webhook_tx);
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let leecher_dir = tempfile::TempDir::with_prefix("test_e2e_hooks_leecher")?;
+    let command_output = leecher_dir.path().join("hook_output");
+    let leecher = start_session(
+        leecher_dir.path(),
+        SessionOptions {
+            hooks: SessionHooks {
+                on_completed: vec![
+                    Hook::Webhook(format!("http://127.0.0.1:{port}/hook")),
+                    Hook::Command(format!(
+                        "echo \"$RQBIT_EVENT $RQBIT_TORRENT_NAME\" > '{}'",
+                        command_output.display()
+                    )),
+                ],
+                ..Default::default()
+            },
+            ..test_session_options(None)
+        },
+    )
+    .await?;
+    let leecher_handle = add_torrent(
+        &leecher,
+        torrent_bytes,
+        AddTorrentOptions {
+            initial_peers: Some(vec![seeder_addr]),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let payload = timeout(Duration::from_secs(30), webhook_rx.recv())
+        .await
+        .context("timeout waiting for the webhook")?
+        .context("webhook server stopped")?;
+    assert_eq!(payload["event"], "completed");
+    assert_eq!(payload["name"], "hooks");
+    assert_eq!(
+        payload["info_hash"],
+        leecher_handle.info_hash().as_string().as_str()
+    );
+    assert!(leecher_handle.stats().finished);
+
+    let output = timeout(Duration::from_secs(10), async {
+        loop {
+            if let Ok(output) = std::fs::read_to_string(&command_output) {
+                if output.ends_with('\n') {
+                    return output;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .context("timeout waiting for the command hook")?;
+    assert_eq!(output, "completed hooks\n");
+
+    // Only completion hooks were configured, and the torrent completes only once.
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(webhook_rx.try_recv().is_err());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_e2e_hooks() -> anyhow::Result<()> {
+    let _ = tracing_subscriber::fmt::try_init();
+    timeout(Duration::from_secs(60), e2e_hooks()).await?
+}
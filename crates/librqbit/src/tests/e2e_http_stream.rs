@@ -0,0 +1,201 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::Context;
+use http::{header, StatusCode};
+use tokio::time::timeout;
+
+use crate::{
+    create_torrent,
+    tests::test_util::{
+        add_torrent, create_default_random_dir_with_torrents, start_http_api, start_session,
+        test_session_options,
+    },
+    AddTorrentOptions, CreateTorrentOptions,
+};
+
+struct StreamResponse {
+    status: StatusCode,
+    headers: http::HeaderMap,
+    body: Vec<u8>,
+}
+
+async fn request(
+    method: reqwest::Method,
+    url: &str,
+    headers: &[(header::HeaderName, &str)],
+) -> anyhow::Result<StreamResponse> {
+    let mut req = reqwest::Client::new().request(method, url);
+    for (name, value) in headers {
+        req = req.header(name, *value);
+    }
+    let response = req.send().await?;
+    Ok(StreamResponse {
+        status: response.status(),
+        headers: response.headers().clone(),
+        body: response.bytes().await?.to_vec(),
+    })
+}
+
+async fn get(url: &str, headers: &[(header::HeaderName, &str)]) -> anyhow::Result<StreamResponse> {
+    request(reqwest::Method::GET, url, headers).await
+}
+
+impl StreamResponse {
+    fn header(&self, name: header::HeaderName) -> &str {
+        self.headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+    }
+}
+
+async fn start_streaming_session() -> anyhow::Result<(SocketAddr, Vec<u8>, tempfile::TempDir)> {
+    let files = create_default_random_dir_with_torrents(1, 100_000, Some("test_e2e_http_stream"));
+    let data = std::fs::read(files.path().join("0.data"))?;
+    let torrent = create_torrent(
+        files.path(),
+        CreateTorrentOptions {
+            name: None,
+            piece_length: Some(16384),
+        },
+    )
+    .await?;
+    let session = start_session(files.path(), test_session_options(None)).await?;
+    let handle = add_torrent(
+        &session,
+        torrent.as_bytes()?,
+        AddTorrentOptions {
+            output_folder: Some(files.path().to_str().unwrap().to_owned()),
+            overwrite: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    timeout(Duration::from_secs(10), handle.wait_until_completed())
+        .await
+        .context("timeout checking the torrent")??;
+    let addr = start_http_api(&session).await?;
+    Ok((addr, data, files))
+}
+
+#[tokio::test]
+async fn test_e2e_http_stream() -> anyhow::Result<()> {
+    let (addr, data, _files) = start_streaming_session().await?;
+    let url = format!("http://{addr}/torrents/0/stream/0");
+    let len = data.len();
+
+    let full = get(&url, &[]).await?;
+    assert_eq!(full.status, StatusCode::OK);
+    assert_eq!(full.header(header::ACCEPT_RANGES), "bytes");
+    assert_eq!(full.header(header::CONTENT_LENGTH), len.to_string());
+    assert!(full.body == data);
+    let etag = full.header(header::ETAG).to_owned();
+    assert!(etag.starts_with('"') && etag.ends_with('"'));
+
+    let head = request(reqwest::Method::HEAD, &url, &[]).await?;
+    assert_eq!(head.status, StatusCode::OK);
+    assert_eq!(head.header(header::CONTENT_LENGTH), len.to_string());
+    assert!(head.body.is_empty());
+
+    let partial = get(&url, &[(header::RANGE, "bytes=100-199")]).await?;
+    assert_eq!(partial.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        partial.header(header::CONTENT_RANGE),
+        format!("bytes 100-199/{len}")
+    );
+    assert_eq!(partial.header(header::CONTENT_LENGTH), "100");
+    assert!(partial.body == data[100..200]);
+
+    let suffix = get(&url, &[(header::RANGE, "bytes=-10")]).await?;
+    assert_eq!(suffix.status, StatusCode::PARTIAL_CONTENT);
+    assert!(suffix.body == data[len - 10..]);
+
+    let head_partial = request(
+        reqwest::Method::HEAD,
+        &url,
+        &[(header::RANGE, "bytes=100-199")],
+    )
+    .await?;
+    assert_eq!(head_partial.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(head_partial.header(header::CONTENT_LENGTH), "100");
+    assert!(head_partial.body.is_empty());
+
+    let unsatisfiable = get(&url, &[(header::RANGE, &format!("bytes={len}-"))]).await?;
+    assert_eq!(unsatisfiable.status, StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(
+        unsatisfiable.header(header::CONTENT_RANGE),
+        format!("bytes */{len}")
+    );
+    assert!(unsatisfiable.body.is_empty());
+
+    // If-Range with the current ETag honors the range, anything else gets the full file.
+    let if_range = get(
+        &url,
+        &[(header::RANGE, "bytes=0-9"), (header::IF_RANGE, &etag)],
+    )
+    .await?;
+    assert_eq!(if_range.status, StatusCode::PARTIAL_CONTENT);
+    assert!(if_range.body == data[..10]);
+    let stale = get(
+        &url,
+        &[
+            (header::RANGE, "bytes=0-9"),
+            (header::IF_RANGE, "\"other\""),
+        ],
+    )
+    .await?;
+    assert_eq!(stale.status, StatusCode::OK);
+    assert!(stale.body == data);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_e2e_http_stream_multiple_ranges() -> anyhow::Result<()> {
+    let (addr, data, _files) = start_streaming_session().await?;
+    let url = format!("http://{addr}/torrents/0/stream/0");
+    let len = data.len();
+
+    let response = get(&url, &[(header::RANGE, "bytes=0-9,1000-1019")]).await?;
+    assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+    let boundary = response
+        .header(header::CONTENT_TYPE)
+        .strip_prefix("multipart/byteranges; boundary=")
+        .context("expected a multipart/byteranges response")?
+        .to_owned();
+    assert!(response.headers.get(header::CONTENT_RANGE).is_none());
+
+    let mut expected = Vec::new();
+    expected.extend_from_slice(
+        format!("--{boundary}\r\nContent-Range: bytes 0-9/{len}\r\n\r\n").as_bytes(),
+    );
+    expected.extend_from_slice(&data[..10]);
+    expected.extend_from_slice(
+        format!("\r\n--{boundary}\r\nContent-Range: bytes 1000-1019/{len}\r\n\r\n").as_bytes(),
+    );
+    expected.extend_from_slice(&data[1000..1020]);
+    expected.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    assert!(
+        response.body == expected,
+        "unexpected body: {:?}",
+        String::from_utf8_lossy(&response.body)
+    );
+    assert_eq!(
+        response.header(header::CONTENT_LENGTH),
+        expected.len().to_string()
+    );
+
+    // The length announced for HEAD is the one of the body GET would send.
+    let head = request(
+        reqwest::Method::HEAD,
+        &url,
+        &[(header::RANGE, "bytes=0-9,1000-1019")],
+    )
+    .await?;
+    assert_eq!(head.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        head.header(header::CONTENT_LENGTH),
+        expected.len().to_string()
+    );
+    assert!(head.body.is_empty());
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::time::timeout;
+
+use crate::{
+    create_torrent,
+    tests::test_util::{
+        create_default_random_dir_with_torrents, start_session, test_session_options,
+    },
+    AddTorrent, AddTorrentOptions, AddTorrentResponse, CreateTorrentOptions, ManagedTorrentState,
+    TorrentEventKind,
+};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_e2e_move_storage() -> anyhow::Result<()> {
+    let _ = tracing_subscriber::fmt::try_init();
+
+    let files = create_default_random_dir_with_torrents(3, 100_000, Some("test_e2e_move_storage"));
+    let expected = (0..3)
+        .map(|f| std::fs::read(files.path().join(format!("{f}.data"))))
+        .collect::<Result<Vec<_>, _>>()?;
+    let torrent = create_torrent(
+        files.path(),
+        CreateTorrentOptions {
+            name: None,
+            piece_length: Some(16384),
+        },
+    )
+    .await?;
+
+    let session = start_session(files.path(), test_session_options(None)).await?;
+
+    let (id, handle) = match session
+        .add_torrent(
+            AddTorrent::from_bytes(torrent.as_bytes()?),
+            Some(AddTorrentOptions {
+                output_folder: Some(files.path().to_str().unwrap().to_owned()),
+                overwrite: true,
+                ..Default::default()
+            }),
+        )
+        .await?
+    {
+        AddTorrentResponse::Added(id, handle) => (id, handle),
+        _ => anyhow::bail!("expected the torrent to be added"),
+    };
+    timeout(Duration::from_secs(10), handle.wait_until_completed())
+        .await
+        .context("timeout checking the torrent")??;
+
+    let new_dir = tempfile::TempDir::with_prefix("test_e2e_move_storage_new")?;
+    let new_folder = new_dir.path().join("moved");
+    let mut events = session.subscribe_events();
+    session.move_storage(id, new_folder.clone()).await?;
+
+    assert_eq!(handle.info().output_folder(), new_folder);
+    for (f, expected) in expected.iter().enumerate() {
+        let name = format!("{f}.data");
+        assert!(!files.path().join(&name).exists());
+        assert!(std::fs::read(new_folder.join(&name))? == *expected);
+    }
+
+    // Paused for the move, then started again without being checked.
+    let mut kinds = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        kinds.push(event.kind);
+    }
+    assert_eq!(
+        kinds,
+        vec![TorrentEventKind::Paused, TorrentEventKind::Started]
+    );
+    let stats = handle.stats();
+    assert!(stats.finished);
+    assert_eq!(stats.progress_bytes, handle.get_total_bytes());
+
+    // Paused torrents stay paused.
+    handle.pause()?;
+    session.move_storage(id, files.path().to_owned()).await?;
+    assert!(handle.with_state(|s| matches!(s, ManagedTorrentState::Paused(_))));
+    assert!(files.path().join("0.data").exists());
+    assert!(!new_folder.join("0.data").exists());
+
+    session.unpause(&handle)?;
+    timeout(Duration::from_secs(10), handle.wait_until_completed())
+        .await
+        .context("timeout waiting for the torrent")??;
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::{net::SocketAddr, num::NonZeroU32, time::Duration};
+
+use anyhow::Context;
+use tokio::time::timeout;
+use tracing::info;
+
+use crate::{
+    create_torrent,
+    limits::RateLimitsConfig,
+    torrent_state::peer::stats::snapshot::{PeerStatsFilter, PeerStatsFilterState},
+    AddTorrentOptions, CreateTorrentOptions, SessionOptions,
+};
+
+use super::test_util::{
+    add_torrent, create_default_random_dir_with_torrents, start_session, test_session_options,
+};
+
+async fn e2e_pex() -> anyhow::Result<()> {
+    let files = create_default_random_dir_with_torrents(4, 256 * 1024, Some("test_e2e_pex"));
+    let torrent = create_torrent(
+        files.path(),
+        CreateTorrentOptions {
+            name: None,
+            piece_length: Some(16384),
+        },
+    )
+    .await?;
+    let torrent_bytes = torrent.as_bytes()?;
+
+    let seeder_dir = tempfile::TempDir::with_prefix("test_e2e_pex_seeder")?;
+    let seeder = start_session(seeder_dir.path(), test_session_options(Some(16400..16500))).await?;
+    let seeder_handle = add_torrent(
+        &seeder,
+        torrent_bytes.clone(),
+        AddTorrentOptions {
+            output_folder: Some(files.path().to_str().unwrap().to_owned()),
+            overwrite: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    timeout(
+        Duration::from_secs(10),
+        seeder_handle.wait_until_completed(),
+    )
+    .await
+    .context("timeout initializing seeder")??;
+    let seeder_addr = SocketAddr::new(
+        "127.0.0.1".parse().unwrap(),
+        seeder.tcp_listen_port().unwrap(),
+    );
+
+    // The middle peer downloads slowly, so that it stays connected to the seeder
+    // while it tells the leecher about it.
+    let middle_dir = tempfile::TempDir::with_prefix("test_e2e_pex_middle")?;
+    let middle = start_session(
+        middle_dir.path(),
+        SessionOptions {
+            ratelimits: RateLimitsConfig {
+                upload_bps: None,
+                download_bps: NonZeroU32::new(64 * 1024),
+            },
+            ..test_session_options(Some(16500..16600))
+        },
+    )
+    .await?;
+    add_torrent(
+        &middle,
+        torrent_bytes.clone(),
+        AddTorrentOptions {
+            initial_peers: Some(vec![seeder_addr]),
+            ..Default::default()
+        },
+    )
+    .await?;
+    let middle_addr = SocketAddr::new(
+        "127.0.0.1".parse().unwrap(),
+        middle.tcp_listen_port().unwrap(),
+    );
+    info!(?seeder_addr, ?middle_addr, "started seeder and middle peer");
+
+    // The leecher only knows about the middle peer.
+    let leecher_dir = tempfile::TempDir::with_prefix("test_e2e_pex_leecher")?;
+    let leecher = start_session(leecher_dir.path(), test_session_options(None)).await?;
+    let leecher_handle = add_torrent(
+        &leecher,
+        torrent_bytes,
+        AddTorrentOptions {
+            initial_peers: Some(vec![middle_addr]),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    // The torrent may still be initializing, so don't require it to be live yet.
+    let connections_to_seeder = || {
+        leecher_handle
+            .live()
+            .and_then(|live| {
+                live.per_peer_stats_snapshot(PeerStatsFilter {
+                    state: PeerStatsFilterState::All,
+                })
+                .peers
+                .get(&seeder_addr.to_string())
+                .map(|s| s.counters.connections)
+            })
+            .unwrap_or(0)
+    };
+    timeout(Duration::from_secs(20), async {
+        while connections_to_seeder() == 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .context("leecher never learned about the seeder through pex")?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_e2e_pex() -> anyhow::Result<()> {
+    let _ = tracing_subscriber::fmt::try_init();
+    timeout(Duration::from_secs(60), e2e_pex()).await?
+}
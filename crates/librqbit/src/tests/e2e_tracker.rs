@@ -0,0 +1,322 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::Context;
+use buffers::ByteBufOwned;
+use byteorder::{ReadBytesExt, WriteBytesExt, BE};
+use http::StatusCode;
+use librqbit_core::Id20;
+use serde::Deserialize;
+use tokio::{net::UdpSocket, time::timeout};
+
+use crate::{
+    create_torrent,
+    tests::test_util::{
+        add_torrent, create_default_random_dir_with_torrents, start_http_api, start_session,
+        test_session_options,
+    },
+    AddTorrent, AddTorrentOptions, Api, CreateTorrentOptions, EmbeddedTrackerOptions,
+    ManagedTorrentState, SessionOptions,
+};
+
+#[derive(Deserialize)]
+struct AnnounceResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<ByteBufOwned>,
+    peers: Option<ByteBufOwned>,
+}
+
+fn announce_query(info_hash: Id20, port: u16) -> String {
+    format!(
+        "info_hash={}&peer_id={}&port={port}&uploaded=0&downloaded=0&left=100&compact=1",
+        urlencoding::encode_binary(&info_hash.0),
+        urlencoding::encode_binary(&[1; 20]),
+    )
+}
+
+fn announce(api: &Api, info_hash: Id20, port: u16) -> anyhow::Result<AnnounceResponse> {
+    let ip: IpAddr = "127.0.0.1".parse().unwrap();
+    let body = api.api_tracker_announce(&announce_query(info_hash, port), ip)?;
+    bencode::from_bytes(&body)
+}
+
+async fn http_announce(
+    http_addr: SocketAddr,
+    info_hash: Id20,
+    port: u16,
+) -> anyhow::Result<AnnounceResponse> {
+    let url = format!(
+        "http://{http_addr}/announce?{}",
+        announce_query(info_hash, port)
+    );
+    let body = reqwest::get(&url)
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    bencode::from_bytes(&body)
+}
+
+async fn udp_request(sock: &UdpSocket, to: SocketAddr, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+    sock.send_to(request, to).await?;
+    let mut buf = vec![0u8; 1024];
+    let len = timeout(Duration::from_secs(5), sock.recv(&mut buf))
+        .await
+        .context("timeout waiting for the UDP tracker")??;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+// A BEP 15 connect and announce. Returns the seeders, leechers and peers.
+async fn udp_announce(
+    tracker: SocketAddr,
+    info_hash: Id20,
+    port: u16,
+) -> anyhow::Result<(u32, u32, Vec<SocketAddr>)> {
+    let sock = UdpSocket::bind("127.0.0.1:0").await?;
+
+    let mut connect = Vec::new();
+    connect.write_u64::<BE>(0x41727101980)?;
+    connect.write_u32::<BE>(0)?;
+    connect.write_u32::<BE>(1)?;
+    let response = udp_request(&sock, tracker, &connect).await?;
+    let mut response = &response[..];
+    assert_eq!(response.read_u32::<BE>()?, 0);
+    assert_eq!(response.read_u32::<BE>()?, 1);
+    let connection_id = response.read_u64::<BE>()?;
+
+    let mut announce = Vec::new();
+    announce.write_u64::<BE>(connection_id)?;
+    announce.write_u32::<BE>(1)?;
+    announce.write_u32::<BE>(2)?;
+    announce.extend_from_slice(&info_hash.0);
+    announce.extend_from_slice(&[1; 20]);
+    announce.write_u64::<BE>(0)?; // downloaded
+    announce.write_u64::<BE>(100)?; // left
+    announce.write_u64::<BE>(0)?; // uploaded
+    announce.write_u32::<BE>(0)?; // event
+    announce.write_u32::<BE>(0)?; // ip
+    announce.write_u32::<BE>(0)?; // key
+    announce.write_i32::<BE>(-1)?; // num_want
+    announce.write_u16::<BE>(port)?;
+    let response = udp_request(&sock, tracker, &announce).await?;
+    let mut response = &response[..];
+    assert_eq!(response.read_u32::<BE>()?, 1);
+    assert_eq!(response.read_u32::<BE>()?, 2);
+    let _interval = response.read_u32::<BE>()?;
+    let leechers = response.read_u32::<BE>()?;
+    let seeders = response.read_u32::<BE>()?;
+    let mut peers = Vec::new();
+    while !response.is_empty() {
+        let ip = std::net::Ipv4Addr::from(response.read_u32::<BE>()?);
+        peers.push(SocketAddr::new(ip.into(), response.read_u16::<BE>()?));
+    }
+    Ok((seeders, leechers, peers))
+}
+
+#[tokio::test]
+async fn test_e2e_tracker() -> anyhow::Result<()> {
+    let files = create_default_random_dir_with_torrents(1, 10_000, Some("test_e2e_tracker"));
+    let torrent = create_torrent(
+        files.path(),
+        CreateTorrentOptions {
+            name: None,
+            piece_length: Some(16384),
+        },
+    )
+    .await?;
+
+    let session = start_session(
+        files.path(),
+        SessionOptions {
+            tracker: Some(EmbeddedTrackerOptions {
+                only_session_torrents: true,
+                ..Default::default()
+            }),
+            ..test_session_options(None)
+        },
+    )
+    .await?;
+    session
+        .add_torrent(
+            AddTorrent::from_bytes(torrent.as_bytes()?),
+            Some(AddTorrentOptions {
+                output_folder: Some(files.path().to_str().unwrap().to_owned()),
+                overwrite: true,
+                paused: true,
+                ..Default::default()
+            }),
+        )
+        .await?;
+    let api = Api::new(session, None, None);
+
+    let first = announce(&api, torrent.info_hash(), 6881)?;
+    assert!(first.failure_reason.is_none());
+    let second = announce(&api, torrent.info_hash(), 6882)?;
+    assert_eq!(
+        second.peers.unwrap().as_ref(),
+        &[127, 0, 0, 1, 0x1a, 0xe1][..]
+    );
+
+    let other = announce(&api, Id20::new([2; 20]), 6881)?;
+    assert!(other.failure_reason.is_some());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_e2e_tracker_http_and_udp() -> anyhow::Result<()> {
+    let files =
+        create_default_random_dir_with_torrents(1, 10_000, Some("test_e2e_tracker_http_and_udp"));
+    let torrent = create_torrent(
+        files.path(),
+        CreateTorrentOptions {
+            name: None,
+            piece_length: Some(16384),
+        },
+    )
+    .await?;
+    let info_hash = torrent.info_hash();
+
+    // Something else may take the port before it's bound again, which is unlikely enough for
+    // tests.
+    let udp_addr = std::net::UdpSocket::bind("127.0.0.1:0")?.local_addr()?;
+    let session = start_session(
+        files.path(),
+        SessionOptions {
+            tracker: Some(EmbeddedTrackerOptions {
+                udp_listen_addr: Some(udp_addr),
+                only_session_torrents: true,
+                ..Default::default()
+            }),
+            ..test_session_options(None)
+        },
+    )
+    .await?;
+    session
+        .add_torrent(
+            AddTorrent::from_bytes(torrent.as_bytes()?),
+            Some(AddTorrentOptions {
+                output_folder: Some(files.path().to_str().unwrap().to_owned()),
+                overwrite: true,
+                paused: true,
+                ..Default::default()
+            }),
+        )
+        .await?;
+
+    let http_addr = start_http_api(&session).await?;
+
+    // HTTP announces come from the address of the connection.
+    let first = http_announce(http_addr, info_hash, 6881).await?;
+    assert!(first.failure_reason.is_none());
+    let second = http_announce(http_addr, info_hash, 6882).await?;
+    assert_eq!(
+        second.peers.unwrap().as_ref(),
+        &[127, 0, 0, 1, 0x1a, 0xe1][..]
+    );
+    let other = http_announce(http_addr, Id20::new([2; 20]), 6881).await?;
+    assert!(other.failure_reason.is_some());
+
+    // Both trackers share the same swarms.
+    let (seeders, leechers, mut peers) = udp_announce(udp_addr, info_hash, 6883).await?;
+    peers.sort();
+    assert_eq!((seeders, leechers), (0, 3));
+    assert_eq!(
+        peers,
+        vec![
+            "127.0.0.1:6881".parse::<SocketAddr>()?,
+            "127.0.0.1:6882".parse()?
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_e2e_scrape_without_trackers() -> anyhow::Result<()> {
+    let files = create_default_random_dir_with_torrents(
+        1,
+        10_000,
+        Some("test_e2e_scrape_without_trackers"),
+    );
+    let torrent = create_torrent(
+        files.path(),
+        CreateTorrentOptions {
+            name: None,
+            piece_length: Some(16384),
+        },
+    )
+    .await?;
+    let session = start_session(files.path(), test_session_options(None)).await?;
+
+    // Scraping a torrent with no trackers fails the same way whether it was added or not.
+    assert!(session
+        .scrape(AddTorrent::from_bytes(torrent.as_bytes()?))
+        .await
+        .is_err());
+    session
+        .add_torrent(
+            AddTorrent::from_bytes(torrent.as_bytes()?),
+            Some(AddTorrentOptions {
+                output_folder: Some(files.path().to_str().unwrap().to_owned()),
+                overwrite: true,
+                paused: true,
+                ..Default::default()
+            }),
+        )
+        .await?;
+    let api = Api::new(session, None, None);
+    let err = api
+        .api_scrape(AddTorrent::from_bytes(torrent.as_bytes()?))
+        .await
+        .err()
+        .context("expected an error")?;
+    assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    let err = api
+        .api_torrent_scrape(0)
+        .await
+        .err()
+        .context("expected an error")?;
+    assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+#[test]
+fn test_e2e_pause_outside_runtime() -> anyhow::Result<()> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let files =
+        create_default_random_dir_with_torrents(1, 10_000, Some("test_e2e_pause_outside_runtime"));
+    let (_session, handle) = rt.block_on(async {
+        let torrent = create_torrent(
+            files.path(),
+            CreateTorrentOptions {
+                name: None,
+                piece_length: Some(16384),
+            },
+        )
+        .await?;
+        let session = start_session(files.path(), test_session_options(None)).await?;
+        let handle = add_torrent(
+            &session,
+            torrent.as_bytes()?,
+            AddTorrentOptions {
+                output_folder: Some(files.path().to_str().unwrap().to_owned()),
+                overwrite: true,
+                ..Default::default()
+            },
+        )
+        .await?;
+        timeout(Duration::from_secs(10), handle.wait_until_completed())
+            .await
+            .context("timeout checking the torrent")??;
+        Ok::<_, anyhow::Error>((session, handle))
+    })?;
+
+    // The "stopped" announce runs on the session's runtime.
+    handle.pause()?;
+    assert!(handle.with_state(|s| matches!(s, ManagedTorrentState::Paused(_))));
+    Ok(())
+}
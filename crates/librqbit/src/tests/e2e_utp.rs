@@ -0,0 +1,132 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::Context;
+use tokio::time::timeout;
+use tracing::info;
+
+use crate::{
+    create_torrent,
+    torrent_state::peer::stats::snapshot::{PeerStatsFilter, PeerStatsFilterState},
+    AddTorrentOptions, CreateTorrentOptions, PeerConnectionOptions, Session, SessionOptions,
+};
+
+use super::test_util::{
+    add_torrent, create_default_random_dir_with_torrents, start_session, test_session_options,
+};
+
+async fn start_seeder(
+    torrent_bytes: Vec<u8>,
+    output_folder: &str,
+    port_range: std::ops::Range<u16>,
+    disable_utp: bool,
+) -> anyhow::Result<(std::sync::Arc<Session>, SocketAddr)> {
+    let session = start_session(
+        &std::env::temp_dir().join("does_not_exist"),
+        SessionOptions {
+            disable_utp,
+            ..test_session_options(Some(port_range))
+        },
+    )
+    .await?;
+
+    timeout(
+        Duration::from_secs(10),
+        add_torrent(
+            &session,
+            torrent_bytes,
+            AddTorrentOptions {
+                output_folder: Some(output_folder.to_owned()),
+                overwrite: true,
+                ..Default::default()
+            },
+        )
+        .await?
+        .wait_until_completed(),
+    )
+    .await?
+    .context("error adding torrent to seeder")?;
+
+    let addr = SocketAddr::new(
+        "127.0.0.1".parse().unwrap(),
+        session.tcp_listen_port().unwrap(),
+    );
+    Ok((session, addr))
+}
+
+async fn e2e_utp() -> anyhow::Result<()> {
+    let files = create_default_random_dir_with_torrents(4, 256 * 1024, Some("test_e2e_utp"));
+    let torrent = create_torrent(
+        files.path(),
+        CreateTorrentOptions {
+            name: None,
+            piece_length: Some(16384),
+        },
+    )
+    .await?;
+    let torrent_bytes = torrent.as_bytes()?;
+    let output_folder = files.path().to_str().unwrap();
+
+    // One seeder that speaks uTP, and one that only speaks TCP.
+    let (_utp_seeder, utp_addr) =
+        start_seeder(torrent_bytes.clone(), output_folder, 16200..16300, false).await?;
+    let (_tcp_seeder, tcp_addr) =
+        start_seeder(torrent_bytes.clone(), output_folder, 16300..16400, true).await?;
+    info!(?utp_addr, ?tcp_addr, "started seeders");
+
+    let client_dir = tempfile::TempDir::with_prefix("test_e2e_utp_client")?;
+    let client = start_session(
+        client_dir.path(),
+        SessionOptions {
+            prefer_utp: true,
+            peer_opts: Some(PeerConnectionOptions {
+                // The TCP-only seeder never answers uTP, so fall back quickly.
+                connect_timeout: Some(Duration::from_secs(2)),
+                ..Default::default()
+            }),
+            ..test_session_options(None)
+        },
+    )
+    .await?;
+
+    let handle = add_torrent(
+        &client,
+        torrent_bytes,
+        AddTorrentOptions {
+            initial_peers: Some(vec![utp_addr, tcp_addr]),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    timeout(Duration::from_secs(60), handle.wait_until_completed())
+        .await
+        .context("timeout downloading")??;
+    info!("client completed");
+
+    // The download may finish before the fallback to TCP completes, so poll for it.
+    let live = handle.live().context("torrent isn't live")?;
+    let counters = |addr: SocketAddr| {
+        live.per_peer_stats_snapshot(PeerStatsFilter {
+            state: PeerStatsFilterState::All,
+        })
+        .peers
+        .get(&addr.to_string())
+        .map(|s| (s.counters.connections, s.counters.utp_connections))
+    };
+    timeout(Duration::from_secs(10), async {
+        while counters(tcp_addr) != Some((1, 0)) {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .context("never connected to the TCP-only seeder")?;
+    assert_eq!(counters(utp_addr), Some((1, 1)));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_e2e_utp_with_tcp_fallback() -> anyhow::Result<()> {
+    let _ = tracing_subscriber::fmt::try_init();
+    timeout(Duration::from_secs(90), e2e_utp()).await?
+}
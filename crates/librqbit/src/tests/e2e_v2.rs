@@ -0,0 +1,141 @@
+use std::{net::SocketAddr, path::Path, time::Duration};
+
+use anyhow::Context;
+use buffers::ByteBufOwned;
+use librqbit_core::{
+    hash_id::Id20,
+    merkle::{leaves_for_len, MerkleHasher, MERKLE_BLOCK_SIZE},
+    torrent_metainfo::{PieceLayers, TorrentMetaV1Info, TorrentMetaV1Owned, V2File, V2FileTree},
+};
+use tokio::time::timeout;
+
+use crate::AddTorrentOptions;
+
+use super::test_util::{
+    add_torrent, create_new_file_with_random_content, start_session, test_session_options,
+};
+
+fn merkle_root(data: &[u8], leaves: u32) -> [u8; 32] {
+    let mut h = MerkleHasher::new();
+    h.update(data);
+    h.finish(leaves).0
+}
+
+// Builds a v2-only torrent of the files in "dir".
+fn create_v2_torrent(dir: &Path, names: &[&str], piece_length: u32) -> anyhow::Result<Vec<u8>> {
+    let mut files = Vec::new();
+    let mut layers = Vec::new();
+    for name in names {
+        let data = std::fs::read(dir.join(name))?;
+        let root = merkle_root(&data, leaves_for_len(data.len() as u64));
+        if data.len() > piece_length as usize {
+            let layer: Vec<u8> = data
+                .chunks(piece_length as usize)
+                .flat_map(|piece| merkle_root(piece, piece_length / MERKLE_BLOCK_SIZE))
+                .collect();
+            layers.push((ByteBufOwned::from(&root[..]), ByteBufOwned::from(layer)));
+        }
+        files.push(V2File {
+            path: vec![ByteBufOwned::from(name.as_bytes())],
+            length: data.len() as u64,
+            pieces_root: Some(ByteBufOwned::from(&root[..])),
+        });
+    }
+    let torrent = TorrentMetaV1Owned {
+        announce: None,
+        announce_list: Vec::new(),
+        info: TorrentMetaV1Info {
+            name: Some(ByteBufOwned::from(&b"test_e2e_v2"[..])),
+            pieces: None,
+            piece_length,
+            length: None,
+            md5sum: None,
+            files: None,
+            private: None,
+            meta_version: Some(2),
+            file_tree: Some(V2FileTree { files }),
+        },
+        comment: None,
+        created_by: None,
+        encoding: None,
+        publisher: None,
+        publisher_url: None,
+        creation_date: None,
+        url_list: Vec::new(),
+        piece_layers: Some(PieceLayers(layers)),
+        info_hash: Id20::default(),
+        info_hash_v2: None,
+    };
+    let mut buf = Vec::new();
+    bencode::bencode_serialize_to_writer(&torrent, &mut buf)?;
+    Ok(buf)
+}
+
+async fn e2e_v2() -> anyhow::Result<()> {
+    // Sizes not aligned to the piece length, so that the files are padded in the piece space.
+    let piece_length = MERKLE_BLOCK_SIZE * 2;
+    let names = ["0.data", "1.data", "2.data"];
+    let sizes = [100_000, 20_000, 50_000];
+    let files = tempfile::TempDir::with_prefix("test_e2e_v2_files")?;
+    for (name, size) in names.iter().zip(sizes) {
+        create_new_file_with_random_content(&files.path().join(name), size);
+    }
+    let torrent_bytes = create_v2_torrent(files.path(), &names, piece_length)?;
+
+    let seeder_dir = tempfile::TempDir::with_prefix("test_e2e_v2_seeder")?;
+    let seeder = start_session(seeder_dir.path(), test_session_options(Some(16600..16700))).await?;
+    let seeder_handle = add_torrent(
+        &seeder,
+        torrent_bytes.clone(),
+        AddTorrentOptions {
+            output_folder: Some(files.path().to_str().unwrap().to_owned()),
+            overwrite: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    assert!(seeder_handle.info().info_hash_v2.is_some());
+    timeout(
+        Duration::from_secs(10),
+        seeder_handle.wait_until_completed(),
+    )
+    .await
+    .context("seeder didn't verify its files")??;
+    let seeder_addr = SocketAddr::new(
+        "127.0.0.1".parse().unwrap(),
+        seeder.tcp_listen_port().unwrap(),
+    );
+
+    let leecher_dir = tempfile::TempDir::with_prefix("test_e2e_v2_leecher")?;
+    let leecher = start_session(leecher_dir.path(), test_session_options(None)).await?;
+    let output = leecher_dir.path().join("output");
+    let leecher_handle = add_torrent(
+        &leecher,
+        torrent_bytes,
+        AddTorrentOptions {
+            output_folder: Some(output.to_str().unwrap().to_owned()),
+            initial_peers: Some(vec![seeder_addr]),
+            ..Default::default()
+        },
+    )
+    .await?;
+    timeout(
+        Duration::from_secs(30),
+        leecher_handle.wait_until_completed(),
+    )
+    .await
+    .context("timeout downloading")??;
+
+    for name in names {
+        let expected = std::fs::read(files.path().join(name))?;
+        let downloaded = std::fs::read(output.join(name))?;
+        assert!(expected == downloaded, "{name} differs");
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_e2e_v2() -> anyhow::Result<()> {
+    let _ = tracing_subscriber::fmt::try_init();
+    timeout(Duration::from_secs(60), e2e_v2()).await?
+}
@@ -0,0 +1,92 @@
+use std::{collections::HashSet, time::Duration};
+
+use anyhow::Context;
+use tokio::time::timeout;
+
+use crate::{
+    create_torrent,
+    tests::test_util::{
+        create_default_random_dir_with_torrents, start_session, test_session_options,
+    },
+    AddTorrentOptions, CreateTorrentOptions, SessionOptions, WatchFolderOptions,
+};
+
+#[tokio::test]
+async fn test_e2e_watch_folder() -> anyhow::Result<()> {
+    let files = create_default_random_dir_with_torrents(1, 10_000, Some("test_e2e_watch_folder"));
+    let torrent = create_torrent(
+        files.path(),
+        CreateTorrentOptions {
+            name: None,
+            piece_length: Some(16384),
+        },
+    )
+    .await?;
+
+    // A tracker that never responds, so that the magnet never resolves. Each attempt to add it
+    // connects from a new port.
+    let tracker = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+    let tracker_addr = tracker.local_addr()?;
+
+    let watch_dir = tempfile::TempDir::with_prefix("test_e2e_watch_folder_watch")?;
+    let session = start_session(
+        files.path(),
+        SessionOptions {
+            watch_folder: Some(WatchFolderOptions {
+                folder: watch_dir.path().to_owned(),
+                poll_interval: Some(Duration::from_millis(50)),
+                magnet_timeout: Some(Duration::from_millis(500)),
+                add_options: AddTorrentOptions {
+                    output_folder: Some(files.path().to_str().unwrap().to_owned()),
+                    overwrite: true,
+                    ..Default::default()
+                },
+            }),
+            ..test_session_options(None)
+        },
+    )
+    .await?;
+
+    std::fs::write(watch_dir.path().join("good.torrent"), torrent.as_bytes()?)?;
+    std::fs::write(watch_dir.path().join("bad.torrent"), b"not a torrent")?;
+    std::fs::write(
+        watch_dir.path().join("ignored.txt"),
+        b"magnet:?xt=urn:btih:abcd",
+    )?;
+
+    timeout(Duration::from_secs(10), async {
+        while !(watch_dir.path().join("good.torrent.added").exists()
+            && watch_dir.path().join("bad.torrent.invalid").exists())
+        {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .context("timeout waiting for watch folder files to be processed")?;
+
+    assert_eq!(session.with_torrents(|t| t.count()), 1);
+    assert!(watch_dir.path().join("ignored.txt").exists());
+
+    // Magnets that time out are left in place and retried.
+    std::fs::write(
+        watch_dir.path().join("slow.magnet"),
+        format!(
+            "magnet:?xt=urn:btih:{}&tr=udp://{tracker_addr}",
+            "ab".repeat(20)
+        ),
+    )?;
+    timeout(Duration::from_secs(10), async {
+        let mut attempts = HashSet::new();
+        let mut buf = [0u8; 1024];
+        while attempts.len() < 2 {
+            let (_, from) = tracker.recv_from(&mut buf).await?;
+            attempts.insert(from);
+        }
+        anyhow::Ok(())
+    })
+    .await
+    .context("timeout waiting for the magnet to be retried")??;
+    assert!(watch_dir.path().join("slow.magnet").exists());
+    assert_eq!(session.with_torrents(|t| t.count()), 1);
+    Ok(())
+}
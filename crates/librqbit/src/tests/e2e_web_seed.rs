@@ -0,0 +1,107 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use tokio::time::timeout;
+
+use crate::{
+    create_torrent,
+    tests::test_util::{
+        create_default_random_dir_with_torrents, start_session, test_session_options,
+    },
+    AddTorrent, AddTorrentOptions, CreateTorrentOptions,
+};
+
+// Serves files from a directory, supporting only single "bytes=start-end" ranges.
+async fn serve_file(
+    State(dir): State<Arc<PathBuf>>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let data = match std::fs::read(dir.join(&name)) {
+        Ok(data) => data,
+        Err(_) => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|r| r.to_str().ok())
+        .and_then(|r| r.strip_prefix("bytes="))
+        .and_then(|r| r.split_once('-'))
+        .and_then(|(s, e)| Some((s.parse::<usize>().ok()?, e.parse::<usize>().ok()?)));
+    match range {
+        Some((start, end)) if start <= end && end < data.len() => (
+            StatusCode::PARTIAL_CONTENT,
+            [(
+                header::CONTENT_RANGE,
+                format!("bytes {start}-{end}/{}", data.len()),
+            )],
+            data[start..=end].to_vec(),
+        )
+            .into_response(),
+        Some(_) => StatusCode::RANGE_NOT_SATISFIABLE.into_response(),
+        None => data.into_response(),
+    }
+}
+
+async fn e2e_web_seed() -> anyhow::Result<()> {
+    let files = create_default_random_dir_with_torrents(3, 300_000, Some("test_e2e_web_seed"));
+    let mut torrent = create_torrent(
+        files.path(),
+        CreateTorrentOptions {
+            name: Some("web_seed"),
+            piece_length: Some(65536),
+        },
+    )
+    .await?
+    .as_info()
+    .clone();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let app = Router::new()
+        .route("/web_seed/:name", get(serve_file))
+        .with_state(Arc::new(files.path().to_owned()));
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    torrent.url_list = vec![format!("http://127.0.0.1:{port}/").into_bytes().into()];
+
+    let leecher_dir = tempfile::TempDir::with_prefix("test_e2e_web_seed_leecher")?;
+    let session = start_session(leecher_dir.path(), test_session_options(None)).await?;
+    let output = leecher_dir.path().join("output");
+    let handle = session
+        .add_torrent(
+            AddTorrent::TorrentInfo(Box::new(torrent)),
+            Some(AddTorrentOptions {
+                output_folder: Some(output.to_str().unwrap().to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await?
+        .into_handle()
+        .context("expected a handle")?;
+    assert_eq!(handle.info().web_seeds.len(), 1);
+
+    timeout(Duration::from_secs(30), handle.wait_until_completed())
+        .await
+        .context("timeout downloading from web seed")??;
+
+    for f in 0..3 {
+        let name = format!("{f}.data");
+        let expected = std::fs::read(files.path().join(&name))?;
+        let downloaded = std::fs::read(output.join(&name))?;
+        assert!(expected == downloaded, "{name} differs");
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_e2e_web_seed() -> anyhow::Result<()> {
+    let _ = tracing_subscriber::fmt::try_init();
+    timeout(Duration::from_secs(60), e2e_web_seed()).await?
+}
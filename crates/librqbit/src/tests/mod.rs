@@ -1,3 +1,15 @@
 mod e2e;
+mod e2e_events;
+// The command hook uses a POSIX shell.
+#[cfg(unix)]
+mod e2e_hooks;
+mod e2e_http_stream;
+mod e2e_move_storage;
+mod e2e_pex;
 mod e2e_stream;
+mod e2e_tracker;
+mod e2e_utp;
+mod e2e_v2;
+mod e2e_watch_folder;
+mod e2e_web_seed;
 pub mod test_util;
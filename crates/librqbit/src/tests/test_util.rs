@@ -1,9 +1,14 @@
-use std::{io::Write, path::Path};
+use std::{io::Write, net::SocketAddr, ops::Range, path::Path, sync::Arc, time::Duration};
 
+use anyhow::Context;
 use librqbit_core::Id20;
 use rand::{RngCore, SeedableRng};
 use tempfile::TempDir;
 
+use crate::{
+    http_api::HttpApi, AddTorrent, AddTorrentOptions, Api, ManagedTorrent, Session, SessionOptions,
+};
+
 pub fn create_new_file_with_random_content(path: &Path, mut size: usize) {
     let mut file = std::fs::OpenOptions::new()
         .create_new(true)
@@ -36,6 +41,55 @@ pub fn create_default_random_dir_with_torrents(
     dir
 }
 
+// Session options for tests: no DHT, persistence or port forwarding. Only listens for peers if
+// a port range is given.
+pub fn test_session_options(listen_port_range: Option<Range<u16>>) -> SessionOptions {
+    SessionOptions {
+        disable_dht: true,
+        persistence: false,
+        listen_port_range,
+        enable_upnp_port_forwarding: false,
+        ..Default::default()
+    }
+}
+
+pub async fn start_session(
+    output_folder: &Path,
+    opts: SessionOptions,
+) -> anyhow::Result<Arc<Session>> {
+    Session::new_with_opts(output_folder.to_owned(), opts)
+        .await
+        .context("error creating session")
+}
+
+pub async fn add_torrent(
+    session: &Arc<Session>,
+    torrent_bytes: Vec<u8>,
+    opts: AddTorrentOptions,
+) -> anyhow::Result<Arc<ManagedTorrent>> {
+    session
+        .add_torrent(AddTorrent::from_bytes(torrent_bytes), Some(opts))
+        .await?
+        .into_handle()
+        .context("expected a handle")
+}
+
+// Serves the HTTP API of the session on a free local port, once it accepts connections.
+pub async fn start_http_api(session: &Arc<Session>) -> anyhow::Result<SocketAddr> {
+    // Something else may take the port before it's bound again, which is unlikely enough for
+    // tests.
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let api = HttpApi::new(Api::new(session.clone(), None, None), None);
+    tokio::spawn(api.make_http_api_and_run(addr));
+    for _ in 0..50 {
+        if tokio::net::TcpStream::connect(addr).await.is_ok() {
+            return Ok(addr);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    anyhow::bail!("HTTP API didn't start on {addr}")
+}
+
 #[derive(Debug)]
 pub struct TestPeerMetadata {
     pub server_id: u8,
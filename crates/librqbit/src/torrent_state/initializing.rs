@@ -10,6 +10,8 @@ use tracing::{debug, info, warn};
 
 use crate::{
     chunk_tracker::ChunkTracker,
+    fastresume::FastResumeData,
+    file_info::FilePriority,
     file_ops::FileOps,
     storage::{BoxStorageFactory, StorageFactory},
 };
@@ -19,15 +21,26 @@ use super::{paused::TorrentStatePaused, ManagedTorrentInfo};
 pub struct TorrentStateInitializing {
     pub(crate) meta: Arc<ManagedTorrentInfo>,
     pub(crate) only_files: Option<Vec<usize>>,
+    // If set, only_files must be consistent with it.
+    pub(crate) file_priorities: Option<Vec<FilePriority>>,
     pub(crate) checked_bytes: AtomicU64,
+    // If set, will be used to skip re-hashing files that didn't change since last run.
+    pub(crate) fastresume: Option<FastResumeData>,
 }
 
 impl TorrentStateInitializing {
-    pub fn new(meta: Arc<ManagedTorrentInfo>, only_files: Option<Vec<usize>>) -> Self {
+    pub(crate) fn new(
+        meta: Arc<ManagedTorrentInfo>,
+        only_files: Option<Vec<usize>>,
+        file_priorities: Option<Vec<FilePriority>>,
+        fastresume: Option<FastResumeData>,
+    ) -> Self {
         Self {
             meta,
             only_files,
+            file_priorities,
             checked_bytes: AtomicU64::new(0),
+            fastresume,
         }
     }
 
@@ -41,15 +54,31 @@ impl TorrentStateInitializing {
         storage_factory: &BoxStorageFactory,
     ) -> anyhow::Result<TorrentStatePaused> {
         let files = storage_factory.init_storage(&self.meta)?;
-        info!("Doing initial checksum validation, this might take a while...");
+        let trusted = self.fastresume.as_ref().and_then(|fr| {
+            fr.validate(
+                &self.meta.lengths,
+                &self.meta.file_infos,
+                &self.meta.output_folder(),
+            )
+        });
+        if trusted.is_some() {
+            info!("Using fast-resume data, will only re-check changed files");
+        } else {
+            info!("Doing initial checksum validation, this might take a while...");
+        }
         let initial_check_results = self.meta.spawner.spawn_block_in_place(|| {
             FileOps::new(
                 &self.meta.info,
                 &*files,
                 &self.meta.file_infos,
                 &self.meta.lengths,
+                self.meta.v2_piece_hashes.as_deref(),
+            )
+            .initial_check(
+                self.only_files.as_deref(),
+                &self.checked_bytes,
+                trusted.as_ref(),
             )
-            .initial_check(self.only_files.as_deref(), &self.checked_bytes)
         })?;
 
         info!(
@@ -92,6 +121,12 @@ impl TorrentStateInitializing {
             initial_check_results.selected_pieces,
             self.meta.lengths,
             &self.meta.file_infos,
+            self.file_priorities.clone().unwrap_or_else(|| {
+                FilePriority::from_only_files(
+                    self.meta.file_infos.len(),
+                    self.only_files.as_deref(),
+                )
+            }),
         )
         .context("error creating chunk tracker")?;
 
@@ -0,0 +1,109 @@
+// Tit-for-tat choking (BEP 3).
+//
+// Every CHOKE_INTERVAL, the interested peers are ranked by how fast they recently sent data to us
+// (or, once we are seeding, how fast we sent data to them). The best ones get the regular upload
+// slots. One more slot is given to a random peer and rotated every OPTIMISTIC_UNCHOKE_INTERVAL,
+// so that new peers get a chance to prove themselves.
+
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
+
+use rand::seq::IteratorRandom;
+
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+pub(crate) const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+pub(crate) const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+
+pub(crate) struct ChokeCandidate {
+    pub addr: SocketAddr,
+    // Bytes per second transferred with this peer since the previous round, in the direction
+    // that matters for the current mode.
+    pub rate: u64,
+}
+
+// Returns the peers to unchoke, and the new optimistic unchoke. "slots" includes the optimistic
+// one. 0 slots means unlimited.
+pub(crate) fn choose_unchoked(
+    mut candidates: Vec<ChokeCandidate>,
+    slots: usize,
+    optimistic: Option<SocketAddr>,
+    rotate_optimistic: bool,
+) -> (HashSet<SocketAddr>, Option<SocketAddr>) {
+    if slots == 0 || candidates.len() <= slots {
+        return (candidates.iter().map(|c| c.addr).collect(), None);
+    }
+
+    // Keep the previous optimistic unchoke until it's time to rotate, if it's still interested.
+    let optimistic = optimistic
+        .filter(|o| !rotate_optimistic && candidates.iter().any(|c| c.addr == *o))
+        .or_else(|| {
+            candidates
+                .iter()
+                .map(|c| c.addr)
+                .filter(|a| Some(*a) != optimistic)
+                .choose(&mut rand::thread_rng())
+        });
+
+    candidates.retain(|c| Some(c.addr) != optimistic);
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.rate));
+    let unchoked = candidates
+        .iter()
+        .take(slots - 1)
+        .map(|c| c.addr)
+        .chain(optimistic)
+        .collect();
+    (unchoked, optimistic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+    }
+
+    fn candidates(rates: &[u64]) -> Vec<ChokeCandidate> {
+        (0u16..)
+            .zip(rates)
+            .map(|(i, rate)| ChokeCandidate {
+                addr: addr(i),
+                rate: *rate,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_unchokes_everyone_if_enough_slots() {
+        let (unchoked, optimistic) = choose_unchoked(candidates(&[1, 2, 3]), 4, None, true);
+        assert_eq!(unchoked.len(), 3);
+        assert_eq!(optimistic, None);
+
+        let (unchoked, _) = choose_unchoked(candidates(&[1, 2, 3, 4, 5]), 0, None, true);
+        assert_eq!(unchoked.len(), 5);
+    }
+
+    #[test]
+    fn test_fastest_peers_and_optimistic() {
+        let (unchoked, optimistic) = choose_unchoked(
+            candidates(&[10, 50, 40, 0, 30, 20]),
+            3,
+            Some(addr(3)),
+            false,
+        );
+        assert_eq!(optimistic, Some(addr(3)));
+        assert_eq!(unchoked, HashSet::from_iter([addr(1), addr(2), addr(3)]));
+    }
+
+    #[test]
+    fn test_optimistic_rotates() {
+        let (unchoked, optimistic) =
+            choose_unchoked(candidates(&[10, 50, 40, 0, 30, 20]), 3, Some(addr(1)), true);
+        let optimistic = optimistic.unwrap();
+        assert_ne!(optimistic, addr(1));
+        assert_eq!(unchoked.len(), 3);
+        assert!(unchoked.contains(&optimistic));
+        // The previous optimistic unchoke competes for a regular slot.
+        assert!(unchoked.contains(&addr(1)));
+    }
+}
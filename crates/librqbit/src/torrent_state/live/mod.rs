@@ -21,7 +21,16 @@
 // - "manage_peer" - this talks to the peer over network and calls callbacks on PeerHandler. The callbacks are not async,
 //   and are supposed to finish quickly (apart from writing to disk, which is accounted for as "spawn_blocking").
 // - "peer_chunk_requester" - this continuously sends requests for chunks to the peer.
-//   it may steal chunks/pieces from other peers.
+//   it may steal chunks/pieces from other peers. Pieces are picked rarest-first, see piece_picker.rs.
+//
+// PEX task (not for private torrents):
+// - periodically tells peers that support ut_pex which peers we connected to or dropped since last time.
+//
+// Choker task:
+// - periodically decides which interested peers we upload to (tit-for-tat with an optimistic unchoke), see choker.rs.
+//
+// Web seed tasks (one per BEP 19 URL):
+// - reserve pieces like peers do, fetch them over HTTP and write/check them the same way, see web_seed.rs.
 //
 // ## Peer lifecycle
 // State transitions:
@@ -39,13 +48,16 @@
 // > so don't lock them both at the same time at all, or at the worst lock them in the
 // > same order (peers one first, then the global one).
 
+pub mod choker;
 pub mod peer;
 pub mod peers;
+mod piece_picker;
 pub mod stats;
+mod web_seed;
 
 use std::{
     collections::{HashMap, HashSet},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
@@ -66,7 +78,9 @@ use librqbit_core::{
 };
 use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use peer_binary_protocol::{
-    extended::handshake::ExtendedHandshake, Handshake, Message, MessageOwned, Piece, Request,
+    allowed_fast_set,
+    extended::{handshake::ExtendedHandshake, ut_pex::UtPex, ExtendedMessage},
+    Handshake, Message, MessageOwned, Piece, Request, MY_EXTENDED_UT_PEX,
 };
 use tokio::{
     sync::{
@@ -77,19 +91,23 @@ use tokio::{
 };
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, error_span, info, trace, warn};
+use tracker_comms::TrackerComms;
 
 use crate::{
     chunk_tracker::{ChunkMarkingResult, ChunkTracker, HaveNeededSelected},
+    events::TorrentEventKind,
+    file_info::FilePriority,
     file_ops::FileOps,
     peer_connection::{
         PeerConnection, PeerConnectionHandler, PeerConnectionOptions, WriterRequest,
     },
     session::CheckedIncomingConnection,
     torrent_state::{peer::Peer, utils::atomic_inc},
-    type_aliases::{DiskWorkQueueSender, FilePriorities, FileStorage, PeerHandle, BF},
+    type_aliases::{DiskWorkQueueSender, FileOrder, FileStorage, PeerHandle, BF},
 };
 
 use self::{
+    choker::{choose_unchoked, ChokeCandidate, CHOKE_INTERVAL, OPTIMISTIC_UNCHOKE_INTERVAL},
     peer::{
         stats::{
             atomic::PeerCountersAtomic as AtomicPeerCounters,
@@ -98,7 +116,9 @@ use self::{
         PeerRx, PeerState, PeerTx,
     },
     peers::PeerStates,
+    piece_picker::{pick_piece, MAX_ENDGAME_PEERS_PER_PIECE, RANDOM_FIRST_PIECES},
     stats::{atomic::AtomicStats, snapshot::StatsSnapshot},
+    web_seed::{WebSeed, WEB_SEED_IDLE_INTERVAL},
 };
 
 use super::{
@@ -112,6 +132,9 @@ use super::{
 struct InflightPiece {
     peer: PeerHandle,
     started: Instant,
+    // Other peers requesting the missing chunks of this piece, in end-game mode or because a
+    // stream needs it urgently.
+    endgame_peers: HashSet<PeerHandle>,
 }
 
 fn make_piece_bitfield(lengths: &Lengths) -> BF {
@@ -124,12 +147,15 @@ pub(crate) struct TorrentStateLocked {
     pub(crate) chunks: Option<ChunkTracker>,
 
     // The sorted file list in which order to download them.
-    file_priorities: FilePriorities,
+    file_order: FileOrder,
 
     // At a moment in time, we are expecting a piece from only one peer.
     // inflight_pieces stores this information.
     inflight_pieces: HashMap<ValidPieceIndex, InflightPiece>,
 
+    // Pieces being downloaded from web seeds. Peers don't steal these.
+    web_seed_pieces: HashSet<ValidPieceIndex>,
+
     // If this is None, then it was already used
     fatal_errors_tx: Option<tokio::sync::oneshot::Sender<anyhow::Error>>,
 }
@@ -182,6 +208,23 @@ pub struct TorrentStateLive {
     have_broadcast_tx: tokio::sync::broadcast::Sender<ValidPieceIndex>,
 }
 
+// How often to send peer exchange deltas. BEP 11 asks for no more than once a minute.
+const PEX_INTERVAL: Duration = if cfg!(test) {
+    Duration::from_secs(1)
+} else {
+    Duration::from_secs(60)
+};
+// BEP 11 limit for both added and dropped peers in one message.
+const PEX_MAX_PEERS: usize = 50;
+// 128 should be more than enough to maintain 100mbps
+// for a single peer that has 100ms ping
+// https://www.desmos.com/calculator/x3szur87ps
+const MAX_INFLIGHT_REQUESTS: usize = 128;
+// How many allowed fast pieces we offer to each peer (BEP 6 suggests 10).
+const ALLOWED_FAST_SET_SIZE: usize = 10;
+// How many requests for allowed fast pieces we keep in flight while choked.
+const MAX_ALLOWED_FAST_INFLIGHT_REQUESTS: usize = 16;
+
 impl TorrentStateLive {
     pub(crate) fn new(
         paused: TorrentStatePaused,
@@ -197,7 +240,7 @@ impl TorrentStateLive {
         let lengths = *paused.chunk_tracker.get_lengths();
 
         // TODO: make it configurable
-        let file_priorities = {
+        let file_order = {
             let mut pri = (0..paused.info.file_infos.len()).collect::<Vec<usize>>();
             // sort by filename, cause many torrents have random sort order.
             pri.sort_unstable_by_key(|id| {
@@ -214,12 +257,13 @@ impl TorrentStateLive {
 
         let state = Arc::new(TorrentStateLive {
             meta: paused.info.clone(),
-            peers: Default::default(),
+            peers: PeerStates::new(lengths.total_pieces()),
             locked: RwLock::new(TorrentStateLocked {
                 chunks: Some(paused.chunk_tracker),
                 // TODO: move under per_piece_locks?
                 inflight_pieces: Default::default(),
-                file_priorities,
+                web_seed_pieces: Default::default(),
+                file_order,
                 fatal_errors_tx: Some(fatal_errors_tx),
             }),
             files: paused.files,
@@ -271,6 +315,28 @@ impl TorrentStateLive {
             error_span!(parent: state.meta.span.clone(), "peer_adder"),
             state.clone().task_peer_adder(peer_queue_rx),
         );
+
+        if !state.meta.info.is_private() {
+            state.spawn(
+                error_span!(parent: state.meta.span.clone(), "pex"),
+                state.clone().task_pex(),
+            );
+        }
+
+        state.spawn(
+            error_span!(parent: state.meta.span.clone(), "choker"),
+            state.clone().task_choker(),
+        );
+
+        for url in state.meta.web_seeds.iter() {
+            match WebSeed::new(url, &state.meta.info, &state.lengths) {
+                Ok(seed) => state.spawn(
+                    error_span!(parent: state.meta.span.clone(), "web_seed", url = url),
+                    state.clone().task_web_seed(seed),
+                ),
+                Err(e) => warn!("ignoring web seed {url:?}: {e:#}"),
+            }
+        }
         Ok(state)
     }
 
@@ -336,6 +402,9 @@ impl TorrentStateLive {
             }
         };
         atomic_inc(&counters.incoming_connections);
+        if checked_peer.stream.get_ref().is_utp() {
+            atomic_inc(&counters.utp_connections);
+        }
 
         self.spawn(
             error_span!(
@@ -362,8 +431,16 @@ impl TorrentStateLive {
             addr: checked_peer.addr,
             on_bitfield_notify: Default::default(),
             unchoke_notify: Default::default(),
-            locked: RwLock::new(PeerHandlerLocked { i_am_choked: true }),
+            locked: RwLock::new(PeerHandlerLocked {
+                i_am_choked: true,
+                supports_fast: false,
+                allowed_fast: Default::default(),
+                allowed_fast_sent: Default::default(),
+                fast_requests: Default::default(),
+                rejected: Default::default(),
+            }),
             requests_sem: Semaphore::new(0),
+            fast_requests_sem: Semaphore::new(MAX_ALLOWED_FAST_INFLIGHT_REQUESTS),
             state: self.clone(),
             tx,
             counters,
@@ -371,15 +448,18 @@ impl TorrentStateLive {
         let options = PeerConnectionOptions {
             connect_timeout: self.meta.options.peer_connect_timeout,
             read_write_timeout: self.meta.options.peer_read_write_timeout,
+            encryption: self.meta.options.peer_encryption,
             ..Default::default()
         };
+        // Hybrid torrents accept peers from both swarms, reply with the hash the peer used.
         let peer_connection = PeerConnection::new(
             checked_peer.addr,
-            self.meta.info_hash,
+            Id20::new(checked_peer.handshake.info_hash),
             self.meta.peer_id,
             &handler,
             Some(options),
             self.meta.spawner,
+            Some(self.meta.ratelimits.clone()),
         );
         let requester = handler.task_peer_chunk_requester();
 
@@ -424,8 +504,16 @@ impl TorrentStateLive {
             addr,
             on_bitfield_notify: Default::default(),
             unchoke_notify: Default::default(),
-            locked: RwLock::new(PeerHandlerLocked { i_am_choked: true }),
+            locked: RwLock::new(PeerHandlerLocked {
+                i_am_choked: true,
+                supports_fast: false,
+                allowed_fast: Default::default(),
+                allowed_fast_sent: Default::default(),
+                fast_requests: Default::default(),
+                rejected: Default::default(),
+            }),
             requests_sem: Semaphore::new(0),
+            fast_requests_sem: Semaphore::new(MAX_ALLOWED_FAST_INFLIGHT_REQUESTS),
             state: state.clone(),
             tx,
             counters,
@@ -433,6 +521,7 @@ impl TorrentStateLive {
         let options = PeerConnectionOptions {
             connect_timeout: state.meta.options.peer_connect_timeout,
             read_write_timeout: state.meta.options.peer_read_write_timeout,
+            encryption: state.meta.options.peer_encryption,
             ..Default::default()
         };
         let peer_connection = PeerConnection::new(
@@ -442,6 +531,7 @@ impl TorrentStateLive {
             &handler,
             Some(options),
             state.meta.spawner,
+            Some(state.meta.ratelimits.clone()),
         );
         let requester = handler.task_peer_chunk_requester();
 
@@ -451,7 +541,11 @@ impl TorrentStateLive {
             .fetch_add(1, Ordering::Relaxed);
         let res = tokio::select! {
             r = requester => {r}
-            r = peer_connection.manage_peer_outgoing(rx, state.have_broadcast_tx.subscribe()) => {r}
+            r = peer_connection.manage_peer_outgoing(
+                rx,
+                state.have_broadcast_tx.subscribe(),
+                &state.meta.options.transports,
+            ) => {r}
         };
 
         match res {
@@ -508,6 +602,7 @@ impl TorrentStateLive {
             &*self.files,
             &self.meta().file_infos,
             &self.lengths,
+            self.meta.v2_piece_hashes.as_deref(),
         )
     }
 
@@ -526,8 +621,13 @@ impl TorrentStateLive {
 
     fn set_peer_live<B>(&self, handle: PeerHandle, h: Handshake<B>) {
         self.peers.with_peer_mut(handle, "set_peer_live", |p| {
-            p.state
-                .connecting_to_live(Id20::new(h.peer_id), &self.peers.stats);
+            if let Some(live) = p
+                .state
+                .connecting_to_live(Id20::new(h.peer_id), &self.peers.stats)
+            {
+                // We connected to this address ourselves, so others can too.
+                live.listen_addr = Some(handle);
+            }
         });
     }
 
@@ -609,7 +709,12 @@ impl TorrentStateLive {
             .chunks
             .take()
             .context("bug: pausing already paused torrent")?;
-        for piece_id in g.inflight_pieces.keys().copied() {
+        for piece_id in g
+            .inflight_pieces
+            .keys()
+            .chain(g.web_seed_pieces.iter())
+            .copied()
+        {
             chunk_tracker.mark_piece_broken_if_not_have(piece_id);
         }
 
@@ -638,7 +743,32 @@ impl TorrentStateLive {
     pub(crate) fn update_only_files(&self, only_files: &HashSet<usize>) -> anyhow::Result<()> {
         let mut g = self.lock_write("update_only_files");
         let ct = g.get_chunks_mut()?;
-        let hns = ct.update_only_files(self.meta().file_infos.iter().map(|f| f.len), only_files)?;
+        let hns = ct.update_only_files(
+            self.meta()
+                .file_infos
+                .iter()
+                .map(|f| (f.offset_in_torrent, f.len)),
+            only_files,
+        )?;
+        if !hns.finished() {
+            self.reconnect_all_not_needed_peers();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn update_file_priorities(
+        &self,
+        file_priorities: Vec<FilePriority>,
+    ) -> anyhow::Result<()> {
+        let mut g = self.lock_write("update_file_priorities");
+        let ct = g.get_chunks_mut()?;
+        let hns = ct.update_file_priorities(
+            self.meta()
+                .file_infos
+                .iter()
+                .map(|f| (f.offset_in_torrent, f.len)),
+            file_priorities,
+        )?;
         if !hns.finished() {
             self.reconnect_all_not_needed_peers();
         }
@@ -679,8 +809,14 @@ impl TorrentStateLive {
             .skip_while(|(_, fi)| !fi.piece_range.contains(&id.get()))
             .take_while(|(_, fi)| fi.piece_range.contains(&id.get()))
         {
-            let _remaining = chunks.update_file_have_on_piece_completed(id, idx, file_info);
+            let remaining = chunks.update_file_have_on_piece_completed(id, idx, file_info);
+            if remaining == 0 {
+                self.meta
+                    .send_event(TorrentEventKind::FileCompleted { file_id: idx });
+            }
         }
+        self.meta
+            .send_event(TorrentEventKind::PieceCompleted { piece: id.get() });
 
         self.streams
             .wake_streams_on_piece_completed(id, &self.meta.lengths);
@@ -688,6 +824,8 @@ impl TorrentStateLive {
         if chunks.is_finished() {
             if chunks.get_selected_pieces()[id.get_usize()] {
                 info!("torrent finished downloading");
+                self.meta.send_event(TorrentEventKind::Completed);
+                TrackerComms::announce_completed(&self.meta.trackers);
             }
             self.finished_notify.notify_waiters();
 
@@ -700,21 +838,352 @@ impl TorrentStateLive {
         Ok(())
     }
 
+    // Called when a downloaded piece passed the hash check, no matter where it came from.
+    fn on_piece_verified(
+        &self,
+        index: ValidPieceIndex,
+        download_time: Duration,
+    ) -> anyhow::Result<()> {
+        {
+            let mut g = self.lock_write("mark_piece_downloaded");
+            g.get_chunks_mut()?.mark_piece_downloaded(index);
+        }
+
+        // Global piece counters.
+        let piece_len = self.lengths.piece_length(index) as u64;
+        self.stats
+            .downloaded_and_checked_bytes
+            // This counter is used to compute "is_finished", so using
+            // stronger ordering.
+            .fetch_add(piece_len, Ordering::Release);
+        self.stats
+            .downloaded_and_checked_pieces
+            // This counter is used to compute "is_finished", so using
+            // stronger ordering.
+            .fetch_add(1, Ordering::Release);
+        self.stats
+            .have_bytes
+            .fetch_add(piece_len, Ordering::Relaxed);
+        #[allow(clippy::cast_possible_truncation)]
+        self.stats
+            .total_piece_download_ms
+            .fetch_add(download_time.as_millis() as u64, Ordering::Relaxed);
+
+        self.on_piece_completed(index)?;
+        self.transmit_haves(index);
+        Ok(())
+    }
+
+    /// Called when a chunk from an end-game peer arrives first: the piece becomes theirs, the
+    /// same way as if they stole it, and the previous owner may get it back the same way.
+    /// Returns None if someone is writing the piece right now.
+    fn take_over_endgame_piece(
+        &self,
+        addr: PeerHandle,
+        index: ValidPieceIndex,
+    ) -> Option<RwLockReadGuard<'_, ()>> {
+        let mut g = self.lock_write("take_over_endgame_piece");
+        let piece = g.inflight_pieces.get_mut(&index)?;
+        if !piece.endgame_peers.contains(&addr) {
+            return None;
+        }
+        let ppl = self.per_piece_locks.get(index.get_usize())?.try_write()?;
+        piece.endgame_peers.remove(&addr);
+        let prev = std::mem::replace(&mut piece.peer, addr);
+        piece.endgame_peers.insert(prev);
+        Some(RwLockWriteGuard::downgrade(ppl))
+    }
+
+    fn reserve_piece_for_web_seed(&self) -> anyhow::Result<Option<ValidPieceIndex>> {
+        let mut g = self.lock_write("reserve_piece_for_web_seed");
+        let n = {
+            let chunk_tracker = g.get_chunks()?;
+            let priority_streamed_pieces =
+                self.streams.iter_next_pieces(&self.lengths).filter(|pid| {
+                    !chunk_tracker.is_piece_have(*pid)
+                        && !g.inflight_pieces.contains_key(pid)
+                        && !g.web_seed_pieces.contains(pid)
+                });
+            let natural_order_pieces =
+                chunk_tracker.iter_queued_pieces(&g.file_order, &self.meta.file_infos);
+            match priority_streamed_pieces.chain(natural_order_pieces).next() {
+                Some(n) => n,
+                None => return Ok(None),
+            }
+        };
+        g.web_seed_pieces.insert(n);
+        g.get_chunks_mut()?.reserve_needed_piece(n);
+        Ok(Some(n))
+    }
+
+    // Writes a piece downloaded from a web seed chunk by chunk, as if a peer sent it, and checks
+    // its hash. Returns false if the hash didn't match.
+    fn on_web_seed_piece(
+        &self,
+        seed: &WebSeed,
+        index: ValidPieceIndex,
+        data: &[u8],
+        download_time: Duration,
+    ) -> anyhow::Result<bool> {
+        let mut last_chunk = None;
+        for chunk_info in self.lengths.iter_chunk_infos(index) {
+            let block = &data[chunk_info.offset as usize..][..chunk_info.size as usize];
+            let piece = Piece {
+                index: index.get(),
+                begin: chunk_info.offset,
+                block: ByteBuf(block),
+            };
+            if let Err(e) = self.file_ops().write_chunk(&seed.url, &piece, &chunk_info) {
+                error!("FATAL: error writing chunk to disk: {:?}", e);
+                return self.on_fatal_error(e).map(|_| false);
+            }
+            self.lock_write("mark_chunk_downloaded")
+                .get_chunks_mut()?
+                .mark_chunk_downloaded(&piece);
+            last_chunk = Some(chunk_info);
+        }
+        let last_chunk = last_chunk.context("bug: piece has no chunks")?;
+
+        if !self
+            .file_ops()
+            .check_piece(&seed.url, index, &last_chunk)
+            .with_context(|| format!("error checking piece={index}"))?
+        {
+            return Ok(false);
+        }
+        debug!("piece={} successfully downloaded and verified", index);
+        self.on_piece_verified(index, download_time)?;
+        Ok(true)
+    }
+
+    async fn task_web_seed(self: Arc<Self>, seed: WebSeed) -> anyhow::Result<()> {
+        let mut backoff = backoff::ExponentialBackoffBuilder::new()
+            .with_initial_interval(Duration::from_secs(1))
+            .with_max_interval(Duration::from_secs(60))
+            .with_max_elapsed_time(None)
+            .build();
+
+        loop {
+            let index = match self.reserve_piece_for_web_seed()? {
+                Some(index) => index,
+                None => {
+                    tokio::time::sleep(WEB_SEED_IDLE_INTERVAL).await;
+                    continue;
+                }
+            };
+
+            let started = Instant::now();
+            let result = match seed
+                .fetch_piece(&self.lengths, &self.meta.file_infos, index)
+                .await
+            {
+                Ok(data) => {
+                    self.stats
+                        .fetched_bytes
+                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                    let elapsed = started.elapsed();
+                    self.meta.spawner.spawn_block_in_place(|| {
+                        self.on_web_seed_piece(&seed, index, &data, elapsed)
+                    })
+                }
+                Err(e) => Err(e),
+            };
+
+            let ok = matches!(result, Ok(true));
+            {
+                let mut g = self.lock_write("web_seed_piece_done");
+                g.web_seed_pieces.remove(&index);
+                if !ok {
+                    g.get_chunks_mut()?.mark_piece_broken_if_not_have(index);
+                }
+            }
+
+            match result {
+                Ok(true) => {
+                    backoff.reset();
+                    continue;
+                }
+                Ok(false) => warn!(
+                    "checksum for piece={} from web seed did not validate",
+                    index
+                ),
+                Err(e) => warn!("error downloading piece={} from web seed: {:#}", index, e),
+            }
+            if let Some(delay) = backoff.next_backoff() {
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+
     fn disconnect_all_peers_that_have_full_torrent(&self) {
         for mut pe in self.peers.states.iter_mut() {
             if let PeerState::Live(l) = pe.value().state.get() {
                 if l.has_full_torrent(self.lengths.total_pieces() as usize) {
                     let prev = pe.value_mut().state.set_not_needed(&self.peers.stats);
-                    let _ = prev
-                        .take_live_no_counters()
-                        .unwrap()
-                        .tx
-                        .send(WriterRequest::Disconnect(Ok(())));
+                    let live = prev.take_live_no_counters().unwrap();
+                    self.peers.on_live_peer_gone(&live);
+                    let _ = live.tx.send(WriterRequest::Disconnect(Ok(())));
                 }
             }
         }
     }
 
+    async fn task_pex(self: Arc<Self>) -> anyhow::Result<()> {
+        let mut interval = tokio::time::interval(PEX_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.send_pex_deltas();
+        }
+    }
+
+    fn send_pex_deltas(&self) {
+        let connected: HashSet<SocketAddr> = self
+            .peers
+            .states
+            .iter()
+            .filter_map(|pe| pe.value().state.get_live().and_then(|l| l.listen_addr))
+            .collect();
+
+        for mut pe in self.peers.states.iter_mut() {
+            let peer = *pe.key();
+            let live = match pe.value_mut().state.get_live_mut() {
+                Some(live) if live.supports_pex => live,
+                _ => continue,
+            };
+            let added: Vec<SocketAddr> = connected
+                .iter()
+                .filter(|a| Some(**a) != live.listen_addr && !live.pex_sent.contains(a))
+                .take(PEX_MAX_PEERS)
+                .copied()
+                .collect();
+            let dropped: Vec<SocketAddr> = live
+                .pex_sent
+                .iter()
+                .filter(|a| !connected.contains(a))
+                .take(PEX_MAX_PEERS)
+                .copied()
+                .collect();
+            if added.is_empty() && dropped.is_empty() {
+                continue;
+            }
+            for addr in dropped.iter() {
+                live.pex_sent.remove(addr);
+            }
+            live.pex_sent.extend(added.iter().copied());
+            trace!(%peer, ?added, ?dropped, "sending pex");
+            let msg = ExtendedMessage::UtPex(UtPex::from_addrs(&added, &dropped));
+            let _ = live
+                .tx
+                .send(WriterRequest::Message(MessageOwned::Extended(msg)));
+        }
+    }
+
+    async fn task_choker(self: Arc<Self>) -> anyhow::Result<()> {
+        let mut interval = tokio::time::interval(CHOKE_INTERVAL);
+        let rounds_per_optimistic =
+            (OPTIMISTIC_UNCHOKE_INTERVAL.as_secs() / CHOKE_INTERVAL.as_secs()).max(1);
+        let mut prev_counters = HashMap::new();
+        let mut optimistic = None;
+        let mut rounds_until_rotate = 0;
+        loop {
+            interval.tick().await;
+            let rotate = rounds_until_rotate == 0;
+            if rotate {
+                rounds_until_rotate = rounds_per_optimistic;
+            }
+            rounds_until_rotate -= 1;
+            optimistic = self.rechoke(&mut prev_counters, optimistic, rotate);
+        }
+    }
+
+    // "prev_counters" holds the (fetched, uploaded) bytes of each peer at the previous round.
+    fn rechoke(
+        &self,
+        prev_counters: &mut HashMap<PeerHandle, (u64, u64)>,
+        optimistic: Option<PeerHandle>,
+        rotate_optimistic: bool,
+    ) -> Option<PeerHandle> {
+        // When seeding, nobody sends us anything, so prefer the peers that download the fastest.
+        let seeding = self.is_finished();
+        let mut counters = HashMap::new();
+        let mut candidates = Vec::new();
+        for pe in self.peers.states.iter() {
+            let live = match pe.value().state.get_live() {
+                Some(live) => live,
+                None => continue,
+            };
+            let c = &pe.value().stats.counters;
+            let current = (
+                c.fetched_bytes.load(Ordering::Relaxed),
+                c.uploaded_bytes.load(Ordering::Relaxed),
+            );
+            let prev = prev_counters.get(pe.key()).copied().unwrap_or(current);
+            counters.insert(*pe.key(), current);
+            if !live.peer_interested {
+                continue;
+            }
+            let rate = if seeding {
+                current.1.saturating_sub(prev.1)
+            } else {
+                current.0.saturating_sub(prev.0)
+            } / CHOKE_INTERVAL.as_secs();
+            candidates.push(ChokeCandidate {
+                addr: *pe.key(),
+                rate,
+            });
+        }
+        *prev_counters = counters;
+
+        let (unchoked, optimistic) = choose_unchoked(
+            candidates,
+            self.meta.options.upload_slots,
+            optimistic,
+            rotate_optimistic,
+        );
+        for mut pe in self.peers.states.iter_mut() {
+            let peer = *pe.key();
+            let live = match pe.value_mut().state.get_live_mut() {
+                Some(live) => live,
+                None => continue,
+            };
+            let choke = !unchoked.contains(&peer);
+            if live.am_choking == choke {
+                continue;
+            }
+            live.am_choking = choke;
+            trace!(%peer, choke, "rechoking");
+            let msg = if choke {
+                MessageOwned::Choke
+            } else {
+                MessageOwned::Unchoke
+            };
+            let _ = live.tx.send(WriterRequest::Message(msg));
+        }
+        optimistic
+    }
+
+    // Give a free upload slot to a newly interested peer right away, instead of waiting for
+    // the next round of the choker.
+    fn unchoke_if_free_slot(&self, handle: PeerHandle) {
+        let slots = self.meta.options.upload_slots;
+        let unchoked = self
+            .peers
+            .states
+            .iter()
+            .filter(|pe| pe.value().state.get_live().is_some_and(|l| !l.am_choking))
+            .count();
+        if slots != 0 && unchoked >= slots {
+            return;
+        }
+        self.peers
+            .with_live_mut(handle, "unchoke_if_free_slot", |live| {
+                if live.am_choking {
+                    live.am_choking = false;
+                    let _ = live.tx.send(WriterRequest::Message(MessageOwned::Unchoke));
+                }
+            });
+    }
+
     pub(crate) fn reconnect_all_not_needed_peers(&self) {
         for mut pe in self.peers.states.iter_mut() {
             if pe.state.not_needed_to_queued(&self.peers.stats)
@@ -728,6 +1197,17 @@ impl TorrentStateLive {
 
 struct PeerHandlerLocked {
     pub i_am_choked: bool,
+    // BEP 6 Fast Extension was negotiated with the peer.
+    pub supports_fast: bool,
+    // Pieces the peer allows us to request even while we are choked.
+    pub allowed_fast: HashSet<ValidPieceIndex>,
+    // Pieces we allow the peer to request while we are choking it.
+    pub allowed_fast_sent: HashSet<ValidPieceIndex>,
+    // In-flight requests that took their permit from "fast_requests_sem".
+    pub fast_requests: HashSet<ChunkInfo>,
+    // Chunks the peer rejected, with whether we were unchoked at the time. They are requested
+    // again while their piece is still ours.
+    pub rejected: Vec<(ChunkInfo, bool)>,
 }
 
 // All peer state that would never be used by other actors should pe put here.
@@ -752,16 +1232,24 @@ struct PeerHandler {
     // This is used to limit the number of chunk requests we send to a peer at a time.
     requests_sem: Semaphore,
 
+    // The same for requests of allowed fast pieces while we are choked (BEP 6).
+    fast_requests_sem: Semaphore,
+
     addr: SocketAddr,
 
     tx: PeerTx,
 }
 
 impl<'a> PeerConnectionHandler for &'a PeerHandler {
-    fn on_connected(&self, connection_time: Duration) {
+    fn on_connected(&self, connection_time: Duration, over_utp: bool) {
         self.counters
             .outgoing_connections
             .fetch_add(1, Ordering::Relaxed);
+        if over_utp {
+            self.counters
+                .utp_connections
+                .fetch_add(1, Ordering::Relaxed);
+        }
         #[allow(clippy::cast_possible_truncation)]
         self.counters
             .total_time_connecting_ms
@@ -788,12 +1276,21 @@ impl<'a> PeerConnectionHandler for &'a PeerHandler {
                 trace!("keepalive received");
             }
             Message::Have(h) => self.on_have(h),
-            Message::NotInterested => {
-                trace!("received \"not interested\", but we don't process it yet")
-            }
+            Message::NotInterested => self.on_peer_not_interested(),
             Message::Cancel(_) => {
                 trace!("received \"cancel\", but we don't process it yet")
             }
+            Message::Extended(ExtendedMessage::UtPex(pex)) => self.on_pex(&pex),
+            Message::HaveAll => self.on_have_all(),
+            Message::HaveNone => self.on_have_none(),
+            Message::AllowedFast(index) => self.on_allowed_fast(index),
+            Message::RejectRequest(request) => self
+                .on_reject_request(request)
+                .context("on_reject_request")?,
+            Message::SuggestPiece(index) => {
+                // Only a hint, pieces are still picked rarest-first.
+                trace!(index, "received \"suggest piece\", ignoring")
+            }
             message => {
                 warn!("received unsupported message {:?}, ignoring", message);
             }
@@ -801,22 +1298,40 @@ impl<'a> PeerConnectionHandler for &'a PeerHandler {
         Ok(())
     }
 
-    fn serialize_bitfield_message_to_buf(&self, buf: &mut Vec<u8>) -> anyhow::Result<usize> {
+    fn serialize_bitfield_message_to_buf(
+        &self,
+        buf: &mut Vec<u8>,
+        supports_fast: bool,
+    ) -> anyhow::Result<usize> {
         let g = self.state.lock_read("serialize_bitfield_message_to_buf");
-        let msg = Message::Bitfield(ByteBuf(g.get_chunks()?.get_have_pieces().as_raw_slice()));
-        let len = msg.serialize(buf, &|| None)?;
+        let have = g.get_chunks()?.get_have_pieces();
+        let total_pieces = self.state.lengths.total_pieces() as usize;
+        let have_count = have[..total_pieces].count_ones();
+        let msg = match (supports_fast, have_count) {
+            (true, 0) => Message::HaveNone,
+            (true, c) if c == total_pieces => Message::HaveAll,
+            (false, 0) => return Ok(0),
+            _ => Message::Bitfield(ByteBuf(have.as_raw_slice())),
+        };
+        let len = msg.serialize(buf, &Default::default)?;
         trace!("sending: {:?}, length={}", &msg, len);
         Ok(len)
     }
 
     fn on_handshake<B>(&self, handshake: Handshake<B>) -> anyhow::Result<()> {
+        let supports_fast = handshake.supports_fast();
+        self.locked.write().supports_fast = supports_fast;
         self.state.set_peer_live(self.addr, handshake);
-        self.tx
-            .send(WriterRequest::Message(MessageOwned::Unchoke))?;
+        if supports_fast {
+            self.send_allowed_fast_set()?;
+        }
         Ok(())
     }
 
     fn on_uploaded_bytes(&self, bytes: u32) {
+        self.counters
+            .uploaded_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
         self.state
             .stats
             .uploaded_bytes
@@ -827,12 +1342,29 @@ impl<'a> PeerConnectionHandler for &'a PeerHandler {
         self.state.file_ops().read_chunk(self.addr, chunk, buf)
     }
 
-    fn on_extended_handshake(&self, _: &ExtendedHandshake<ByteBuf>) -> anyhow::Result<()> {
+    fn on_extended_handshake(&self, h: &ExtendedHandshake<ByteBuf>) -> anyhow::Result<()> {
+        let supports_pex = !self.state.meta.info.is_private() && h.ut_pex().is_some();
+        let listen_port = h.p.and_then(|p| u16::try_from(p).ok()).filter(|p| *p != 0);
+        self.state
+            .peers
+            .with_live_mut(self.addr, "on_extended_handshake", |live| {
+                live.supports_pex = supports_pex;
+                if let Some(port) = listen_port {
+                    live.listen_addr = Some(SocketAddr::new(self.addr.ip(), port));
+                }
+            });
         Ok(())
     }
 
-    fn get_have_bytes(&self) -> u64 {
-        self.state.get_approx_have_bytes()
+    fn update_my_extended_handshake(
+        &self,
+        handshake: &mut ExtendedHandshake<ByteBuf<'static>>,
+    ) -> anyhow::Result<()> {
+        if !self.state.meta.info.is_private() {
+            handshake.m.insert(ByteBuf(b"ut_pex"), MY_EXTENDED_UT_PEX);
+        }
+        handshake.p = self.state.meta.options.listen_port.map(u32::from);
+        Ok(())
     }
 
     fn should_transmit_have(&self, id: ValidPieceIndex) -> bool {
@@ -848,6 +1380,195 @@ impl<'a> PeerConnectionHandler for &'a PeerHandler {
 }
 
 impl PeerHandler {
+    // Tell the peer which of our pieces it may request even while choked.
+    fn send_allowed_fast_set(&self) -> anyhow::Result<()> {
+        let ip = match self.addr.ip() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => ip,
+                None => return Ok(()),
+            },
+        };
+        let pieces = allowed_fast_set(
+            ip,
+            self.state.info_hash(),
+            self.state.lengths.total_pieces(),
+            ALLOWED_FAST_SET_SIZE,
+        );
+        let g = self.state.lock_read("send_allowed_fast_set");
+        let have = g.get_chunks()?.get_have_pieces();
+        let mut sent = HashSet::new();
+        for piece in pieces {
+            let index = match self.state.lengths.validate_piece_index(piece) {
+                Some(index) => index,
+                None => continue,
+            };
+            if have.get(piece as usize).map(|p| *p).unwrap_or_default() {
+                self.tx
+                    .send(WriterRequest::Message(MessageOwned::AllowedFast(piece)))?;
+                sent.insert(index);
+            }
+        }
+        drop(g);
+        self.locked.write().allowed_fast_sent = sent;
+        Ok(())
+    }
+
+    fn on_have_all(&self) {
+        let mut bitfield = make_piece_bitfield(&self.state.lengths);
+        bitfield[..self.state.lengths.total_pieces() as usize].fill(true);
+        self.state.peers.update_bitfield(self.addr, bitfield);
+        self.on_bitfield_notify.notify_waiters();
+    }
+
+    fn on_have_none(&self) {
+        let bitfield = make_piece_bitfield(&self.state.lengths);
+        self.state.peers.update_bitfield(self.addr, bitfield);
+        self.on_bitfield_notify.notify_waiters();
+    }
+
+    fn on_allowed_fast(&self, index: u32) {
+        let index = match self.state.lengths.validate_piece_index(index) {
+            Some(index) => index,
+            None => {
+                debug!(index, "received invalid allowed fast piece, ignoring");
+                return;
+            }
+        };
+        let mut g = self.locked.write();
+        if !g.supports_fast {
+            debug!("received allowed fast without negotiating the fast extension, ignoring");
+            return;
+        }
+        g.allowed_fast.insert(index);
+        drop(g);
+        self.unchoke_notify.notify_waiters();
+    }
+
+    // The peer won't send us this chunk. The piece stays ours, so that the chunks already received
+    // aren't lost, and the requester asks for this one again once it can.
+    fn on_reject_request(&self, request: Request) -> anyhow::Result<()> {
+        let piece_index = self
+            .state
+            .lengths
+            .validate_piece_index(request.index)
+            .with_context(|| format!("peer rejected an invalid piece {}", request.index))?;
+        let chunk_info = self
+            .state
+            .lengths
+            .chunk_info_from_received_data(piece_index, request.begin, request.length)
+            .with_context(|| format!("peer rejected an invalid request {request:?}"))?;
+
+        let was_requested = self
+            .state
+            .peers
+            .with_live_mut(self.addr, "on_reject_request", |live| {
+                live.inflight_requests.remove(&chunk_info)
+            })
+            .unwrap_or_default();
+        if !was_requested {
+            debug!(?request, "peer rejected a request we didn't send, ignoring");
+            return Ok(());
+        }
+        self.release_request_permit(&chunk_info);
+
+        let mut g = self.locked.write();
+        // Don't ask for it again while choked.
+        g.allowed_fast.remove(&piece_index);
+        let unchoked = !g.i_am_choked;
+        g.rejected.push((chunk_info, unchoked));
+        trace!(?chunk_info, unchoked, "request rejected");
+        Ok(())
+    }
+
+    // Give back the permit of a request that was answered or rejected. Permits of requests sent
+    // before we were choked are dropped, as unchoking grants a full budget again.
+    fn release_request_permit(&self, chunk: &ChunkInfo) {
+        let mut g = self.locked.write();
+        if g.fast_requests.remove(chunk) {
+            self.fast_requests_sem.add_permits(1);
+        } else if !g.i_am_choked {
+            self.requests_sem.add_permits(1);
+        }
+    }
+
+    // Wait for a permit to send a request for a chunk of this piece. While choked, allowed fast
+    // pieces use their own budget. Returns whether the permit came from it.
+    async fn acquire_request_permit(&self, piece: ValidPieceIndex) -> anyhow::Result<bool> {
+        loop {
+            let fast = {
+                let g = self.locked.read();
+                g.i_am_choked && g.allowed_fast.contains(&piece)
+            };
+            let sem = if fast {
+                &self.fast_requests_sem
+            } else {
+                &self.requests_sem
+            };
+            // Check again from time to time, as being choked or unchoked changes the budget.
+            match timeout(Duration::from_secs(10), sem.acquire()).await {
+                Ok(acq) => {
+                    acq?.forget();
+                    return Ok(fast);
+                }
+                Err(_) => continue,
+            };
+        }
+    }
+
+    // Rejected chunks that can be requested again now: their piece is still ours, and we are
+    // unchoked or it's allowed fast. Those of pieces that aren't ours anymore are forgotten.
+    fn take_rejected_chunks(&self) -> anyhow::Result<(Vec<ChunkInfo>, bool)> {
+        let (rejected, choked, allowed_fast) = {
+            let mut l = self.locked.write();
+            if l.rejected.is_empty() {
+                return Ok((Vec::new(), false));
+            }
+            (
+                std::mem::take(&mut l.rejected),
+                l.i_am_choked,
+                l.allowed_fast.clone(),
+            )
+        };
+
+        let mut retry = Vec::new();
+        let mut rejected_while_unchoked = false;
+        let mut keep = Vec::new();
+        {
+            let g = self.state.lock_read("take_rejected_chunks");
+            let chunk_tracker = g.get_chunks()?;
+            for (chunk, unchoked) in rejected {
+                let ours = g
+                    .inflight_pieces
+                    .get(&chunk.piece_index)
+                    .is_some_and(|p| p.peer == self.addr || p.endgame_peers.contains(&self.addr));
+                if !ours || chunk_tracker.is_chunk_downloaded(&chunk) {
+                    continue;
+                }
+                if !choked || allowed_fast.contains(&chunk.piece_index) {
+                    retry.push(chunk);
+                    rejected_while_unchoked |= unchoked;
+                } else {
+                    keep.push((chunk, unchoked));
+                }
+            }
+        }
+        self.locked.write().rejected.extend(keep);
+        Ok((retry, rejected_while_unchoked))
+    }
+
+    fn on_pex(&self, pex: &UtPex<ByteBuf>) {
+        // BEP 27: private torrents must only get peers from their trackers.
+        if self.state.meta.info.is_private() {
+            return;
+        }
+        for addr in pex.added_peers() {
+            if let Err(e) = self.state.add_peer_if_not_seen(addr) {
+                debug!(?addr, "error adding peer from pex: {:#}", e);
+            }
+        }
+    }
+
     fn on_peer_died(self, error: Option<anyhow::Error>) -> anyhow::Result<()> {
         let peers = &self.state.peers;
         let pstats = &peers.stats;
@@ -864,8 +1585,17 @@ impl PeerHandler {
         match prev {
             PeerState::Connecting(_) => {}
             PeerState::Live(live) => {
+                peers.on_live_peer_gone(&live);
                 let mut g = self.state.lock_write("mark_chunk_requests_canceled");
                 for req in live.inflight_requests {
+                    // Someone else owns the piece now, it's theirs to finish. Queueing it again
+                    // would let a third peer reserve it, and overwrite it once it's verified.
+                    if g.inflight_pieces
+                        .get(&req.piece_index)
+                        .is_some_and(|p| p.peer != handle)
+                    {
+                        continue;
+                    }
                     debug!(
                         "peer dead, marking chunk request cancelled, index={}, chunk={}",
                         req.piece_index.get(),
@@ -954,32 +1684,54 @@ impl PeerHandler {
         self.state
             .peers
             .with_live_mut(self.addr, "reserve_next_needed_piece", |live| {
-                if self.locked.read().i_am_choked {
-                    debug!("we are choked, can't reserve next piece");
-                    return Ok(None);
-                }
+                // While choked, only the allowed fast pieces can be requested.
+                let allowed_fast = {
+                    let l = self.locked.read();
+                    match (l.i_am_choked, l.allowed_fast.is_empty()) {
+                        (false, _) => None,
+                        (true, false) => Some(l.allowed_fast.clone()),
+                        (true, true) => {
+                            debug!("we are choked, can't reserve next piece");
+                            return Ok(None);
+                        }
+                    }
+                };
                 let mut g = self.state.lock_write("reserve_next_needed_piece");
 
                 let n = {
-                    let mut n_opt = None;
                     let bf = &live.bitfield;
+                    let can_request = |n: &ValidPieceIndex| {
+                        allowed_fast.as_ref().map_or(true, |a| a.contains(n))
+                            && bf.get(n.get_usize()).map(|v| *v) == Some(true)
+                    };
                     let chunk_tracker = g.get_chunks()?;
-                    let priority_streamed_pieces = self
+
+                    // Streamed pieces go first, in order.
+                    let streamed_piece = self
                         .state
                         .streams
                         .iter_next_pieces(&self.state.lengths)
                         .filter(|pid| {
                             !chunk_tracker.is_piece_have(*pid)
                                 && !g.inflight_pieces.contains_key(pid)
-                        });
-                    let natural_order_pieces = chunk_tracker
-                        .iter_queued_pieces(&g.file_priorities, &self.state.meta().file_infos);
-                    for n in priority_streamed_pieces.chain(natural_order_pieces) {
-                        if bf.get(n.get() as usize).map(|v| *v) == Some(true) {
-                            n_opt = Some(n);
-                            break;
-                        }
-                    }
+                                && !g.web_seed_pieces.contains(pid)
+                        })
+                        .find(&can_request);
+
+                    let random_first = chunk_tracker.get_hns().have_bytes
+                        < RANDOM_FIRST_PIECES * self.state.lengths.default_piece_length() as u64;
+                    let n_opt = streamed_piece.or_else(|| {
+                        pick_piece(
+                            chunk_tracker
+                                .iter_queued_pieces_with_priority(
+                                    &g.file_order,
+                                    &self.state.meta().file_infos,
+                                )
+                                .filter(|(_, n)| can_request(n)),
+                            |n| self.state.peers.availability.get(n.get_usize()),
+                            random_first,
+                        )
+                    });
 
                     match n_opt {
                         Some(n_opt) => n_opt,
@@ -991,6 +1743,7 @@ impl PeerHandler {
                     InflightPiece {
                         peer: self.addr,
                         started: Instant::now(),
+                        endgame_peers: Default::default(),
                     },
                 );
                 g.get_chunks_mut()?.reserve_needed_piece(n);
@@ -1000,6 +1753,114 @@ impl PeerHandler {
             .map(|r| r.flatten())
     }
 
+    /// End-game mode: when all the pieces we need are already requested from someone, also
+    /// request one of them from this peer. Whoever delivers a chunk first wins.
+    fn reserve_endgame_piece(&self) -> anyhow::Result<Option<ValidPieceIndex>> {
+        self.state
+            .peers
+            .with_live(self.addr, |live| {
+                // Don't bother with fast extension pieces here.
+                if self.locked.read().i_am_choked {
+                    return Ok(None);
+                }
+                let mut g = self.state.lock_write("reserve_endgame_piece");
+                if g.get_chunks()?.has_queued_pieces() {
+                    return Ok(None);
+                }
+                let addr = self.addr;
+                let piece = g
+                    .inflight_pieces
+                    .iter_mut()
+                    .filter(|(idx, p)| {
+                        p.peer != addr
+                            && !p.endgame_peers.contains(&addr)
+                            && p.endgame_peers.len() < MAX_ENDGAME_PEERS_PER_PIECE
+                            && live.bitfield.get(idx.get_usize()).map(|v| *v) == Some(true)
+                    })
+                    // The ones waited on the longest first.
+                    .min_by_key(|(_, p)| (p.endgame_peers.len(), p.started));
+                Ok(piece.map(|(idx, p)| {
+                    debug!(piece=%idx, owner=%p.peer, "end-game: requesting piece from this peer too");
+                    p.endgame_peers.insert(addr);
+                    *idx
+                }))
+            })
+            .transpose()
+            .map(|r| r.flatten())
+    }
+
+    /// Streaming: when a stream is about to read a piece that a slower peer is downloading, also
+    /// request it from this peer. Whoever delivers a chunk first wins, as in end-game mode.
+    fn reserve_urgent_piece(&self) -> anyhow::Result<Option<ValidPieceIndex>> {
+        let my_avg_time = match self.counters.average_piece_download_time() {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        let urgent = self.state.streams.urgent_pieces(&self.state.lengths);
+        if urgent.is_empty() {
+            return Ok(None);
+        }
+
+        let addr = self.addr;
+        let candidates = {
+            let g = self.state.lock_read("reserve_urgent_piece");
+            urgent
+                .into_iter()
+                .filter_map(|idx| {
+                    let p = g.inflight_pieces.get(&idx)?;
+                    if p.peer == addr
+                        || p.endgame_peers.contains(&addr)
+                        || p.endgame_peers.len() >= MAX_ENDGAME_PEERS_PER_PIECE
+                    {
+                        return None;
+                    }
+                    Some((idx, p.peer, p.started.elapsed()))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Looked up without the state lock, as peers are locked before it.
+        let candidates = candidates
+            .into_iter()
+            .filter(|(_, owner, elapsed)| {
+                *elapsed > my_avg_time
+                    || self
+                        .state
+                        .peers
+                        .with_peer(*owner, |p| p.stats.counters.average_piece_download_time())
+                        .flatten()
+                        .map_or(true, |t| t > my_avg_time)
+            })
+            .map(|(idx, _, _)| idx)
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return Ok(None);
+        }
+
+        let reserved = self.state.peers.with_live(addr, |live| {
+                if self.locked.read().i_am_choked {
+                    return None;
+                }
+                let mut g = self.state.lock_write("reserve_urgent_piece");
+                candidates.into_iter().find(|idx| {
+                    if live.bitfield.get(idx.get_usize()).map(|v| *v) != Some(true) {
+                        return false;
+                    }
+                    match g.inflight_pieces.get_mut(idx) {
+                        Some(p)
+                            if p.peer != addr
+                                && p.endgame_peers.len() < MAX_ENDGAME_PEERS_PER_PIECE =>
+                        {
+                            debug!(piece=%idx, owner=%p.peer, "requesting urgent streamed piece from this peer too");
+                            p.endgame_peers.insert(addr)
+                        }
+                        _ => false,
+                    }
+                })
+            });
+        Ok(reserved.flatten())
+    }
+
     /// Try to steal a piece from a slower peer. Threshold is
     /// "how many times is my average download speed faster to be able to steal".
     ///
@@ -1072,12 +1933,36 @@ impl PeerHandler {
             }
         };
 
+        let am_choking = self
+            .state
+            .peers
+            .with_live(self.addr, |live| live.am_choking)
+            .unwrap_or(true);
+        if am_choking {
+            let g = self.locked.read();
+            if !g.allowed_fast_sent.contains(&piece_index) {
+                if g.supports_fast {
+                    self.tx
+                        .send(WriterRequest::Message(MessageOwned::RejectRequest(request)))?;
+                } else {
+                    debug!(?chunk_info, "ignoring request from a choked peer");
+                }
+                return Ok(());
+            }
+        }
+
         if !self
             .state
             .lock_read("is_chunk_ready_to_upload")
             .get_chunks()?
             .is_chunk_ready_to_upload(&chunk_info)
         {
+            if self.locked.read().supports_fast {
+                debug!(?chunk_info, "rejecting request for a chunk we don't have");
+                self.tx
+                    .send(WriterRequest::Message(MessageOwned::RejectRequest(request)))?;
+                return Ok(());
+            }
             anyhow::bail!(
                 "got request for a chunk that is not ready to upload. chunk {:?}",
                 &chunk_info
@@ -1102,7 +1987,11 @@ impl PeerHandler {
                     live.bitfield = make_piece_bitfield(&self.state.lengths);
                 }
                 match live.bitfield.get_mut(have as usize) {
-                    Some(mut v) => *v = true,
+                    Some(mut v) if !*v => {
+                        *v = true;
+                        self.state.peers.availability.inc(have as usize);
+                    }
+                    Some(_) => {}
                     None => {
                         warn!("received have {} out of range", have);
                         return;
@@ -1148,13 +2037,21 @@ impl PeerHandler {
         .await;
     }
 
+    // Wait until we are unchoked, or the peer allowed us to request some pieces while choked.
+    // Allowed fast pieces are ignored once we are finished, otherwise we'd wake up only to
+    // disconnect a peer that never unchoked us.
     async fn wait_for_unchoke(&self) {
-        self.wait_for_any_notify(&self.unchoke_notify, || !self.locked.read().i_am_choked)
-            .await;
+        self.wait_for_any_notify(&self.unchoke_notify, || {
+            let (choked, has_allowed_fast) = {
+                let g = self.locked.read();
+                (g.i_am_choked, !g.allowed_fast.is_empty())
+            };
+            !choked || (has_allowed_fast && !self.state.is_finished_and_dont_need_peers())
+        })
+        .await;
     }
 
     async fn task_peer_chunk_requester(&self) -> anyhow::Result<()> {
-        let handle = self.addr;
         self.wait_for_bitfield().await;
 
         // TODO: this check needs to happen more often, we need to update our
@@ -1190,86 +2087,160 @@ impl PeerHandler {
                 return Ok(());
             }
 
+            let (rejected, rejected_while_unchoked) = self.take_rejected_chunks()?;
+            if !rejected.is_empty() {
+                // Don't hammer a peer that rejects requests without choking us.
+                if rejected_while_unchoked {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                if !self.request_chunks(rejected).await? {
+                    return Ok(());
+                }
+                continue;
+            }
+
             // Try steal a pice from a very slow peer first. Otherwise we might wait too long
             // to download early pieces.
             // Then try get the next one in queue.
             // Afterwards means we are close to completion, try stealing more aggressively.
-            let next = match self
-                .try_steal_old_slow_piece(10.)
-                .map_or_else(|| self.reserve_next_needed_piece(), |v| Ok(Some(v)))?
-                .or_else(|| self.try_steal_old_slow_piece(3.))
-            {
+            // If there's nothing to steal, enter end-game mode.
+            //
+            // Pieces streams are about to read go before all of it, even if someone else is
+            // downloading them already.
+            //
+            // While choked, only our allowed fast pieces can be requested, and they are never
+            // stolen from others.
+            let choked = self.locked.read().i_am_choked;
+            let mut endgame = false;
+            let next = if choked {
+                self.reserve_next_needed_piece()?
+            } else {
+                match self.reserve_urgent_piece()? {
+                    Some(next) => {
+                        endgame = true;
+                        Some(next)
+                    }
+                    None => self
+                        .try_steal_old_slow_piece(10.)
+                        .map_or_else(|| self.reserve_next_needed_piece(), |v| Ok(Some(v)))?
+                        .or_else(|| self.try_steal_old_slow_piece(3.)),
+                }
+            };
+            let next = match next {
                 Some(next) => next,
-                None => {
-                    debug!("no pieces to request");
-                    tokio::time::sleep(Duration::from_secs(10)).await;
+                None if choked => {
+                    // Our allowed fast pieces are done, nothing to do until the peer unchokes us
+                    // or allows more.
+                    self.wait_for_any_notify(&self.unchoke_notify, || {
+                        !self.locked.read().i_am_choked
+                    })
+                    .await;
                     continue;
                 }
+                None => match self.reserve_endgame_piece()? {
+                    Some(next) => {
+                        endgame = true;
+                        next
+                    }
+                    None => {
+                        debug!("no pieces to request");
+                        tokio::time::sleep(Duration::from_secs(10)).await;
+                        continue;
+                    }
+                },
             };
 
-            for chunk in self.state.lengths.iter_chunk_infos(next) {
-                let request = Request {
-                    index: next.get(),
-                    begin: chunk.offset,
-                    length: chunk.size,
-                };
+            let chunks = {
+                let g = self.state.lock_read("chunks_to_request");
+                let chunk_tracker = g.get_chunks()?;
+                self.state
+                    .lengths
+                    .iter_chunk_infos(next)
+                    // When requesting a piece from several peers, only ask for what others
+                    // haven't delivered yet.
+                    .filter(|c| !endgame || !chunk_tracker.is_chunk_downloaded(c))
+                    .collect::<Vec<_>>()
+            };
 
-                match self
-                    .state
-                    .peers
-                    .with_live_mut(handle, "add chunk request", |live| {
-                        live.inflight_requests.insert(chunk)
-                    }) {
-                    Some(true) => {}
-                    Some(false) => {
-                        // This request was already in-flight for this peer for this chunk.
-                        // This might happen in theory, but not very likely.
-                        //
-                        // Example:
-                        // someone stole a piece from us, and then died, the piece became "needed" again, and we reserved it
-                        // all before the piece request was processed by us.
-                        warn!("we already requested {:?} previously", chunk);
-                        continue;
-                    }
-                    // peer died
-                    None => return Ok(()),
-                };
+            if !self.request_chunks(chunks).await? {
+                return Ok(());
+            }
+        }
+    }
 
-                loop {
-                    match timeout(Duration::from_secs(10), self.requests_sem.acquire()).await {
-                        Ok(acq) => break acq?.forget(),
-                        Err(_) => continue,
-                    };
-                }
+    // Request these chunks, waiting for permits. Returns false if the peer is gone.
+    async fn request_chunks(&self, chunks: Vec<ChunkInfo>) -> anyhow::Result<bool> {
+        for chunk in chunks {
+            let request = Request {
+                index: chunk.piece_index.get(),
+                begin: chunk.offset,
+                length: chunk.size,
+            };
 
-                if self
-                    .tx
-                    .send(WriterRequest::Message(MessageOwned::Request(request)))
-                    .is_err()
-                {
-                    return Ok(());
+            match self
+                .state
+                .peers
+                .with_live_mut(self.addr, "add chunk request", |live| {
+                    live.inflight_requests.insert(chunk)
+                }) {
+                Some(true) => {}
+                Some(false) => {
+                    // This request was already in-flight for this peer for this chunk.
+                    // This might happen in theory, but not very likely.
+                    //
+                    // Example:
+                    // someone stole a piece from us, and then died, the piece became "needed" again, and we reserved it
+                    // all before the piece request was processed by us.
+                    warn!("we already requested {:?} previously", chunk);
+                    continue;
                 }
+                // peer died
+                None => return Ok(false),
+            };
+
+            if self.acquire_request_permit(chunk.piece_index).await? {
+                self.locked.write().fast_requests.insert(chunk);
+            }
+
+            if self
+                .tx
+                .send(WriterRequest::Message(MessageOwned::Request(request)))
+                .is_err()
+            {
+                return Ok(false);
             }
         }
+        Ok(true)
     }
 
     fn on_i_am_choked(&self) {
         self.locked.write().i_am_choked = true;
+        // The peer drops our queued requests, or rejects them with the fast extension. Take back
+        // the unused budget, unchoking grants a full one again.
+        self.requests_sem
+            .forget_permits(self.requests_sem.available_permits());
     }
 
     fn on_peer_interested(&self) {
         trace!("peer is interested");
         self.state.peers.mark_peer_interested(self.addr, true);
+        self.state.unchoke_if_free_slot(self.addr);
+    }
+
+    fn on_peer_not_interested(&self) {
+        trace!("peer is not interested");
+        self.state.peers.mark_peer_interested(self.addr, false);
     }
 
     fn on_i_am_unchoked(&self) {
         trace!("we are unchoked");
-        self.locked.write().i_am_choked = false;
+        if !std::mem::replace(&mut self.locked.write().i_am_choked, false) {
+            return;
+        }
         self.unchoke_notify.notify_waiters();
-        // 128 should be more than enough to maintain 100mbps
-        // for a single peer that has 100ms ping
-        // https://www.desmos.com/calculator/x3szur87ps
-        self.requests_sem.add_permits(128);
+        self.requests_sem
+            .forget_permits(self.requests_sem.available_permits());
+        self.requests_sem.add_permits(MAX_INFLIGHT_REQUESTS);
     }
 
     async fn on_received_piece(&self, piece: Piece<ByteBuf<'_>>) -> anyhow::Result<()> {
@@ -1289,8 +2260,6 @@ impl PeerHandler {
             }
         };
 
-        self.requests_sem.add_permits(1);
-
         // Peer chunk/byte counters.
         self.counters
             .fetched_bytes
@@ -1310,6 +2279,7 @@ impl PeerHandler {
                 Ok(())
             })
             .context("peer not found")??;
+        self.release_request_permit(&chunk_info);
 
         // This one is used to calculate download speed.
         self.state
@@ -1331,7 +2301,7 @@ impl PeerHandler {
             // So that by the time we are done writing AND if it was the last piece,
             // we can actually checksum etc.
             // Otherwise it might get into some weird state.
-            let ppl_guard = {
+            let ppl_guard = 'ppl: {
                 let g = state.lock_read("check_steal");
 
                 let ppl = state
@@ -1341,6 +2311,20 @@ impl PeerHandler {
 
                 match g.inflight_pieces.get(&chunk_info.piece_index) {
                     Some(InflightPiece { peer, .. }) if *peer == addr => {}
+                    Some(InflightPiece { endgame_peers, .. }) if endgame_peers.contains(&addr) => {
+                        drop(ppl);
+                        drop(g);
+                        match state.take_over_endgame_piece(addr, chunk_info.piece_index) {
+                            Some(ppl) => break 'ppl Some(ppl),
+                            None => {
+                                debug!(
+                                    "in-flight piece {} is being written by someone else, ignoring",
+                                    chunk_info.piece_index
+                                );
+                                return Ok(());
+                            }
+                        }
+                    }
                     Some(InflightPiece { peer, .. }) => {
                         debug!(
                             "in-flight piece {} was stolen by {}, ignoring",
@@ -1371,7 +2355,7 @@ impl PeerHandler {
                 }
             };
 
-            let full_piece_download_time = {
+            let (full_piece_download_time, cancel_peers) = {
                 let mut g = state.lock_write("mark_chunk_downloaded");
                 let chunk_marking_result = g.get_chunks_mut()?.mark_chunk_downloaded(piece);
                 trace!(?piece, chunk_marking_result=?chunk_marking_result);
@@ -1380,18 +2364,26 @@ impl PeerHandler {
                     Some(ChunkMarkingResult::Completed) => {
                         trace!("piece={} done, will write and checksum", piece.index);
                         // This will prevent others from stealing it.
-                        {
-                            let piece = chunk_info.piece_index;
-                            g.inflight_pieces.remove(&piece)
+                        match g.inflight_pieces.remove(&chunk_info.piece_index) {
+                            Some(p) => {
+                                // In end-game mode, others may still be downloading it.
+                                let others = p
+                                    .endgame_peers
+                                    .into_iter()
+                                    .chain(std::iter::once(p.peer))
+                                    .filter(|p| *p != addr)
+                                    .collect::<Vec<_>>();
+                                (Some(p.started.elapsed()), others)
+                            }
+                            None => (None, Vec::new()),
                         }
-                        .map(|t| t.started.elapsed())
                     }
                     Some(ChunkMarkingResult::PreviouslyCompleted) => {
                         // TODO: we might need to send cancellations here.
                         debug!("piece={} was done by someone else, ignoring", piece.index);
                         return Ok(());
                     }
-                    Some(ChunkMarkingResult::NotCompleted) => None,
+                    Some(ChunkMarkingResult::NotCompleted) => (None, Vec::new()),
                     None => {
                         anyhow::bail!(
                             "bogus data received: {:?}, cannot map this to a chunk, dropping peer",
@@ -1401,6 +2393,11 @@ impl PeerHandler {
                 }
             };
 
+            // Peers are locked before the torrent state, so this is done after releasing it.
+            for peer in cancel_peers {
+                state.peers.send_cancellations(peer, chunk_info.piece_index);
+            }
+
             // We don't care about per piece lock anymore, as it's removed from inflight pieces.
             // It shouldn't impact perf anyway, but dropping just in case.
             drop(ppl_guard);
@@ -1416,45 +2413,14 @@ impl PeerHandler {
                 .with_context(|| format!("error checking piece={index}"))?
             {
                 true => {
-                    {
-                        let mut g = state.lock_write("mark_piece_downloaded");
-                        g.get_chunks_mut()?
-                            .mark_piece_downloaded(chunk_info.piece_index);
-                    }
-
-                    // Global piece counters.
-                    let piece_len = state.lengths.piece_length(chunk_info.piece_index) as u64;
-                    state
-                        .stats
-                        .downloaded_and_checked_bytes
-                        // This counter is used to compute "is_finished", so using
-                        // stronger ordering.
-                        .fetch_add(piece_len, Ordering::Release);
-                    state
-                        .stats
-                        .downloaded_and_checked_pieces
-                        // This counter is used to compute "is_finished", so using
-                        // stronger ordering.
-                        .fetch_add(1, Ordering::Release);
-                    state
-                        .stats
-                        .have_bytes
-                        .fetch_add(piece_len, Ordering::Relaxed);
-                    #[allow(clippy::cast_possible_truncation)]
-                    state.stats.total_piece_download_ms.fetch_add(
-                        full_piece_download_time.as_millis() as u64,
-                        Ordering::Relaxed,
-                    );
-
                     // Per-peer piece counters.
+                    let piece_len = state.lengths.piece_length(chunk_info.piece_index) as u64;
                     counters.on_piece_completed(piece_len, full_piece_download_time);
                     state.peers.reset_peer_backoff(addr);
 
                     debug!("piece={} successfully downloaded and verified", index);
 
-                    state.on_piece_completed(chunk_info.piece_index)?;
-
-                    state.transmit_haves(chunk_info.piece_index);
+                    state.on_piece_verified(chunk_info.piece_index, full_piece_download_time)?;
                 }
                 false => {
                     warn!(
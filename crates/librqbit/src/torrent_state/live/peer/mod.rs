@@ -1,6 +1,7 @@
 pub mod stats;
 
 use std::collections::HashSet;
+use std::net::SocketAddr;
 
 use librqbit_core::hash_id::Id20;
 use librqbit_core::lengths::ChunkInfo;
@@ -178,6 +179,9 @@ pub(crate) struct LivePeerState {
 
     pub peer_interested: bool,
 
+    // Whether we refuse to upload to the peer. Managed by the choker.
+    pub am_choking: bool,
+
     // This is used to track the pieces the peer has.
     pub bitfield: BF,
 
@@ -186,6 +190,14 @@ pub(crate) struct LivePeerState {
 
     // The main channel to send requests to peer.
     pub tx: PeerTx,
+
+    // The address other peers can reach this peer on. Unknown for incoming
+    // connections until the peer tells us its listen port.
+    pub listen_addr: Option<SocketAddr>,
+
+    // PEX state: whether the peer understands ut_pex, and which peers we told it about.
+    pub supports_pex: bool,
+    pub pex_sent: HashSet<SocketAddr>,
 }
 
 impl LivePeerState {
@@ -193,9 +205,13 @@ impl LivePeerState {
         LivePeerState {
             peer_id,
             peer_interested: false,
+            am_choking: true,
             bitfield: BF::default(),
             inflight_requests: Default::default(),
             tx,
+            listen_addr: None,
+            supports_pex: false,
+            pex_sent: Default::default(),
         }
     }
 
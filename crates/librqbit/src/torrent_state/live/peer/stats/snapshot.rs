@@ -8,9 +8,11 @@ use crate::torrent_state::live::peer::{Peer, PeerState};
 pub struct PeerCounters {
     pub incoming_connections: u32,
     pub fetched_bytes: u64,
+    pub uploaded_bytes: u64,
     pub total_time_connecting_ms: u64,
     pub connection_attempts: u32,
     pub connections: u32,
+    pub utp_connections: u32,
     pub errors: u32,
     pub fetched_chunks: u32,
     pub downloaded_and_checked_pieces: u32,
@@ -30,11 +32,13 @@ impl From<&super::atomic::PeerCountersAtomic> for PeerCounters {
         Self {
             incoming_connections: counters.incoming_connections.load(Ordering::Relaxed),
             fetched_bytes: counters.fetched_bytes.load(Ordering::Relaxed),
+            uploaded_bytes: counters.uploaded_bytes.load(Ordering::Relaxed),
             total_time_connecting_ms: counters.total_time_connecting_ms.load(Ordering::Relaxed),
             connection_attempts: counters
                 .outgoing_connection_attempts
                 .load(Ordering::Relaxed),
             connections: counters.outgoing_connections.load(Ordering::Relaxed),
+            utp_connections: counters.utp_connections.load(Ordering::Relaxed),
             errors: counters.errors.load(Ordering::Relaxed),
             fetched_chunks: counters.fetched_chunks.load(Ordering::Relaxed),
             downloaded_and_checked_pieces: counters
@@ -0,0 +1,84 @@
+// How many connected peers have each piece. Used for rarest-first piece selection.
+//
+// Updated when live peers send bitfields or haves, and when they go away. The counters are atomic
+// so that they can be updated while holding a peer's DashMap entry, without taking the torrent
+// state lock.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::type_aliases::BF;
+
+pub(crate) struct PieceAvailability {
+    counts: Box<[AtomicU32]>,
+}
+
+impl PieceAvailability {
+    pub fn new(total_pieces: u32) -> Self {
+        Self {
+            counts: (0..total_pieces).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    pub fn get(&self, piece: usize) -> u32 {
+        self.counts
+            .get(piece)
+            .map(|c| c.load(Ordering::Relaxed))
+            .unwrap_or_default()
+    }
+
+    pub fn inc(&self, piece: usize) {
+        if let Some(c) = self.counts.get(piece) {
+            c.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn dec(&self, piece: usize) {
+        if let Some(c) = self.counts.get(piece) {
+            // Don't wrap around if the accounting is off.
+            let _ = c.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| v.checked_sub(1));
+        }
+    }
+
+    pub fn add_bitfield(&self, bf: &BF) {
+        for piece in bf.iter_ones() {
+            self.inc(piece);
+        }
+    }
+
+    pub fn remove_bitfield(&self, bf: &BF) {
+        for piece in bf.iter_ones() {
+            self.dec(piece);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::type_aliases::BF;
+
+    use super::PieceAvailability;
+
+    #[test]
+    fn test_availability() {
+        let a = PieceAvailability::new(10);
+        let mut bf = BF::from_boxed_slice(vec![0u8; 2].into_boxed_slice());
+        bf.set(1, true);
+        bf.set(9, true);
+        // Out of range bits (bitfield padding) are ignored.
+        bf.set(15, true);
+
+        a.add_bitfield(&bf);
+        a.add_bitfield(&bf);
+        a.inc(2);
+        assert_eq!(a.get(1), 2);
+        assert_eq!(a.get(2), 1);
+        assert_eq!(a.get(9), 2);
+        assert_eq!(a.get(15), 0);
+
+        a.remove_bitfield(&bf);
+        a.dec(2);
+        a.dec(2);
+        assert_eq!(a.get(1), 1);
+        assert_eq!(a.get(2), 0);
+    }
+}
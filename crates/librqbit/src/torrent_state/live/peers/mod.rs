@@ -12,19 +12,32 @@ use crate::{
     type_aliases::{PeerHandle, BF},
 };
 
-use self::stats::{atomic::AggregatePeerStatsAtomic, snapshot::AggregatePeerStats};
+use self::{
+    availability::PieceAvailability,
+    stats::{atomic::AggregatePeerStatsAtomic, snapshot::AggregatePeerStats},
+};
 
 use super::peer::{LivePeerState, Peer, PeerRx, PeerState, PeerTx};
 
+pub mod availability;
 pub mod stats;
 
-#[derive(Default)]
 pub(crate) struct PeerStates {
     pub stats: AggregatePeerStatsAtomic,
     pub states: DashMap<PeerHandle, Peer>,
+    // Must be kept in sync with the bitfields of live peers.
+    pub availability: PieceAvailability,
 }
 
 impl PeerStates {
+    pub fn new(total_pieces: u32) -> Self {
+        Self {
+            stats: Default::default(),
+            states: Default::default(),
+            availability: PieceAvailability::new(total_pieces),
+        }
+    }
+
     pub fn stats(&self) -> AggregatePeerStats {
         AggregatePeerStats::from(&self.stats)
     }
@@ -85,10 +98,21 @@ impl PeerStates {
         })
     }
     pub fn update_bitfield_from_vec(&self, handle: PeerHandle, bitfield: Box<[u8]>) -> Option<()> {
-        self.with_live_mut(handle, "update_bitfield_from_vec", |live| {
-            live.bitfield = BF::from_boxed_slice(bitfield);
+        self.update_bitfield(handle, BF::from_boxed_slice(bitfield))
+    }
+
+    pub fn update_bitfield(&self, handle: PeerHandle, bitfield: BF) -> Option<()> {
+        self.with_live_mut(handle, "update_bitfield", |live| {
+            self.availability.remove_bitfield(&live.bitfield);
+            self.availability.add_bitfield(&bitfield);
+            live.bitfield = bitfield;
         })
     }
+
+    // Call when a live peer's state is taken away, so that its pieces aren't counted anymore.
+    pub fn on_live_peer_gone(&self, live: &LivePeerState) {
+        self.availability.remove_bitfield(&live.bitfield);
+    }
     pub fn mark_peer_connecting(&self, h: PeerHandle) -> anyhow::Result<(PeerRx, PeerTx)> {
         let rx = self
             .with_peer_mut(h, "mark_peer_connecting", |peer| {
@@ -110,6 +134,9 @@ impl PeerStates {
         let prev = self.with_peer_mut(handle, "mark_peer_not_needed", |peer| {
             peer.state.set_not_needed(&self.stats)
         })?;
+        if let PeerState::Live(live) = &prev {
+            self.on_live_peer_gone(live);
+        }
         Some(prev)
     }
 
@@ -126,19 +153,23 @@ impl PeerStates {
             atomic_inc(&p.stats.counters.times_stolen_from_me);
         });
         self.stats.inc_steals();
+        self.send_cancellations(from_peer, stolen_idx);
+    }
 
-        self.with_live_mut(from_peer, "send_cancellations", |live| {
+    // Cancel all requests to the peer for chunks of this piece.
+    pub(crate) fn send_cancellations(&self, peer: SocketAddr, piece: ValidPieceIndex) {
+        self.with_live_mut(peer, "send_cancellations", |live| {
             let to_remove = live
                 .inflight_requests
                 .iter()
-                .filter(|r| r.piece_index == stolen_idx)
+                .filter(|r| r.piece_index == piece)
                 .copied()
                 .collect::<Vec<_>>();
             for req in to_remove {
                 let _ = live
                     .tx
                     .send(WriterRequest::Message(Message::Cancel(Request {
-                        index: stolen_idx.get(),
+                        index: piece.get(),
                         begin: req.offset,
                         length: req.size,
                     })));
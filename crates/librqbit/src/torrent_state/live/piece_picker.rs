@@ -0,0 +1,88 @@
+// Piece selection for peers.
+//
+// Among the pieces of the highest priority files that a peer has, the ones fewest connected peers
+// have are requested first (rarest-first), so that rare pieces spread before their owners leave.
+// Until we have a few complete pieces, a random one is picked instead: rare pieces are slow to
+// get, and we want something to upload to others as soon as possible.
+//
+// Once every needed piece is in flight, peers enter end-game mode: they also request the missing
+// chunks of pieces in flight from other peers, so that the last pieces don't wait on one slow peer.
+
+use librqbit_core::lengths::ValidPieceIndex;
+use rand::seq::IteratorRandom;
+
+use crate::file_info::FilePriority;
+
+// How many pieces to pick at random before switching to rarest-first.
+pub(crate) const RANDOM_FIRST_PIECES: u64 = 4;
+
+// How many peers, apart from the one that reserved it, may download an in-flight piece in end-game mode.
+pub(crate) const MAX_ENDGAME_PEERS_PER_PIECE: usize = 2;
+
+// Candidates are the pieces we may request from the peer, from higher priority files first.
+pub(crate) fn pick_piece(
+    candidates: impl Iterator<Item = (FilePriority, ValidPieceIndex)>,
+    availability: impl Fn(ValidPieceIndex) -> u32,
+    random: bool,
+) -> Option<ValidPieceIndex> {
+    let mut candidates = candidates.peekable();
+    let priority = candidates.peek()?.0;
+    let candidates = candidates
+        .take_while(|(p, _)| *p == priority)
+        .map(|(_, id)| id);
+
+    if random {
+        return candidates.choose(&mut rand::thread_rng());
+    }
+
+    // On ties, the first candidate wins to keep the file order.
+    let mut best: Option<(ValidPieceIndex, u32)> = None;
+    for id in candidates {
+        let a = availability(id);
+        if best.map_or(true, |(_, best_a)| a < best_a) {
+            best = Some((id, a));
+        }
+        // Only the peer we are asking has it, can't do better.
+        if a <= 1 {
+            break;
+        }
+    }
+    best.map(|(id, _)| id)
+}
+
+#[cfg(test)]
+mod tests {
+    use librqbit_core::lengths::{Lengths, ValidPieceIndex};
+
+    use crate::file_info::FilePriority;
+
+    use super::pick_piece;
+
+    fn pieces(l: &Lengths, p: FilePriority, ids: &[u32]) -> Vec<(FilePriority, ValidPieceIndex)> {
+        ids.iter()
+            .map(|id| (p, l.validate_piece_index(*id).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_rarest_first() {
+        let l = Lengths::new(16384 * 10, 16384).unwrap();
+        let availability = [5, 3, 2, 2, 4, 1, 1, 1, 1, 1];
+        let avail = |id: ValidPieceIndex| availability[id.get_usize()];
+
+        let mut c = pieces(&l, FilePriority::High, &[0, 1, 2, 3, 4]);
+        c.extend(pieces(&l, FilePriority::Normal, &[5, 6]));
+        assert_eq!(
+            pick_piece(c.iter().copied(), avail, false).map(|p| p.get()),
+            Some(2)
+        );
+
+        // Random picks stay within the highest priority.
+        for _ in 0..10 {
+            let p = pick_piece(c.iter().copied(), avail, true).unwrap().get();
+            assert!(p < 5);
+        }
+
+        assert_eq!(pick_piece(std::iter::empty(), avail, false), None);
+    }
+}
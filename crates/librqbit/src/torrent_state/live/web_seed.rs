@@ -0,0 +1,188 @@
+// BEP 19 (GetRight-style) web seeds.
+//
+// A web seed is an HTTP server that has the torrent's files laid out the same way as on disk.
+// Pieces are fetched with Range requests, one request per file a piece spans, and then go
+// through the same chunk tracking and hash checking as pieces received from peers.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use buffers::ByteBufOwned;
+use librqbit_core::{
+    lengths::{Lengths, ValidPieceIndex},
+    torrent_metainfo::{FileIteratorName, TorrentMetaV1Info},
+};
+use reqwest::{header::RANGE, StatusCode};
+use url::Url;
+
+use crate::{file_ops::iter_segments, type_aliases::FileInfos};
+
+// How long to wait before looking for pieces again when there's nothing to download.
+pub(crate) const WEB_SEED_IDLE_INTERVAL: Duration = Duration::from_secs(5);
+
+pub(crate) struct WebSeed {
+    pub url: String,
+    client: reqwest::Client,
+    // One per file in FileInfos order.
+    file_urls: Vec<Url>,
+}
+
+// For single-file torrents, the URL points to the file itself, unless it ends with a slash, in
+// which case the torrent name is appended. For multi-file torrents, the URL is the directory
+// containing the torrent's directory.
+fn file_url(
+    base: &Url,
+    name: Option<&str>,
+    filename: FileIteratorName<'_, ByteBufOwned>,
+) -> anyhow::Result<Url> {
+    let mut url = base.clone();
+    let is_single = matches!(filename, FileIteratorName::Single(..));
+    if is_single && !base.path().ends_with('/') {
+        return Ok(url);
+    }
+    {
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("{base} can't be a base URL"))?;
+        segments.pop_if_empty();
+        if !is_single {
+            segments.push(name.context("multi-file torrent has no name")?);
+        }
+        for component in filename.iter_components() {
+            segments.push(component?);
+        }
+    }
+    Ok(url)
+}
+
+impl WebSeed {
+    pub fn new(
+        url: &str,
+        info: &TorrentMetaV1Info<ByteBufOwned>,
+        lengths: &Lengths,
+    ) -> anyhow::Result<Self> {
+        let base = Url::parse(url).with_context(|| format!("invalid web seed URL {url:?}"))?;
+        if !matches!(base.scheme(), "http" | "https") {
+            bail!("unsupported web seed URL scheme {:?}", base.scheme());
+        }
+        let name = info
+            .name
+            .as_ref()
+            .map(|n| std::str::from_utf8(n.as_ref()))
+            .transpose()
+            .context("torrent name isn't valid UTF-8")?;
+        let file_urls = info
+            .iter_file_details(lengths)?
+            .map(|fd| file_url(&base, name, fd.filename))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .build()?;
+        Ok(Self {
+            url: url.to_owned(),
+            client,
+            file_urls,
+        })
+    }
+
+    // Downloads the whole piece. Padding between files is filled with zeros.
+    pub async fn fetch_piece(
+        &self,
+        lengths: &Lengths,
+        file_infos: &FileInfos,
+        index: ValidPieceIndex,
+    ) -> anyhow::Result<Vec<u8>> {
+        let piece_length = lengths.piece_length(index);
+        let mut buf = vec![0u8; piece_length as usize];
+        let mut pos = &mut buf[..];
+        for segment in iter_segments(file_infos, lengths.piece_offset(index), piece_length as u64) {
+            let (current, rest) = std::mem::take(&mut pos).split_at_mut(segment.len.try_into()?);
+            pos = rest;
+            if let Some(file_id) = segment.file_id {
+                self.fetch_range(&self.file_urls[file_id], segment.offset_in_file, current)
+                    .await?;
+            }
+        }
+        Ok(buf)
+    }
+
+    async fn fetch_range(&self, url: &Url, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        let end = offset + buf.len() as u64 - 1;
+        let response = self
+            .client
+            .get(url.clone())
+            .header(RANGE, format!("bytes={offset}-{end}"))
+            .send()
+            .await
+            .with_context(|| format!("error requesting {url}"))?;
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => {}
+            // Servers may ignore Range. That's fine if we asked for the whole file.
+            StatusCode::OK
+                if offset == 0 && response.content_length() == Some(buf.len() as u64) => {}
+            s => bail!("{url}: unexpected response status {s} for range {offset}-{end}"),
+        }
+        let body = response
+            .bytes()
+            .await
+            .with_context(|| format!("error reading response body from {url}"))?;
+        if body.len() != buf.len() {
+            bail!(
+                "{url}: expected {} bytes for range {offset}-{end}, got {}",
+                buf.len(),
+                body.len()
+            );
+        }
+        buf.copy_from_slice(&body);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use buffers::ByteBufOwned;
+    use librqbit_core::torrent_metainfo::FileIteratorName;
+    use url::Url;
+
+    use super::file_url;
+
+    #[test]
+    fn test_file_url_single() {
+        let name = ByteBufOwned::from(&b"file.iso"[..]);
+        let filename = || FileIteratorName::Single(Some(&name));
+
+        let base = Url::parse("http://example.com/pub/file.iso").unwrap();
+        assert_eq!(
+            file_url(&base, Some("file.iso"), filename())
+                .unwrap()
+                .as_str(),
+            "http://example.com/pub/file.iso"
+        );
+
+        let base = Url::parse("http://example.com/pub/").unwrap();
+        assert_eq!(
+            file_url(&base, Some("file.iso"), filename())
+                .unwrap()
+                .as_str(),
+            "http://example.com/pub/file.iso"
+        );
+    }
+
+    #[test]
+    fn test_file_url_multi() {
+        let path = [
+            ByteBufOwned::from(&b"sub dir"[..]),
+            ByteBufOwned::from(&b"a#b.txt"[..]),
+        ];
+        let filename = || FileIteratorName::Tree(&path);
+        for base in ["http://example.com/pub", "http://example.com/pub/"] {
+            let base = Url::parse(base).unwrap();
+            assert_eq!(
+                file_url(&base, Some("torrent"), filename())
+                    .unwrap()
+                    .as_str(),
+                "http://example.com/pub/torrent/sub%20dir/a%23b.txt"
+            );
+        }
+    }
+}
@@ -5,6 +5,7 @@ pub mod stats;
 mod streaming;
 pub mod utils;
 
+use std::any::TypeId;
 use std::collections::HashSet;
 use std::path::PathBuf;
 use std::sync::atomic::Ordering;
@@ -16,12 +17,12 @@ use anyhow::Context;
 use buffers::ByteBufOwned;
 use futures::future::BoxFuture;
 use futures::FutureExt;
-use librqbit_core::hash_id::Id20;
+use librqbit_core::hash_id::{Id20, Id32};
 use librqbit_core::lengths::Lengths;
 use librqbit_core::peer_id::generate_peer_id;
 
-use librqbit_core::spawn_utils::spawn_with_cancel;
-use librqbit_core::torrent_metainfo::TorrentMetaV1Info;
+use librqbit_core::spawn_utils::{spawn, spawn_with_cancel};
+use librqbit_core::torrent_metainfo::{PieceHashV2, PieceLayers, TorrentMetaV1Info};
 pub use live::*;
 use parking_lot::RwLock;
 
@@ -32,11 +33,19 @@ use tokio_util::sync::CancellationToken;
 use tracing::debug;
 use tracing::error_span;
 use tracing::warn;
+use tracker_comms::{TrackerComms, TrackerCommsStats, TrackerList};
 
 use crate::chunk_tracker::ChunkTracker;
-use crate::file_info::FileInfo;
+use crate::events::{TorrentEventKind, TorrentEventSender};
+use crate::fastresume::FastResumeData;
+use crate::file_info::{FileInfo, FilePriority};
+use crate::limits::{RateLimits, RateLimitsConfig};
+use crate::mse::EncryptionMode;
+use crate::peer_connection::OutgoingTransports;
 use crate::spawn_utils::BlockingSpawner;
+use crate::storage::filesystem::{move_files, FilesystemStorage, FilesystemStorageFactory};
 use crate::storage::BoxStorageFactory;
+use crate::torrent_state::live::choker::DEFAULT_UPLOAD_SLOTS;
 use crate::torrent_state::stats::LiveStats;
 use crate::type_aliases::DiskWorkQueueSender;
 use crate::type_aliases::FileInfos;
@@ -46,7 +55,7 @@ use initializing::TorrentStateInitializing;
 
 use self::paused::TorrentStatePaused;
 pub use self::stats::{TorrentStats, TorrentStatsState};
-pub use self::streaming::FileStream;
+pub use self::streaming::{FileStream, StreamOptions};
 
 pub enum ManagedTorrentState {
     Initializing(Arc<TorrentStateInitializing>),
@@ -84,6 +93,10 @@ impl ManagedTorrentState {
 pub(crate) struct ManagedTorrentLocked {
     pub state: ManagedTorrentState,
     pub(crate) only_files: Option<Vec<usize>>,
+    // None if all selected files have normal priority.
+    pub(crate) file_priorities: Option<Vec<FilePriority>>,
+    // Set while the files are being moved to another folder, so that the torrent isn't started.
+    pub(crate) moving: bool,
 }
 
 #[derive(Default)]
@@ -91,21 +104,82 @@ pub(crate) struct ManagedTorrentOptions {
     pub force_tracker_interval: Option<Duration>,
     pub peer_connect_timeout: Option<Duration>,
     pub peer_read_write_timeout: Option<Duration>,
+    pub peer_encryption: Option<EncryptionMode>,
+    pub listen_port: Option<u16>,
     pub allow_overwrite: bool,
-    pub output_folder: PathBuf,
+    // Changes when the torrent is moved.
+    pub output_folder: RwLock<PathBuf>,
     pub disk_write_queue: Option<DiskWorkQueueSender>,
+    pub transports: OutgoingTransports,
+    // How many peers we upload to at once. 0 means unlimited.
+    pub upload_slots: usize,
 }
 
 pub struct ManagedTorrentInfo {
     pub info: TorrentMetaV1Info<ByteBufOwned>,
     pub info_hash: Id20,
+    // Set for v2 and hybrid torrents.
+    pub info_hash_v2: Option<Id32>,
+    pub piece_layers: Option<PieceLayers<ByteBufOwned>>,
+    // Expected piece hashes of v2-only torrents. Hybrid torrents are verified with SHA1.
+    pub(crate) v2_piece_hashes: Option<Vec<PieceHashV2>>,
     pub(crate) spawner: BlockingSpawner,
-    pub trackers: HashSet<String>,
+    // Shared with the running tracker announces.
+    pub trackers: TrackerList,
+    // BEP 19 web seed URLs.
+    pub web_seeds: Vec<String>,
     pub peer_id: Id20,
     pub lengths: Lengths,
     pub file_infos: FileInfos,
     pub span: tracing::Span,
     pub(crate) options: ManagedTorrentOptions,
+    pub(crate) ratelimits: Arc<RateLimits>,
+    pub(crate) events: Option<TorrentEventSender>,
+    // The runtime the torrent was created in. Pausing is sync and may happen outside of it, but
+    // still needs to spawn the "stopped" announce.
+    pub(crate) runtime: tokio::runtime::Handle,
+}
+
+impl ManagedTorrentInfo {
+    pub fn output_folder(&self) -> PathBuf {
+        self.options.output_folder.read().clone()
+    }
+
+    pub(crate) fn send_event(&self, kind: TorrentEventKind) {
+        if let Some(events) = self.events.as_ref() {
+            events.send(kind);
+        }
+    }
+
+    // Tell the trackers we stopped, in all swarms the torrent is in. Hybrid torrents are in both
+    // the v1 and the v2 swarm.
+    pub(crate) fn announce_stopped(&self, stats: TrackerCommsStats) -> BoxFuture<'static, ()> {
+        let v2_swarm = self
+            .info_hash_v2
+            .map(|h| h.truncate_for_dht())
+            .filter(|h| *h != self.info_hash);
+        let announces = std::iter::once(self.info_hash)
+            .chain(v2_swarm)
+            .map(|swarm_hash| {
+                TrackerComms::announce_stopped(
+                    swarm_hash,
+                    self.peer_id,
+                    self.trackers.clone(),
+                    stats,
+                    self.options.listen_port,
+                )
+            })
+            .collect::<Vec<_>>();
+        futures::future::join_all(announces).map(|_| ()).boxed()
+    }
+
+    pub(crate) fn spawn_announce_stopped(&self, stats: TrackerCommsStats) {
+        let _guard = self.runtime.enter();
+        spawn(
+            error_span!(parent: self.span.clone(), "announce_stopped"),
+            self.announce_stopped(stats).map(Ok),
+        );
+    }
 }
 
 pub struct ManagedTorrent {
@@ -129,10 +203,38 @@ impl ManagedTorrent {
         self.info.info_hash
     }
 
+    /// The info hashes peers may know this torrent by. For hybrid torrents, this includes the
+    /// truncated v2 info hash.
+    pub fn swarm_info_hashes(&self) -> impl Iterator<Item = Id20> {
+        let info_hash = self.info.info_hash;
+        std::iter::once(info_hash).chain(
+            self.info
+                .info_hash_v2
+                .map(|h| h.truncate_for_dht())
+                .filter(|h| *h != info_hash),
+        )
+    }
+
     pub fn only_files(&self) -> Option<Vec<usize>> {
         self.locked.read().only_files.clone()
     }
 
+    pub fn file_priorities(&self) -> Vec<FilePriority> {
+        let g = self.locked.read();
+        g.file_priorities.clone().unwrap_or_else(|| {
+            FilePriority::from_only_files(self.info.file_infos.len(), g.only_files.as_deref())
+        })
+    }
+
+    pub fn ratelimits(&self) -> RateLimitsConfig {
+        self.info.ratelimits.get_config()
+    }
+
+    /// Change the torrent's upload and download limits. Applies immediately to all peers.
+    pub fn set_ratelimits(&self, config: RateLimitsConfig) {
+        self.info.ratelimits.set_config(config)
+    }
+
     pub fn with_state<R>(&self, f: impl FnOnce(&ManagedTorrentState) -> R) -> R {
         f(&self.locked.read().state)
     }
@@ -188,7 +290,11 @@ impl ManagedTorrent {
 
         self.state_change_notify.notify_waiters();
 
-        g.state = ManagedTorrentState::Error(error)
+        let error_string = format!("{error:#}");
+        g.state = ManagedTorrentState::Error(error);
+        self.info.send_event(TorrentEventKind::Errored {
+            error: error_string,
+        });
     }
 
     pub(crate) fn start(
@@ -198,6 +304,9 @@ impl ManagedTorrent {
         live_cancellation_token: CancellationToken,
     ) -> anyhow::Result<()> {
         let mut g = self.locked.write();
+        if g.moving {
+            bail!("torrent is being moved");
+        }
 
         let spawn_fatal_errors_receiver =
             |state: &Arc<Self>,
@@ -283,10 +392,12 @@ impl ManagedTorrent {
                                     debug!("no need to start torrent anymore, as it switched state from initilizing");
                                     return Ok(());
                                 }
+                                t.info.send_event(TorrentEventKind::Initialized);
 
                                 if start_paused {
                                     g.state = ManagedTorrentState::Paused(paused);
                                     t.state_change_notify.notify_waiters();
+                                    t.info.send_event(TorrentEventKind::Paused);
                                     return Ok(());
                                 }
 
@@ -295,6 +406,7 @@ impl ManagedTorrent {
                                     TorrentStateLive::new(paused, tx, live_cancellation_token)?;
                                 g.state = ManagedTorrentState::Live(live.clone());
                                 t.state_change_notify.notify_waiters();
+                                t.info.send_event(TorrentEventKind::Started);
 
                                 spawn_fatal_errors_receiver(&t, rx, token);
                                 spawn_peer_adder(&live, peer_rx);
@@ -303,8 +415,12 @@ impl ManagedTorrent {
                             }
                             Err(err) => {
                                 let result = anyhow::anyhow!("{:?}", err);
+                                let error_string = format!("{err:#}");
                                 t.locked.write().state = ManagedTorrentState::Error(err);
                                 t.state_change_notify.notify_waiters();
+                                t.info.send_event(TorrentEventKind::Errored {
+                                    error: error_string,
+                                });
                                 Err(result)
                             }
                         }
@@ -317,14 +433,18 @@ impl ManagedTorrent {
                 let (tx, rx) = tokio::sync::oneshot::channel();
                 let live = TorrentStateLive::new(paused, tx, live_cancellation_token.clone())?;
                 g.state = ManagedTorrentState::Live(live.clone());
+                self.info.send_event(TorrentEventKind::Started);
                 spawn_fatal_errors_receiver(self, rx, live_cancellation_token);
                 spawn_peer_adder(&live, peer_rx);
                 Ok(())
             }
             ManagedTorrentState::Error(_) => {
+                // Fast-resume data is not used here, as the error might be caused by the data on disk.
                 let initializing = Arc::new(TorrentStateInitializing::new(
                     self.info.clone(),
                     g.only_files.clone(),
+                    g.file_priorities.clone(),
+                    None,
                 ));
                 g.state = ManagedTorrentState::Initializing(initializing.clone());
                 self.state_change_notify.notify_waiters();
@@ -339,12 +459,27 @@ impl ManagedTorrent {
 
     /// Pause the torrent if it's live.
     pub fn pause(&self) -> anyhow::Result<()> {
-        let mut g = self.locked.write();
+        let stats = self.tracker_comms_stats();
+        self.pause_locked(&mut self.locked.write())?;
+        self.info.spawn_announce_stopped(stats);
+        Ok(())
+    }
+
+    // Returns the "stopped" announce to the trackers for the caller to run.
+    pub(crate) fn pause_inner(&self) -> anyhow::Result<BoxFuture<'static, ()>> {
+        let stats = self.tracker_comms_stats();
+        self.pause_locked(&mut self.locked.write())?;
+        Ok(self.info.announce_stopped(stats))
+    }
+
+    // Pauses the live torrent with the lock held. The caller tells the trackers we stopped.
+    fn pause_locked(&self, g: &mut ManagedTorrentLocked) -> anyhow::Result<()> {
         match &g.state {
             ManagedTorrentState::Live(live) => {
                 let paused = live.pause()?;
                 g.state = ManagedTorrentState::Paused(paused);
                 self.state_change_notify.notify_waiters();
+                self.info.send_event(TorrentEventKind::Paused);
                 Ok(())
             }
             ManagedTorrentState::Initializing(_) => {
@@ -360,6 +495,86 @@ impl ManagedTorrent {
         }
     }
 
+    /// Move the files to a new output folder. Live torrents are paused first, and left paused.
+    /// The pieces that were already checked are kept, nothing is re-checked.
+    pub(crate) async fn move_storage(&self, new_folder: PathBuf) -> anyhow::Result<()> {
+        if !self
+            .storage_factory
+            .is_type_id(TypeId::of::<FilesystemStorageFactory>())
+        {
+            bail!("only torrents stored on the filesystem can be moved");
+        }
+
+        // The lock is released while the files are moved.
+        {
+            let stats = self.tracker_comms_stats();
+            let mut g = self.locked.write();
+            if g.moving {
+                bail!("torrent is already being moved");
+            }
+            match &g.state {
+                ManagedTorrentState::Live(_) => {
+                    self.pause_locked(&mut g)?;
+                    self.info.spawn_announce_stopped(stats);
+                }
+                ManagedTorrentState::Paused(_) => {}
+                s => bail!("can't move torrent in {} state", s.name()),
+            }
+            // Close the files, open files can't be moved on Windows.
+            if let ManagedTorrentState::Paused(p) = &g.state {
+                drop(p.files.take()?);
+            }
+            g.moving = true;
+        }
+
+        let old_folder = self.info.output_folder();
+        let result = if old_folder == new_folder {
+            Ok(())
+        } else {
+            let files = self
+                .info
+                .file_infos
+                .iter()
+                .map(|fi| fi.relative_filename.clone())
+                .collect::<Vec<_>>();
+            let allow_overwrite = self.info.options.allow_overwrite;
+            let (from, to) = (old_folder.clone(), new_folder.clone());
+            tokio::task::spawn_blocking(move || {
+                move_files(
+                    &from,
+                    &to,
+                    files.iter().map(|f| f.as_path()),
+                    allow_overwrite,
+                )
+            })
+            .await
+            .context("bug: moving files panicked")
+            .and_then(|r| r)
+        };
+
+        let mut g = self.locked.write();
+        g.moving = false;
+        if result.is_ok() {
+            *self.info.options.output_folder.write() = new_folder;
+        }
+        // Reopen the files wherever they are now. They exist already, so overwriting is fine.
+        let paused = match &mut g.state {
+            ManagedTorrentState::Paused(p) => p,
+            _ => bail!("torrent was removed while moving"),
+        };
+        match FilesystemStorage::open(&self.info, true) {
+            Ok(files) => paused.files = Box::new(files),
+            Err(e) => {
+                drop(g);
+                let e = e.context("error reopening files after moving");
+                let msg = format!("{e:#}");
+                self.stop_with_error(e);
+                bail!(msg);
+            }
+        }
+        result.context("error moving files")
+    }
+
     /// Get stats.
     pub fn stats(&self) -> TorrentStats {
         use stats::TorrentStatsState as S;
@@ -372,6 +587,7 @@ impl ManagedTorrent {
             uploaded_bytes: 0,
             finished: false,
             live: None,
+            trackers: self.info.trackers.status(),
         };
 
         self.with_state(|s| {
@@ -417,6 +633,24 @@ impl ManagedTorrent {
         })
     }
 
+    pub(crate) fn tracker_comms_stats(&self) -> TrackerCommsStats {
+        use stats::TorrentStatsState as TS;
+        use tracker_comms::TrackerCommsStatsState as S;
+
+        let stats = self.stats();
+        TrackerCommsStats {
+            downloaded_bytes: stats.progress_bytes,
+            total_bytes: stats.total_bytes,
+            uploaded_bytes: stats.uploaded_bytes,
+            torrent_state: match stats.state {
+                TS::Initializing => S::Initializing,
+                TS::Live => S::Live,
+                TS::Paused => S::Paused,
+                TS::Error => S::None,
+            },
+        }
+    }
+
     #[inline(never)]
     pub fn wait_until_initialized(&self) -> BoxFuture<'_, anyhow::Result<()>> {
         async move {
@@ -490,6 +724,39 @@ impl ManagedTorrent {
         };
 
         g.only_files = Some(only_files.iter().copied().collect());
+        if let Some(p) = g.file_priorities.as_mut() {
+            FilePriority::apply_only_files(p, only_files);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn update_file_priorities(
+        &self,
+        file_priorities: Vec<FilePriority>,
+    ) -> anyhow::Result<()> {
+        if file_priorities.len() != self.info.file_infos.len() {
+            anyhow::bail!(
+                "expected {} file priorities, got {}",
+                self.info.file_infos.len(),
+                file_priorities.len()
+            )
+        }
+
+        let mut g = self.locked.write();
+        match &mut g.state {
+            ManagedTorrentState::Initializing(_) => bail!("can't update initializing torrent"),
+            ManagedTorrentState::Error(_) => {}
+            ManagedTorrentState::None => {}
+            ManagedTorrentState::Paused(p) => {
+                p.update_file_priorities(file_priorities.clone())?;
+            }
+            ManagedTorrentState::Live(l) => {
+                l.update_file_priorities(file_priorities.clone())?;
+            }
+        };
+
+        g.only_files = Some(FilePriority::only_files(&file_priorities));
+        g.file_priorities = Some(file_priorities);
         Ok(())
     }
 }
@@ -501,13 +768,24 @@ pub(crate) struct ManagedTorrentBuilder {
     force_tracker_interval: Option<Duration>,
     peer_connect_timeout: Option<Duration>,
     peer_read_write_timeout: Option<Duration>,
+    peer_encryption: Option<EncryptionMode>,
+    listen_port: Option<u16>,
+    info_hash_v2: Option<Id32>,
+    piece_layers: Option<PieceLayers<ByteBufOwned>>,
     only_files: Option<Vec<usize>>,
-    trackers: Vec<String>,
+    file_priorities: Option<Vec<FilePriority>>,
+    trackers: TrackerList,
+    web_seeds: Vec<String>,
     peer_id: Option<Id20>,
     spawner: Option<BlockingSpawner>,
     allow_overwrite: bool,
     storage_factory: BoxStorageFactory,
     disk_writer: Option<DiskWorkQueueSender>,
+    fastresume: Option<FastResumeData>,
+    ratelimits: Option<Arc<RateLimits>>,
+    transports: OutgoingTransports,
+    upload_slots: usize,
+    events: Option<TorrentEventSender>,
 }
 
 impl ManagedTorrentBuilder {
@@ -524,13 +802,24 @@ impl ManagedTorrentBuilder {
             force_tracker_interval: None,
             peer_connect_timeout: None,
             peer_read_write_timeout: None,
+            peer_encryption: None,
+            listen_port: None,
+            info_hash_v2: None,
+            piece_layers: None,
             only_files: None,
+            file_priorities: None,
             trackers: Default::default(),
+            web_seeds: Default::default(),
             peer_id: None,
             allow_overwrite: false,
             output_folder,
             storage_factory,
             disk_writer: None,
+            fastresume: None,
+            ratelimits: None,
+            transports: Default::default(),
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            events: None,
         }
     }
 
@@ -539,11 +828,23 @@ impl ManagedTorrentBuilder {
         self
     }
 
-    pub fn trackers(&mut self, trackers: Vec<String>) -> &mut Self {
+    // Overrides only_files.
+    pub fn file_priorities(&mut self, file_priorities: Vec<FilePriority>) -> &mut Self {
+        self.only_files = Some(FilePriority::only_files(&file_priorities));
+        self.file_priorities = Some(file_priorities);
+        self
+    }
+
+    pub fn trackers(&mut self, trackers: TrackerList) -> &mut Self {
         self.trackers = trackers;
         self
     }
 
+    pub fn web_seeds(&mut self, web_seeds: Vec<String>) -> &mut Self {
+        self.web_seeds = web_seeds;
+        self
+    }
+
     pub fn force_tracker_interval(&mut self, force_tracker_interval: Duration) -> &mut Self {
         self.force_tracker_interval = Some(force_tracker_interval);
         self
@@ -574,11 +875,56 @@ impl ManagedTorrentBuilder {
         self
     }
 
+    pub fn peer_encryption(&mut self, mode: EncryptionMode) -> &mut Self {
+        self.peer_encryption = Some(mode);
+        self
+    }
+
+    pub fn listen_port(&mut self, port: u16) -> &mut Self {
+        self.listen_port = Some(port);
+        self
+    }
+
+    pub fn info_hash_v2(&mut self, value: Id32) -> &mut Self {
+        self.info_hash_v2 = Some(value);
+        self
+    }
+
+    pub fn piece_layers(&mut self, value: PieceLayers<ByteBufOwned>) -> &mut Self {
+        self.piece_layers = Some(value);
+        self
+    }
+
     pub fn disk_writer(&mut self, value: DiskWorkQueueSender) -> &mut Self {
         self.disk_writer = Some(value);
         self
     }
 
+    pub fn fastresume(&mut self, value: FastResumeData) -> &mut Self {
+        self.fastresume = Some(value);
+        self
+    }
+
+    pub fn ratelimits(&mut self, value: Arc<RateLimits>) -> &mut Self {
+        self.ratelimits = Some(value);
+        self
+    }
+
+    pub fn transports(&mut self, value: OutgoingTransports) -> &mut Self {
+        self.transports = value;
+        self
+    }
+
+    pub fn upload_slots(&mut self, value: usize) -> &mut Self {
+        self.upload_slots = value;
+        self
+    }
+
+    pub(crate) fn events(&mut self, value: TorrentEventSender) -> &mut Self {
+        self.events = Some(value);
+        self
+    }
+
     pub fn build(self, span: tracing::Span) -> anyhow::Result<ManagedTorrentHandle> {
         let lengths = Lengths::from_torrent(&self.info)?;
         let file_infos = self
@@ -593,13 +939,35 @@ impl ManagedTorrentBuilder {
                 })
             })
             .collect::<anyhow::Result<Vec<FileInfo>>>()?;
+        if let Some(p) = self.file_priorities.as_ref() {
+            if p.len() != file_infos.len() {
+                bail!(
+                    "expected {} file priorities, got {}",
+                    file_infos.len(),
+                    p.len()
+                );
+            }
+        }
+        let v2_piece_hashes = if self.info.is_v1() {
+            None
+        } else {
+            Some(
+                self.info
+                    .v2_piece_hashes(&lengths, self.piece_layers.as_ref())
+                    .context("error validating v2 piece layers")?,
+            )
+        };
 
         let info = Arc::new(ManagedTorrentInfo {
             span,
             file_infos,
             info: self.info,
             info_hash: self.info_hash,
-            trackers: self.trackers.into_iter().collect(),
+            info_hash_v2: self.info_hash_v2,
+            piece_layers: self.piece_layers,
+            v2_piece_hashes,
+            trackers: self.trackers,
+            web_seeds: self.web_seeds,
             spawner: self.spawner.unwrap_or_default(),
             peer_id: self.peer_id.unwrap_or_else(generate_peer_id),
             lengths,
@@ -607,20 +975,34 @@ impl ManagedTorrentBuilder {
                 force_tracker_interval: self.force_tracker_interval,
                 peer_connect_timeout: self.peer_connect_timeout,
                 peer_read_write_timeout: self.peer_read_write_timeout,
+                peer_encryption: self.peer_encryption,
+                listen_port: self.listen_port,
                 allow_overwrite: self.allow_overwrite,
-                output_folder: self.output_folder,
+                output_folder: RwLock::new(self.output_folder),
                 disk_write_queue: self.disk_writer,
+                transports: self.transports,
+                upload_slots: self.upload_slots,
             },
+            ratelimits: self
+                .ratelimits
+                .unwrap_or_else(|| Arc::new(RateLimits::new(Default::default()))),
+            events: self.events,
+            runtime: tokio::runtime::Handle::try_current()
+                .context("torrents must be created within a tokio runtime")?,
         });
 
         let initializing = Arc::new(TorrentStateInitializing::new(
             info.clone(),
             self.only_files.clone(),
+            self.file_priorities.clone(),
+            self.fastresume,
         ));
         Ok(Arc::new(ManagedTorrent {
             locked: RwLock::new(ManagedTorrentLocked {
                 state: ManagedTorrentState::Initializing(initializing),
                 only_files: self.only_files,
+                file_priorities: self.file_priorities,
+                moving: false,
             }),
             state_change_notify: Notify::new(),
             storage_factory: self.storage_factory,
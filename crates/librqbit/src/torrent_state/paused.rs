@@ -2,6 +2,7 @@ use std::{collections::HashSet, sync::Arc};
 
 use crate::{
     chunk_tracker::{ChunkTracker, HaveNeededSelected},
+    file_info::FilePriority,
     type_aliases::FileStorage,
 };
 
@@ -16,8 +17,27 @@ pub struct TorrentStatePaused {
 
 impl TorrentStatePaused {
     pub(crate) fn update_only_files(&mut self, only_files: &HashSet<usize>) -> anyhow::Result<()> {
-        self.chunk_tracker
-            .update_only_files(self.info.info.iter_file_lengths()?, only_files)?;
+        self.chunk_tracker.update_only_files(
+            self.info
+                .file_infos
+                .iter()
+                .map(|f| (f.offset_in_torrent, f.len)),
+            only_files,
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn update_file_priorities(
+        &mut self,
+        file_priorities: Vec<FilePriority>,
+    ) -> anyhow::Result<()> {
+        self.chunk_tracker.update_file_priorities(
+            self.info
+                .file_infos
+                .iter()
+                .map(|f| (f.offset_in_torrent, f.len)),
+            file_priorities,
+        )?;
         Ok(())
     }
 
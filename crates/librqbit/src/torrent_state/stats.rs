@@ -1,6 +1,7 @@
 use std::time::Duration;
 
 use serde::Serialize;
+use tracker_comms::TrackerStatus;
 
 use super::{live::stats::snapshot::StatsSnapshot, TorrentStateLive};
 use size_format::SizeFormatterBinary as SF;
@@ -76,6 +77,7 @@ pub struct TorrentStats {
     pub total_bytes: u64,
     pub finished: bool,
     pub live: Option<LiveStats>,
+    pub trackers: Vec<TrackerStatus>,
 }
 
 impl std::fmt::Display for TorrentStats {
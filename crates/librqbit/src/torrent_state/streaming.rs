@@ -1,17 +1,19 @@
 use std::{
-    collections::VecDeque,
     io::SeekFrom,
+    iter::Peekable,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
     },
     task::{Poll, Waker},
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use dashmap::DashMap;
 
 use librqbit_core::lengths::{CurrentPiece, Lengths, ValidPieceIndex};
+use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncSeek};
 use tracing::{debug, trace};
 
@@ -21,8 +23,83 @@ use super::ManagedTorrentHandle;
 
 type StreamId = usize;
 
-// 32 mb lookahead by default.
-const PER_STREAM_BUF_DEFAULT: u64 = 32 * 1024 * 1024;
+// Assumed read rate of a stream until we measure it: about 8 Mbit/s, a typical HD video.
+const DEFAULT_BYTES_PER_SEC: f64 = 1024. * 1024.;
+
+// How long to measure the read rate for before updating the estimate.
+const READ_RATE_WINDOW: Duration = Duration::from_secs(2);
+
+/// Tuning of how a stream downloads pieces ahead of the position it's read at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamOptions {
+    /// Download this many seconds of the stream ahead of the read position, at the rate the
+    /// stream is read.
+    pub read_ahead_secs: u32,
+    pub min_read_ahead_bytes: u64,
+    pub max_read_ahead_bytes: u64,
+    /// How many pieces from the read position are urgent. Urgent pieces are also requested
+    /// from faster peers than the one downloading them, whoever is first wins.
+    pub urgent_pieces: u32,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            read_ahead_secs: 30,
+            min_read_ahead_bytes: 4 * 1024 * 1024,
+            max_read_ahead_bytes: 256 * 1024 * 1024,
+            urgent_pieces: 2,
+        }
+    }
+}
+
+impl StreamOptions {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.min_read_ahead_bytes > self.max_read_ahead_bytes {
+            anyhow::bail!("min_read_ahead_bytes can't be larger than max_read_ahead_bytes");
+        }
+        Ok(())
+    }
+}
+
+// Estimates how fast a stream is read. The time spent waiting for pieces or seeking doesn't count,
+// otherwise a stalled stream would look slow and get less read-ahead.
+#[derive(Default)]
+struct ReadRate {
+    window_start: Option<Instant>,
+    window_bytes: u64,
+    bytes_per_sec: Option<f64>,
+}
+
+impl ReadRate {
+    fn on_read(&mut self, bytes: u64, now: Instant) {
+        let start = match self.window_start {
+            Some(s) => s,
+            None => {
+                self.window_start = Some(now);
+                return;
+            }
+        };
+        self.window_bytes += bytes;
+        let elapsed = now - start;
+        if elapsed < READ_RATE_WINDOW {
+            return;
+        }
+        let sample = self.window_bytes as f64 / elapsed.as_secs_f64();
+        self.bytes_per_sec = Some(match self.bytes_per_sec {
+            Some(r) => r * 0.7 + sample * 0.3,
+            None => sample,
+        });
+        self.window_start = Some(now);
+        self.window_bytes = 0;
+    }
+
+    fn pause(&mut self) {
+        self.window_start = None;
+        self.window_bytes = 0;
+    }
+}
 
 struct StreamState {
     file_id: usize,
@@ -30,6 +107,8 @@ struct StreamState {
     file_abs_offset: u64,
     position: u64,
     waker: Option<Waker>,
+    opts: StreamOptions,
+    rate: ReadRate,
 }
 
 impl StreamState {
@@ -37,13 +116,32 @@ impl StreamState {
         lengths.compute_current_piece(self.position, self.file_abs_offset)
     }
 
-    fn queue<'a>(&self, lengths: &'a Lengths) -> impl Iterator<Item = ValidPieceIndex> + 'a {
+    fn bytes_per_sec(&self) -> f64 {
+        self.rate.bytes_per_sec.unwrap_or(DEFAULT_BYTES_PER_SEC)
+    }
+
+    fn read_ahead_bytes(&self) -> u64 {
+        #[allow(clippy::cast_possible_truncation)]
+        let bytes = (self.bytes_per_sec() * self.opts.read_ahead_secs as f64) as u64;
+        bytes.clamp(
+            self.opts.min_read_ahead_bytes,
+            self.opts.max_read_ahead_bytes,
+        )
+    }
+
+    // The pieces to read next, with the number of seconds until the stream gets to them.
+    fn queue<'a>(&self, lengths: &'a Lengths) -> impl Iterator<Item = (f64, ValidPieceIndex)> + 'a {
         let start = self.file_abs_offset + self.position;
-        let end = (start + PER_STREAM_BUF_DEFAULT).min(self.file_abs_offset + self.file_len);
-        let dpl = lengths.default_piece_length();
-        let start_id = (start / dpl as u64).try_into().unwrap();
-        let end_id = end.div_ceil(dpl as u64).try_into().unwrap();
-        (start_id..end_id).filter_map(|i| lengths.validate_piece_index(i))
+        let end = (start + self.read_ahead_bytes()).min(self.file_abs_offset + self.file_len);
+        let dpl = lengths.default_piece_length() as u64;
+        let bytes_per_sec = self.bytes_per_sec();
+        let start_id = (start / dpl).try_into().unwrap();
+        let end_id = end.div_ceil(dpl).try_into().unwrap();
+        (start_id..end_id).filter_map(move |i| {
+            let piece = lengths.validate_piece_index(i)?;
+            let deadline = (i as u64 * dpl).saturating_sub(start) as f64 / bytes_per_sec;
+            Some((deadline, piece))
+        })
     }
 }
 
@@ -62,39 +160,61 @@ impl TorrentStreams {
         if let Some(mut s) = self.streams.get_mut(&stream_id) {
             let vm = s.value_mut();
             vm.waker = Some(waker);
+            vm.rate.pause();
         }
     }
 
-    // Interleave 1st, 2nd etc pieces from each active stream in turn until they get 1/10th of the file .
+    // Pieces of all active streams, the ones needed the soonest first. A stream that was just
+    // seeked, or is read faster than the others, gets its pieces ahead of theirs.
     pub(crate) fn iter_next_pieces<'a>(
         &'a self,
         lengths: &'a Lengths,
     ) -> impl Iterator<Item = ValidPieceIndex> + 'a {
-        struct Interleave<I> {
-            all: VecDeque<I>,
+        struct MergeByDeadline<I: Iterator> {
+            all: Vec<Peekable<I>>,
         }
 
-        impl<I: Iterator<Item = ValidPieceIndex>> Iterator for Interleave<I> {
+        impl<I: Iterator<Item = (f64, ValidPieceIndex)>> Iterator for MergeByDeadline<I> {
             type Item = ValidPieceIndex;
 
             fn next(&mut self) -> Option<Self::Item> {
-                while let Some(mut it) = self.all.pop_front() {
-                    if let Some(piece) = it.next() {
-                        self.all.push_back(it);
-                        return Some(piece);
+                // On ties, the first stream wins, so the streams take turns.
+                let mut best: Option<(usize, f64)> = None;
+                for (idx, it) in self.all.iter_mut().enumerate() {
+                    if let Some((deadline, _)) = it.peek() {
+                        if best.map_or(true, |(_, d)| *deadline < d) {
+                            best = Some((idx, *deadline));
+                        }
                     }
                 }
-                None
+                self.all[best?.0].next().map(|(_, piece)| piece)
             }
         }
 
-        let mut all: Vec<_> = self.streams.iter().map(|s| s.queue(lengths)).collect();
+        let mut all: Vec<_> = self
+            .streams
+            .iter()
+            .map(|s| s.queue(lengths).peekable())
+            .collect();
 
         // Shuffle to decrease determinism and make queueing fairer.
         use rand::seq::SliceRandom;
         all.shuffle(&mut rand::thread_rng());
 
-        Interleave { all: all.into() }
+        MergeByDeadline { all }
+    }
+
+    // The pieces at the read position of each stream, that playback will stall on.
+    pub(crate) fn urgent_pieces(&self, lengths: &Lengths) -> Vec<ValidPieceIndex> {
+        self.streams
+            .iter()
+            .flat_map(|s| {
+                s.queue(lengths)
+                    .take(s.opts.urgent_pieces as usize)
+                    .map(|(_, piece)| piece)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
     }
 
     pub(crate) fn wake_streams_on_piece_completed(
@@ -312,6 +432,15 @@ impl ManagedTorrent {
     }
 
     pub fn stream(self: Arc<Self>, file_id: usize) -> anyhow::Result<FileStream> {
+        self.stream_with_options(file_id, StreamOptions::default())
+    }
+
+    pub fn stream_with_options(
+        self: Arc<Self>,
+        file_id: usize,
+        opts: StreamOptions,
+    ) -> anyhow::Result<FileStream> {
+        opts.validate()?;
         let (fd_len, fd_offset) =
             self.with_storage_and_file(file_id, |_fd, fi| (fi.len, fi.offset_in_torrent))?;
         let streams = self.streams()?;
@@ -334,6 +463,8 @@ impl ManagedTorrent {
                 waker: None,
                 file_len: fd_len,
                 file_abs_offset: fd_offset,
+                opts,
+                rate: Default::default(),
             },
         );
 
@@ -349,20 +480,46 @@ impl FileStream {
     }
 
     fn advance(&mut self, diff: u64) {
-        self.set_position(self.position + diff)
+        self.position += diff;
+        let mut s = self.streams.streams.get_mut(&self.stream_id).unwrap();
+        let s = s.value_mut();
+        s.position = self.position;
+        s.rate.on_read(diff, Instant::now());
     }
 
     fn set_position(&mut self, new_pos: u64) {
         self.position = new_pos;
-        self.streams
-            .streams
-            .get_mut(&self.stream_id)
-            .unwrap()
-            .value_mut()
-            .position = new_pos;
+        let mut s = self.streams.streams.get_mut(&self.stream_id).unwrap();
+        let s = s.value_mut();
+        s.position = new_pos;
+        s.rate.pause();
     }
 
     pub fn len(&self) -> u64 {
         self.file_len
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::ReadRate;
+
+    #[test]
+    fn test_read_rate() {
+        let mut r = ReadRate::default();
+        let t = Instant::now();
+        r.on_read(1000, t);
+        r.on_read(1000, t + Duration::from_secs(1));
+        assert_eq!(r.bytes_per_sec, None);
+        r.on_read(1000, t + Duration::from_secs(2));
+        assert_eq!(r.bytes_per_sec, Some(1000.));
+
+        // A long wait for a piece doesn't make the stream look slow.
+        r.pause();
+        r.on_read(1000, t + Duration::from_secs(60));
+        r.on_read(4000, t + Duration::from_secs(62));
+        assert_eq!(r.bytes_per_sec, Some(1000. * 0.7 + 2000. * 0.3));
+    }
+}
@@ -10,7 +10,8 @@ pub type PeerHandle = SocketAddr;
 pub type PeerStream = BoxStream<'static, SocketAddr>;
 pub type FileInfos = Vec<FileInfo>;
 pub(crate) type FileStorage = Box<dyn TorrentStorage>;
-pub(crate) type FilePriorities = Vec<usize>;
+// File ids in the order they should be downloaded, for files of the same priority.
+pub(crate) type FileOrder = Vec<usize>;
 
 pub(crate) type DiskWorkQueueItem = Box<dyn FnOnce() + Send + Sync>;
 pub(crate) type DiskWorkQueueSender = tokio::sync::mpsc::Sender<DiskWorkQueueItem>;
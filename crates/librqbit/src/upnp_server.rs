@@ -0,0 +1,316 @@
+// Exposes the torrents of a session as a UPnP MediaServer, so that TVs and other DLNA clients on
+// the LAN can browse and play them.
+//
+// Torrents are containers under the root, with their media files as items. Items point to the
+// HTTP API stream endpoint, so this is served by the HTTP API under UPNP_HTTP_PREFIX.
+
+use std::{path::Path, sync::Arc};
+
+use axum::{
+    extract::State,
+    response::IntoResponse,
+    routing::{get, post},
+    Router,
+};
+use http::{HeaderMap, StatusCode};
+use librqbit_upnp::{
+    media_server::{
+        BrowseObject, Container, ContentDirectoryBrowser, Item, MediaServer, MediaServerOptions,
+        SoapFault, DEVICE_TYPE_MEDIA_SERVER, ROOT_OBJECT_ID, SERVICE_TYPE_CONNECTION_MANAGER,
+        SERVICE_TYPE_CONTENT_DIRECTORY,
+    },
+    ssdp_server::{SsdpServer, SsdpServerOptions},
+};
+use sha1w::{ISha1, Sha1};
+use tracing::{debug, error_span};
+
+use crate::{
+    http_api::{base_url, stream_url},
+    mime::guess_mime_type,
+    session::TorrentId,
+    torrent_state::ManagedTorrentHandle,
+    Session,
+};
+
+pub(crate) const UPNP_HTTP_PREFIX: &str = "/upnp";
+
+#[derive(Debug, Clone)]
+pub struct UpnpServerOptions {
+    /// The name TVs show for this server.
+    pub friendly_name: String,
+}
+
+impl Default for UpnpServerOptions {
+    fn default() -> Self {
+        Self {
+            friendly_name: "rqbit".to_owned(),
+        }
+    }
+}
+
+enum ObjectId {
+    Root,
+    Torrent(TorrentId),
+    File(TorrentId, usize),
+}
+
+// Torrents are "t<id>", their files "t<id>/<file id>".
+impl ObjectId {
+    fn parse(id: &str) -> Option<Self> {
+        if id == ROOT_OBJECT_ID {
+            return Some(ObjectId::Root);
+        }
+        let id = id.strip_prefix('t')?;
+        match id.split_once('/') {
+            None => Some(ObjectId::Torrent(id.parse().ok()?)),
+            Some((t, f)) => Some(ObjectId::File(t.parse().ok()?, f.parse().ok()?)),
+        }
+    }
+}
+
+// Players only care about what they can play.
+fn playable_mime_type(filename: &Path) -> Option<&'static str> {
+    guess_mime_type(filename)
+        .filter(|m| m.starts_with("video/") || m.starts_with("audio/") || m.starts_with("image/"))
+}
+
+struct TorrentsBrowser {
+    session: Arc<Session>,
+}
+
+impl TorrentsBrowser {
+    fn container(&self, id: TorrentId, mgr: &ManagedTorrentHandle) -> BrowseObject {
+        let info = mgr.info();
+        BrowseObject::Container(Container {
+            id: format!("t{id}"),
+            parent_id: ROOT_OBJECT_ID.to_owned(),
+            title: info
+                .info
+                .name
+                .as_ref()
+                .map(|n| n.to_string())
+                .unwrap_or_else(|| info.info_hash.as_string()),
+            child_count: Some(
+                info.file_infos
+                    .iter()
+                    .filter(|fi| playable_mime_type(&fi.relative_filename).is_some())
+                    .count(),
+            ),
+        })
+    }
+
+    fn item(
+        &self,
+        id: TorrentId,
+        mgr: &ManagedTorrentHandle,
+        file_id: usize,
+        base_url: &str,
+    ) -> Option<BrowseObject> {
+        let fi = mgr.info().file_infos.get(file_id)?;
+        let mime_type = playable_mime_type(&fi.relative_filename)?;
+        Some(BrowseObject::Item(Item {
+            id: format!("t{id}/{file_id}"),
+            parent_id: format!("t{id}"),
+            title: fi.relative_filename.to_string_lossy().into_owned(),
+            mime_type: mime_type.to_owned(),
+            url: stream_url(base_url, id, file_id, &fi.relative_filename),
+            size: fi.len,
+        }))
+    }
+}
+
+impl ContentDirectoryBrowser for TorrentsBrowser {
+    fn browse_direct_children(&self, object_id: &str, base_url: &str) -> Option<Vec<BrowseObject>> {
+        match ObjectId::parse(object_id)? {
+            ObjectId::Root => Some(self.session.with_torrents(|torrents| {
+                torrents.map(|(id, mgr)| self.container(id, mgr)).collect()
+            })),
+            ObjectId::Torrent(id) => {
+                let mgr = self.session.get(id)?;
+                let files = mgr.info().file_infos.len();
+                Some(
+                    (0..files)
+                        .filter_map(|file_id| self.item(id, &mgr, file_id, base_url))
+                        .collect(),
+                )
+            }
+            ObjectId::File(..) => Some(Vec::new()),
+        }
+    }
+
+    fn browse_metadata(&self, object_id: &str, base_url: &str) -> Option<BrowseObject> {
+        match ObjectId::parse(object_id)? {
+            ObjectId::Root => Some(BrowseObject::Container(Container {
+                id: ROOT_OBJECT_ID.to_owned(),
+                parent_id: "-1".to_owned(),
+                title: "rqbit".to_owned(),
+                child_count: Some(self.session.with_torrents(|torrents| torrents.count())),
+            })),
+            ObjectId::Torrent(id) => Some(self.container(id, &self.session.get(id)?)),
+            ObjectId::File(id, file_id) => self.item(id, &self.session.get(id)?, file_id, base_url),
+        }
+    }
+}
+
+type TorrentsMediaServer = MediaServer<TorrentsBrowser>;
+
+// Stays the same across restarts, so that clients don't see a new server every time. Servers on
+// the same LAN need different names.
+fn make_udn(friendly_name: &str) -> String {
+    let mut hash = Sha1::new();
+    hash.update(friendly_name.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hash.finish()[..16]);
+    format!(
+        "uuid:{}",
+        uuid::Builder::from_random_bytes(bytes).into_uuid()
+    )
+}
+
+fn soap_result(r: Result<String, SoapFault>) -> impl IntoResponse {
+    let (status, body) = match r {
+        Ok(body) => (StatusCode::OK, body),
+        Err(fault) => {
+            debug!(?fault, "UPnP request failed");
+            (StatusCode::INTERNAL_SERVER_ERROR, fault.to_xml())
+        }
+    };
+    (
+        status,
+        [("Content-Type", "text/xml; charset=\"utf-8\"")],
+        body,
+    )
+}
+
+fn soap_action(headers: &HeaderMap) -> &str {
+    headers
+        .get("soapaction")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+}
+
+async fn description(State(server): State<Arc<TorrentsMediaServer>>) -> impl IntoResponse {
+    (
+        [("Content-Type", "text/xml; charset=\"utf-8\"")],
+        server.device_description(),
+    )
+}
+
+async fn content_directory_scpd(
+    State(server): State<Arc<TorrentsMediaServer>>,
+) -> impl IntoResponse {
+    (
+        [("Content-Type", "text/xml; charset=\"utf-8\"")],
+        server.content_directory_scpd(),
+    )
+}
+
+async fn connection_manager_scpd(
+    State(server): State<Arc<TorrentsMediaServer>>,
+) -> impl IntoResponse {
+    (
+        [("Content-Type", "text/xml; charset=\"utf-8\"")],
+        server.connection_manager_scpd(),
+    )
+}
+
+async fn content_directory_control(
+    State(server): State<Arc<TorrentsMediaServer>>,
+    headers: HeaderMap,
+    body: String,
+) -> impl IntoResponse {
+    // Stream URLs use the address the client reached us at.
+    soap_result(server.handle_content_directory(soap_action(&headers), &body, &base_url(&headers)))
+}
+
+async fn connection_manager_control(
+    State(server): State<Arc<TorrentsMediaServer>>,
+    headers: HeaderMap,
+    body: String,
+) -> impl IntoResponse {
+    soap_result(server.handle_connection_manager(soap_action(&headers), &body))
+}
+
+pub(crate) struct UpnpServer {
+    server: Arc<TorrentsMediaServer>,
+}
+
+impl UpnpServer {
+    pub fn new(session: Arc<Session>, opts: UpnpServerOptions) -> Self {
+        let udn = make_udn(&opts.friendly_name);
+        let server = MediaServer::new(
+            MediaServerOptions {
+                friendly_name: opts.friendly_name,
+                udn,
+                http_prefix: UPNP_HTTP_PREFIX.to_owned(),
+            },
+            TorrentsBrowser { session },
+        );
+        Self {
+            server: Arc::new(server),
+        }
+    }
+
+    /// The HTTP routes, to be nested under UPNP_HTTP_PREFIX.
+    pub fn router<S>(&self) -> Router<S> {
+        Router::new()
+            .route("/description.xml", get(description))
+            .route("/content_directory.xml", get(content_directory_scpd))
+            .route("/connection_manager.xml", get(connection_manager_scpd))
+            .route(
+                "/content_directory/control",
+                post(content_directory_control),
+            )
+            .route(
+                "/connection_manager/control",
+                post(connection_manager_control),
+            )
+            .with_state(self.server.clone())
+    }
+
+    /// Advertises the server on the LAN, pointing to the HTTP API on the given port.
+    pub async fn spawn_ssdp(&self, http_port: u16) -> anyhow::Result<()> {
+        let mut opts = SsdpServerOptions::new(
+            self.server.udn().to_owned(),
+            DEVICE_TYPE_MEDIA_SERVER.to_owned(),
+            http_port,
+            self.server.description_path(),
+        );
+        opts.service_types = vec![
+            SERVICE_TYPE_CONTENT_DIRECTORY.to_owned(),
+            SERVICE_TYPE_CONNECTION_MANAGER.to_owned(),
+        ];
+        let ssdp = SsdpServer::new(opts).await?;
+        crate::spawn_utils::spawn(
+            "ssdp_server",
+            error_span!("ssdp_server"),
+            ssdp.run_forever(),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{make_udn, ObjectId};
+
+    #[test]
+    fn test_make_udn() {
+        let udn = make_udn("rqbit");
+        assert!(udn.starts_with("uuid:"));
+        assert_eq!(udn, make_udn("rqbit"));
+        assert_ne!(udn, make_udn("rqbit 2"));
+    }
+
+    #[test]
+    fn test_parse_object_id() {
+        assert!(matches!(ObjectId::parse("0"), Some(ObjectId::Root)));
+        assert!(matches!(ObjectId::parse("t0"), Some(ObjectId::Torrent(0))));
+        assert!(matches!(
+            ObjectId::parse("t3/5"),
+            Some(ObjectId::File(3, 5))
+        ));
+        assert!(ObjectId::parse("3").is_none());
+        assert!(ObjectId::parse("t3/x").is_none());
+    }
+}
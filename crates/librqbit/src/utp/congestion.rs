@@ -0,0 +1,195 @@
+// LEDBAT congestion control (RFC 6817), as used by uTP.
+//
+// The window grows while the measured one-way queuing delay is below the target, and
+// shrinks when it's above. This makes uTP yield to other traffic on the same link.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use super::MAX_PAYLOAD;
+
+const TARGET_DELAY_US: i64 = 100_000;
+const MAX_CWND_INCREASE_BYTES_PER_RTT: f64 = 3000.;
+const MIN_CWND: u32 = 2 * MAX_PAYLOAD as u32;
+const MAX_CWND: u32 = 4 * 1024 * 1024;
+const INITIAL_CWND: u32 = 4 * MAX_PAYLOAD as u32;
+
+// Base delay is the minimum over this many of the latest intervals.
+const BASE_DELAY_HISTORY: usize = 2;
+const BASE_DELAY_INTERVAL: Duration = Duration::from_secs(60);
+
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(60);
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+
+// Timestamps are microseconds that wrap around, so is the one-way delay.
+fn wrapping_min(a: u32, b: u32) -> u32 {
+    if (a.wrapping_sub(b) as i32) < 0 {
+        a
+    } else {
+        b
+    }
+}
+
+pub struct Ledbat {
+    cwnd: u32,
+    // Per-interval minimums of the one-way delay. These include the clock offset between
+    // the hosts, which cancels out when subtracting.
+    base_delays: VecDeque<u32>,
+    base_delay_interval_started: Instant,
+}
+
+impl Ledbat {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            cwnd: INITIAL_CWND,
+            base_delays: VecDeque::new(),
+            base_delay_interval_started: now,
+        }
+    }
+
+    pub fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn base_delay(&self) -> Option<u32> {
+        self.base_delays.iter().copied().reduce(wrapping_min)
+    }
+
+    fn update_base_delay(&mut self, now: Instant, delay_us: u32) {
+        if self.base_delays.is_empty()
+            || now - self.base_delay_interval_started > BASE_DELAY_INTERVAL
+        {
+            self.base_delay_interval_started = now;
+            self.base_delays.push_back(delay_us);
+            if self.base_delays.len() > BASE_DELAY_HISTORY {
+                self.base_delays.pop_front();
+            }
+        } else if let Some(last) = self.base_delays.back_mut() {
+            *last = wrapping_min(*last, delay_us);
+        }
+    }
+
+    // Called when new data is acked. "delay_us" is the one-way delay the peer measured for
+    // our packets, 0 if unknown.
+    pub fn on_ack(&mut self, now: Instant, bytes_acked: u32, delay_us: u32) {
+        if delay_us == 0 {
+            return;
+        }
+        self.update_base_delay(now, delay_us);
+        let base_delay = self.base_delay().unwrap_or(delay_us);
+        let queuing_delay = delay_us.wrapping_sub(base_delay) as i32 as i64;
+        let off_target = (TARGET_DELAY_US - queuing_delay) as f64 / TARGET_DELAY_US as f64;
+        let gain =
+            MAX_CWND_INCREASE_BYTES_PER_RTT * off_target * bytes_acked as f64 / self.cwnd as f64;
+        self.cwnd = (self.cwnd as f64 + gain).clamp(MIN_CWND as f64, MAX_CWND as f64) as u32;
+    }
+
+    pub fn on_loss(&mut self) {
+        self.cwnd = (self.cwnd / 2).max(MIN_CWND);
+    }
+
+    pub fn on_timeout(&mut self) {
+        self.cwnd = MIN_CWND;
+    }
+}
+
+// Retransmission timeout calculation, same as TCP (RFC 6298).
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+}
+
+impl RttEstimator {
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    pub fn on_sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        self.rto = (self.srtt.unwrap_or_default() + self.rttvar * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    pub fn on_timeout(&mut self) {
+        self.rto = (self.rto * 2).min(MAX_RTO);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Ledbat, RttEstimator, MIN_CWND, MIN_RTO};
+
+    #[test]
+    fn test_ledbat_grows_below_target_and_shrinks_above() {
+        let now = Instant::now();
+        let mut l = Ledbat::new(now);
+        let initial = l.cwnd();
+
+        // Establish the base delay, then ack with no queuing.
+        for _ in 0..100 {
+            l.on_ack(now, 1400, 50_000);
+        }
+        let grown = l.cwnd();
+        assert!(grown > initial);
+
+        // Queuing delay of 200ms is twice the target.
+        for _ in 0..1000 {
+            l.on_ack(now, 1400, 250_000);
+        }
+        assert!(l.cwnd() < grown);
+
+        l.on_loss();
+        l.on_timeout();
+        assert_eq!(l.cwnd(), MIN_CWND);
+    }
+
+    #[test]
+    fn test_ledbat_ignores_clock_offset() {
+        let now = Instant::now();
+        let mut l = Ledbat::new(now);
+        let initial = l.cwnd();
+        // The peer's clock is far ahead of ours, and the value wraps around.
+        for _ in 0..100 {
+            l.on_ack(now, 1400, u32::MAX - 10);
+            l.on_ack(now, 1400, 5);
+        }
+        assert!(l.cwnd() > initial);
+    }
+
+    #[test]
+    fn test_rto() {
+        let mut r = RttEstimator::default();
+        r.on_sample(Duration::from_millis(10));
+        assert_eq!(r.rto(), MIN_RTO);
+        r.on_sample(Duration::from_secs(2));
+        assert!(r.rto() > Duration::from_secs(2));
+        let before = r.rto();
+        r.on_timeout();
+        assert_eq!(r.rto(), before * 2);
+    }
+}
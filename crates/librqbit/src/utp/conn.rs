@@ -0,0 +1,600 @@
+// The state of a single uTP connection.
+//
+// This is only protocol logic, packets are sent through "Transmit" and everything is driven
+// by the socket: incoming packets, periodic ticks and reads/writes from UtpStream.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::OnceLock,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use tokio::net::UdpSocket;
+use tracing::trace;
+
+use super::{
+    congestion::{Ledbat, RttEstimator},
+    packet::{seq_less_than, Header, Packet, PacketType, HEADER_LEN},
+    MAX_PAYLOAD,
+};
+
+const RECV_WINDOW: usize = 1024 * 1024;
+// Drop data past this even if the peer ignores our window.
+const MAX_RECV_BUF: usize = 2 * RECV_WINDOW;
+// How far ahead of ack_nr we buffer out-of-order packets.
+const MAX_OUT_OF_ORDER: u16 = 4096;
+const DUPLICATE_ACKS_BEFORE_RESEND: u32 = 3;
+const MAX_SYN_TRANSMISSIONS: u32 = 3;
+const MAX_TRANSMISSIONS: u32 = 8;
+// How often to check if the peer opened its receive window.
+const ZERO_WINDOW_PROBE_INTERVAL: Duration = Duration::from_secs(1);
+// How long to keep trying to deliver the remaining data and FIN after the stream was dropped.
+const LINGER: Duration = Duration::from_secs(10);
+
+// Microsecond timestamps for packet headers. Only differences between them matter.
+fn now_us() -> u32 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_micros() as u32
+}
+
+pub(crate) struct Transmit<'a> {
+    pub udp: &'a UdpSocket,
+    pub addr: SocketAddr,
+    #[cfg(test)]
+    pub loss_probability: f64,
+}
+
+impl Transmit<'_> {
+    fn send(&self, header: &Header, payload: &[u8]) {
+        let mut buf = [0u8; HEADER_LEN + MAX_PAYLOAD];
+        let (hbuf, _) = buf.split_at_mut(HEADER_LEN);
+        header.serialize(hbuf.try_into().unwrap());
+        buf[HEADER_LEN..HEADER_LEN + payload.len()].copy_from_slice(payload);
+
+        // Poor man's fault injection for tests.
+        #[cfg(test)]
+        {
+            use rand::Rng;
+            if rand::thread_rng().gen_bool(self.loss_probability) {
+                return;
+            }
+        }
+
+        // UDP is lossy anyway, if the socket buffer is full this will be retransmitted.
+        if let Err(e) = self
+            .udp
+            .try_send_to(&buf[..HEADER_LEN + payload.len()], self.addr)
+        {
+            trace!(addr=?self.addr, "error sending uTP packet: {e:#}");
+        }
+    }
+
+    pub fn send_reset(&self, connection_id: u16, ack_nr: u16) {
+        self.send(
+            &Header {
+                packet_type: PacketType::Reset,
+                connection_id,
+                timestamp_us: now_us(),
+                timestamp_diff_us: 0,
+                wnd_size: 0,
+                seq_nr: 0,
+                ack_nr,
+            },
+            &[],
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    SynSent,
+    Connected,
+}
+
+struct SentPacket {
+    packet_type: PacketType,
+    seq_nr: u16,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+    // Acked through a selective ack, but not cumulatively yet.
+    sacked: bool,
+    // Considered lost after a timeout, waiting to be sent again.
+    need_resend: bool,
+}
+
+impl SentPacket {
+    fn counts_in_flight(&self) -> bool {
+        !self.sacked && !self.need_resend
+    }
+}
+
+pub(crate) struct ConnState {
+    status: Status,
+    error: Option<io::ErrorKind>,
+    recv_id: u16,
+    send_id: u16,
+
+    // Next sequence number to send.
+    seq_nr: u16,
+    // Last sequence number received in order.
+    ack_nr: u16,
+
+    in_flight: VecDeque<SentPacket>,
+    bytes_in_flight: usize,
+    peer_wnd: u32,
+    ledbat: Ledbat,
+    rtt: RttEstimator,
+    duplicate_acks: u32,
+    fin_sent: bool,
+    next_zero_window_probe: Option<Instant>,
+    allow_zero_window_probe: bool,
+
+    recv_buf: VecDeque<u8>,
+    out_of_order: HashMap<u16, Vec<u8>>,
+    // Sequence number of the peer's FIN, once we see it.
+    eof_seq_nr: Option<u16>,
+    eof: bool,
+    // The latest one-way delay from the peer to us, sent back with each packet.
+    reply_micro: u32,
+
+    // When the stream handle was dropped.
+    closed_at: Option<Instant>,
+
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    connect_waker: Option<Waker>,
+}
+
+impl ConnState {
+    fn new(status: Status, recv_id: u16, send_id: u16, ack_nr: u16) -> Self {
+        Self {
+            status,
+            error: None,
+            recv_id,
+            send_id,
+            seq_nr: rand::random(),
+            ack_nr,
+            in_flight: Default::default(),
+            bytes_in_flight: 0,
+            peer_wnd: RECV_WINDOW as u32,
+            ledbat: Ledbat::new(Instant::now()),
+            rtt: Default::default(),
+            duplicate_acks: 0,
+            fin_sent: false,
+            next_zero_window_probe: None,
+            allow_zero_window_probe: false,
+            recv_buf: Default::default(),
+            out_of_order: Default::default(),
+            eof_seq_nr: None,
+            eof: false,
+            reply_micro: 0,
+            closed_at: None,
+            read_waker: None,
+            write_waker: None,
+            connect_waker: None,
+        }
+    }
+
+    pub fn new_outgoing(recv_id: u16) -> Self {
+        Self::new(Status::SynSent, recv_id, recv_id.wrapping_add(1), 0)
+    }
+
+    pub fn new_incoming(syn: &Header) -> Self {
+        Self::new(
+            Status::Connected,
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+            syn.seq_nr,
+        )
+    }
+
+    pub fn send_id(&self) -> u16 {
+        self.send_id
+    }
+
+    fn recv_window(&self) -> u32 {
+        RECV_WINDOW.saturating_sub(self.recv_buf.len()) as u32
+    }
+
+    fn header(&self, packet_type: PacketType, seq_nr: u16) -> Header {
+        Header {
+            packet_type,
+            connection_id: match packet_type {
+                PacketType::Syn => self.recv_id,
+                _ => self.send_id,
+            },
+            timestamp_us: now_us(),
+            timestamp_diff_us: self.reply_micro,
+            wnd_size: self.recv_window(),
+            seq_nr,
+            ack_nr: self.ack_nr,
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for w in [
+            self.read_waker.take(),
+            self.write_waker.take(),
+            self.connect_waker.take(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            w.wake()
+        }
+    }
+
+    fn set_error(&mut self, kind: io::ErrorKind) {
+        if self.error.is_none() {
+            self.error = Some(kind);
+        }
+        self.wake_all();
+    }
+
+    pub fn send_state(&self, tx: &Transmit) {
+        tx.send(&self.header(PacketType::State, self.seq_nr), &[]);
+    }
+
+    fn send_new(&mut self, tx: &Transmit, packet_type: PacketType, payload: &[u8]) {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        tx.send(&self.header(packet_type, seq_nr), payload);
+        self.bytes_in_flight += payload.len();
+        self.in_flight.push_back(SentPacket {
+            packet_type,
+            seq_nr,
+            payload: payload.to_owned(),
+            sent_at: Instant::now(),
+            transmissions: 1,
+            sacked: false,
+            need_resend: false,
+        });
+    }
+
+    fn resend(&self, tx: &Transmit, idx: usize) {
+        let p = &self.in_flight[idx];
+        tx.send(&self.header(p.packet_type, p.seq_nr), &p.payload);
+    }
+
+    pub fn send_syn(&mut self, tx: &Transmit) {
+        self.send_new(tx, PacketType::Syn, &[]);
+    }
+
+    // Resend packets considered lost after a timeout, as the window allows.
+    fn flush_resends(&mut self, tx: &Transmit, now: Instant) {
+        let window = self.ledbat.cwnd().min(self.peer_wnd) as usize;
+        for idx in 0..self.in_flight.len() {
+            let p = &self.in_flight[idx];
+            if !p.need_resend {
+                continue;
+            }
+            if self.bytes_in_flight > 0 && self.bytes_in_flight + p.payload.len() > window {
+                break;
+            }
+            self.resend(tx, idx);
+            let p = &mut self.in_flight[idx];
+            p.need_resend = false;
+            p.transmissions += 1;
+            p.sent_at = now;
+            self.bytes_in_flight += p.payload.len();
+        }
+    }
+
+    fn has_pending_resends(&self) -> bool {
+        self.in_flight.iter().any(|p| p.need_resend)
+    }
+
+    fn on_ack(&mut self, tx: &Transmit, now: Instant, packet: &Packet) {
+        let ack_nr = packet.header.ack_nr;
+        let mut acked_bytes = 0;
+        let mut acked_any = false;
+
+        while let Some(p) = self.in_flight.front() {
+            if seq_less_than(ack_nr, p.seq_nr) {
+                break;
+            }
+            let p = self.in_flight.pop_front().unwrap();
+            if p.counts_in_flight() {
+                self.bytes_in_flight -= p.payload.len();
+            }
+            if !p.sacked {
+                acked_bytes += p.payload.len();
+            }
+            // Karn's algorithm: samples from retransmitted packets are ambiguous.
+            if p.transmissions == 1 {
+                self.rtt.on_sample(now - p.sent_at);
+            }
+            acked_any = true;
+        }
+
+        // Bit N of the selective ack mask refers to ack_nr + 2 + N.
+        if let Some(mask) = packet.selective_ack {
+            for p in self.in_flight.iter_mut() {
+                let bit = p.seq_nr.wrapping_sub(ack_nr).wrapping_sub(2) as usize;
+                if p.sacked || bit >= mask.len() * 8 || mask[bit / 8] & (1 << (bit % 8)) == 0 {
+                    continue;
+                }
+                if p.counts_in_flight() {
+                    self.bytes_in_flight -= p.payload.len();
+                }
+                p.sacked = true;
+                acked_bytes += p.payload.len();
+            }
+        }
+
+        if acked_bytes > 0 {
+            self.ledbat
+                .on_ack(now, acked_bytes as u32, packet.header.timestamp_diff_us);
+        }
+
+        if acked_any {
+            self.duplicate_acks = 0;
+        } else if packet.header.packet_type == PacketType::State && !self.in_flight.is_empty() {
+            self.duplicate_acks += 1;
+            if self.duplicate_acks == DUPLICATE_ACKS_BEFORE_RESEND {
+                // Fast retransmit: the peer keeps receiving packets past the first one we haven't
+                // got an ack for, so it's likely lost.
+                self.duplicate_acks = 0;
+                self.ledbat.on_loss();
+                if self.in_flight[0].counts_in_flight() {
+                    trace!(seq_nr = self.in_flight[0].seq_nr, "fast retransmit");
+                    self.resend(tx, 0);
+                    self.in_flight[0].transmissions += 1;
+                    self.in_flight[0].sent_at = now;
+                }
+            }
+        }
+
+        self.flush_resends(tx, now);
+        if let Some(w) = self.write_waker.take() {
+            w.wake();
+        }
+    }
+
+    fn deliver(&mut self, seq_nr: u16, payload: &[u8]) {
+        self.ack_nr = seq_nr;
+        if self.eof_seq_nr == Some(seq_nr) {
+            self.eof = true;
+        } else {
+            self.recv_buf.extend(payload);
+        }
+    }
+
+    fn on_data(&mut self, seq_nr: u16, payload: &[u8]) {
+        if self.eof {
+            return;
+        }
+        if seq_nr == self.ack_nr.wrapping_add(1) {
+            if self.recv_buf.len() + payload.len() > MAX_RECV_BUF {
+                trace!("receive buffer full, dropping packet");
+                return;
+            }
+            self.deliver(seq_nr, payload);
+            while let Some(p) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+                self.deliver(self.ack_nr.wrapping_add(1), &p);
+            }
+        } else if seq_less_than(self.ack_nr, seq_nr)
+            && seq_nr.wrapping_sub(self.ack_nr) <= MAX_OUT_OF_ORDER
+        {
+            self.out_of_order
+                .entry(seq_nr)
+                .or_insert_with(|| payload.to_owned());
+        }
+        if let Some(w) = self.read_waker.take() {
+            w.wake();
+        }
+    }
+
+    pub fn on_packet(&mut self, tx: &Transmit, packet: &Packet) {
+        let now = Instant::now();
+        let h = &packet.header;
+        self.reply_micro = now_us().wrapping_sub(h.timestamp_us);
+        self.peer_wnd = h.wnd_size;
+
+        match h.packet_type {
+            PacketType::Reset => {
+                let kind = match self.status {
+                    Status::SynSent => io::ErrorKind::ConnectionRefused,
+                    Status::Connected => io::ErrorKind::ConnectionReset,
+                };
+                self.set_error(kind);
+                return;
+            }
+            // Our reply to their SYN was lost.
+            PacketType::Syn => {
+                if self.status == Status::Connected && h.seq_nr == self.ack_nr {
+                    self.send_state(tx);
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        if self.status == Status::SynSent {
+            // The first packet from the other side has its initial sequence number.
+            self.ack_nr = h.seq_nr.wrapping_sub(1);
+            self.status = Status::Connected;
+            if let Some(w) = self.connect_waker.take() {
+                w.wake();
+            }
+        }
+
+        self.on_ack(tx, now, packet);
+
+        match h.packet_type {
+            PacketType::Data => {
+                self.on_data(h.seq_nr, packet.payload);
+                self.send_state(tx);
+            }
+            PacketType::Fin => {
+                self.eof_seq_nr = Some(h.seq_nr);
+                self.on_data(h.seq_nr, &[]);
+                self.send_state(tx);
+            }
+            _ => {}
+        }
+    }
+
+    // Returns true when the connection is done and can be forgotten.
+    pub fn on_tick(&mut self, tx: &Transmit, now: Instant) -> bool {
+        if self.error.is_some() {
+            return true;
+        }
+
+        let rto = self.rtt.rto();
+        let timed_out = self
+            .in_flight
+            .iter()
+            .find(|p| p.counts_in_flight())
+            .filter(|p| now - p.sent_at >= rto)
+            .map(|p| (p.packet_type, p.transmissions));
+        if let Some((packet_type, transmissions)) = timed_out {
+            let max = match packet_type {
+                PacketType::Syn => MAX_SYN_TRANSMISSIONS,
+                _ => MAX_TRANSMISSIONS,
+            };
+            if transmissions >= max {
+                trace!("uTP connection timed out");
+                self.set_error(io::ErrorKind::TimedOut);
+                return true;
+            }
+            self.rtt.on_timeout();
+            self.ledbat.on_timeout();
+            for p in self.in_flight.iter_mut().filter(|p| p.counts_in_flight()) {
+                p.need_resend = true;
+            }
+            self.bytes_in_flight = 0;
+            self.flush_resends(tx, now);
+        }
+
+        // The writer is blocked on the peer's closed window, and we have nothing in flight to
+        // get window updates from.
+        if self.in_flight.is_empty() && self.write_waker.is_some() {
+            match self.next_zero_window_probe {
+                None => self.next_zero_window_probe = Some(now + ZERO_WINDOW_PROBE_INTERVAL),
+                Some(t) if now >= t => {
+                    self.next_zero_window_probe = None;
+                    self.allow_zero_window_probe = true;
+                    if let Some(w) = self.write_waker.take() {
+                        w.wake();
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+
+        match self.closed_at {
+            Some(closed_at) => {
+                (self.fin_sent && self.in_flight.is_empty()) || now - closed_at > LINGER
+            }
+            None => false,
+        }
+    }
+
+    pub fn poll_connected(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(kind) = self.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        match self.status {
+            Status::Connected => Poll::Ready(Ok(())),
+            Status::SynSent => {
+                self.connect_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    pub fn poll_read(
+        &mut self,
+        cx: &mut Context<'_>,
+        tx: &Transmit,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.recv_buf.is_empty() {
+            let window_was_closed = self.recv_window() < MAX_PAYLOAD as u32;
+            let len = buf.remaining().min(self.recv_buf.len());
+            let (a, b) = self.recv_buf.as_slices();
+            let from_a = len.min(a.len());
+            buf.put_slice(&a[..from_a]);
+            buf.put_slice(&b[..len - from_a]);
+            self.recv_buf.drain(..len);
+            if window_was_closed && self.recv_window() >= MAX_PAYLOAD as u32 {
+                self.send_state(tx);
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if self.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = self.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        self.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub fn poll_write(
+        &mut self,
+        cx: &mut Context<'_>,
+        tx: &Transmit,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if let Some(kind) = self.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if self.fin_sent {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let window = self.ledbat.cwnd().min(self.peer_wnd) as usize;
+        let mut budget = window.saturating_sub(self.bytes_in_flight);
+        let wanted = buf.len().min(MAX_PAYLOAD);
+        if self.status != Status::Connected || self.has_pending_resends() {
+            budget = 0;
+        } else if budget < wanted && self.in_flight.is_empty() && self.allow_zero_window_probe {
+            // Let one packet through, the peer will tell us its window in the ack.
+            budget = wanted;
+        }
+        if budget < wanted {
+            self.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        self.allow_zero_window_probe = false;
+        self.next_zero_window_probe = None;
+
+        let mut written = 0;
+        while written < buf.len() {
+            let len = (buf.len() - written).min(MAX_PAYLOAD).min(budget);
+            if len == 0 {
+                break;
+            }
+            self.send_new(tx, PacketType::Data, &buf[written..written + len]);
+            written += len;
+            budget -= len;
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    pub fn shutdown(&mut self, tx: &Transmit) {
+        if self.status == Status::Connected && !self.fin_sent && self.error.is_none() {
+            self.fin_sent = true;
+            self.send_new(tx, PacketType::Fin, &[]);
+        }
+    }
+
+    // The stream handle was dropped.
+    pub fn close(&mut self, tx: &Transmit) {
+        self.shutdown(tx);
+        if self.status != Status::Connected {
+            self.set_error(io::ErrorKind::NotConnected);
+        }
+        self.closed_at = Some(Instant::now());
+    }
+}
@@ -0,0 +1,355 @@
+// uTP (BEP 29): a reliable stream transport over UDP, with LEDBAT congestion control.
+//
+// One UtpSocket multiplexes all connections over a single UDP socket, which shares the port
+// with the TCP listener. UtpStream implements AsyncRead + AsyncWrite so peer connections can
+// use it the same way as TcpStream.
+
+// Window sizes, timestamps and payload lengths are all well within u32 by construction.
+#![allow(clippy::cast_possible_truncation)]
+
+mod congestion;
+mod conn;
+mod packet;
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::UdpSocket,
+};
+use tracing::{debug, trace};
+
+use self::{
+    conn::{ConnState, Transmit},
+    packet::{Packet, PacketType},
+};
+
+// Keep packets under a typical MTU, as uTP doesn't do path MTU discovery.
+pub(crate) const MAX_PAYLOAD: usize = 1380;
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+const ACCEPT_QUEUE_LEN: usize = 64;
+
+struct Connection {
+    addr: SocketAddr,
+    state: Mutex<ConnState>,
+}
+
+pub(crate) struct UtpSocket {
+    udp: UdpSocket,
+    // Keyed by the connection ID the peer puts into its packets.
+    conns: Mutex<HashMap<(SocketAddr, u16), Arc<Connection>>>,
+    // None if not accepting incoming connections.
+    accept_tx: Option<tokio::sync::mpsc::Sender<Arc<Connection>>>,
+    accept_rx: tokio::sync::Mutex<tokio::sync::mpsc::Receiver<Arc<Connection>>>,
+    #[cfg(test)]
+    loss_probability: f64,
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: SocketAddr, accept_incoming: bool) -> anyhow::Result<Arc<Self>> {
+        let udp = UdpSocket::bind(addr)
+            .await
+            .with_context(|| format!("error binding UDP socket to {addr}"))?;
+        let (accept_tx, accept_rx) = tokio::sync::mpsc::channel(ACCEPT_QUEUE_LEN);
+        Ok(Arc::new(Self {
+            udp,
+            conns: Default::default(),
+            accept_tx: accept_incoming.then_some(accept_tx),
+            accept_rx: tokio::sync::Mutex::new(accept_rx),
+            #[cfg(test)]
+            loss_probability: 0.,
+        }))
+    }
+
+    fn transmit(&self, addr: SocketAddr) -> Transmit<'_> {
+        Transmit {
+            udp: &self.udp,
+            addr,
+            #[cfg(test)]
+            loss_probability: self.loss_probability,
+        }
+    }
+
+    pub async fn connect(self: &Arc<Self>, addr: SocketAddr) -> io::Result<UtpStream> {
+        let conn = {
+            let mut conns = self.conns.lock();
+            let recv_id = loop {
+                let id: u16 = rand::random();
+                if !conns.contains_key(&(addr, id)) {
+                    break id;
+                }
+            };
+            let conn = Arc::new(Connection {
+                addr,
+                state: Mutex::new(ConnState::new_outgoing(recv_id)),
+            });
+            conns.insert((addr, recv_id), conn.clone());
+            conn
+        };
+        // Create the stream right away, so that the connection is cleaned up if this is cancelled.
+        let stream = UtpStream {
+            socket: self.clone(),
+            conn,
+        };
+        stream.conn.state.lock().send_syn(&self.transmit(addr));
+        std::future::poll_fn(|cx| stream.conn.state.lock().poll_connected(cx)).await?;
+        Ok(stream)
+    }
+
+    pub async fn accept(self: &Arc<Self>) -> anyhow::Result<UtpStream> {
+        let conn = self
+            .accept_rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .context("uTP socket closed")?;
+        Ok(UtpStream {
+            socket: self.clone(),
+            conn,
+        })
+    }
+
+    fn on_datagram(&self, buf: &[u8], addr: SocketAddr) {
+        let packet = match Packet::deserialize(buf) {
+            Ok(p) => p,
+            Err(e) => {
+                trace!(?addr, "error parsing uTP packet: {e:#}");
+                return;
+            }
+        };
+        let h = packet.header;
+        let tx = self.transmit(addr);
+
+        let conn = {
+            let conns = self.conns.lock();
+            match conns.get(&(addr, h.connection_id)) {
+                Some(c) => Some(c.clone()),
+                // Resets may come with either of the connection's IDs.
+                None if h.packet_type == PacketType::Reset => conns
+                    .values()
+                    .find(|c| c.addr == addr && c.state.lock().send_id() == h.connection_id)
+                    .cloned(),
+                None => None,
+            }
+        };
+        if let Some(conn) = conn {
+            conn.state.lock().on_packet(&tx, &packet);
+            return;
+        }
+
+        match h.packet_type {
+            PacketType::Syn => {
+                let key = (addr, h.connection_id.wrapping_add(1));
+                let mut conns = self.conns.lock();
+                if let Some(conn) = conns.get(&key) {
+                    conn.state.lock().on_packet(&tx, &packet);
+                    return;
+                }
+                let conn = Arc::new(Connection {
+                    addr,
+                    state: Mutex::new(ConnState::new_incoming(&h)),
+                });
+                match self.accept_tx.as_ref().map(|a| a.try_send(conn.clone())) {
+                    Some(Ok(())) => {
+                        trace!(?addr, "accepted uTP connection");
+                        conn.state.lock().send_state(&tx);
+                        conns.insert(key, conn);
+                    }
+                    _ => tx.send_reset(h.connection_id, h.seq_nr),
+                }
+            }
+            PacketType::Reset => {}
+            _ => tx.send_reset(h.connection_id, h.seq_nr),
+        }
+    }
+
+    fn on_tick(&self) {
+        let now = Instant::now();
+        let conns = self
+            .conns
+            .lock()
+            .iter()
+            .map(|(k, c)| (*k, c.clone()))
+            .collect::<Vec<_>>();
+        let done = conns
+            .into_iter()
+            .filter(|(_, c)| c.state.lock().on_tick(&self.transmit(c.addr), now))
+            .map(|(k, _)| k)
+            .collect::<Vec<_>>();
+        if !done.is_empty() {
+            let mut conns = self.conns.lock();
+            for k in done {
+                conns.remove(&k);
+            }
+        }
+    }
+
+    pub async fn run_forever(self: Arc<Self>) -> anyhow::Result<()> {
+        let mut buf = vec![0u8; 65536];
+        let mut tick = tokio::time::interval(TICK_INTERVAL);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                r = self.udp.recv_from(&mut buf) => match r {
+                    Ok((len, addr)) => self.on_datagram(&buf[..len], addr),
+                    // E.g. ICMP errors on some platforms. Not fatal for the other connections.
+                    Err(e) => debug!("error receiving from UDP socket: {e:#}"),
+                },
+                _ = tick.tick() => self.on_tick(),
+            }
+        }
+    }
+}
+
+pub(crate) struct UtpStream {
+    socket: Arc<UtpSocket>,
+    conn: Arc<Connection>,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.conn.addr
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let tx = self.socket.transmit(self.conn.addr);
+        self.conn.state.lock().poll_read(cx, &tx, buf)
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let tx = self.socket.transmit(self.conn.addr);
+        self.conn.state.lock().poll_write(cx, &tx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Everything written is already sent or queued for retransmission.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let tx = self.socket.transmit(self.conn.addr);
+        self.conn.state.lock().shutdown(&tx);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let tx = self.socket.transmit(self.conn.addr);
+        self.conn.state.lock().close(&tx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::UtpSocket;
+
+    async fn make_socket(accept_incoming: bool, loss_probability: f64) -> Arc<UtpSocket> {
+        let mut s = UtpSocket::bind("127.0.0.1:0".parse().unwrap(), accept_incoming)
+            .await
+            .unwrap();
+        Arc::get_mut(&mut s).unwrap().loss_probability = loss_probability;
+        tokio::spawn(s.clone().run_forever());
+        s
+    }
+
+    async fn test_echo(len: u32, loss_probability: f64) {
+        let server = make_socket(true, loss_probability).await;
+        let client = make_socket(false, loss_probability).await;
+        let server_addr = server.udp.local_addr().unwrap();
+
+        let data = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        // The server echoes everything back.
+        let server_task = tokio::spawn(async move {
+            let mut stream = server.accept().await.unwrap();
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                stream.write_all(&buf[..n]).await.unwrap();
+            }
+            stream.shutdown().await.unwrap();
+        });
+
+        let stream = tokio::time::timeout(Duration::from_secs(5), client.connect(server_addr))
+            .await
+            .unwrap()
+            .unwrap();
+        let (mut read_half, mut write_half) = tokio::io::split(stream);
+        let to_write = data.clone();
+        let writer = tokio::spawn(async move {
+            write_half.write_all(&to_write).await.unwrap();
+            write_half.shutdown().await.unwrap();
+            write_half
+        });
+
+        let mut received = Vec::new();
+        tokio::time::timeout(
+            Duration::from_secs(30),
+            read_half.read_to_end(&mut received),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(received.len(), data.len());
+        assert!(received == data);
+
+        writer.await.unwrap();
+        server_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_utp_loopback_transfer() {
+        test_echo(4 * 1024 * 1024, 0.).await
+    }
+
+    #[tokio::test]
+    async fn test_utp_loopback_transfer_with_packet_loss() {
+        test_echo(256 * 1024, 0.05).await
+    }
+
+    #[tokio::test]
+    async fn test_utp_connect_refused() {
+        let server = make_socket(false, 0.).await;
+        let client = make_socket(false, 0.).await;
+        let err = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.connect(server.udp.local_addr().unwrap()),
+        )
+        .await
+        .unwrap()
+        .err()
+        .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+    }
+}
@@ -0,0 +1,174 @@
+use anyhow::{bail, Context};
+use byteorder::{ByteOrder, BE};
+
+pub const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+const EXTENSION_SELECTIVE_ACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl PacketType {
+    fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            0 => Self::Data,
+            1 => Self::Fin,
+            2 => Self::State,
+            3 => Self::Reset,
+            4 => Self::Syn,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub packet_type: PacketType,
+    pub connection_id: u16,
+    pub timestamp_us: u32,
+    pub timestamp_diff_us: u32,
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+}
+
+impl Header {
+    pub fn serialize(&self, buf: &mut [u8; HEADER_LEN]) {
+        buf[0] = ((self.packet_type as u8) << 4) | VERSION;
+        // We don't send any extensions.
+        buf[1] = 0;
+        BE::write_u16(&mut buf[2..4], self.connection_id);
+        BE::write_u32(&mut buf[4..8], self.timestamp_us);
+        BE::write_u32(&mut buf[8..12], self.timestamp_diff_us);
+        BE::write_u32(&mut buf[12..16], self.wnd_size);
+        BE::write_u16(&mut buf[16..18], self.seq_nr);
+        BE::write_u16(&mut buf[18..20], self.ack_nr);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Packet<'a> {
+    pub header: Header,
+    // Bitmask of received packets past ack_nr + 1, if the peer sent one.
+    pub selective_ack: Option<&'a [u8]>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    pub fn deserialize(buf: &'a [u8]) -> anyhow::Result<Self> {
+        if buf.len() < HEADER_LEN {
+            bail!("packet too short: {} bytes", buf.len());
+        }
+        if buf[0] & 0xf != VERSION {
+            bail!("unsupported uTP version {}", buf[0] & 0xf);
+        }
+        let packet_type = PacketType::from_u8(buf[0] >> 4).context("unknown uTP packet type")?;
+        let header = Header {
+            packet_type,
+            connection_id: BE::read_u16(&buf[2..4]),
+            timestamp_us: BE::read_u32(&buf[4..8]),
+            timestamp_diff_us: BE::read_u32(&buf[8..12]),
+            wnd_size: BE::read_u32(&buf[12..16]),
+            seq_nr: BE::read_u16(&buf[16..18]),
+            ack_nr: BE::read_u16(&buf[18..20]),
+        };
+
+        // Walk the extension list: each one is (next extension type, length, payload).
+        let mut selective_ack = None;
+        let mut extension = buf[1];
+        let mut rest = &buf[HEADER_LEN..];
+        while extension != 0 {
+            if rest.len() < 2 {
+                bail!("truncated uTP extension header");
+            }
+            let (next, len) = (rest[0], rest[1] as usize);
+            let data = rest.get(2..2 + len).context("truncated uTP extension")?;
+            if extension == EXTENSION_SELECTIVE_ACK {
+                selective_ack = Some(data);
+            }
+            extension = next;
+            rest = &rest[2 + len..];
+        }
+
+        Ok(Self {
+            header,
+            selective_ack,
+            payload: rest,
+        })
+    }
+}
+
+// Sequence numbers wrap around, so compare them as distances.
+pub fn seq_less_than(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize_deserialize() {
+        let header = Header {
+            packet_type: PacketType::Data,
+            connection_id: 12345,
+            timestamp_us: 0xdeadbeef,
+            timestamp_diff_us: 42,
+            wnd_size: 1 << 20,
+            seq_nr: 65535,
+            ack_nr: 7,
+        };
+        let mut buf = [0u8; HEADER_LEN];
+        header.serialize(&mut buf);
+        let mut packet = buf.to_vec();
+        packet.extend_from_slice(b"hello");
+
+        let p = Packet::deserialize(&packet).unwrap();
+        assert_eq!(p.header, header);
+        assert_eq!(p.payload, b"hello");
+        assert_eq!(p.selective_ack, None);
+    }
+
+    #[test]
+    fn test_deserialize_extensions() {
+        let mut buf = [0u8; HEADER_LEN];
+        Header {
+            packet_type: PacketType::State,
+            connection_id: 1,
+            timestamp_us: 0,
+            timestamp_diff_us: 0,
+            wnd_size: 0,
+            seq_nr: 1,
+            ack_nr: 1,
+        }
+        .serialize(&mut buf);
+        let mut packet = buf.to_vec();
+        packet[1] = EXTENSION_SELECTIVE_ACK;
+        // Selective ack followed by an unknown extension, which must be skipped.
+        packet.extend_from_slice(&[42, 4, 0b101, 0, 0, 0]);
+        packet.extend_from_slice(&[0, 1, 0xff]);
+
+        let p = Packet::deserialize(&packet).unwrap();
+        assert_eq!(p.selective_ack, Some(&[0b101, 0, 0, 0][..]));
+        assert!(p.payload.is_empty());
+
+        packet.truncate(packet.len() - 1);
+        assert!(Packet::deserialize(&packet).is_err());
+    }
+
+    #[test]
+    fn test_seq_less_than() {
+        assert!(seq_less_than(1, 2));
+        assert!(!seq_less_than(2, 1));
+        assert!(!seq_less_than(5, 5));
+        assert!(seq_less_than(65535, 0));
+        assert!(seq_less_than(65000, 100));
+        assert!(!seq_less_than(100, 65000));
+    }
+}
@@ -0,0 +1,266 @@
+// Adds .torrent and .magnet files dropped into a folder. The folder is polled, so that it works
+// the same everywhere, including on network drives.
+//
+// Added files are renamed to "<name>.added", and files that couldn't be added to "<name>.invalid",
+// so that they aren't picked up again.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use anyhow::Context;
+use parking_lot::Mutex;
+use tokio::task::spawn_blocking;
+use tracing::{debug, error_span, info, warn};
+
+use crate::{
+    session::{AddTorrent, AddTorrentOptions},
+    Session,
+};
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_MAGNET_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Clone)]
+pub struct WatchFolderOptions {
+    pub folder: PathBuf,
+    /// How often to look for new files. Defaults to 5 seconds.
+    pub poll_interval: Option<Duration>,
+    /// How long to wait for the metadata of a magnet. When it times out, the file is left as is
+    /// and retried later. Defaults to 10 minutes.
+    pub magnet_timeout: Option<Duration>,
+    /// Used for all torrents added from the folder.
+    pub add_options: AddTorrentOptions,
+}
+
+impl WatchFolderOptions {
+    pub fn new(folder: PathBuf) -> Self {
+        Self {
+            folder,
+            poll_interval: None,
+            magnet_timeout: None,
+            add_options: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WatchedKind {
+    Torrent,
+    Magnet,
+}
+
+fn watched_kind(path: &Path) -> Option<WatchedKind> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "torrent" => Some(WatchedKind::Torrent),
+        "magnet" => Some(WatchedKind::Magnet),
+        _ => None,
+    }
+}
+
+// "Show.torrent" -> "Show.torrent.added"
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+// The first line that isn't empty, so that files saved by browsers or editors work too.
+fn parse_magnet_file(content: &str) -> Option<&str> {
+    content.lines().map(|l| l.trim()).find(|l| !l.is_empty())
+}
+
+async fn add_file(
+    session: &Arc<Session>,
+    path: &Path,
+    kind: WatchedKind,
+    opts: AddTorrentOptions,
+) -> anyhow::Result<()> {
+    let content = spawn_blocking({
+        let path = path.to_owned();
+        move || std::fs::read(path)
+    })
+    .await?
+    .context("error reading file")?;
+    let add = match kind {
+        WatchedKind::Torrent => AddTorrent::from_bytes(content),
+        WatchedKind::Magnet => {
+            let content = String::from_utf8(content).context("magnet file isn't utf-8")?;
+            let url = parse_magnet_file(&content).context("magnet file is empty")?;
+            AddTorrent::from_url(url.to_owned())
+        }
+    };
+    session
+        .add_torrent(add, Some(opts))
+        .await
+        .context("error adding torrent")?;
+    Ok(())
+}
+
+async fn process_file(
+    session: Arc<Session>,
+    path: PathBuf,
+    kind: WatchedKind,
+    opts: AddTorrentOptions,
+    magnet_timeout: Duration,
+) -> anyhow::Result<()> {
+    let result = match kind {
+        WatchedKind::Torrent => add_file(&session, &path, kind, opts).await,
+        WatchedKind::Magnet => {
+            match tokio::time::timeout(magnet_timeout, add_file(&session, &path, kind, opts)).await
+            {
+                Ok(r) => r,
+                Err(_) => {
+                    warn!(
+                        ?path,
+                        "timed out resolving magnet from watch folder, will retry"
+                    );
+                    return Ok(());
+                }
+            }
+        }
+    };
+    let suffix = match result {
+        Ok(()) => {
+            info!(?path, "added torrent from watch folder");
+            ".added"
+        }
+        Err(e) => {
+            warn!(?path, "error adding torrent from watch folder: {e:#}");
+            ".invalid"
+        }
+    };
+    let renamed = with_suffix(&path, suffix);
+    spawn_blocking({
+        let (path, renamed) = (path.clone(), renamed.clone());
+        move || std::fs::rename(path, renamed)
+    })
+    .await?
+    .with_context(|| format!("error renaming {path:?} to {renamed:?}"))
+}
+
+// The watched files with their sizes.
+fn list_folder(folder: &Path) -> anyhow::Result<Vec<(PathBuf, WatchedKind, u64)>> {
+    let entries = std::fs::read_dir(folder).with_context(|| format!("error reading {folder:?}"))?;
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let kind = match watched_kind(&path) {
+            Some(kind) => kind,
+            None => continue,
+        };
+        match std::fs::metadata(&path) {
+            Ok(m) if m.is_file() => files.push((path, kind, m.len())),
+            _ => continue,
+        }
+    }
+    Ok(files)
+}
+
+struct Watcher {
+    session: Weak<Session>,
+    opts: WatchFolderOptions,
+    // Files are only picked up when their size didn't change since the previous poll, so that
+    // we don't read them while they are still being written.
+    sizes: HashMap<PathBuf, u64>,
+    // Adding magnets takes a while, don't pick them up again in the meantime.
+    in_progress: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl Watcher {
+    // Returns false if the session is gone.
+    async fn poll(&mut self) -> anyhow::Result<bool> {
+        let files = spawn_blocking({
+            let folder = self.opts.folder.clone();
+            move || list_folder(&folder)
+        })
+        .await??;
+        let mut sizes = HashMap::new();
+        for (path, kind, len) in files {
+            if self.in_progress.lock().contains(&path) {
+                continue;
+            }
+            if len == 0 || self.sizes.get(&path) != Some(&len) {
+                debug!(?path, len, "waiting for the file size to settle");
+                sizes.insert(path, len);
+                continue;
+            }
+
+            let session = match self.session.upgrade() {
+                Some(s) => s,
+                None => return Ok(false),
+            };
+            self.in_progress.lock().insert(path.clone());
+            let fut = process_file(
+                session.clone(),
+                path.clone(),
+                kind,
+                self.opts.add_options.clone(),
+                self.opts.magnet_timeout.unwrap_or(DEFAULT_MAGNET_TIMEOUT),
+            );
+            let in_progress = self.in_progress.clone();
+            session.spawn(error_span!("watch_folder_add", ?path), async move {
+                let result = fut.await;
+                in_progress.lock().remove(&path);
+                result
+            });
+        }
+        self.sizes = sizes;
+        Ok(true)
+    }
+}
+
+/// Polls the folder until the session is dropped.
+pub(crate) async fn watch_folder(
+    session: Weak<Session>,
+    opts: WatchFolderOptions,
+) -> anyhow::Result<()> {
+    let interval = opts.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL);
+    let mut watcher = Watcher {
+        session,
+        opts,
+        sizes: Default::default(),
+        in_progress: Default::default(),
+    };
+    loop {
+        match watcher.poll().await {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(e) => warn!("error polling watch folder: {e:#}"),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{parse_magnet_file, watched_kind, with_suffix, WatchedKind};
+
+    #[test]
+    fn test_watched_files() {
+        assert_eq!(
+            watched_kind(Path::new("/w/Show.TORRENT")),
+            Some(WatchedKind::Torrent)
+        );
+        assert_eq!(
+            watched_kind(Path::new("/w/Show.magnet")),
+            Some(WatchedKind::Magnet)
+        );
+        assert_eq!(watched_kind(Path::new("/w/Show.torrent.added")), None);
+        assert_eq!(
+            with_suffix(Path::new("/w/Show.torrent"), ".invalid"),
+            Path::new("/w/Show.torrent.invalid")
+        );
+        assert_eq!(
+            parse_magnet_file("\n  magnet:?xt=urn:btih:abcd \r\n"),
+            Some("magnet:?xt=urn:btih:abcd")
+        );
+        assert_eq!(parse_magnet_file(" \n"), None);
+    }
+}
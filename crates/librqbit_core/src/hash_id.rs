@@ -164,6 +164,15 @@ pub type Id20 = Id<20>;
 /// A 32-byte hash used in Bittorrent V2, for torrent info hashes, piece hashing, etc.
 pub type Id32 = Id<32>;
 
+impl Id32 {
+    /// V2 info hashes are truncated to 20 bytes in the peer protocol, in trackers and in DHT.
+    pub fn truncate_for_dht(&self) -> Id20 {
+        let mut id = [0u8; 20];
+        id.copy_from_slice(&self.0[..20]);
+        Id20::new(id)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
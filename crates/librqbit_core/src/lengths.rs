@@ -76,7 +76,7 @@ impl Lengths {
     pub fn from_torrent<ByteBuf: AsRef<[u8]>>(
         torrent: &TorrentMetaV1Info<ByteBuf>,
     ) -> anyhow::Result<Lengths> {
-        let total_length = torrent.iter_file_lengths()?.sum();
+        let total_length = torrent.total_length()?;
         Lengths::new(total_length, torrent.piece_length)
     }
 
@@ -3,6 +3,7 @@ pub mod directories;
 pub mod hash_id;
 pub mod lengths;
 pub mod magnet;
+pub mod merkle;
 pub mod peer_id;
 pub mod spawn_utils;
 pub mod speed_estimator;
@@ -0,0 +1,163 @@
+// SHA-256 merkle trees used by BitTorrent v2 (BEP 52) to verify file data.
+//
+// Every file is split into 16KiB blocks, which are the leaves of the tree. The number of leaves
+// is padded up to a power of two with zero hashes. The "piece layer" is the layer of the tree
+// where each hash covers exactly one piece.
+
+use sha1w::{ISha256, Sha256};
+
+use crate::{constants::CHUNK_SIZE, hash_id::Id32};
+
+pub const MERKLE_BLOCK_SIZE: u32 = CHUNK_SIZE;
+
+pub fn sha256(data: &[u8]) -> Id32 {
+    let mut h = Sha256::new();
+    h.update(data);
+    Id32::new(h.finish())
+}
+
+fn hash_pair(left: &Id32, right: &Id32) -> Id32 {
+    let mut h = Sha256::new();
+    h.update(&left.0);
+    h.update(&right.0);
+    Id32::new(h.finish())
+}
+
+// How many leaves the tree of "len" bytes of data has.
+pub fn leaves_for_len(len: u64) -> u32 {
+    let blocks = len.div_ceil(MERKLE_BLOCK_SIZE as u64).max(1);
+    u32::try_from(blocks.next_power_of_two()).unwrap_or(u32::MAX)
+}
+
+/// Computes the root of a tree that has "width" leaves (must be a power of two).
+/// "layer" holds the first leaves, the rest of them are "pad".
+pub fn merkle_root(mut layer: Vec<Id32>, width: u32, mut pad: Id32) -> Id32 {
+    debug_assert!(width.is_power_of_two());
+    debug_assert!(layer.len() <= width as usize);
+    let mut width = width;
+    while width > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pad)))
+            .collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+/// The root of a subtree with "width" leaves that are all beyond the end of the file.
+pub fn pad_hash(width: u32) -> Id32 {
+    merkle_root(Vec::new(), width, Id32::default())
+}
+
+/// Computes the root of the file from its piece layer.
+pub fn root_from_piece_layer(layer: Vec<Id32>, piece_length: u32) -> Id32 {
+    let leaves_per_piece = piece_length / MERKLE_BLOCK_SIZE;
+    let width = u32::try_from(layer.len().next_power_of_two()).unwrap_or(u32::MAX);
+    merkle_root(layer, width, pad_hash(leaves_per_piece))
+}
+
+/// Hashes a stream of data in 16KiB blocks and computes the merkle root over them.
+pub struct MerkleHasher {
+    leaves: Vec<Id32>,
+    block: Sha256,
+    block_len: u32,
+}
+
+impl Default for MerkleHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MerkleHasher {
+    pub fn new() -> Self {
+        Self {
+            leaves: Vec::new(),
+            block: Sha256::new(),
+            block_len: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut buf: &[u8]) {
+        while !buf.is_empty() {
+            let remaining = (MERKLE_BLOCK_SIZE - self.block_len) as usize;
+            let (head, tail) = buf.split_at(remaining.min(buf.len()));
+            self.block.update(head);
+            self.block_len += head.len() as u32;
+            buf = tail;
+            if self.block_len == MERKLE_BLOCK_SIZE {
+                self.flush_block();
+            }
+        }
+    }
+
+    fn flush_block(&mut self) {
+        if self.block_len == 0 {
+            return;
+        }
+        let block = std::mem::replace(&mut self.block, Sha256::new());
+        self.leaves.push(Id32::new(block.finish()));
+        self.block_len = 0;
+    }
+
+    /// The root of the tree of "width" leaves. The last block may be shorter than 16KiB,
+    /// and is hashed as is.
+    pub fn finish(mut self, width: u32) -> Id32 {
+        self.flush_block();
+        merkle_root(self.leaves, width, Id32::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merkle_root_pads_with_zeros() {
+        let data = vec![1u8; MERKLE_BLOCK_SIZE as usize * 2 + 100];
+        let leaves: Vec<Id32> = data
+            .chunks(MERKLE_BLOCK_SIZE as usize)
+            .map(sha256)
+            .collect();
+        assert_eq!(leaves_for_len(data.len() as u64), 4);
+
+        let expected = hash_pair(
+            &hash_pair(&leaves[0], &leaves[1]),
+            &hash_pair(&leaves[2], &Id32::default()),
+        );
+        assert_eq!(merkle_root(leaves, 4, Id32::default()), expected);
+
+        let mut h = MerkleHasher::new();
+        for chunk in data.chunks(1000) {
+            h.update(chunk);
+        }
+        assert_eq!(h.finish(4), expected);
+    }
+
+    #[test]
+    fn test_root_from_piece_layer() {
+        // 5 blocks with 2 blocks per piece: the last piece is half padding, and the layer
+        // itself needs padding to 4 pieces.
+        let piece_length = MERKLE_BLOCK_SIZE * 2;
+        let data: Vec<u8> = (0..MERKLE_BLOCK_SIZE * 5)
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        let layer: Vec<Id32> = data
+            .chunks(piece_length as usize)
+            .map(|piece| {
+                let mut h = MerkleHasher::new();
+                h.update(piece);
+                h.finish(2)
+            })
+            .collect();
+
+        let mut h = MerkleHasher::new();
+        h.update(&data);
+        let root = h.finish(leaves_for_len(data.len() as u64));
+
+        assert_eq!(root_from_piece_layer(layer, piece_length), root);
+    }
+}
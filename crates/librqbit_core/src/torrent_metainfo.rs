@@ -5,29 +5,53 @@ use bencode::BencodeDeserializer;
 use buffers::{ByteBuf, ByteBufOwned};
 use clone_to_owned::CloneToOwned;
 use itertools::Either;
-use serde::{Deserialize, Serialize};
-
-use crate::{hash_id::Id20, lengths::Lengths};
+use serde::{
+    de::{DeserializeSeed, MapAccess, SeqAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::{
+    hash_id::{Id20, Id32},
+    lengths::Lengths,
+    merkle,
+};
 
 pub type TorrentMetaV1Borrowed<'a> = TorrentMetaV1<ByteBuf<'a>>;
 pub type TorrentMetaV1Owned = TorrentMetaV1<ByteBufOwned>;
 
 /// Parse torrent metainfo from bytes.
-pub fn torrent_from_bytes<'de, BufType: Deserialize<'de>>(
-    buf: &'de [u8],
-) -> anyhow::Result<TorrentMetaV1<BufType>> {
+pub fn torrent_from_bytes<'de, BufType>(buf: &'de [u8]) -> anyhow::Result<TorrentMetaV1<BufType>>
+where
+    BufType: Deserialize<'de> + AsRef<[u8]> + Clone,
+{
     let mut de = BencodeDeserializer::new_from_buf(buf);
     de.is_torrent_info = true;
-    let mut t = TorrentMetaV1::deserialize(&mut de)?;
+    let mut t = TorrentMetaV1::<BufType>::deserialize(&mut de)?;
     t.info_hash = Id20::new(
         de.torrent_info_digest
             .ok_or_else(|| anyhow::anyhow!("programming error"))?,
     );
+    if t.info.meta_version == Some(2) {
+        let info_hash_v2 = Id32::new(
+            de.torrent_info_digest_v2
+                .ok_or_else(|| anyhow::anyhow!("programming error"))?,
+        );
+        t.info_hash_v2 = Some(info_hash_v2);
+        // v2-only torrents are known to peers by the truncated v2 info hash.
+        if t.info.pieces.is_none() {
+            t.info_hash = info_hash_v2.truncate_for_dht();
+        }
+    }
     Ok(t)
 }
 
 /// A parsed .torrent file.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(bound(
+    serialize = "BufType: Serialize + AsRef<[u8]>",
+    deserialize = "BufType: Deserialize<'de> + AsRef<[u8]> + Clone"
+))]
 pub struct TorrentMetaV1<BufType> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub announce: Option<BufType>,
@@ -51,8 +75,25 @@ pub struct TorrentMetaV1<BufType> {
     #[serde(rename = "creation date", skip_serializing_if = "Option::is_none")]
     pub creation_date: Option<usize>,
 
+    // BEP 19 web seeds. Either a single URL or a list of them.
+    #[serde(
+        rename = "url-list",
+        default = "Vec::new",
+        deserialize_with = "deserialize_url_list",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub url_list: Vec<BufType>,
+
+    // BEP 52: piece layers of v2 files larger than one piece, keyed by their pieces root.
+    #[serde(rename = "piece layers", skip_serializing_if = "Option::is_none")]
+    pub piece_layers: Option<PieceLayers<BufType>>,
+
+    // For v2-only torrents, this is the truncated v2 info hash.
     #[serde(skip)]
     pub info_hash: Id20,
+    // Set for v2 and hybrid torrents.
+    #[serde(skip)]
+    pub info_hash_v2: Option<Id32>,
 }
 
 impl<BufType> TorrentMetaV1<BufType> {
@@ -62,14 +103,63 @@ impl<BufType> TorrentMetaV1<BufType> {
         }
         itertools::Either::Right(self.announce.iter())
     }
+
+    /// BEP 12 tiers of trackers. Without "announce-list", "announce" is the only tier.
+    pub fn announce_tiers(&self) -> Vec<Vec<&BufType>> {
+        if self.announce_list.iter().flatten().next().is_some() {
+            return self
+                .announce_list
+                .iter()
+                .map(|tier| tier.iter().collect())
+                .collect();
+        }
+        self.announce.iter().map(|a| vec![a]).collect()
+    }
+}
+
+fn deserialize_url_list<'de, D, BufType>(deserializer: D) -> Result<Vec<BufType>, D::Error>
+where
+    D: Deserializer<'de>,
+    BufType: Deserialize<'de>,
+{
+    struct UrlListVisitor<BufType>(std::marker::PhantomData<BufType>);
+
+    impl<'de, BufType: Deserialize<'de>> Visitor<'de> for UrlListVisitor<BufType> {
+        type Value = Vec<BufType>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a URL or a list of URLs")
+        }
+
+        fn visit_borrowed_bytes<E: serde::de::Error>(self, v: &'de [u8]) -> Result<Self::Value, E> {
+            let url = BufType::deserialize(serde::de::value::BorrowedBytesDeserializer::new(v))?;
+            Ok(vec![url])
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut urls = Vec::new();
+            while let Some(url) = seq.next_element()? {
+                urls.push(url);
+            }
+            Ok(urls)
+        }
+    }
+
+    deserializer.deserialize_any(UrlListVisitor(Default::default()))
 }
 
 /// Main torrent information, shared by .torrent files and magnet link contents.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(bound(
+    serialize = "BufType: Serialize + AsRef<[u8]>",
+    deserialize = "BufType: Deserialize<'de> + AsRef<[u8]> + Clone"
+))]
 pub struct TorrentMetaV1Info<BufType> {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<BufType>,
-    pub pieces: BufType,
+    // Not present in v2-only torrents.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pieces: Option<BufType>,
     #[serde(rename = "piece length")]
     pub piece_length: u32,
 
@@ -83,6 +173,224 @@ pub struct TorrentMetaV1Info<BufType> {
     // Multi-file mode
     #[serde(skip_serializing_if = "Option::is_none")]
     pub files: Option<Vec<TorrentMetaV1File<BufType>>>,
+
+    // BEP 27: peers should only come from the trackers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+
+    // BEP 52 (BitTorrent v2). Hybrid torrents have both these and the v1 fields above.
+    #[serde(rename = "meta version", skip_serializing_if = "Option::is_none")]
+    pub meta_version: Option<u32>,
+    #[serde(rename = "file tree", skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<V2FileTree<BufType>>,
+}
+
+/// BEP 52 "file tree". In the torrent it's a dict of nested directories, here it's flattened
+/// into the list of files in torrent order (sorted by path).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct V2FileTree<BufType> {
+    pub files: Vec<V2File<BufType>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct V2File<BufType> {
+    pub path: Vec<BufType>,
+    pub length: u64,
+    // Root of the file's merkle tree. Not present for empty files.
+    pub pieces_root: Option<BufType>,
+}
+
+// The attributes of a file, stored under an empty key in the file tree.
+#[derive(Serialize, Deserialize)]
+struct V2FileAttributes<BufType> {
+    length: u64,
+    #[serde(rename = "pieces root", skip_serializing_if = "Option::is_none")]
+    pieces_root: Option<BufType>,
+}
+
+struct FileTreeSeed<'a, BufType> {
+    prefix: Vec<BufType>,
+    files: &'a mut Vec<V2File<BufType>>,
+}
+
+impl<'de, 'a, BufType> DeserializeSeed<'de> for FileTreeSeed<'a, BufType>
+where
+    BufType: Deserialize<'de> + AsRef<[u8]> + Clone,
+{
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a, BufType> Visitor<'de> for FileTreeSeed<'a, BufType>
+where
+    BufType: Deserialize<'de> + AsRef<[u8]> + Clone,
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a BEP 52 file tree")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        use serde::de::Error;
+        let FileTreeSeed { prefix, files } = self;
+        while let Some(key) = map.next_key::<BufType>()? {
+            if !key.as_ref().is_empty() {
+                let mut path = prefix.clone();
+                path.push(key);
+                map.next_value_seed(FileTreeSeed {
+                    prefix: path,
+                    files: &mut *files,
+                })?;
+                continue;
+            }
+            if prefix.is_empty() {
+                return Err(A::Error::custom("file tree has a file without a name"));
+            }
+            let attrs: V2FileAttributes<BufType> = map.next_value()?;
+            files.push(V2File {
+                path: prefix.clone(),
+                length: attrs.length,
+                pieces_root: attrs.pieces_root,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<'de, BufType> Deserialize<'de> for V2FileTree<BufType>
+where
+    BufType: Deserialize<'de> + AsRef<[u8]> + Clone,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut files = Vec::new();
+        FileTreeSeed {
+            prefix: Vec::new(),
+            files: &mut files,
+        }
+        .deserialize(deserializer)?;
+        Ok(V2FileTree { files })
+    }
+}
+
+// Serializes the files sharing the same path prefix of "depth" components as a nested dict.
+struct FileTreeLevel<'a, BufType> {
+    files: &'a [V2File<BufType>],
+    depth: usize,
+}
+
+impl<'a, BufType: Serialize + AsRef<[u8]>> Serialize for FileTreeLevel<'a, BufType> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::Error;
+        let mut map = serializer.serialize_map(None)?;
+        let mut rest = self.files;
+        while let Some(first) = rest.first() {
+            let name = first
+                .path
+                .get(self.depth)
+                .ok_or_else(|| S::Error::custom("file tree paths conflict"))?;
+            let group_len = rest
+                .iter()
+                .take_while(|f| f.path.get(self.depth).map(|p| p.as_ref()) == Some(name.as_ref()))
+                .count();
+            let (group, tail) = rest.split_at(group_len);
+            if first.path.len() == self.depth + 1 {
+                let attrs = V2FileAttributes {
+                    length: first.length,
+                    pieces_root: first.pieces_root.as_ref(),
+                };
+                map.serialize_entry(name, &std::collections::BTreeMap::from([("", attrs)]))?;
+            } else {
+                map.serialize_entry(
+                    name,
+                    &FileTreeLevel {
+                        files: group,
+                        depth: self.depth + 1,
+                    },
+                )?;
+            }
+            rest = tail;
+        }
+        map.end()
+    }
+}
+
+impl<BufType: Serialize + AsRef<[u8]>> Serialize for V2FileTree<BufType> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        FileTreeLevel {
+            files: &self.files,
+            depth: 0,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// BEP 52 "piece layers": pieces root -> concatenated SHA-256 hashes of the file's pieces.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PieceLayers<BufType>(pub Vec<(BufType, BufType)>);
+
+impl<BufType: AsRef<[u8]>> PieceLayers<BufType> {
+    pub fn get(&self, pieces_root: &[u8]) -> Option<&BufType> {
+        self.0
+            .iter()
+            .find_map(|(root, layer)| (root.as_ref() == pieces_root).then_some(layer))
+    }
+}
+
+impl<'de, BufType: Deserialize<'de>> Deserialize<'de> for PieceLayers<BufType> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PieceLayersVisitor<BufType>(std::marker::PhantomData<BufType>);
+
+        impl<'de, BufType: Deserialize<'de>> Visitor<'de> for PieceLayersVisitor<BufType> {
+            type Value = PieceLayers<BufType>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a dict of piece layers")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut layers = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    layers.push(entry);
+                }
+                Ok(PieceLayers(layers))
+            }
+        }
+
+        deserializer.deserialize_map(PieceLayersVisitor(Default::default()))
+    }
+}
+
+impl<BufType: Serialize> Serialize for PieceLayers<BufType> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (root, layer) in self.0.iter() {
+            map.serialize_entry(root, layer)?;
+        }
+        map.end()
+    }
+}
+
+/// The expected hash of a piece of a v2 torrent: the merkle root over "leaves" 16KiB blocks.
+/// Pieces of files larger than a piece come from the piece layers, the single piece of a
+/// smaller file is checked against its pieces root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceHashV2 {
+    pub hash: Id32,
+    pub leaves: u32,
+}
+
+// A file's position in the torrent's piece space.
+struct FileLayout<'a, BufType> {
+    filename: FileIteratorName<'a, BufType>,
+    offset: u64,
+    len: u64,
+    // BEP 47 padding files are only there for alignment, they are never stored.
+    padding: bool,
+    pieces_root: Option<&'a BufType>,
 }
 
 #[derive(Clone, Copy)]
@@ -165,50 +473,179 @@ impl<'a, BufType> FileDetails<'a, BufType> {
 }
 
 impl<BufType: AsRef<[u8]>> TorrentMetaV1Info<BufType> {
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2)
+    }
+
+    // Hybrid torrents are both v1 and v2.
+    pub fn is_v1(&self) -> bool {
+        self.pieces.is_some()
+    }
+
     pub fn get_hash(&self, piece: u32) -> Option<&[u8]> {
         let start = piece as usize * 20;
         let end = start + 20;
-        let expected_hash = self.pieces.as_ref().get(start..end)?;
+        let expected_hash = self.pieces.as_ref()?.as_ref().get(start..end)?;
         Some(expected_hash)
     }
 
     pub fn compare_hash(&self, piece: u32, hash: [u8; 20]) -> Option<bool> {
-        let start = piece as usize * 20;
-        let end = start + 20;
-        let expected_hash = self.pieces.as_ref().get(start..end)?;
+        let expected_hash = self.get_hash(piece)?;
         Some(expected_hash == hash)
     }
 
-    #[inline(never)]
-    pub fn iter_filenames_and_lengths(
-        &self,
-    ) -> anyhow::Result<impl Iterator<Item = (FileIteratorName<'_, BufType>, u64)>> {
-        match (self.length, self.files.as_ref()) {
+    // All files, including padding, with their offsets. In v2 torrents every file starts at
+    // a piece boundary. Hybrid torrents achieve the same with explicit padding files in the v1
+    // file list, and that's what is used for them.
+    fn iter_layout(&self) -> anyhow::Result<impl Iterator<Item = FileLayout<'_, BufType>>> {
+        match (self.length, self.files.as_ref(), self.file_tree.as_ref()) {
             // Single-file
-            (Some(length), None) => Ok(Either::Left(once((
-                FileIteratorName::Single(self.name.as_ref()),
-                length,
-            )))),
+            (Some(length), None, _) => Ok(Either::Left(once(FileLayout {
+                filename: FileIteratorName::Single(self.name.as_ref()),
+                offset: 0,
+                len: length,
+                padding: false,
+                pieces_root: None,
+            }))),
 
             // Multi-file
-            (None, Some(files)) => {
+            (None, Some(files), _) => {
                 if files.is_empty() {
                     anyhow::bail!("expected multi-file torrent to have at least one file")
                 }
-                Ok(Either::Right(
-                    files
-                        .iter()
-                        .map(|f| (FileIteratorName::Tree(&f.path), f.length)),
-                ))
+                Ok(Either::Right(Either::Left(files.iter().scan(
+                    0u64,
+                    |acc_offset, f| {
+                        let offset = *acc_offset;
+                        *acc_offset += f.length;
+                        Some(FileLayout {
+                            filename: FileIteratorName::Tree(&f.path),
+                            offset,
+                            len: f.length,
+                            padding: f.is_padding(),
+                            pieces_root: None,
+                        })
+                    },
+                ))))
+            }
+
+            // v2-only
+            (None, None, Some(tree)) if self.is_v2() => {
+                if tree.files.is_empty() {
+                    anyhow::bail!("expected v2 torrent to have at least one file")
+                }
+                let piece_length = self.piece_length as u64;
+                Ok(Either::Right(Either::Right(tree.files.iter().scan(
+                    0u64,
+                    move |acc_offset, f| {
+                        if f.length > 0 {
+                            *acc_offset = acc_offset.next_multiple_of(piece_length);
+                        }
+                        let offset = *acc_offset;
+                        *acc_offset += f.length;
+                        Some(FileLayout {
+                            filename: FileIteratorName::Tree(&f.path),
+                            offset,
+                            len: f.length,
+                            padding: false,
+                            pieces_root: f.pieces_root.as_ref(),
+                        })
+                    },
+                ))))
             }
-            _ => anyhow::bail!("torrent can't be both in single and multi-file mode"),
+            (Some(_), Some(_), _) => {
+                anyhow::bail!("torrent can't be both in single and multi-file mode")
+            }
+            _ => anyhow::bail!("torrent has no files"),
         }
     }
 
+    /// The length of the torrent's piece space, including padding between files.
+    pub fn total_length(&self) -> anyhow::Result<u64> {
+        Ok(self
+            .iter_layout()?
+            .map(|f| f.offset + f.len)
+            .max()
+            .unwrap_or_default())
+    }
+
+    /// Iterate the files of the torrent. Padding files are skipped.
+    #[inline(never)]
+    pub fn iter_filenames_and_lengths(
+        &self,
+    ) -> anyhow::Result<impl Iterator<Item = (FileIteratorName<'_, BufType>, u64)>> {
+        Ok(self
+            .iter_layout()?
+            .filter(|f| !f.padding)
+            .map(|f| (f.filename, f.len)))
+    }
+
     pub fn iter_file_lengths(&self) -> anyhow::Result<impl Iterator<Item = u64> + '_> {
         Ok(self.iter_filenames_and_lengths()?.map(|(_, l)| l))
     }
 
+    /// Resolves the expected hash of every piece of a v2-only torrent, and validates the
+    /// piece layers against the files' pieces roots.
+    pub fn v2_piece_hashes(
+        &self,
+        lengths: &Lengths,
+        piece_layers: Option<&PieceLayers<BufType>>,
+    ) -> anyhow::Result<Vec<PieceHashV2>> {
+        if !self.piece_length.is_power_of_two() || self.piece_length < merkle::MERKLE_BLOCK_SIZE {
+            anyhow::bail!(
+                "v2 piece length must be a power of two of at least 16KiB, got {}",
+                self.piece_length
+            );
+        }
+        let mut hashes: Vec<Option<PieceHashV2>> = vec![None; lengths.total_pieces() as usize];
+        for file in self.iter_layout()?.filter(|f| f.len > 0) {
+            let root = file
+                .pieces_root
+                .and_then(|r| r.as_ref().try_into().ok())
+                .map(Id32::new)
+                .with_context(|| format!("no valid pieces root for {:?}", file.filename))?;
+            let pieces = lengths.iter_pieces_within_offset(file.offset, file.len);
+            let slots = hashes
+                .get_mut(pieces.start as usize..pieces.end as usize)
+                .context("file is outside of the torrent")?;
+
+            if file.len <= self.piece_length as u64 {
+                slots[0] = Some(PieceHashV2 {
+                    hash: root,
+                    leaves: merkle::leaves_for_len(file.len),
+                });
+                continue;
+            }
+
+            let layer = piece_layers
+                .and_then(|l| l.get(&root.0))
+                .with_context(|| format!("missing piece layer for {:?}", file.filename))?;
+            let layer = layer
+                .as_ref()
+                .chunks_exact(32)
+                .map(|h| Id32::new(h.try_into().unwrap()))
+                .collect::<Vec<_>>();
+            if layer.len() != slots.len() {
+                anyhow::bail!("wrong piece layer length for {:?}", file.filename);
+            }
+            if merkle::root_from_piece_layer(layer.clone(), self.piece_length) != root {
+                anyhow::bail!("piece layer of {:?} doesn't match its root", file.filename);
+            }
+            let leaves = self.piece_length / merkle::MERKLE_BLOCK_SIZE;
+            for (slot, hash) in slots.iter_mut().zip(layer) {
+                *slot = Some(PieceHashV2 { hash, leaves });
+            }
+        }
+        hashes
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .context("some pieces don't belong to any file")
+    }
+
     // NOTE: lenghts MUST be construced with Lenghts::from_torrent, otherwise
     // the yielded results will be garbage.
     pub fn iter_file_details<'a>(
@@ -216,16 +653,13 @@ impl<BufType: AsRef<[u8]>> TorrentMetaV1Info<BufType> {
         lengths: &'a Lengths,
     ) -> anyhow::Result<impl Iterator<Item = FileDetails<'a, BufType>> + 'a> {
         Ok(self
-            .iter_filenames_and_lengths()?
-            .scan(0u64, |acc_offset, (filename, len)| {
-                let offset = *acc_offset;
-                *acc_offset += len;
-                Some(FileDetails {
-                    filename,
-                    pieces: lengths.iter_pieces_within_offset(offset, len),
-                    offset,
-                    len,
-                })
+            .iter_layout()?
+            .filter(|f| !f.padding)
+            .map(|f| FileDetails {
+                filename: f.filename,
+                pieces: lengths.iter_pieces_within_offset(f.offset, f.len),
+                offset: f.offset,
+                len: f.len,
             }))
     }
 }
@@ -234,12 +668,21 @@ impl<BufType: AsRef<[u8]>> TorrentMetaV1Info<BufType> {
 pub struct TorrentMetaV1File<BufType> {
     pub length: u64,
     pub path: Vec<BufType>,
+    // BEP 47 file attributes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attr: Option<BufType>,
 }
 
 impl<BufType> TorrentMetaV1File<BufType>
 where
     BufType: AsRef<[u8]>,
 {
+    pub fn is_padding(&self) -> bool {
+        self.attr
+            .as_ref()
+            .is_some_and(|a| a.as_ref().contains(&b'p'))
+    }
+
     pub fn full_path(&self, parent: &mut PathBuf) -> anyhow::Result<()> {
         for p in self.path.iter() {
             let bit = std::str::from_utf8(p.as_ref())?;
@@ -259,6 +702,7 @@ where
         TorrentMetaV1File {
             length: self.length,
             path: self.path.clone_to_owned(),
+            attr: self.attr.clone_to_owned(),
         }
     }
 }
@@ -277,10 +721,50 @@ where
             length: self.length,
             md5sum: self.md5sum.clone_to_owned(),
             files: self.files.clone_to_owned(),
+            private: self.private,
+            meta_version: self.meta_version,
+            file_tree: self.file_tree.clone_to_owned(),
+        }
+    }
+}
+
+impl<BufType> CloneToOwned for V2FileTree<BufType>
+where
+    BufType: CloneToOwned,
+{
+    type Target = V2FileTree<<BufType as CloneToOwned>::Target>;
+
+    fn clone_to_owned(&self) -> Self::Target {
+        V2FileTree {
+            files: self
+                .files
+                .iter()
+                .map(|f| V2File {
+                    path: f.path.clone_to_owned(),
+                    length: f.length,
+                    pieces_root: f.pieces_root.clone_to_owned(),
+                })
+                .collect(),
         }
     }
 }
 
+impl<BufType> CloneToOwned for PieceLayers<BufType>
+where
+    BufType: CloneToOwned,
+{
+    type Target = PieceLayers<<BufType as CloneToOwned>::Target>;
+
+    fn clone_to_owned(&self) -> Self::Target {
+        PieceLayers(
+            self.0
+                .iter()
+                .map(|(root, layer)| (root.clone_to_owned(), layer.clone_to_owned()))
+                .collect(),
+        )
+    }
+}
+
 impl<BufType> CloneToOwned for TorrentMetaV1<BufType>
 where
     BufType: CloneToOwned,
@@ -298,7 +782,10 @@ where
             publisher: self.publisher.clone_to_owned(),
             publisher_url: self.publisher_url.clone_to_owned(),
             creation_date: self.creation_date,
+            url_list: self.url_list.clone_to_owned(),
+            piece_layers: self.piece_layers.clone_to_owned(),
             info_hash: self.info_hash,
+            info_hash_v2: self.info_hash_v2,
         }
     }
 }
@@ -335,6 +822,15 @@ mod tests {
         dbg!(torrent);
     }
 
+    #[test]
+    fn test_private_flag() {
+        let torrent: TorrentMetaV1Borrowed = torrent_from_bytes(
+            b"d4:infod6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1eee",
+        )
+        .unwrap();
+        assert!(torrent.info.is_private());
+    }
+
     #[test]
     fn test_deserialize_torrent_with_info_hash() {
         let mut buf = Vec::new();
@@ -368,4 +864,138 @@ mod tests {
 
         assert_eq!(torrent, deserialized);
     }
+
+    #[test]
+    fn test_url_list() {
+        let torrent: TorrentMetaV1Borrowed = torrent_from_bytes(
+            b"d4:infod6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-list18:http://example.come",
+        )
+        .unwrap();
+        assert_eq!(torrent.url_list, vec![ByteBuf(b"http://example.com")]);
+
+        let torrent: TorrentMetaV1Borrowed = torrent_from_bytes(
+            b"d4:infod6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-listl12:http://a.com12:http://b.comee",
+        )
+        .unwrap();
+        assert_eq!(
+            torrent.url_list,
+            vec![ByteBuf(b"http://a.com"), ByteBuf(b"http://b.com")]
+        );
+
+        let mut buf = Vec::new();
+        bencode::bencode_serialize_to_writer(&torrent, &mut buf).unwrap();
+        let torrent: TorrentMetaV1Owned = torrent_from_bytes(&buf).unwrap();
+        assert_eq!(torrent.url_list.len(), 2);
+    }
+
+    fn v2_torrent_bytes(piece_length: u32, files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tree = Vec::new();
+        let mut layers = Vec::new();
+        for (name, data) in files {
+            let mut h = merkle::MerkleHasher::new();
+            h.update(data);
+            let root = h.finish(merkle::leaves_for_len(data.len() as u64));
+            if data.len() > piece_length as usize {
+                let leaves = piece_length / merkle::MERKLE_BLOCK_SIZE;
+                let layer: Vec<u8> = data
+                    .chunks(piece_length as usize)
+                    .flat_map(|piece| {
+                        let mut h = merkle::MerkleHasher::new();
+                        h.update(piece);
+                        h.finish(leaves).0
+                    })
+                    .collect();
+                layers.push((ByteBufOwned::from(&root.0[..]), ByteBufOwned::from(layer)));
+            }
+            tree.push(V2File {
+                path: vec![ByteBufOwned::from(name.as_bytes())],
+                length: data.len() as u64,
+                pieces_root: Some(ByteBufOwned::from(&root.0[..])),
+            });
+        }
+        let torrent = TorrentMetaV1Owned {
+            announce: None,
+            announce_list: Vec::new(),
+            info: TorrentMetaV1Info {
+                name: Some(ByteBufOwned::from(&b"test"[..])),
+                pieces: None,
+                piece_length,
+                length: None,
+                md5sum: None,
+                files: None,
+                private: None,
+                meta_version: Some(2),
+                file_tree: Some(V2FileTree { files: tree }),
+            },
+            comment: None,
+            created_by: None,
+            encoding: None,
+            publisher: None,
+            publisher_url: None,
+            creation_date: None,
+            url_list: Vec::new(),
+            piece_layers: Some(PieceLayers(layers)),
+            info_hash: Id20::default(),
+            info_hash_v2: None,
+        };
+        let mut buf = Vec::new();
+        bencode::bencode_serialize_to_writer(&torrent, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_v2_file_tree_layout() {
+        let piece_length = merkle::MERKLE_BLOCK_SIZE * 2;
+        let big = vec![1u8; piece_length as usize * 2 + 100];
+        let buf = v2_torrent_bytes(piece_length, &[("a", &big), ("b", b"hello")]);
+
+        let torrent: TorrentMetaV1Borrowed = torrent_from_bytes(&buf).unwrap();
+        let info_hash_v2 = torrent.info_hash_v2.unwrap();
+        assert_eq!(torrent.info_hash, info_hash_v2.truncate_for_dht());
+        assert!(torrent.info.is_v2());
+        assert!(!torrent.info.is_v1());
+
+        // The second file starts at a piece boundary.
+        let lengths = Lengths::from_torrent(&torrent.info).unwrap();
+        let details: Vec<_> = torrent.info.iter_file_details(&lengths).unwrap().collect();
+        assert_eq!(details[1].offset, piece_length as u64 * 3);
+        assert_eq!(details[1].pieces, 3..4);
+        assert_eq!(lengths.total_pieces(), 4);
+
+        let hashes = torrent
+            .info
+            .v2_piece_hashes(&lengths, torrent.piece_layers.as_ref())
+            .unwrap();
+        assert_eq!(hashes.len(), 4);
+        assert_eq!(hashes[3].hash.0, merkle::sha256(b"hello").0);
+        assert_eq!(hashes[3].leaves, 1);
+
+        // A missing piece layer can't be verified.
+        assert!(torrent.info.v2_piece_hashes(&lengths, None).is_err());
+    }
+
+    #[test]
+    fn test_v2_file_tree_roundtrip() {
+        let buf = v2_torrent_bytes(
+            merkle::MERKLE_BLOCK_SIZE,
+            &[("a", b"x"), ("b", b"yy"), ("c", b"")],
+        );
+        let torrent: TorrentMetaV1Borrowed = torrent_from_bytes(&buf).unwrap();
+        let mut writer = Vec::new();
+        bencode::bencode_serialize_to_writer(&torrent, &mut writer).unwrap();
+        assert_eq!(buf, writer);
+    }
+
+    #[test]
+    fn test_padding_files_are_skipped() {
+        let torrent: TorrentMetaV1Borrowed = torrent_from_bytes(
+            b"d4:infod5:filesld6:lengthi10e4:pathl1:aeed4:attr1:p6:lengthi16374e4:pathl4:.pad5:16374eed6:lengthi5e4:pathl1:beee4:name1:x12:piece lengthi16384e6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaee",
+        )
+        .unwrap();
+        let lengths = Lengths::from_torrent(&torrent.info).unwrap();
+        let details: Vec<_> = torrent.info.iter_file_details(&lengths).unwrap().collect();
+        assert_eq!(details.len(), 2);
+        assert_eq!(details[1].offset, 16384);
+        assert_eq!(details[1].pieces, 1..2);
+    }
 }
@@ -11,6 +11,8 @@ use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::MY_EXTENDED_UT_METADATA;
 
+use super::PeerExtendedMessageIds;
+
 #[derive(Deserialize, Serialize, Debug, Default)]
 pub struct ExtendedHandshake<ByteBuf: Eq + std::hash::Hash> {
     #[serde(bound(deserialize = "ByteBuf: From<&'de [u8]>"))]
@@ -66,6 +68,23 @@ impl<ByteBuf: Eq + std::hash::Hash> ExtendedHandshake<ByteBuf> {
     {
         self.get_msgid(b"ut_metadata")
     }
+
+    pub fn ut_pex(&self) -> Option<u8>
+    where
+        ByteBuf: AsRef<[u8]>,
+    {
+        self.get_msgid(b"ut_pex")
+    }
+
+    pub fn peer_extended_messages(&self) -> PeerExtendedMessageIds
+    where
+        ByteBuf: AsRef<[u8]>,
+    {
+        PeerExtendedMessageIds {
+            ut_metadata: self.ut_metadata(),
+            ut_pex: self.ut_pex(),
+        }
+    }
 }
 
 impl<ByteBuf> CloneToOwned for ExtendedHandshake<ByteBuf>
@@ -4,19 +4,28 @@ use bencode::BencodeValue;
 use clone_to_owned::CloneToOwned;
 use serde::{Deserialize, Serialize};
 
-use self::{handshake::ExtendedHandshake, ut_metadata::UtMetadata};
+use self::{handshake::ExtendedHandshake, ut_metadata::UtMetadata, ut_pex::UtPex};
 
 use super::MessageDeserializeError;
 
 pub mod handshake;
 pub mod ut_metadata;
+pub mod ut_pex;
 
-use super::MY_EXTENDED_UT_METADATA;
+use super::{MY_EXTENDED_UT_METADATA, MY_EXTENDED_UT_PEX};
+
+/// The message ids the peer assigned to extensions in its extended handshake.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PeerExtendedMessageIds {
+    pub ut_metadata: Option<u8>,
+    pub ut_pex: Option<u8>,
+}
 
 #[derive(Debug)]
 pub enum ExtendedMessage<ByteBuf: std::hash::Hash + Eq> {
     Handshake(ExtendedHandshake<ByteBuf>),
     UtMetadata(UtMetadata<ByteBuf>),
+    UtPex(UtPex<ByteBuf>),
     Dyn(u8, BencodeValue<ByteBuf>),
 }
 
@@ -32,6 +41,7 @@ where
             ExtendedMessage::Handshake(h) => ExtendedMessage::Handshake(h.clone_to_owned()),
             ExtendedMessage::Dyn(u, d) => ExtendedMessage::Dyn(*u, d.clone_to_owned()),
             ExtendedMessage::UtMetadata(m) => ExtendedMessage::UtMetadata(m.clone_to_owned()),
+            ExtendedMessage::UtPex(m) => ExtendedMessage::UtPex(m.clone_to_owned()),
         }
     }
 }
@@ -40,7 +50,7 @@ impl<'a, ByteBuf: 'a + std::hash::Hash + Eq + Serialize> ExtendedMessage<ByteBuf
     pub fn serialize(
         &self,
         out: &mut Vec<u8>,
-        extended_handshake_ut_metadata: &dyn Fn() -> Option<u8>,
+        peer_extended_messages: &dyn Fn() -> PeerExtendedMessageIds,
     ) -> anyhow::Result<()>
     where
         ByteBuf: AsRef<[u8]>,
@@ -55,12 +65,19 @@ impl<'a, ByteBuf: 'a + std::hash::Hash + Eq + Serialize> ExtendedMessage<ByteBuf
                 bencode_serialize_to_writer(h, out)?;
             }
             ExtendedMessage::UtMetadata(u) => {
-                let emsg_id = extended_handshake_ut_metadata().ok_or_else(|| {
+                let emsg_id = peer_extended_messages().ut_metadata.ok_or_else(|| {
                     anyhow::anyhow!("need peer's handshake to serialize ut_metadata")
                 })?;
                 out.push(emsg_id);
                 u.serialize(out);
             }
+            ExtendedMessage::UtPex(p) => {
+                let emsg_id = peer_extended_messages()
+                    .ut_pex
+                    .ok_or_else(|| anyhow::anyhow!("need peer's handshake to serialize ut_pex"))?;
+                out.push(emsg_id);
+                bencode_serialize_to_writer(p, out)?;
+            }
         }
         Ok(())
     }
@@ -86,6 +103,7 @@ impl<'a, ByteBuf: 'a + std::hash::Hash + Eq + Serialize> ExtendedMessage<ByteBuf
             MY_EXTENDED_UT_METADATA => {
                 Ok(ExtendedMessage::UtMetadata(UtMetadata::deserialize(buf)?))
             }
+            MY_EXTENDED_UT_PEX => Ok(ExtendedMessage::UtPex(from_bytes(buf)?)),
             _ => Ok(ExtendedMessage::Dyn(emsg_id, from_bytes(buf)?)),
         }
     }
@@ -0,0 +1,130 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use buffers::ByteBufOwned;
+use byteorder::{ByteOrder, BE};
+use clone_to_owned::CloneToOwned;
+use serde::{Deserialize, Serialize};
+
+// BEP 11 peer exchange message. Peers are in compact format: 6 bytes for IPv4, 18 for IPv6.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UtPex<ByteBuf> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub added: Option<ByteBuf>,
+    #[serde(rename = "added.f", skip_serializing_if = "Option::is_none")]
+    pub added_f: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub added6: Option<ByteBuf>,
+    #[serde(rename = "added6.f", skip_serializing_if = "Option::is_none")]
+    pub added6_f: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dropped: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dropped6: Option<ByteBuf>,
+}
+
+impl<ByteBuf: CloneToOwned> CloneToOwned for UtPex<ByteBuf> {
+    type Target = UtPex<<ByteBuf as CloneToOwned>::Target>;
+
+    fn clone_to_owned(&self) -> Self::Target {
+        UtPex {
+            added: self.added.clone_to_owned(),
+            added_f: self.added_f.clone_to_owned(),
+            added6: self.added6.clone_to_owned(),
+            added6_f: self.added6_f.clone_to_owned(),
+            dropped: self.dropped.clone_to_owned(),
+            dropped6: self.dropped6.clone_to_owned(),
+        }
+    }
+}
+
+fn iter_compact<'a, ByteBuf: AsRef<[u8]>>(
+    v4: Option<&'a ByteBuf>,
+    v6: Option<&'a ByteBuf>,
+) -> impl Iterator<Item = SocketAddr> + 'a {
+    let v4 = v4.map(|b| b.as_ref()).unwrap_or_default();
+    let v6 = v6.map(|b| b.as_ref()).unwrap_or_default();
+    let v4 = v4.chunks_exact(6).map(|c| {
+        let ip = Ipv4Addr::new(c[0], c[1], c[2], c[3]);
+        SocketAddr::new(IpAddr::V4(ip), BE::read_u16(&c[4..]))
+    });
+    let v6 = v6.chunks_exact(18).map(|c| {
+        let ip: [u8; 16] = c[..16].try_into().unwrap();
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), BE::read_u16(&c[16..]))
+    });
+    v4.chain(v6)
+}
+
+impl<ByteBuf: AsRef<[u8]>> UtPex<ByteBuf> {
+    pub fn added_peers(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        iter_compact(self.added.as_ref(), self.added6.as_ref())
+    }
+
+    pub fn dropped_peers(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        iter_compact(self.dropped.as_ref(), self.dropped6.as_ref())
+    }
+}
+
+impl UtPex<ByteBufOwned> {
+    pub fn from_addrs(added: &[SocketAddr], dropped: &[SocketAddr]) -> Self {
+        fn compact(addrs: &[SocketAddr], v6: bool) -> Option<ByteBufOwned> {
+            let mut buf = Vec::new();
+            for addr in addrs.iter().filter(|a| a.is_ipv6() == v6) {
+                match addr.ip() {
+                    IpAddr::V4(ip) => buf.extend_from_slice(&ip.octets()),
+                    IpAddr::V6(ip) => buf.extend_from_slice(&ip.octets()),
+                }
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            if buf.is_empty() {
+                None
+            } else {
+                Some(buf.into())
+            }
+        }
+        // We don't know anything about the peers that flags could express.
+        let flags = |v6: bool| {
+            let count = added.iter().filter(|a| a.is_ipv6() == v6).count();
+            (count > 0).then(|| vec![0u8; count].into())
+        };
+        Self {
+            added: compact(added, false),
+            added_f: flags(false),
+            added6: compact(added, true),
+            added6_f: flags(true),
+            dropped: compact(dropped, false),
+            dropped6: compact(dropped, true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use bencode::{bencode_serialize_to_writer, from_bytes};
+    use buffers::ByteBuf;
+
+    use super::UtPex;
+
+    #[test]
+    fn test_ut_pex_roundtrip() {
+        let added: Vec<SocketAddr> = vec![
+            "1.2.3.4:5678".parse().unwrap(),
+            "[2001:db8::1]:6881".parse().unwrap(),
+            "10.0.0.1:1".parse().unwrap(),
+        ];
+        let dropped: Vec<SocketAddr> = vec!["5.6.7.8:80".parse().unwrap()];
+
+        let mut buf = Vec::new();
+        bencode_serialize_to_writer(UtPex::from_addrs(&added, &dropped), &mut buf).unwrap();
+        let pex: UtPex<ByteBuf> = from_bytes(&buf).unwrap();
+
+        assert_eq!(
+            pex.added_peers().collect::<Vec<_>>(),
+            vec![added[0], added[2], added[1]]
+        );
+        assert_eq!(pex.dropped_peers().collect::<Vec<_>>(), dropped);
+        assert_eq!(pex.added_f.unwrap().as_ref(), &[0, 0]);
+        assert!(pex.dropped6.is_none());
+    }
+}
@@ -4,14 +4,17 @@
 
 pub mod extended;
 
+use std::net::Ipv4Addr;
+
 use bincode::Options;
 use buffers::{ByteBuf, ByteBufOwned};
 use byteorder::{ByteOrder, BE};
 use clone_to_owned::CloneToOwned;
 use librqbit_core::{constants::CHUNK_SIZE, hash_id::Id20, lengths::ChunkInfo};
 use serde::{Deserialize, Serialize};
+use sha1w::{ISha1, Sha1};
 
-use self::extended::ExtendedMessage;
+use self::extended::{ExtendedMessage, PeerExtendedMessageIds};
 
 const INTEGER_LEN: usize = 4;
 const MSGID_LEN: usize = 1;
@@ -31,6 +34,8 @@ const LEN_PREFIX_NOT_INTERESTED: u32 = 1;
 const LEN_PREFIX_HAVE: u32 = 5;
 const LEN_PREFIX_PIECE: u32 = 9;
 const LEN_PREFIX_REQUEST: u32 = 13;
+const LEN_PREFIX_HAVE_ALL: u32 = 1;
+const LEN_PREFIX_HAVE_NONE: u32 = 1;
 
 const MSGID_CHOKE: u8 = 0;
 const MSGID_UNCHOKE: u8 = 1;
@@ -41,9 +46,16 @@ const MSGID_BITFIELD: u8 = 5;
 const MSGID_REQUEST: u8 = 6;
 const MSGID_PIECE: u8 = 7;
 const MSGID_CANCEL: u8 = 8;
+// BEP 6 Fast Extension.
+const MSGID_SUGGEST_PIECE: u8 = 13;
+const MSGID_HAVE_ALL: u8 = 14;
+const MSGID_HAVE_NONE: u8 = 15;
+const MSGID_REJECT_REQUEST: u8 = 16;
+const MSGID_ALLOWED_FAST: u8 = 17;
 const MSGID_EXTENDED: u8 = 20;
 
 pub const MY_EXTENDED_UT_METADATA: u8 = 3;
+pub const MY_EXTENDED_UT_PEX: u8 = 1;
 
 #[derive(Debug)]
 pub enum MessageDeserializeError {
@@ -192,6 +204,12 @@ pub enum Message<ByteBuf: std::hash::Hash + Eq> {
     NotInterested,
     Piece(Piece<ByteBuf>),
     Extended(ExtendedMessage<ByteBuf>),
+    // Fast Extension messages. Only sent if both sides set the reserved bit.
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest(Request),
+    AllowedFast(u32),
 }
 
 pub type MessageBorrowed<'a> = Message<ByteBuf<'a>>;
@@ -228,6 +246,11 @@ where
             Message::Have(v) => Message::Have(*v),
             Message::NotInterested => Message::NotInterested,
             Message::Extended(e) => Message::Extended(e.clone_to_owned()),
+            Message::SuggestPiece(v) => Message::SuggestPiece(*v),
+            Message::HaveAll => Message::HaveAll,
+            Message::HaveNone => Message::HaveNone,
+            Message::RejectRequest(req) => Message::RejectRequest(*req),
+            Message::AllowedFast(v) => Message::AllowedFast(*v),
         }
     }
 }
@@ -269,12 +292,17 @@ where
             Message::KeepAlive => (LEN_PREFIX_KEEPALIVE, 0),
             Message::Have(_) => (LEN_PREFIX_HAVE, MSGID_HAVE),
             Message::Extended(_) => (0, MSGID_EXTENDED),
+            Message::SuggestPiece(_) => (LEN_PREFIX_HAVE, MSGID_SUGGEST_PIECE),
+            Message::HaveAll => (LEN_PREFIX_HAVE_ALL, MSGID_HAVE_ALL),
+            Message::HaveNone => (LEN_PREFIX_HAVE_NONE, MSGID_HAVE_NONE),
+            Message::RejectRequest(_) => (LEN_PREFIX_REQUEST, MSGID_REJECT_REQUEST),
+            Message::AllowedFast(_) => (LEN_PREFIX_HAVE, MSGID_ALLOWED_FAST),
         }
     }
     pub fn serialize(
         &self,
         out: &mut Vec<u8>,
-        extended_handshake_ut_metadata: &dyn Fn() -> Option<u8>,
+        peer_extended_messages: &dyn Fn() -> PeerExtendedMessageIds,
     ) -> anyhow::Result<usize> {
         let (lp, msg_id) = self.len_prefix_and_msg_id();
 
@@ -286,7 +314,9 @@ where
         let ser = bopts();
 
         match self {
-            Message::Request(request) | Message::Cancel(request) => {
+            Message::Request(request)
+            | Message::Cancel(request)
+            | Message::RejectRequest(request) => {
                 const MSG_LEN: usize = PREAMBLE_LEN + 12;
                 out.resize(MSG_LEN, 0);
                 debug_assert_eq!(out[PREAMBLE_LEN..].len(), 12);
@@ -301,9 +331,12 @@ where
                 out[PREAMBLE_LEN..PREAMBLE_LEN + block_len].copy_from_slice(b.as_ref());
                 Ok(msg_len)
             }
-            Message::Choke | Message::Unchoke | Message::Interested | Message::NotInterested => {
-                Ok(PREAMBLE_LEN)
-            }
+            Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::HaveAll
+            | Message::HaveNone => Ok(PREAMBLE_LEN),
             Message::Piece(p) => {
                 let block_len = p.block.as_ref().len();
                 let payload_len = 8 + block_len;
@@ -317,14 +350,14 @@ where
                 // the len prefix was already written out to buf
                 Ok(4)
             }
-            Message::Have(v) => {
+            Message::Have(v) | Message::SuggestPiece(v) | Message::AllowedFast(v) => {
                 let msg_len = PREAMBLE_LEN + 4;
                 out.resize(msg_len, 0);
                 BE::write_u32(&mut out[PREAMBLE_LEN..], *v);
                 Ok(msg_len)
             }
             Message::Extended(e) => {
-                e.serialize(out, extended_handshake_ut_metadata)?;
+                e.serialize(out, peer_extended_messages)?;
                 let msg_size = out.len();
                 // no fucking idea why +1, but I tweaked that for it all to match up
                 // with real messages.
@@ -397,16 +430,46 @@ where
                 }
                 Ok((Message::NotInterested, NO_PAYLOAD_MSG_LEN))
             }
-            MSGID_HAVE => {
+            MSGID_HAVE | MSGID_SUGGEST_PIECE | MSGID_ALLOWED_FAST => {
                 let expected_len = 4;
                 match rest.get(..expected_len) {
-                    Some(h) => Ok((Message::Have(BE::read_u32(h)), PREAMBLE_LEN + expected_len)),
+                    Some(h) => {
+                        let index = BE::read_u32(h);
+                        let msg = match msg_id {
+                            MSGID_HAVE => Message::Have(index),
+                            MSGID_SUGGEST_PIECE => Message::SuggestPiece(index),
+                            _ => Message::AllowedFast(index),
+                        };
+                        Ok((msg, PREAMBLE_LEN + expected_len))
+                    }
                     None => {
                         let missing = expected_len - rest.len();
-                        Err(MessageDeserializeError::NotEnoughData(missing, "have"))
+                        Err(MessageDeserializeError::NotEnoughData(
+                            missing,
+                            match msg_id {
+                                MSGID_HAVE => "have",
+                                MSGID_SUGGEST_PIECE => "suggest piece",
+                                _ => "allowed fast",
+                            },
+                        ))
                     }
                 }
             }
+            MSGID_HAVE_ALL | MSGID_HAVE_NONE => {
+                if len_prefix != LEN_PREFIX_HAVE_ALL {
+                    return Err(MessageDeserializeError::IncorrectLenPrefix {
+                        received: len_prefix,
+                        expected: LEN_PREFIX_HAVE_ALL,
+                        msg_id,
+                    });
+                }
+                let msg = if msg_id == MSGID_HAVE_ALL {
+                    Message::HaveAll
+                } else {
+                    Message::HaveNone
+                };
+                Ok((msg, NO_PAYLOAD_MSG_LEN))
+            }
             MSGID_BITFIELD => {
                 if len_prefix <= 1 {
                     return Err(MessageDeserializeError::IncorrectLenPrefix {
@@ -427,15 +490,15 @@ where
                     }
                 }
             }
-            MSGID_REQUEST | MSGID_CANCEL => {
+            MSGID_REQUEST | MSGID_CANCEL | MSGID_REJECT_REQUEST => {
                 let expected_len = 12;
                 match rest.get(..expected_len) {
                     Some(b) => {
                         let request = decoder_config.deserialize::<Request>(b).unwrap();
-                        let req = if msg_id == MSGID_REQUEST {
-                            Message::Request(request)
-                        } else {
-                            Message::Cancel(request)
+                        let req = match msg_id {
+                            MSGID_REQUEST => Message::Request(request),
+                            MSGID_CANCEL => Message::Cancel(request),
+                            _ => Message::RejectRequest(request),
                         };
                         Ok((req, PREAMBLE_LEN + expected_len))
                     }
@@ -443,10 +506,10 @@ where
                         let missing = expected_len - rest.len();
                         Err(MessageDeserializeError::NotEnoughData(
                             missing,
-                            if msg_id == MSGID_REQUEST {
-                                "request"
-                            } else {
-                                "cancel"
+                            match msg_id {
+                                MSGID_REQUEST => "request",
+                                MSGID_CANCEL => "cancel",
+                                _ => "reject request",
                             },
                         ))
                     }
@@ -520,6 +583,8 @@ impl Handshake<ByteBuf<'static>> {
         let mut reserved: u64 = 0;
         // supports extended messaging
         reserved |= 1 << 20;
+        // supports the fast extension
+        reserved |= 1 << 2;
         let mut reserved_arr = [0u8; 8];
         BE::write_u64(&mut reserved_arr, reserved);
 
@@ -567,6 +632,9 @@ impl<B> Handshake<B> {
     pub fn supports_extended(&self) -> bool {
         self.reserved[5] & 0x10 > 0
     }
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[7] & 0x04 > 0
+    }
     fn bopts() -> impl bincode::Options {
         bincode::DefaultOptions::new()
     }
@@ -612,6 +680,33 @@ impl Request {
     }
 }
 
+/// The canonical "allowed fast" set of BEP 6: pieces a peer with this IP may request even
+/// while choked. Only defined for IPv4 peers.
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: Id20, num_pieces: u32, k: usize) -> Vec<u32> {
+    let k = k.min(num_pieces as usize);
+    let mut result = Vec::with_capacity(k);
+    let mut x = Vec::with_capacity(24);
+    x.extend_from_slice(&(u32::from(ip) & 0xffffff00).to_be_bytes());
+    x.extend_from_slice(&info_hash.0);
+    while result.len() < k {
+        let mut h = Sha1::new();
+        h.update(&x);
+        let digest = h.finish();
+        for y in digest.chunks_exact(4) {
+            if result.len() >= k {
+                break;
+            }
+            let index = BE::read_u32(y) % num_pieces;
+            if !result.contains(&index) {
+                result.push(index);
+            }
+        }
+        x.clear();
+        x.extend_from_slice(&digest);
+    }
+    result
+}
+
 #[cfg(test)]
 mod tests {
     use crate::extended::handshake::ExtendedHandshake;
@@ -628,13 +723,51 @@ mod tests {
         let mut buf = Vec::new();
         Handshake::new(info_hash, peer_id).serialize(&mut buf);
         assert_eq!(buf.len(), 20 + 20 + 8 + 19 + 1);
+
+        let (h, _) = Handshake::deserialize(&buf).unwrap();
+        assert!(h.supports_extended());
+        assert!(h.supports_fast());
+    }
+
+    #[test]
+    fn test_fast_extension_messages_roundtrip() {
+        let messages = [
+            MessageOwned::SuggestPiece(5),
+            MessageOwned::HaveAll,
+            MessageOwned::HaveNone,
+            MessageOwned::RejectRequest(Request::new(1, 16384, 16384)),
+            MessageOwned::AllowedFast(42),
+        ];
+        for msg in messages {
+            let mut buf = Vec::new();
+            let len = msg.serialize(&mut buf, &Default::default).unwrap();
+            let (de, de_len) = MessageBorrowed::deserialize(&buf).unwrap();
+            assert_eq!(len, de_len);
+            assert_eq!(format!("{msg:?}"), format!("{de:?}"));
+        }
+    }
+
+    #[test]
+    fn test_allowed_fast_set() {
+        // Test vectors from BEP 6.
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let info_hash = Id20::new([0xaa; 20]);
+        assert_eq!(
+            allowed_fast_set(ip, info_hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, info_hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        assert_eq!(allowed_fast_set(ip, info_hash, 3, 10).len(), 3);
     }
 
     #[test]
     fn test_extended_serialize() {
         let msg = Message::Extended(ExtendedMessage::Handshake(ExtendedHandshake::new()));
         let mut out = Vec::new();
-        msg.serialize(&mut out, &|| None).unwrap();
+        msg.serialize(&mut out, &Default::default).unwrap();
         dbg!(out);
     }
 
@@ -650,7 +783,7 @@ mod tests {
         let (msg, size) = MessageBorrowed::deserialize(&buf).unwrap();
         assert_eq!(size, buf.len());
         let mut write_buf = Vec::new();
-        msg.serialize(&mut write_buf, &|| None).unwrap();
+        msg.serialize(&mut write_buf, &Default::default).unwrap();
         if buf != write_buf {
             {
                 use std::io::Write;
@@ -1,4 +1,4 @@
-use std::{io, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+use std::{io, net::SocketAddr, num::NonZeroU32, path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::Context;
 use clap::{CommandFactory, Parser, ValueEnum};
@@ -12,8 +12,9 @@ use librqbit::{
         StorageFactory, StorageFactoryExt,
     },
     tracing_subscriber_config_utils::{init_logging, InitLoggingOptions},
-    AddTorrent, AddTorrentOptions, AddTorrentResponse, Api, ListOnlyResponse,
-    PeerConnectionOptions, Session, SessionOptions, TorrentStatsState,
+    AddTorrent, AddTorrentOptions, AddTorrentResponse, Api, EmbeddedTrackerOptions, EncryptionMode,
+    Hook, ListOnlyResponse, PeerConnectionOptions, RateLimitsConfig, Session, SessionHooks,
+    SessionOptions, TorrentStatsState, UpnpServerOptions, WatchFolderOptions,
 };
 use size_format::SizeFormatterBinary as SF;
 use tracing::{error, error_span, info, trace_span, warn};
@@ -75,6 +76,10 @@ struct Opts {
     #[arg(long = "peer-read-write-timeout" , value_parser = parse_duration::parse, default_value="10s")]
     peer_read_write_timeout: Duration,
 
+    /// Peer connection encryption (MSE/PE): prefer, require or disable.
+    #[arg(long = "encryption", default_value = "prefer")]
+    encryption: EncryptionMode,
+
     /// How many threads to spawn for the executor.
     #[arg(short = 't', long)]
     worker_threads: Option<usize>,
@@ -95,6 +100,23 @@ struct Opts {
     #[arg(long = "disable-upnp")]
     disable_upnp: bool,
 
+    /// Serve the torrents as a UPnP MediaServer on the HTTP API port, and advertise it on the
+    /// LAN, so that TVs and other DLNA clients can browse and play them.
+    #[arg(long = "enable-upnp-server")]
+    enable_upnp_server: bool,
+
+    /// The name TVs show for the UPnP MediaServer.
+    #[arg(long = "upnp-server-friendly-name")]
+    upnp_server_friendly_name: Option<String>,
+
+    /// Disable uTP. By default uTP listens on the same port as TCP.
+    #[arg(long = "disable-utp")]
+    disable_utp: bool,
+
+    /// Connect to peers over uTP first, falling back to TCP.
+    #[arg(long = "prefer-utp")]
+    prefer_utp: bool,
+
     #[command(subcommand)]
     subcommand: SubCommand,
 
@@ -115,6 +137,18 @@ struct Opts {
     /// If you use it, you know what you are doing.
     #[arg(long)]
     experimental_mmap_storage: bool,
+
+    /// Limit total download speed, in bytes per second. Can be changed at runtime through the HTTP API.
+    #[arg(long = "ratelimit-download")]
+    ratelimit_download_bps: Option<NonZeroU32>,
+
+    /// Limit total upload speed, in bytes per second. Can be changed at runtime through the HTTP API.
+    #[arg(long = "ratelimit-upload")]
+    ratelimit_upload_bps: Option<NonZeroU32>,
+
+    /// How many peers to upload to at once per torrent. 0 means unlimited.
+    #[arg(long = "upload-slots")]
+    upload_slots: Option<usize>,
 }
 
 #[derive(Parser)]
@@ -128,6 +162,45 @@ struct ServerStartOptions {
     disable_persistence: bool,
     #[arg(long = "persistence-filename")]
     persistence_filename: Option<String>,
+
+    /// Run this when a torrent is added, also when restored on startup. Either an http(s):// URL
+    /// to POST the torrent to as JSON, or a shell command that gets it in RQBIT_* environment
+    /// variables. Can be repeated.
+    #[arg(long = "on-added")]
+    on_added: Vec<Hook>,
+
+    /// Run this when a torrent finishes downloading, same format as --on-added.
+    #[arg(long = "on-completed")]
+    on_completed: Vec<Hook>,
+
+    /// Run this when a torrent fails, same format as --on-added.
+    #[arg(long = "on-error")]
+    on_error: Vec<Hook>,
+
+    /// Kill hooks that run for longer than this.
+    #[arg(long = "hook-timeout", value_parser = parse_duration::parse, default_value = "60s")]
+    hook_timeout: Duration,
+
+    /// Add .torrent and .magnet files dropped into this folder. They are renamed to .added or
+    /// .invalid once processed.
+    #[arg(long = "watch-folder")]
+    watch_folder: Option<PathBuf>,
+
+    /// How often to look for new files in the watch folder.
+    #[arg(long = "watch-folder-interval", value_parser = parse_duration::parse, default_value = "5s")]
+    watch_folder_interval: Duration,
+
+    /// Run a BitTorrent tracker. HTTP announces go to /announce on the HTTP API address.
+    #[arg(long = "tracker")]
+    tracker: bool,
+
+    /// Also serve UDP tracker requests on this address, e.g. 0.0.0.0:6969.
+    #[arg(long = "tracker-udp-listen-addr", requires = "tracker")]
+    tracker_udp_listen_addr: Option<SocketAddr>,
+
+    /// Only track torrents that are in the session.
+    #[arg(long = "tracker-only-session-torrents", requires = "tracker")]
+    tracker_only_session_torrents: bool,
 }
 
 #[derive(Parser)]
@@ -195,6 +268,21 @@ impl From<&str> for InitialPeers {
     }
 }
 
+#[derive(Parser)]
+struct MoveOpts {
+    /// The id of the torrent on the running server.
+    id: usize,
+
+    /// The folder to move the files of the torrent to. If not exists, it will be created.
+    output_folder: PathBuf,
+}
+
+#[derive(Parser)]
+struct ScrapeOpts {
+    /// The magnet link, URL or path to the .torrent file.
+    torrent_path: String,
+}
+
 #[derive(Parser)]
 struct CompletionsOpts {
     /// The shell to generate completions for
@@ -208,9 +296,24 @@ struct CompletionsOpts {
 enum SubCommand {
     Server(ServerOpts),
     Download(DownloadOpts),
+    /// Move the files of a torrent on a running server to another folder.
+    Move(MoveOpts),
+    /// Ask the trackers of a torrent about its swarm through a running server, without adding it.
+    Scrape(ScrapeOpts),
     Completions(CompletionsOpts),
 }
 
+fn upnp_server_opts(opts: &Opts) -> Option<UpnpServerOptions> {
+    if !opts.enable_upnp_server {
+        return None;
+    }
+    let mut upnp_opts = UpnpServerOptions::default();
+    if let Some(name) = opts.upnp_server_friendly_name.clone() {
+        upnp_opts.friendly_name = name;
+    }
+    Some(upnp_opts)
+}
+
 fn _start_deadlock_detector_thread() {
     use parking_lot::deadlock;
     use std::thread;
@@ -292,6 +395,7 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
         peer_opts: Some(PeerConnectionOptions {
             connect_timeout: Some(opts.peer_connect_timeout),
             read_write_timeout: Some(opts.peer_read_write_timeout),
+            encryption: Some(opts.encryption),
             ..Default::default()
         }),
         listen_port_range: if !opts.disable_tcp_listen {
@@ -300,6 +404,8 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
             None
         },
         enable_upnp_port_forwarding: !opts.disable_upnp,
+        disable_utp: opts.disable_utp,
+        prefer_utp: opts.prefer_utp,
         defer_writes_up_to: opts.defer_writes_up_to,
         default_storage_factory: Some({
             fn wrap<S: StorageFactory + Clone>(s: S) -> impl StorageFactory {
@@ -320,6 +426,14 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
                 wrap(FilesystemStorageFactory::default()).boxed()
             }
         }),
+        ratelimits: RateLimitsConfig {
+            upload_bps: opts.ratelimit_upload_bps,
+            download_bps: opts.ratelimit_download_bps,
+        },
+        upload_slots: opts.upload_slots,
+        hooks: Default::default(),
+        watch_folder: None,
+        tracker: None,
     };
 
     let stats_printer = |session: Arc<Session>| async move {
@@ -380,6 +494,24 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
                 sopts.persistence = !start_opts.disable_persistence;
                 sopts.persistence_filename =
                     start_opts.persistence_filename.clone().map(PathBuf::from);
+                sopts.hooks = SessionHooks {
+                    on_added: start_opts.on_added.clone(),
+                    on_completed: start_opts.on_completed.clone(),
+                    on_error: start_opts.on_error.clone(),
+                    timeout: Some(start_opts.hook_timeout),
+                };
+                sopts.watch_folder = start_opts.watch_folder.clone().map(|folder| {
+                    let mut watch_opts = WatchFolderOptions::new(folder);
+                    watch_opts.poll_interval = Some(start_opts.watch_folder_interval);
+                    watch_opts
+                });
+                if start_opts.tracker {
+                    sopts.tracker = Some(EmbeddedTrackerOptions {
+                        udp_listen_addr: start_opts.tracker_udp_listen_addr,
+                        only_session_torrents: start_opts.tracker_only_session_torrents,
+                        ..Default::default()
+                    });
+                }
 
                 let session =
                     Session::new_with_opts(PathBuf::from(&start_opts.output_folder), sopts)
@@ -395,7 +527,13 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
                     Some(log_config.rust_log_reload_tx),
                     Some(log_config.line_broadcast),
                 );
-                let http_api = HttpApi::new(api, Some(HttpApiOptions { read_only: false }));
+                let http_api = HttpApi::new(
+                    api,
+                    Some(HttpApiOptions {
+                        read_only: false,
+                        upnp_server: upnp_server_opts(&opts),
+                    }),
+                );
                 let http_api_listen_addr = opts.http_api_listen_addr;
                 http_api
                     .make_http_api_and_run(http_api_listen_addr)
@@ -481,7 +619,13 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
                     Some(log_config.rust_log_reload_tx),
                     Some(log_config.line_broadcast),
                 );
-                let http_api = HttpApi::new(api, Some(HttpApiOptions { read_only: true }));
+                let http_api = HttpApi::new(
+                    api,
+                    Some(HttpApiOptions {
+                        read_only: true,
+                        upnp_server: upnp_server_opts(&opts),
+                    }),
+                );
                 let http_api_listen_addr = opts.http_api_listen_addr;
                 librqbit_spawn(
                     "http_api",
@@ -567,6 +711,50 @@ async fn async_main(opts: Opts) -> anyhow::Result<()> {
                 }
             }
         }
+        SubCommand::Move(move_opts) => {
+            let http_api_url = format!("http://{}", opts.http_api_listen_addr);
+            let client = http_api_client::HttpApiClient::new(&http_api_url)?;
+            client
+                .validate_rqbit_server()
+                .await
+                .with_context(|| format!("error connecting to HTTP API at {http_api_url}"))?;
+            // Relative to where we are, not to where the server runs.
+            let output_folder = std::env::current_dir()
+                .context("error getting current directory")?
+                .join(&move_opts.output_folder);
+            client
+                .move_storage(move_opts.id, output_folder.clone())
+                .await
+                .context("error moving torrent")?;
+            info!("moved torrent {} to {:?}", move_opts.id, output_folder);
+            Ok(())
+        }
+        SubCommand::Scrape(scrape_opts) => {
+            let http_api_url = format!("http://{}", opts.http_api_listen_addr);
+            let client = http_api_client::HttpApiClient::new(&http_api_url)?;
+            client
+                .validate_rqbit_server()
+                .await
+                .with_context(|| format!("error connecting to HTTP API at {http_api_url}"))?;
+            let response = client
+                .scrape(AddTorrent::from_cli_argument(&scrape_opts.torrent_path)?)
+                .await
+                .context("error scraping torrent")?;
+            for tracker in response.trackers {
+                match (tracker.error, tracker.complete) {
+                    (Some(e), _) => warn!("{}: error: {}", tracker.url, e),
+                    (None, None) => info!("{}: torrent not known to the tracker", tracker.url),
+                    (None, Some(complete)) => info!(
+                        "{}: seeders {}, leechers {}, downloaded {}",
+                        tracker.url,
+                        complete,
+                        tracker.incomplete.unwrap_or_default(),
+                        tracker.downloaded.unwrap_or_default()
+                    ),
+                }
+            }
+            Ok(())
+        }
         SubCommand::Completions(completions_opts) => {
             clap_complete::generate(
                 completions_opts.shell,
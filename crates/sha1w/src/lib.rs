@@ -37,3 +37,37 @@ impl ISha1 for Sha1System {
         result_arr
     }
 }
+
+// BitTorrent v2 uses SHA-256 for info hashes and piece merkle trees.
+pub type Sha256 = Sha256System;
+
+pub trait ISha256 {
+    fn new() -> Self;
+    fn update(&mut self, buf: &[u8]);
+    fn finish(self) -> [u8; 32];
+}
+
+pub struct Sha256System {
+    inner: crypto_hash::Hasher,
+}
+
+impl ISha256 for Sha256System {
+    fn new() -> Self {
+        Self {
+            inner: crypto_hash::Hasher::new(crypto_hash::Algorithm::SHA256),
+        }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        use std::io::Write;
+        self.inner.write_all(buf).unwrap();
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let result = self.inner.finish();
+        debug_assert_eq!(result.len(), 32);
+        let mut result_arr = [0u8; 32];
+        result_arr.copy_from_slice(&result);
+        result_arr
+    }
+}
@@ -1,5 +1,11 @@
 mod tracker_comms;
 mod tracker_comms_http;
 mod tracker_comms_udp;
+mod tracker_list;
+mod tracker_server;
 
 pub use tracker_comms::*;
+pub use tracker_list::{TrackerList, TrackerState, TrackerStatus};
+pub use tracker_server::{
+    AnnounceReply, AnnounceRequest, InfoHashFilter, TrackerServer, TrackerServerOptions,
+};
@@ -1,24 +1,32 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use anyhow::bail;
 use anyhow::Context;
-use futures::future::Either;
 use futures::stream::BoxStream;
 use futures::stream::FuturesUnordered;
-use futures::FutureExt;
 use futures::StreamExt;
+use serde::Serialize;
 use tracing::debug;
 use tracing::error_span;
 use tracing::trace;
 use tracing::Instrument;
 use url::Url;
 
-use crate::tracker_comms_http;
+use crate::tracker_comms_http::{self, TrackerRequestEvent};
 use crate::tracker_comms_udp;
+use crate::tracker_list::{check_tracker_url, TrackerList, TrackerState};
 use librqbit_core::hash_id::Id20;
 
+// How soon to retry trackers that failed, unless the interval is forced.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+// UDP trackers might never respond.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// How many torrents to scrape in one request. That's the most UDP allows, and it keeps HTTP URLs
+// reasonably short.
+const SCRAPE_BATCH_SIZE: usize = tracker_comms_udp::MAX_SCRAPE_INFO_HASHES;
+
 pub struct TrackerComms {
     info_hash: Id20,
     peer_id: Id20,
@@ -26,9 +34,10 @@ pub struct TrackerComms {
     force_tracker_interval: Option<Duration>,
     tx: Sender,
     tcp_listen_port: Option<u16>,
+    trackers: TrackerList,
 }
 
-#[derive(Default)]
+#[derive(Default, Clone, Copy)]
 pub enum TrackerCommsStatsState {
     #[default]
     None,
@@ -37,7 +46,7 @@ pub enum TrackerCommsStatsState {
     Live,
 }
 
-#[derive(Default)]
+#[derive(Default, Clone, Copy)]
 pub struct TrackerCommsStats {
     pub uploaded_bytes: u64,
     pub downloaded_bytes: u64,
@@ -70,165 +79,389 @@ impl TorrentStatsProvider for () {
     }
 }
 
+impl TorrentStatsProvider for TrackerCommsStats {
+    fn get(&self) -> TrackerCommsStats {
+        *self
+    }
+}
+
 type Sender = tokio::sync::mpsc::Sender<SocketAddr>;
 
-enum SupportedTracker {
-    Udp(Url),
-    Http(Url),
+// What trackers respond with, regardless of the protocol.
+struct AnnounceResult {
+    interval: Duration,
+    seeders: u64,
+    leechers: u64,
+    peers: Vec<SocketAddr>,
 }
 
-impl std::fmt::Debug for SupportedTracker {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            SupportedTracker::Udp(u) => std::fmt::Display::fmt(u, f),
-            SupportedTracker::Http(u) => std::fmt::Display::fmt(u, f),
-        }
-    }
+/// What a tracker knows about the swarm of a torrent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ScrapeStats {
+    /// Seeders.
+    pub complete: u64,
+    /// Leechers.
+    pub incomplete: u64,
+    /// How many times the torrent was downloaded fully.
+    pub downloaded: u64,
+}
+
+#[derive(Debug)]
+pub struct TrackerScrape {
+    pub url: String,
+    /// Torrents the tracker doesn't know about are missing.
+    pub result: anyhow::Result<HashMap<Id20, ScrapeStats>>,
+}
+
+// Keep the query of the tracker URL, private trackers put passkeys there.
+fn append_query(url: &mut Url, query: &str) {
+    let query = match url.query() {
+        Some(q) if !q.is_empty() => format!("{q}&{query}"),
+        _ => query.to_owned(),
+    };
+    url.set_query(Some(&query));
 }
 
 impl TrackerComms {
+    /// Asks all the trackers at once about the given torrents. Each tracker gets as few requests
+    /// as possible, with many info hashes in each.
+    pub async fn scrape(
+        urls: impl IntoIterator<Item = String>,
+        info_hashes: &[Id20],
+    ) -> Vec<TrackerScrape> {
+        futures::future::join_all(urls.into_iter().map(|url| async move {
+            let result =
+                tokio::time::timeout(REQUEST_TIMEOUT, Self::scrape_tracker(&url, info_hashes))
+                    .await
+                    .context("timeout")
+                    .and_then(|r| r);
+            if let Err(e) = &result {
+                debug!("error scraping the tracker {}: {:#}", url, e);
+            }
+            TrackerScrape { url, result }
+        }))
+        .await
+    }
+
+    /// Tell the trackers right away that the download completed. Only the trackers that are
+    /// announced to by a running [`TrackerComms::start`] are told.
+    pub fn announce_completed(trackers: &TrackerList) {
+        trackers.mark_completed();
+    }
+
+    /// Tell the trackers that were told we started that we stopped, so that they don't hand us
+    /// out to other peers. Best-effort: errors are only logged.
+    pub async fn announce_stopped(
+        info_hash: Id20,
+        peer_id: Id20,
+        trackers: TrackerList,
+        stats: TrackerCommsStats,
+        tcp_listen_port: Option<u16>,
+    ) {
+        let urls = trackers.take_started(info_hash);
+        if urls.is_empty() {
+            return;
+        }
+        // Peers aren't needed anymore.
+        let (tx, _) = tokio::sync::mpsc::channel(1);
+        let comms = Self {
+            info_hash,
+            peer_id,
+            stats: Box::new(stats),
+            force_tracker_interval: None,
+            tx,
+            tcp_listen_port,
+            trackers,
+        };
+        futures::future::join_all(urls.iter().map(|url| {
+            let comms = &comms;
+            async move {
+                let result = comms
+                    .request(url, Some(TrackerRequestEvent::Stopped), &mut HashMap::new())
+                    .await;
+                match result {
+                    Ok(_) => debug!(url, ?info_hash, "announced stopped"),
+                    Err(e) => debug!("error announcing stopped to {}: {:#}", url, e),
+                }
+            }
+        }))
+        .await;
+    }
+
+    async fn scrape_tracker(
+        url: &str,
+        info_hashes: &[Id20],
+    ) -> anyhow::Result<HashMap<Id20, ScrapeStats>> {
+        let url = check_tracker_url(url)?;
+        let mut result = HashMap::new();
+        if url.scheme() == "udp" {
+            let hp: (&str, u16) = (
+                url.host_str().context("missing host")?,
+                url.port().context("missing port")?,
+            );
+            let mut requester = tracker_comms_udp::UdpTrackerRequester::new(hp)
+                .await
+                .context("error creating UDP tracker requester")?;
+            for chunk in info_hashes.chunks(SCRAPE_BATCH_SIZE) {
+                let entries = requester.scrape(chunk).await?;
+                result.extend(chunk.iter().zip(entries).map(|(info_hash, e)| {
+                    (
+                        *info_hash,
+                        ScrapeStats {
+                            complete: e.seeders as u64,
+                            incomplete: e.leechers as u64,
+                            downloaded: e.completed as u64,
+                        },
+                    )
+                }));
+            }
+        } else {
+            let scrape_url =
+                tracker_comms_http::scrape_url(&url).context("tracker doesn't support scrape")?;
+            for chunk in info_hashes.chunks(SCRAPE_BATCH_SIZE) {
+                let mut url = scrape_url.clone();
+                append_query(&mut url, &tracker_comms_http::scrape_querystring(chunk));
+                debug!(?url, "scraping tracker over http");
+                let response = reqwest::get(url).await?;
+                if !response.status().is_success() {
+                    anyhow::bail!("tracker responded with {:?}", response.status());
+                }
+                let bytes = response.bytes().await?;
+                if let Ok(error) = bencode::from_bytes::<tracker_comms_http::TrackerError>(&bytes) {
+                    anyhow::bail!(
+                        "tracker returned failure. Failure reason: {}",
+                        error.failure_reason
+                    )
+                };
+                let response = bencode::from_bytes::<tracker_comms_http::ScrapeResponse>(&bytes)?;
+                // Some trackers return all their torrents.
+                result.extend(
+                    response
+                        .files
+                        .into_iter()
+                        .filter(|(info_hash, _)| chunk.contains(info_hash))
+                        .map(|(info_hash, f)| {
+                            (
+                                info_hash,
+                                ScrapeStats {
+                                    complete: f.complete,
+                                    incomplete: f.incomplete,
+                                    downloaded: f.downloaded,
+                                },
+                            )
+                        }),
+                );
+            }
+        }
+        Ok(result)
+    }
+
+    /// Announces to all tiers of the trackers at once. Within a tier, trackers are tried in order
+    /// until one responds, and that one is moved to the front of the tier (BEP 12).
+    ///
+    /// The tiers are re-read when trackers are added or removed.
     pub fn start(
         info_hash: Id20,
         peer_id: Id20,
-        trackers: Vec<String>,
+        trackers: TrackerList,
         stats: Box<dyn TorrentStatsProvider>,
         force_interval: Option<Duration>,
         tcp_listen_port: Option<u16>,
-    ) -> Option<BoxStream<'static, SocketAddr>> {
-        let trackers = trackers
-            .into_iter()
-            .filter_map(|t| match Url::parse(&t) {
-                Ok(parsed) => match parsed.scheme() {
-                    "http" | "https" => Some(SupportedTracker::Http(parsed)),
-                    "udp" => Some(SupportedTracker::Udp(parsed)),
-                    _ => {
-                        debug!("unsuppoted tracker URL: {}", t);
-                        None
-                    }
-                },
-                Err(e) => {
-                    debug!("error parsing tracker URL {}: {}", t, e);
-                    None
-                }
-            })
-            .collect::<Vec<_>>();
+    ) -> BoxStream<'static, SocketAddr> {
         if trackers.is_empty() {
             debug!(?info_hash, "trackers list is empty");
-            return None;
         }
-
         tracing::trace!(?trackers);
 
         let (tx, mut rx) = tokio::sync::mpsc::channel::<SocketAddr>(16);
 
         let s = async_stream::stream! {
-            use futures::StreamExt;
-            let comms = Arc::new(Self {
+            let comms = Self {
                 info_hash,
                 peer_id,
                 stats,
                 force_tracker_interval: force_interval,
                 tx,
                 tcp_listen_port,
-            });
-            let mut futures = FuturesUnordered::new();
-            for tracker in trackers {
-                futures.push(comms.add_tracker(tracker))
-            }
-            while !(futures.is_empty()) {
-                tokio::select! {
-                    addr = rx.recv() => {
-                        if let Some(addr) = addr {
-                            yield addr;
+                trackers,
+            };
+            let mut changed = comms.trackers.subscribe();
+            loop {
+                let mut futures = comms
+                    .trackers
+                    .tiers()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(tier, urls)| {
+                        let span = error_span!(parent: None, "tracker_tier", tier, info_hash = ?info_hash);
+                        comms.task_tier(urls).instrument(span)
+                    })
+                    .collect::<FuturesUnordered<_>>();
+                // Until the trackers change, then start over with the new tiers.
+                loop {
+                    tokio::select! {
+                        addr = rx.recv() => {
+                            if let Some(addr) = addr {
+                                yield addr;
+                            }
                         }
-                    }
-                    e = futures.next(), if !futures.is_empty() => {
-                        if let Some(Err(e)) = e {
-                            debug!("error: {e}");
+                        _ = futures.next(), if !futures.is_empty() => {}
+                        r = changed.changed() => {
+                            if r.is_err() {
+                                return;
+                            }
+                            break;
                         }
                     }
                 }
             }
         };
 
-        Some(s.boxed())
+        s.boxed()
     }
 
-    fn add_tracker(
-        &self,
-        url: SupportedTracker,
-    ) -> Either<
-        impl std::future::Future<Output = anyhow::Result<()>> + '_ + Send,
-        impl std::future::Future<Output = anyhow::Result<()>> + '_ + Send,
-    > {
-        let info_hash = self.info_hash;
-        match url {
-            SupportedTracker::Udp(url) => {
-                let span = error_span!(parent: None, "udp_tracker", tracker = %url, info_hash = ?info_hash);
-                self.task_single_tracker_monitor_udp(url)
-                    .instrument(span)
-                    .right_future()
-            }
-            SupportedTracker::Http(url) => {
-                let span = error_span!(
-                    parent: None,
-                    "http_tracker",
-                    tracker = %url,
-                    info_hash = ?info_hash
-                );
-                self.task_single_tracker_monitor_http(url)
-                    .instrument(span)
-                    .left_future()
-            }
+    async fn task_tier(&self, mut urls: Vec<String>) {
+        let mut udp_requesters = HashMap::new();
+
+        // Don't announce before we were asked to, e.g. when restarted because trackers changed.
+        let next_announce = urls
+            .first()
+            .and_then(|url| {
+                self.trackers
+                    .with_swarm(url, self.info_hash, |s| s.next_announce)
+            })
+            .flatten();
+        if let Some(t) = next_announce {
+            tokio::time::sleep_until(t.into()).await;
         }
-    }
 
-    async fn task_single_tracker_monitor_http(&self, mut tracker_url: Url) -> anyhow::Result<()> {
-        let mut event = Some(tracker_comms_http::TrackerRequestEvent::Started);
-        trace!(url=?tracker_url, "starting monitor");
         loop {
-            let stats = self.stats.get();
-            let request = tracker_comms_http::TrackerRequest {
-                info_hash: self.info_hash,
-                peer_id: self.peer_id,
-                port: self.tcp_listen_port.unwrap_or(0),
-                uploaded: stats.uploaded_bytes,
-                downloaded: stats.downloaded_bytes,
-                left: stats.get_left_to_download_bytes(),
-                compact: true,
-                no_peer_id: false,
-                event,
-                ip: None,
-                numwant: None,
-                key: None,
-                trackerid: None,
-            };
-
-            let request_query = request.as_querystring();
-            tracker_url.set_query(Some(&request_query));
-
-            match self.tracker_one_request_http(tracker_url.clone()).await {
-                Ok(interval) => {
-                    event = None;
-                    let interval = self
-                        .force_tracker_interval
-                        .unwrap_or_else(|| Duration::from_secs(interval));
-                    debug!(
-                        "sleeping for {:?} after calling tracker {}",
-                        interval,
-                        tracker_url.host().unwrap()
-                    );
-                    tokio::time::sleep(interval).await;
+            let mut interval = None;
+            for (idx, url) in urls.iter().enumerate() {
+                if let Some(i) = self.announce(url, &mut udp_requesters).await {
+                    interval = Some((idx, i));
+                    break;
                 }
-                Err(e) => {
-                    debug!("error calling the tracker {}: {:#}", tracker_url, e);
-                    tokio::time::sleep(Duration::from_secs(60)).await;
+            }
+            let interval = match interval {
+                Some((idx, interval)) => {
+                    if idx > 0 {
+                        self.trackers.promote(&urls[idx]);
+                        urls[..=idx].rotate_right(1);
+                    }
+                    interval
                 }
+                None => self.force_tracker_interval.unwrap_or(RETRY_INTERVAL),
             };
+            debug!(
+                "sleeping for {:?} after calling tracker {}",
+                interval, urls[0]
+            );
+            tokio::time::sleep(interval).await;
         }
     }
 
-    async fn tracker_one_request_http(&self, tracker_url: Url) -> anyhow::Result<u64> {
+    // Announces to one tracker and records how it went. Returns when to announce next if the
+    // tracker responded.
+    async fn announce(
+        &self,
+        url: &str,
+        udp_requesters: &mut HashMap<Url, tracker_comms_udp::UdpTrackerRequester>,
+    ) -> Option<Duration> {
+        let event = self
+            .trackers
+            .with_swarm(url, self.info_hash, |s| {
+                s.state = TrackerState::Updating;
+                s.event()
+            })
+            .flatten();
+        let result = self.request(url, event, udp_requesters).await;
+
+        let now = Instant::now();
+        match result {
+            Ok(response) => {
+                let interval = self.force_tracker_interval.unwrap_or(response.interval);
+                trace!(
+                    url,
+                    peers = response.peers.len(),
+                    "received announce response"
+                );
+                self.trackers.with_swarm(url, self.info_hash, |s| {
+                    s.started = true;
+                    if event == Some(TrackerRequestEvent::Completed) {
+                        s.completed = false;
+                    }
+                    s.state = TrackerState::Working;
+                    s.seeders = Some(response.seeders);
+                    s.leechers = Some(response.leechers);
+                    s.failure_reason = None;
+                    s.last_announce = Some(now);
+                    s.next_announce = Some(now + interval);
+                });
+                for peer in response.peers {
+                    if self.tx.send(peer).await.is_err() {
+                        break;
+                    }
+                }
+                Some(interval)
+            }
+            Err(e) => {
+                debug!("error calling the tracker {}: {:#}", url, e);
+                let retry = self.force_tracker_interval.unwrap_or(RETRY_INTERVAL);
+                self.trackers.with_swarm(url, self.info_hash, |s| {
+                    s.state = TrackerState::Error;
+                    s.failure_reason = Some(format!("{e:#}"));
+                    s.last_announce = Some(now);
+                    s.next_announce = Some(now + retry);
+                });
+                None
+            }
+        }
+    }
+
+    async fn request(
+        &self,
+        url: &str,
+        event: Option<TrackerRequestEvent>,
+        udp_requesters: &mut HashMap<Url, tracker_comms_udp::UdpTrackerRequester>,
+    ) -> anyhow::Result<AnnounceResult> {
+        let url = check_tracker_url(url)?;
+        let request = async {
+            if url.scheme() == "udp" {
+                self.announce_udp(&url, event, udp_requesters).await
+            } else {
+                self.announce_http(url.clone(), event).await
+            }
+        };
+        tokio::time::timeout(REQUEST_TIMEOUT, request)
+            .await
+            .context("timeout")?
+    }
+
+    async fn announce_http(
+        &self,
+        mut tracker_url: Url,
+        event: Option<TrackerRequestEvent>,
+    ) -> anyhow::Result<AnnounceResult> {
+        let stats = self.stats.get();
+        let request = tracker_comms_http::TrackerRequest {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+            port: self.tcp_listen_port.unwrap_or(0),
+            uploaded: stats.uploaded_bytes,
+            downloaded: stats.downloaded_bytes,
+            left: stats.get_left_to_download_bytes(),
+            compact: true,
+            no_peer_id: false,
+            event,
+            ip: None,
+            numwant: None,
+            key: None,
+            trackerid: None,
+        };
+
+        append_query(&mut tracker_url, &request.as_querystring());
+
         debug!(url = ?tracker_url, "calling tracker over http");
         let response: reqwest::Response = reqwest::get(tracker_url).await?;
         if !response.status().is_success() {
@@ -242,80 +475,184 @@ impl TrackerComms {
             )
         };
         let response = bencode::from_bytes::<tracker_comms_http::TrackerResponse>(&bytes)?;
-
-        for peer in response.peers.iter_sockaddrs() {
-            self.tx.send(peer).await?;
-        }
-        Ok(response.interval)
+        Ok(AnnounceResult {
+            interval: Duration::from_secs(response.interval),
+            seeders: response.complete,
+            leechers: response.incomplete,
+            peers: response.iter_peers().collect(),
+        })
     }
 
-    async fn task_single_tracker_monitor_udp(&self, url: Url) -> anyhow::Result<()> {
+    async fn announce_udp(
+        &self,
+        url: &Url,
+        event: Option<TrackerRequestEvent>,
+        requesters: &mut HashMap<Url, tracker_comms_udp::UdpTrackerRequester>,
+    ) -> anyhow::Result<AnnounceResult> {
         use tracker_comms_udp::*;
 
-        if url.scheme() != "udp" {
-            bail!("expected UDP scheme in {}", url);
-        }
-        let hp: (&str, u16) = (
-            url.host_str().context("missing host")?,
-            url.port().context("missing port")?,
-        );
-        let mut requester = UdpTrackerRequester::new(hp)
-            .await
-            .context("error creating UDP tracker requester")?;
+        let requester = match requesters.entry(url.clone()) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let hp: (&str, u16) = (
+                    url.host_str().context("missing host")?,
+                    url.port().context("missing port")?,
+                );
+                e.insert(
+                    UdpTrackerRequester::new(hp)
+                        .await
+                        .context("error creating UDP tracker requester")?,
+                )
+            }
+        };
 
-        let mut sleep_interval: Option<Duration> = None;
-        loop {
-            if let Some(i) = sleep_interval {
-                trace!(interval=?sleep_interval, "sleeping");
-                tokio::time::sleep(i).await;
+        let stats = self.stats.get();
+        let request = AnnounceFields {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+            downloaded: stats.downloaded_bytes,
+            left: stats.get_left_to_download_bytes(),
+            uploaded: stats.uploaded_bytes,
+            event: match event {
+                None => EVENT_NONE,
+                Some(TrackerRequestEvent::Started) => EVENT_STARTED,
+                Some(TrackerRequestEvent::Completed) => EVENT_COMPLETED,
+                Some(TrackerRequestEvent::Stopped) => EVENT_STOPPED,
+            },
+            key: 0, // whatever that is?
+            port: self.tcp_listen_port.unwrap_or(0),
+        };
+
+        let response = match requester.announce(request).await {
+            Ok(response) => response,
+            Err(e) => {
+                // Connection ids expire, reconnect next time.
+                requesters.remove(url);
+                return Err(e);
             }
+        };
+        Ok(AnnounceResult {
+            interval: Duration::from_secs(response.interval.max(5) as u64),
+            seeders: response.seeders as u64,
+            leechers: response.leechers as u64,
+            peers: response.addrs,
+        })
+    }
+}
 
-            let stats = self.stats.get();
-            let request = AnnounceFields {
-                info_hash: self.info_hash,
-                peer_id: self.peer_id,
-                downloaded: stats.downloaded_bytes,
-                left: stats.get_left_to_download_bytes(),
-                uploaded: stats.uploaded_bytes,
-                event: match stats.torrent_state {
-                    TrackerCommsStatsState::None => EVENT_NONE,
-                    TrackerCommsStatsState::Initializing => EVENT_STARTED,
-                    TrackerCommsStatsState::Paused => EVENT_STOPPED,
-                    TrackerCommsStatsState::Live => {
-                        if stats.is_completed() {
-                            EVENT_COMPLETED
-                        } else {
-                            EVENT_STARTED
-                        }
-                    }
-                },
-                key: 0, // whatever that is?
-                port: self.tcp_listen_port.unwrap_or(0),
-            };
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use byteorder::{BigEndian, ByteOrder};
+    use futures::StreamExt;
+    use librqbit_core::hash_id::Id20;
+    use tokio::sync::mpsc::UnboundedReceiver;
 
-            match requester.announce(request).await {
-                Ok(response) => {
-                    trace!(len = response.addrs.len(), "received announce response");
-                    for addr in response.addrs {
-                        self.tx
-                            .send(SocketAddr::V4(addr))
-                            .await
-                            .context("rx closed")?;
+    use super::{ScrapeStats, TrackerComms};
+    use crate::tracker_comms_udp::{EVENT_COMPLETED, EVENT_STARTED, EVENT_STOPPED};
+    use crate::{TrackerList, TrackerState};
+
+    // A UDP tracker that knows every torrent, without peers: seeders is the first byte of the
+    // info hash. Returns its URL and the events of the announces it received.
+    async fn fake_udp_tracker() -> (String, UnboundedReceiver<u32>) {
+        let sock = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = sock.local_addr().unwrap().port();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 4096];
+            loop {
+                let (size, addr) = sock.recv_from(&mut buf).await.unwrap();
+                let (action, tid) = (&buf[8..12], &buf[12..16]);
+                let mut response = Vec::new();
+                response.extend_from_slice(action);
+                response.extend_from_slice(tid);
+                match BigEndian::read_u32(action) {
+                    0 => response.extend_from_slice(&42u64.to_be_bytes()),
+                    1 => {
+                        let _ = tx.send(BigEndian::read_u32(&buf[80..84]));
+                        for n in [1800u32, 0, 0] {
+                            response.extend_from_slice(&n.to_be_bytes());
+                        }
                     }
-                    let new_interval = response.interval.max(5);
-                    let new_interval = Duration::from_secs(new_interval as u64);
-                    sleep_interval = Some(self.force_tracker_interval.unwrap_or(new_interval));
-                }
-                Err(e) => {
-                    debug!(url = ?url, "error reading announce response: {e:#}");
-                    if sleep_interval.is_none() {
-                        sleep_interval = Some(
-                            self.force_tracker_interval
-                                .unwrap_or(Duration::from_secs(60)),
-                        );
+                    _ => {
+                        for info_hash in buf[16..size].chunks(20) {
+                            for n in [info_hash[0] as u32, 2, 1] {
+                                response.extend_from_slice(&n.to_be_bytes());
+                            }
+                        }
                     }
                 }
+                sock.send_to(&response, addr).await.unwrap();
+            }
+        });
+        (format!("udp://127.0.0.1:{port}"), rx)
+    }
+
+    #[tokio::test]
+    async fn test_scrape_udp() {
+        let (url, _) = fake_udp_tracker().await;
+        let info_hashes = (0..100).map(|i| Id20::new([i; 20])).collect::<Vec<_>>();
+        let scrapes = TrackerComms::scrape([url, "ws://a".to_owned()], &info_hashes).await;
+        assert_eq!(scrapes.len(), 2);
+        let files = scrapes[0].result.as_ref().unwrap();
+        assert_eq!(files.len(), 100);
+        assert_eq!(
+            files[&info_hashes[99]],
+            ScrapeStats {
+                complete: 99,
+                incomplete: 1,
+                downloaded: 2
             }
+        );
+        assert!(scrapes[1].result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_announce_events() {
+        async fn next_event(events: &mut UnboundedReceiver<u32>) -> u32 {
+            tokio::time::timeout(Duration::from_secs(5), events.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        }
+
+        let (url, mut events) = fake_udp_tracker().await;
+        let info_hash = Id20::new([1; 20]);
+        let peer_id = Id20::new([2; 20]);
+        let trackers = TrackerList::new(vec![vec![url]]);
+
+        let mut peers = TrackerComms::start(
+            info_hash,
+            peer_id,
+            trackers.clone(),
+            Box::new(()),
+            None,
+            None,
+        );
+        let running = tokio::spawn(async move { while peers.next().await.is_some() {} });
+        assert_eq!(next_event(&mut events).await, EVENT_STARTED);
+        while trackers.status()[0].state != TrackerState::Working {
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
+
+        TrackerComms::announce_completed(&trackers);
+        assert_eq!(next_event(&mut events).await, EVENT_COMPLETED);
+
+        running.abort();
+        let stop = || {
+            TrackerComms::announce_stopped(
+                info_hash,
+                peer_id,
+                trackers.clone(),
+                Default::default(),
+                None,
+            )
+        };
+        stop().await;
+        assert_eq!(next_event(&mut events).await, EVENT_STOPPED);
+        // Only trackers that were told we started are told we stopped.
+        stop().await;
+        assert!(events.try_recv().is_err());
     }
 }
@@ -1,21 +1,23 @@
+use anyhow::Context;
 use buffers::ByteBuf;
 use byteorder::ByteOrder;
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::{
+    borrow::Cow,
+    collections::HashMap,
     fmt::Write,
     marker::PhantomData,
-    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     str::FromStr,
 };
 
 use librqbit_core::hash_id::Id20;
+use url::Url;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TrackerRequestEvent {
     Started,
-    #[allow(dead_code)]
     Stopped,
-    #[allow(dead_code)]
     Completed,
 }
 
@@ -36,7 +38,7 @@ pub struct TrackerRequest {
     pub trackerid: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct TrackerError<'a> {
     #[serde(rename = "failure reason", borrow)]
     pub failure_reason: ByteBuf<'a>,
@@ -58,7 +60,7 @@ impl<'a> DictPeer<'a> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Peers {
     addrs: Vec<SocketAddr>,
 }
@@ -69,6 +71,23 @@ impl Peers {
     }
 }
 
+// Always compact, IPv6 peers go to "peers6".
+impl Serialize for Peers {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut buf = Vec::with_capacity(self.addrs.len() * 6);
+        for addr in &self.addrs {
+            if let SocketAddr::V4(addr) = addr {
+                buf.extend_from_slice(&addr.ip().octets());
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+            }
+        }
+        serializer.serialize_bytes(&buf)
+    }
+}
+
 impl<'de> serde::de::Deserialize<'de> for Peers {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -135,6 +154,69 @@ where
     de.deserialize_str(Visitor {})
 }
 
+// BEP 7: "peers6" is a string of 18 byte entries: 16 bytes of IPv6 address and 2 bytes of port.
+#[derive(Debug, Default)]
+pub struct Peers6 {
+    addrs: Vec<SocketAddrV6>,
+}
+
+impl<'de> serde::de::Deserialize<'de> for Peers6 {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct Visitor;
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Peers6;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("IPv6 peers in binary format")
+            }
+
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(Peers6 {
+                    addrs: parse_compact_peers6(v),
+                })
+            }
+        }
+        deserializer.deserialize_bytes(Visitor)
+    }
+}
+
+impl Peers6 {
+    fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+}
+
+impl Serialize for Peers6 {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut buf = Vec::with_capacity(self.addrs.len() * 18);
+        for addr in &self.addrs {
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        serializer.serialize_bytes(&buf)
+    }
+}
+
+fn parse_compact_peers6(b: &[u8]) -> Vec<SocketAddrV6> {
+    b.chunks_exact(18)
+        .map(|chunk| {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&chunk[..16]);
+            let port = byteorder::BigEndian::read_u16(&chunk[16..18]);
+            SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0)
+        })
+        .collect()
+}
+
 fn parse_compact_peers(b: &[u8]) -> Vec<SocketAddrV4> {
     let mut ips = Vec::new();
     for chunk in b.chunks_exact(6) {
@@ -147,20 +229,183 @@ fn parse_compact_peers(b: &[u8]) -> Vec<SocketAddrV4> {
     ips
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct TrackerResponse<'a> {
-    #[serde(rename = "warning message", borrow)]
+    #[serde(
+        rename = "warning message",
+        borrow,
+        skip_serializing_if = "Option::is_none"
+    )]
     pub warning_message: Option<ByteBuf<'a>>,
     pub complete: u64,
     pub interval: u64,
-    #[serde(rename = "min interval")]
+    #[serde(rename = "min interval", skip_serializing_if = "Option::is_none")]
     pub min_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub tracker_id: Option<ByteBuf<'a>>,
     pub incomplete: u64,
+    #[serde(default)]
     pub peers: Peers,
+    #[serde(default, skip_serializing_if = "Peers6::is_empty")]
+    pub peers6: Peers6,
+}
+
+impl<'a> TrackerResponse<'a> {
+    /// A compact response, as trackers send.
+    pub fn new(interval: u64, complete: u64, incomplete: u64, peers: &[SocketAddr]) -> Self {
+        Self {
+            warning_message: None,
+            complete,
+            interval,
+            min_interval: None,
+            tracker_id: None,
+            incomplete,
+            peers: Peers {
+                addrs: peers.iter().copied().filter(|a| a.is_ipv4()).collect(),
+            },
+            peers6: Peers6 {
+                addrs: peers
+                    .iter()
+                    .filter_map(|a| match a {
+                        SocketAddr::V6(a) => Some(*a),
+                        SocketAddr::V4(_) => None,
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    /// Both IPv4 and IPv6 peers.
+    pub fn iter_peers(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.peers
+            .iter_sockaddrs()
+            .chain(self.peers6.addrs.iter().map(|a| SocketAddr::V6(*a)))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScrapeFile {
+    pub complete: u64,
+    #[serde(default)]
+    pub downloaded: u64,
+    pub incomplete: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScrapeResponse {
+    #[serde(default)]
+    pub files: HashMap<Id20, ScrapeFile>,
+}
+
+/// BEP 48: the scrape URL is the announce URL with the last "announce" path component replaced
+/// by "scrape". Trackers whose URL doesn't look like that don't support scraping.
+pub fn scrape_url(announce_url: &Url) -> Option<Url> {
+    let path = announce_url.path();
+    let (dir, last) = path.rsplit_once('/')?;
+    let rest = last.strip_prefix("announce")?;
+    let mut url = announce_url.clone();
+    url.set_path(&format!("{dir}/scrape{rest}"));
+    Some(url)
+}
+
+pub fn scrape_querystring(info_hashes: &[Id20]) -> String {
+    use urlencoding as u;
+    let mut s = String::new();
+    for info_hash in info_hashes {
+        if !s.is_empty() {
+            s.push('&');
+        }
+        s.push_str("info_hash=");
+        s.push_str(u::encode_binary(&info_hash.0).as_ref());
+    }
+    s
+}
+
+// Keys and percent-decoded values. Values are bytes, as info hashes and peer ids are binary.
+fn parse_querystring(qs: &str) -> impl Iterator<Item = (&str, Cow<'_, [u8]>)> {
+    qs.split('&').filter(|kv| !kv.is_empty()).map(|kv| {
+        let (k, v) = kv.split_once('=').unwrap_or((kv, ""));
+        (k, urlencoding::decode_binary(v.as_bytes()))
+    })
+}
+
+fn parse_id20(v: &[u8]) -> anyhow::Result<Id20> {
+    if v.len() != 20 {
+        anyhow::bail!("expected 20 bytes, got {}", v.len());
+    }
+    let mut id = [0u8; 20];
+    id.copy_from_slice(v);
+    Ok(Id20::new(id))
+}
+
+fn parse_str_value<T: FromStr>(key: &str, v: &[u8]) -> anyhow::Result<T> {
+    std::str::from_utf8(v)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .with_context(|| format!("invalid {key}"))
+}
+
+/// The info hashes of a scrape request.
+pub fn parse_scrape_querystring(qs: &str) -> anyhow::Result<Vec<Id20>> {
+    parse_querystring(qs)
+        .filter(|(k, _)| *k == "info_hash")
+        .map(|(_, v)| parse_id20(&v).context("invalid info_hash"))
+        .collect()
 }
 
 impl TrackerRequest {
+    /// Parse the query string of an announce, as trackers do.
+    pub fn from_querystring(qs: &str) -> anyhow::Result<Self> {
+        let mut info_hash = None;
+        let mut peer_id = None;
+        let mut port = None;
+        let mut request = Self {
+            info_hash: Default::default(),
+            peer_id: Default::default(),
+            event: None,
+            port: 0,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            compact: false,
+            no_peer_id: false,
+            ip: None,
+            numwant: None,
+            key: None,
+            trackerid: None,
+        };
+        for (k, v) in parse_querystring(qs) {
+            match k {
+                "info_hash" => info_hash = Some(parse_id20(&v).context("invalid info_hash")?),
+                "peer_id" => peer_id = Some(parse_id20(&v).context("invalid peer_id")?),
+                "port" => port = Some(parse_str_value(k, &v)?),
+                "uploaded" => request.uploaded = parse_str_value(k, &v)?,
+                "downloaded" => request.downloaded = parse_str_value(k, &v)?,
+                "left" => request.left = parse_str_value(k, &v)?,
+                "compact" => request.compact = &*v == b"1",
+                "no_peer_id" => request.no_peer_id = &*v == b"1",
+                "event" => {
+                    request.event = match &*v {
+                        b"started" => Some(TrackerRequestEvent::Started),
+                        b"stopped" => Some(TrackerRequestEvent::Stopped),
+                        b"completed" => Some(TrackerRequestEvent::Completed),
+                        // "empty" is the same as none.
+                        _ => None,
+                    }
+                }
+                "ip" => request.ip = parse_str_value(k, &v).ok(),
+                "numwant" => request.numwant = parse_str_value(k, &v).ok(),
+                "key" => request.key = Some(String::from_utf8_lossy(&v).into_owned()),
+                "trackerid" => request.trackerid = Some(String::from_utf8_lossy(&v).into_owned()),
+                _ => {}
+            }
+        }
+        request.info_hash = info_hash.context("missing info_hash")?;
+        request.peer_id = peer_id.context("missing peer_id")?;
+        request.port = port.context("missing port")?;
+        Ok(request)
+    }
+
     pub fn as_querystring(&self) -> String {
         use urlencoding as u;
         let mut s = String::new();
@@ -230,4 +475,104 @@ mod tests {
         };
         dbg!(request.as_querystring());
     }
+
+    #[test]
+    fn test_parse_response_with_peers6() {
+        let mut buf = b"d8:completei1e10:incompletei2e8:intervali1800e5:peers6:".to_vec();
+        buf.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+        buf.extend_from_slice(b"6:peers618:");
+        let ip6: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        buf.extend_from_slice(&ip6.octets());
+        buf.extend_from_slice(&[0x1a, 0xe2]);
+        buf.push(b'e');
+
+        let response = bencode::from_bytes::<TrackerResponse>(&buf).unwrap();
+        assert_eq!(
+            response.iter_peers().collect::<Vec<_>>(),
+            vec![
+                "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::1]:6882".parse::<SocketAddr>().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_querystring_roundtrip() {
+        let request = TrackerRequest {
+            info_hash: Id20::new([0xff; 20]),
+            peer_id: Id20::new([b'&'; 20]),
+            port: 6881,
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+            compact: true,
+            no_peer_id: false,
+            event: Some(TrackerRequestEvent::Completed),
+            ip: None,
+            numwant: Some(10),
+            key: None,
+            trackerid: None,
+        };
+        let parsed = TrackerRequest::from_querystring(&request.as_querystring()).unwrap();
+        assert_eq!(parsed.info_hash, request.info_hash);
+        assert_eq!(parsed.peer_id, request.peer_id);
+        assert_eq!(
+            (parsed.port, parsed.uploaded, parsed.downloaded, parsed.left),
+            (6881, 1, 2, 3)
+        );
+        assert_eq!(parsed.event, Some(TrackerRequestEvent::Completed));
+        assert_eq!(parsed.numwant, Some(10));
+        assert!(parsed.compact);
+
+        assert!(TrackerRequest::from_querystring("info_hash=abc&port=1").is_err());
+        let hashes = [Id20::new([1; 20]), Id20::new([2; 20])];
+        assert_eq!(
+            parse_scrape_querystring(&scrape_querystring(&hashes)).unwrap(),
+            hashes
+        );
+    }
+
+    #[test]
+    fn test_serialize_response() {
+        let peers = [
+            "127.0.0.1:6881".parse().unwrap(),
+            "[2001:db8::1]:6882".parse().unwrap(),
+        ];
+        let mut buf = Vec::new();
+        bencode::bencode_serialize_to_writer(TrackerResponse::new(1800, 1, 2, &peers), &mut buf)
+            .unwrap();
+        let response = bencode::from_bytes::<TrackerResponse>(&buf).unwrap();
+        assert_eq!((response.interval, response.complete), (1800, 1));
+        assert_eq!(response.iter_peers().collect::<Vec<_>>(), peers);
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        assert_eq!(
+            scrape_url(&url("http://example.com/announce?passkey=x")),
+            Some(url("http://example.com/scrape?passkey=x"))
+        );
+        assert_eq!(
+            scrape_url(&url("http://example.com/x/announce.php")),
+            Some(url("http://example.com/x/scrape.php"))
+        );
+        assert_eq!(scrape_url(&url("http://example.com/a")), None);
+        assert_eq!(scrape_url(&url("http://example.com/announce/x")), None);
+    }
+
+    #[test]
+    fn test_parse_scrape_response() {
+        let hash = Id20::new([1; 20]);
+        let mut buf = b"d5:filesd20:".to_vec();
+        buf.extend_from_slice(&hash.0);
+        buf.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+
+        let response = bencode::from_bytes::<ScrapeResponse>(&buf).unwrap();
+        let file = &response.files[&hash];
+        assert_eq!(
+            (file.complete, file.downloaded, file.incomplete),
+            (5, 50, 10)
+        );
+    }
 }
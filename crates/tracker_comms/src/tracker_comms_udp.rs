@@ -1,4 +1,4 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use anyhow::{bail, Context};
 use librqbit_core::hash_id::Id20;
@@ -8,8 +8,8 @@ use tracing::trace;
 
 const ACTION_CONNECT: u32 = 0;
 const ACTION_ANNOUNCE: u32 = 1;
-// const ACTION_SCRAPE: u32 = 2;
-// const ACTION_ERROR: u32 = 3;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
 
 pub const EVENT_NONE: u32 = 0;
 pub const EVENT_COMPLETED: u32 = 1;
@@ -17,10 +17,13 @@ pub const EVENT_STARTED: u32 = 2;
 pub const EVENT_STOPPED: u32 = 3;
 
 pub type ConnectionId = u64;
-const CONNECTION_ID_MAGIC: ConnectionId = 0x41727101980;
+pub const CONNECTION_ID_MAGIC: ConnectionId = 0x41727101980;
 
 pub type TransactionId = u32;
 
+// BEP 15: up to about 74 torrents can be scraped at once.
+pub const MAX_SCRAPE_INFO_HASHES: usize = 74;
+
 pub fn new_transaction_id() -> TransactionId {
     rand::thread_rng().gen()
 }
@@ -41,6 +44,7 @@ pub struct AnnounceFields {
 pub enum Request {
     Connect,
     Announce(ConnectionId, AnnounceFields),
+    Scrape(ConnectionId, Vec<Id20>),
 }
 
 impl Request {
@@ -67,9 +71,69 @@ impl Request {
                 buf.extend_from_slice(&(-1i32).to_be_bytes()); // num want -1
                 buf.extend_from_slice(&fields.port.to_be_bytes());
             }
+            Request::Scrape(connection_id, info_hashes) => {
+                buf.extend_from_slice(&connection_id.to_be_bytes());
+                buf.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+                buf.extend_from_slice(&transaction_id.to_be_bytes());
+                for info_hash in info_hashes {
+                    buf.extend_from_slice(&info_hash.0);
+                }
+            }
         }
         buf.len() - cur_len
     }
+
+    // What trackers do. The IP address, num_want and BEP 41 options of announces are ignored.
+    pub fn parse(buf: &[u8]) -> anyhow::Result<(TransactionId, Self)> {
+        let (connection_id, buf) = u64::parse_num(buf).context("can't parse connection id")?;
+        let (action, buf) = u32::parse_num(buf).context("can't parse action")?;
+        let (tid, buf) = u32::parse_num(buf).context("can't parse transaction id")?;
+        let request = match action {
+            ACTION_CONNECT => {
+                if connection_id != CONNECTION_ID_MAGIC {
+                    bail!("expected the protocol id in connect request");
+                }
+                Request::Connect
+            }
+            ACTION_ANNOUNCE => {
+                let (info_hash, b) = split_slice(buf, 20).context("can't parse info hash")?;
+                let (peer_id, b) = split_slice(b, 20).context("can't parse peer id")?;
+                let (downloaded, b) = u64::parse_num(b).context("can't parse downloaded")?;
+                let (left, b) = u64::parse_num(b).context("can't parse left")?;
+                let (uploaded, b) = u64::parse_num(b).context("can't parse uploaded")?;
+                let (event, b) = u32::parse_num(b).context("can't parse event")?;
+                let (_ip, b) = u32::parse_num(b).context("can't parse ip")?;
+                let (key, b) = u32::parse_num(b).context("can't parse key")?;
+                let (_num_want, b) = i32::parse_num(b).context("can't parse num_want")?;
+                let (port, _) = u16::parse_num(b).context("can't parse port")?;
+                Request::Announce(
+                    connection_id,
+                    AnnounceFields {
+                        info_hash: Id20::new(s_to_arr(info_hash)),
+                        peer_id: Id20::new(s_to_arr(peer_id)),
+                        downloaded,
+                        left,
+                        uploaded,
+                        event,
+                        key,
+                        port,
+                    },
+                )
+            }
+            ACTION_SCRAPE => {
+                if buf.is_empty() || buf.len() % 20 != 0 {
+                    bail!("expected info hashes, got {} bytes", buf.len());
+                }
+                let info_hashes = buf
+                    .chunks_exact(20)
+                    .map(|h| Id20::new(s_to_arr(h)))
+                    .collect();
+                Request::Scrape(connection_id, info_hashes)
+            }
+            _ => bail!("unsupported action {action}"),
+        };
+        Ok((tid, request))
+    }
 }
 
 #[derive(Debug)]
@@ -77,13 +141,23 @@ pub struct AnnounceResponse {
     pub interval: u32,
     pub leechers: u32,
     pub seeders: u32,
-    pub addrs: Vec<SocketAddrV4>,
+    pub addrs: Vec<SocketAddr>,
+}
+
+// One per requested info hash, in the same order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeResponseEntry {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
 }
 
 #[derive(Debug)]
 pub enum Response {
     Connect(ConnectionId),
     Announce(AnnounceResponse),
+    Scrape(Vec<ScrapeResponseEntry>),
+    Error(String),
 }
 
 fn split_slice(s: &[u8], first_len: usize) -> Option<(&[u8], &[u8])> {
@@ -124,7 +198,49 @@ parse_impl!(i64, 8);
 parse_impl!(i16, 2);
 
 impl Response {
-    pub fn parse(buf: &[u8]) -> anyhow::Result<(TransactionId, Self)> {
+    // Peers are written as they are, so they should all be of the address family of the socket.
+    pub fn serialize(&self, transaction_id: TransactionId, buf: &mut Vec<u8>) -> usize {
+        let cur_len = buf.len();
+        match self {
+            Response::Connect(connection_id) => {
+                buf.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+                buf.extend_from_slice(&transaction_id.to_be_bytes());
+                buf.extend_from_slice(&connection_id.to_be_bytes());
+            }
+            Response::Announce(r) => {
+                buf.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                buf.extend_from_slice(&transaction_id.to_be_bytes());
+                buf.extend_from_slice(&r.interval.to_be_bytes());
+                buf.extend_from_slice(&r.leechers.to_be_bytes());
+                buf.extend_from_slice(&r.seeders.to_be_bytes());
+                for addr in &r.addrs {
+                    match addr.ip() {
+                        IpAddr::V4(ip) => buf.extend_from_slice(&ip.octets()),
+                        IpAddr::V6(ip) => buf.extend_from_slice(&ip.octets()),
+                    }
+                    buf.extend_from_slice(&addr.port().to_be_bytes());
+                }
+            }
+            Response::Scrape(entries) => {
+                buf.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+                buf.extend_from_slice(&transaction_id.to_be_bytes());
+                for e in entries {
+                    buf.extend_from_slice(&e.seeders.to_be_bytes());
+                    buf.extend_from_slice(&e.completed.to_be_bytes());
+                    buf.extend_from_slice(&e.leechers.to_be_bytes());
+                }
+            }
+            Response::Error(message) => {
+                buf.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+                buf.extend_from_slice(&transaction_id.to_be_bytes());
+                buf.extend_from_slice(message.as_bytes());
+            }
+        }
+        buf.len() - cur_len
+    }
+
+    // Peers are 6 bytes long when talking to the tracker over IPv4, and 18 bytes over IPv6.
+    pub fn parse(buf: &[u8], ipv6: bool) -> anyhow::Result<(TransactionId, Self)> {
         let (action, buf) = u32::parse_num(buf).context("can't parse action")?;
         let (tid, mut buf) = u32::parse_num(buf).context("can't parse transaction id")?;
         let response = match action {
@@ -140,13 +256,19 @@ impl Response {
                 let (seeders, mut b) = u32::parse_num(b).context("can't parse seeders")?;
                 let mut addrs = Vec::new();
                 while !b.is_empty() {
-                    let (ip, b2) = u32::parse_num(b)?;
-                    let ip = Ipv4Addr::from(ip);
-                    b = b2;
+                    let ip = if ipv6 {
+                        let (ip, b2) = split_slice(b, 16).context("expected 16 bytes")?;
+                        b = b2;
+                        Ipv6Addr::from(s_to_arr::<16>(ip)).into()
+                    } else {
+                        let (ip, b2) = u32::parse_num(b)?;
+                        b = b2;
+                        Ipv4Addr::from(ip).into()
+                    };
 
                     let (port, b2) = u16::parse_num(b)?;
                     b = b2;
-                    addrs.push(SocketAddrV4::new(ip, port));
+                    addrs.push(SocketAddr::new(ip, port));
                 }
                 buf = b;
                 Response::Announce(AnnounceResponse {
@@ -156,6 +278,26 @@ impl Response {
                     addrs,
                 })
             }
+            ACTION_SCRAPE => {
+                let mut entries = Vec::new();
+                while !buf.is_empty() {
+                    let (seeders, b) = u32::parse_num(buf).context("can't parse seeders")?;
+                    let (completed, b) = u32::parse_num(b).context("can't parse completed")?;
+                    let (leechers, b) = u32::parse_num(b).context("can't parse leechers")?;
+                    buf = b;
+                    entries.push(ScrapeResponseEntry {
+                        seeders,
+                        completed,
+                        leechers,
+                    });
+                }
+                Response::Scrape(entries)
+            }
+            ACTION_ERROR => {
+                let message = String::from_utf8_lossy(buf).into_owned();
+                buf = &[];
+                Response::Error(message)
+            }
             _ => bail!("unsupported action {action}"),
         };
 
@@ -172,6 +314,7 @@ impl Response {
 
 pub struct UdpTrackerRequester {
     sock: tokio::net::UdpSocket,
+    ipv6: bool,
     connection_id: ConnectionId,
     read_buf: Vec<u8>,
     write_buf: Vec<u8>,
@@ -180,7 +323,14 @@ pub struct UdpTrackerRequester {
 impl UdpTrackerRequester {
     // Addr is "host:port"
     pub async fn new(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
-        let sock = tokio::net::UdpSocket::bind("0.0.0.0:0")
+        let addr = tokio::net::lookup_host(addr)
+            .await
+            .context("error resolving tracker address")?
+            .next()
+            .context("tracker address resolved to nothing")?;
+        let ipv6 = addr.is_ipv6();
+        let bind_addr = if ipv6 { "[::]:0" } else { "0.0.0.0:0" };
+        let sock = tokio::net::UdpSocket::bind(bind_addr)
             .await
             .context("error binding UDP socket")?;
         sock.connect(addr)
@@ -204,7 +354,7 @@ impl UdpTrackerRequester {
             .context("error receiving from socket")?;
 
         let (rtid, response) =
-            Response::parse(&read_buf[..size]).context("error parsing response")?;
+            Response::parse(&read_buf[..size], ipv6).context("error parsing response")?;
         if tid != rtid {
             bail!("expected transaction id {} == {}", tid, rtid);
         }
@@ -219,6 +369,7 @@ impl UdpTrackerRequester {
 
         Ok(Self {
             sock,
+            ipv6,
             connection_id,
             read_buf,
             write_buf,
@@ -230,10 +381,34 @@ impl UdpTrackerRequester {
         let response = self.request(request).await?;
         match response {
             Response::Announce(r) => Ok(r),
+            Response::Error(e) => bail!("tracker returned failure. Failure reason: {e}"),
             other => bail!("unexpected response {other:?}, expected announce"),
         }
     }
 
+    pub async fn scrape(
+        &mut self,
+        info_hashes: &[Id20],
+    ) -> anyhow::Result<Vec<ScrapeResponseEntry>> {
+        if info_hashes.len() > MAX_SCRAPE_INFO_HASHES {
+            bail!(
+                "can't scrape more than {} torrents at once",
+                MAX_SCRAPE_INFO_HASHES
+            );
+        }
+        let request = Request::Scrape(self.connection_id, info_hashes.to_vec());
+        match self.request(request).await? {
+            Response::Scrape(r) if r.len() == info_hashes.len() => Ok(r),
+            Response::Scrape(r) => bail!(
+                "expected {} scrape entries, got {}",
+                info_hashes.len(),
+                r.len()
+            ),
+            Response::Error(e) => bail!("tracker returned failure. Failure reason: {e}"),
+            other => bail!("unexpected response {other:?}, expected scrape"),
+        }
+    }
+
     pub async fn request(&mut self, request: Request) -> anyhow::Result<Response> {
         let tid = new_transaction_id();
         self.write_buf.clear();
@@ -245,7 +420,7 @@ impl UdpTrackerRequester {
             .context("error sending")?;
         let size = self.sock.recv(&mut self.read_buf).await?;
 
-        let (rtid, response) = Response::parse(&self.read_buf[..size])?;
+        let (rtid, response) = Response::parse(&self.read_buf[..size], self.ipv6)?;
         trace!("received response");
         if tid != rtid {
             bail!("unexpected transaction id");
@@ -261,16 +436,134 @@ mod tests {
     use librqbit_core::{hash_id::Id20, peer_id::generate_peer_id};
 
     use crate::tracker_comms_udp::{
-        new_transaction_id, AnnounceFields, Request, Response, EVENT_NONE,
+        new_transaction_id, AnnounceFields, AnnounceResponse, Request, Response,
+        ScrapeResponseEntry, EVENT_NONE,
     };
 
     #[test]
     fn test_parse_announce() {
         let b = include_bytes!("../resources/test/udp-tracker-announce-response.bin");
-        let (tid, response) = Response::parse(b).unwrap();
+        let (tid, response) = Response::parse(b, false).unwrap();
         dbg!(tid, response);
     }
 
+    #[test]
+    fn test_parse_announce_ipv6() {
+        let mut b = Vec::new();
+        b.extend_from_slice(&1u32.to_be_bytes()); // action
+        b.extend_from_slice(&42u32.to_be_bytes()); // transaction id
+        b.extend_from_slice(&1800u32.to_be_bytes());
+        b.extend_from_slice(&1u32.to_be_bytes());
+        b.extend_from_slice(&2u32.to_be_bytes());
+        b.extend_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        b.extend_from_slice(&6881u16.to_be_bytes());
+
+        let (tid, response) = Response::parse(&b, true).unwrap();
+        assert_eq!(tid, 42);
+        match response {
+            Response::Announce(r) => {
+                assert_eq!(r.addrs, vec!["[2001:db8::1]:6881".parse().unwrap()])
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let mut b = Vec::new();
+        b.extend_from_slice(&3u32.to_be_bytes()); // action
+        b.extend_from_slice(&42u32.to_be_bytes()); // transaction id
+        b.extend_from_slice(b"torrent not registered");
+
+        let (_, response) = Response::parse(&b, false).unwrap();
+        match response {
+            Response::Error(e) => assert_eq!(e, "torrent not registered"),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_scrape() {
+        let hashes = vec![Id20::new([1; 20]), Id20::new([2; 20])];
+        let mut req = Vec::new();
+        Request::Scrape(5, hashes).serialize(42, &mut req);
+        assert_eq!(req.len(), 16 + 2 * 20);
+        assert_eq!(&req[8..12], &2u32.to_be_bytes());
+        assert_eq!(&req[16..36], &[1; 20]);
+
+        let mut b = Vec::new();
+        b.extend_from_slice(&2u32.to_be_bytes()); // action
+        b.extend_from_slice(&42u32.to_be_bytes()); // transaction id
+        for n in [10u32, 20, 30, 0, 1, 2] {
+            b.extend_from_slice(&n.to_be_bytes());
+        }
+        let (tid, response) = Response::parse(&b, false).unwrap();
+        assert_eq!(tid, 42);
+        match response {
+            Response::Scrape(r) => assert_eq!(
+                r,
+                vec![
+                    ScrapeResponseEntry {
+                        seeders: 10,
+                        completed: 20,
+                        leechers: 30
+                    },
+                    ScrapeResponseEntry {
+                        seeders: 0,
+                        completed: 1,
+                        leechers: 2
+                    }
+                ]
+            ),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut buf = Vec::new();
+        Request::Announce(
+            7,
+            AnnounceFields {
+                info_hash: Id20::new([1; 20]),
+                peer_id: Id20::new([2; 20]),
+                downloaded: 3,
+                left: 4,
+                uploaded: 5,
+                event: EVENT_NONE,
+                key: 6,
+                port: 6881,
+            },
+        )
+        .serialize(42, &mut buf);
+        match Request::parse(&buf).unwrap() {
+            (42, Request::Announce(7, f)) => {
+                assert_eq!((f.info_hash, f.left, f.port), (Id20::new([1; 20]), 4, 6881))
+            }
+            other => panic!("unexpected request {:?}", other),
+        }
+
+        buf.clear();
+        Response::Announce(AnnounceResponse {
+            interval: 1800,
+            leechers: 1,
+            seeders: 2,
+            addrs: vec!["[2001:db8::1]:6881".parse().unwrap()],
+        })
+        .serialize(42, &mut buf);
+        match Response::parse(&buf, true).unwrap() {
+            (42, Response::Announce(r)) => {
+                assert_eq!(r.addrs, vec!["[2001:db8::1]:6881".parse().unwrap()])
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
     #[ignore]
     #[tokio::test]
     async fn test_announce() {
@@ -287,7 +580,7 @@ mod tests {
 
         let size = sock.recv(&mut read_buf).await.unwrap();
 
-        let (rtid, response) = Response::parse(&read_buf[..size]).unwrap();
+        let (rtid, response) = Response::parse(&read_buf[..size], false).unwrap();
         assert_eq!(tid, rtid);
         let connection_id = match response {
             Response::Connect(connection_id) => {
@@ -329,7 +622,7 @@ mod tests {
         }
 
         dbg!(&read_buf[..size]);
-        let (rtid, response) = Response::parse(&read_buf[..size]).unwrap();
+        let (rtid, response) = Response::parse(&read_buf[..size], false).unwrap();
         assert_eq!(tid, rtid);
         match response {
             Response::Announce(r) => {
@@ -0,0 +1,380 @@
+// The trackers of a torrent, grouped in BEP 12 tiers, with the last known status of each.
+// It's shared between the torrent and its running announce tasks, so that trackers can be
+// added and removed while the torrent is live.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::bail;
+use librqbit_core::hash_id::Id20;
+use parking_lot::RwLock;
+use serde::Serialize;
+use url::Url;
+
+use crate::tracker_comms_http::TrackerRequestEvent;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackerState {
+    #[default]
+    NotContacted,
+    Updating,
+    Working,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackerStatus {
+    pub url: String,
+    pub tier: usize,
+    pub state: TrackerState,
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+    pub failure_reason: Option<String>,
+    pub last_announce_secs_ago: Option<u64>,
+    pub next_announce_in_secs: Option<u64>,
+}
+
+// What a tracker knows about one swarm of the torrent. Hybrid torrents have two swarms, v1 and
+// v2, which are announced separately.
+#[derive(Debug, Default)]
+pub(crate) struct SwarmAnnounce {
+    pub state: TrackerState,
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+    pub failure_reason: Option<String>,
+    pub last_announce: Option<Instant>,
+    pub next_announce: Option<Instant>,
+    // Whether the tracker was told we started, and whether it still needs to be told we
+    // completed.
+    pub started: bool,
+    pub completed: bool,
+}
+
+impl SwarmAnnounce {
+    pub fn event(&self) -> Option<TrackerRequestEvent> {
+        if !self.started {
+            Some(TrackerRequestEvent::Started)
+        } else if self.completed {
+            Some(TrackerRequestEvent::Completed)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TrackerEntry {
+    url: String,
+    swarms: HashMap<Id20, SwarmAnnounce>,
+}
+
+impl TrackerEntry {
+    fn new(url: String) -> Self {
+        Self {
+            url,
+            swarms: HashMap::new(),
+        }
+    }
+
+    // The status of the swarm that's doing best, so that a hybrid torrent shows as working if
+    // either of its swarms is.
+    fn status(&self, tier: usize, now: Instant) -> TrackerStatus {
+        fn rank(state: TrackerState) -> u8 {
+            match state {
+                TrackerState::NotContacted => 0,
+                TrackerState::Error => 1,
+                TrackerState::Updating => 2,
+                TrackerState::Working => 3,
+            }
+        }
+        let best = self
+            .swarms
+            .values()
+            .max_by_key(|s| (rank(s.state), s.last_announce));
+        TrackerStatus {
+            url: self.url.clone(),
+            tier,
+            state: best.map(|s| s.state).unwrap_or_default(),
+            seeders: best.and_then(|s| s.seeders),
+            leechers: best.and_then(|s| s.leechers),
+            failure_reason: best.and_then(|s| s.failure_reason.clone()),
+            last_announce_secs_ago: best
+                .and_then(|s| s.last_announce)
+                .map(|t| now.saturating_duration_since(t).as_secs()),
+            next_announce_in_secs: self
+                .swarms
+                .values()
+                .filter_map(|s| s.next_announce)
+                .min()
+                .map(|t| t.saturating_duration_since(now).as_secs()),
+        }
+    }
+}
+
+pub(crate) fn check_tracker_url(url: &str) -> anyhow::Result<Url> {
+    let parsed = Url::parse(url)?;
+    match parsed.scheme() {
+        "http" | "https" | "udp" => Ok(parsed),
+        s => bail!("unsupported tracker URL scheme {s:?}"),
+    }
+}
+
+struct Inner {
+    tiers: RwLock<Vec<Vec<TrackerEntry>>>,
+    changed: tokio::sync::watch::Sender<()>,
+}
+
+#[derive(Clone)]
+pub struct TrackerList {
+    inner: Arc<Inner>,
+}
+
+impl Default for TrackerList {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl std::fmt::Debug for TrackerList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.tiers()).finish()
+    }
+}
+
+impl TrackerList {
+    /// Duplicates and empty tiers are dropped.
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        let mut seen = HashSet::new();
+        let tiers = tiers
+            .into_iter()
+            .map(|tier| {
+                tier.into_iter()
+                    .filter(|url| seen.insert(url.clone()))
+                    .map(TrackerEntry::new)
+                    .collect::<Vec<_>>()
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect();
+        Self {
+            inner: Arc::new(Inner {
+                tiers: RwLock::new(tiers),
+                changed: tokio::sync::watch::Sender::new(()),
+            }),
+        }
+    }
+
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        self.inner
+            .tiers
+            .read()
+            .iter()
+            .map(|tier| tier.iter().map(|e| e.url.clone()).collect())
+            .collect()
+    }
+
+    pub fn urls(&self) -> Vec<String> {
+        self.tiers().into_iter().flatten().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.tiers.read().is_empty()
+    }
+
+    /// Add a tracker to the end of the given tier, or to a new last tier if there's no such tier.
+    /// Returns false if the tracker is already there.
+    pub fn add(&self, url: String, tier: Option<usize>) -> anyhow::Result<bool> {
+        check_tracker_url(&url)?;
+        let mut tiers = self.inner.tiers.write();
+        if tiers.iter().flatten().any(|e| e.url == url) {
+            return Ok(false);
+        }
+        match tier.and_then(|t| tiers.get_mut(t)) {
+            Some(tier) => tier.push(TrackerEntry::new(url)),
+            None => tiers.push(vec![TrackerEntry::new(url)]),
+        }
+        drop(tiers);
+        self.inner.changed.send_replace(());
+        Ok(true)
+    }
+
+    /// Returns false if there was no such tracker.
+    pub fn remove(&self, url: &str) -> bool {
+        let mut tiers = self.inner.tiers.write();
+        let len = tiers.iter().flatten().count();
+        for tier in tiers.iter_mut() {
+            tier.retain(|e| e.url != url);
+        }
+        tiers.retain(|tier| !tier.is_empty());
+        let removed = tiers.iter().flatten().count() != len;
+        drop(tiers);
+        if removed {
+            self.inner.changed.send_replace(());
+        }
+        removed
+    }
+
+    /// For hybrid torrents, each tracker shows the status of the swarm that's doing best.
+    pub fn status(&self) -> Vec<TrackerStatus> {
+        let now = Instant::now();
+        self.inner
+            .tiers
+            .read()
+            .iter()
+            .enumerate()
+            .flat_map(|(tier, entries)| entries.iter().map(move |e| e.status(tier, now)))
+            .collect()
+    }
+
+    // Fires when trackers are added or removed.
+    pub(crate) fn subscribe(&self) -> tokio::sync::watch::Receiver<()> {
+        self.inner.changed.subscribe()
+    }
+
+    // Returns None if there's no such tracker.
+    pub(crate) fn with_swarm<R>(
+        &self,
+        url: &str,
+        info_hash: Id20,
+        f: impl FnOnce(&mut SwarmAnnounce) -> R,
+    ) -> Option<R> {
+        self.inner
+            .tiers
+            .write()
+            .iter_mut()
+            .flatten()
+            .find(|e| e.url == url)
+            .map(|e| f(e.swarms.entry(info_hash).or_default()))
+    }
+
+    // The next announce in the swarms trackers were told we started says we completed, and it's
+    // made right away.
+    pub(crate) fn mark_completed(&self) {
+        for e in self.inner.tiers.write().iter_mut().flatten() {
+            for swarm in e.swarms.values_mut().filter(|s| s.started) {
+                swarm.completed = true;
+                swarm.next_announce = None;
+            }
+        }
+        self.inner.changed.send_replace(());
+    }
+
+    // The trackers that were told we started in this swarm, to tell them we stopped. The swarm
+    // starts over, so they'll be told we started again on the next announce.
+    pub(crate) fn take_started(&self, info_hash: Id20) -> Vec<String> {
+        let mut urls = Vec::new();
+        for e in self.inner.tiers.write().iter_mut().flatten() {
+            if e.swarms.remove(&info_hash).is_some_and(|s| s.started) {
+                urls.push(e.url.clone());
+            }
+        }
+        urls
+    }
+
+    // BEP 12: a tracker that responded goes to the front of its tier.
+    pub(crate) fn promote(&self, url: &str) {
+        let mut tiers = self.inner.tiers.write();
+        for tier in tiers.iter_mut() {
+            if let Some(pos) = tier.iter().position(|e| e.url == url) {
+                tier[..=pos].rotate_right(1);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use librqbit_core::hash_id::Id20;
+
+    use super::{TrackerList, TrackerState};
+    use crate::tracker_comms_http::TrackerRequestEvent;
+
+    #[test]
+    fn test_tracker_list() {
+        let list = TrackerList::new(vec![
+            vec!["udp://a:1".to_owned(), "http://b/announce".to_owned()],
+            vec![],
+            vec!["udp://a:1".to_owned(), "udp://c:1".to_owned()],
+        ]);
+        assert_eq!(
+            list.tiers(),
+            vec![
+                vec!["udp://a:1".to_owned(), "http://b/announce".to_owned()],
+                vec!["udp://c:1".to_owned()],
+            ]
+        );
+
+        list.promote("http://b/announce");
+        assert_eq!(list.tiers()[0], vec!["http://b/announce", "udp://a:1"]);
+
+        assert!(list.add("udp://d:1".to_owned(), Some(1)).unwrap());
+        assert!(!list.add("udp://d:1".to_owned(), None).unwrap());
+        assert!(list.add("https://e/announce".to_owned(), Some(10)).unwrap());
+        assert!(list.add("ws://f".to_owned(), None).is_err());
+        assert_eq!(
+            list.tiers()[1..],
+            vec![
+                vec!["udp://c:1".to_owned(), "udp://d:1".to_owned()],
+                vec!["https://e/announce".to_owned()],
+            ]
+        );
+
+        assert!(list.remove("https://e/announce"));
+        assert!(!list.remove("https://e/announce"));
+        assert_eq!(list.tiers().len(), 2);
+        let status = list.status();
+        assert_eq!(status.len(), 4);
+        assert_eq!((status[3].url.as_str(), status[3].tier), ("udp://d:1", 1));
+    }
+
+    #[test]
+    fn test_hybrid_swarms() {
+        let url = "udp://a:1";
+        let list = TrackerList::new(vec![vec![url.to_owned()]]);
+        let (v1, v2) = (Id20::new([1; 20]), Id20::new([2; 20]));
+        let now = Instant::now();
+
+        list.with_swarm(url, v1, |s| {
+            s.state = TrackerState::Working;
+            s.started = true;
+            s.seeders = Some(5);
+            s.next_announce = Some(now + Duration::from_secs(1800));
+        });
+        list.with_swarm(url, v2, |s| {
+            s.state = TrackerState::Error;
+            s.failure_reason = Some("error".to_owned());
+            s.next_announce = Some(now + Duration::from_secs(60));
+        });
+        // Each swarm keeps its own state, the status shows the working one.
+        let status = &list.status()[0];
+        assert_eq!(status.state, TrackerState::Working);
+        assert_eq!(status.seeders, Some(5));
+        assert_eq!(status.failure_reason, None);
+        assert!(status.next_announce_in_secs.unwrap() <= 60);
+
+        // Only swarms the tracker was told we started in get the completed event.
+        list.mark_completed();
+        assert_eq!(
+            list.with_swarm(url, v1, |s| s.event()).unwrap(),
+            Some(TrackerRequestEvent::Completed)
+        );
+        assert_eq!(
+            list.with_swarm(url, v2, |s| s.event()).unwrap(),
+            Some(TrackerRequestEvent::Started)
+        );
+
+        assert_eq!(list.take_started(v1), vec![url.to_owned()]);
+        assert!(list.take_started(v2).is_empty());
+        assert_eq!(
+            list.with_swarm(url, v1, |s| s.event()).unwrap(),
+            Some(TrackerRequestEvent::Started)
+        );
+        assert!(list.with_swarm("udp://b:1", v1, |_| ()).is_none());
+    }
+}
@@ -0,0 +1,502 @@
+// A BitTorrent tracker, over HTTP (BEP 3) and UDP (BEP 15). Peers are kept in memory and
+// forgotten when they stop announcing. Serving HTTP is left to the caller, this only deals with
+// query strings and bencoded responses.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use buffers::ByteBuf;
+use librqbit_core::hash_id::Id20;
+use parking_lot::Mutex;
+use rand::seq::IteratorRandom;
+use serde::Serialize;
+use tracing::{debug, trace};
+
+use crate::{
+    tracker_comms::ScrapeStats,
+    tracker_comms_http::{
+        parse_scrape_querystring, ScrapeFile, ScrapeResponse, TrackerError, TrackerRequest,
+        TrackerRequestEvent, TrackerResponse,
+    },
+    tracker_comms_udp::{
+        AnnounceResponse, ConnectionId, Request, Response, ScrapeResponseEntry, EVENT_COMPLETED,
+        EVENT_STARTED, EVENT_STOPPED,
+    },
+};
+
+// BEP 15: connection ids are valid for a minute or two.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct TrackerServerOptions {
+    /// How often peers are asked to announce.
+    pub interval: Duration,
+    /// Peers that didn't announce for this long are forgotten.
+    pub peer_expiry: Duration,
+    /// The most peers sent in response to an announce.
+    pub max_peers: usize,
+}
+
+impl Default for TrackerServerOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(300),
+            peer_expiry: Duration::from_secs(900),
+            max_peers: 50,
+        }
+    }
+}
+
+/// Returns whether the torrent is tracked.
+pub type InfoHashFilter = Box<dyn Fn(&Id20) -> bool + Send + Sync>;
+
+pub struct AnnounceRequest {
+    pub info_hash: Id20,
+    /// Where other peers connect to.
+    pub addr: SocketAddr,
+    pub left: u64,
+    pub event: Option<TrackerRequestEvent>,
+    pub numwant: Option<usize>,
+}
+
+pub struct AnnounceReply {
+    pub interval: Duration,
+    pub stats: ScrapeStats,
+    pub peers: Vec<SocketAddr>,
+}
+
+struct SwarmPeer {
+    left: u64,
+    last_announce: Instant,
+}
+
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<SocketAddr, SwarmPeer>,
+    downloaded: u64,
+}
+
+impl Swarm {
+    fn expire(&mut self, now: Instant, expiry: Duration) {
+        self.peers
+            .retain(|_, p| now.saturating_duration_since(p.last_announce) < expiry);
+    }
+
+    fn stats(&self) -> ScrapeStats {
+        let complete = self.peers.values().filter(|p| p.left == 0).count() as u64;
+        ScrapeStats {
+            complete,
+            incomplete: self.peers.len() as u64 - complete,
+            downloaded: self.downloaded,
+        }
+    }
+}
+
+// IPv4 peers connecting to IPv6 sockets show up as IPv4-mapped addresses.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+fn bencoded(value: impl Serialize) -> Vec<u8> {
+    let mut buf = Vec::new();
+    if let Err(e) = bencode::bencode_serialize_to_writer(value, &mut buf) {
+        debug!("error serializing tracker response: {e:#}");
+    }
+    buf
+}
+
+fn failure(e: &anyhow::Error) -> Vec<u8> {
+    let reason = format!("{e:#}");
+    bencoded(TrackerError {
+        failure_reason: ByteBuf(reason.as_bytes()),
+    })
+}
+
+pub struct TrackerServer {
+    opts: TrackerServerOptions,
+    filter: Option<InfoHashFilter>,
+    swarms: Mutex<HashMap<Id20, Swarm>>,
+    // UDP connection ids are derived from the address and time, so they don't need to be stored.
+    connection_id_key: RandomState,
+    created: Instant,
+}
+
+impl TrackerServer {
+    /// Without a filter, any torrent is tracked.
+    pub fn new(opts: TrackerServerOptions, filter: Option<InfoHashFilter>) -> Self {
+        Self {
+            opts,
+            filter,
+            swarms: Default::default(),
+            connection_id_key: RandomState::new(),
+            created: Instant::now(),
+        }
+    }
+
+    /// How often peers are asked to announce.
+    pub fn interval(&self) -> Duration {
+        self.opts.interval
+    }
+
+    fn check_allowed(&self, info_hash: &Id20) -> anyhow::Result<()> {
+        match &self.filter {
+            Some(filter) if !filter(info_hash) => bail!("torrent is not tracked here"),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn announce(&self, request: AnnounceRequest) -> anyhow::Result<AnnounceReply> {
+        self.check_allowed(&request.info_hash)?;
+        let now = Instant::now();
+        let mut swarms = self.swarms.lock();
+        if request.event == Some(TrackerRequestEvent::Stopped) {
+            // Stopped peers get nothing back, and shouldn't create a swarm that isn't there.
+            let stats = match swarms.get_mut(&request.info_hash) {
+                Some(swarm) => {
+                    swarm.peers.remove(&request.addr);
+                    swarm.expire(now, self.opts.peer_expiry);
+                    swarm.stats()
+                }
+                None => Default::default(),
+            };
+            return Ok(AnnounceReply {
+                interval: self.opts.interval,
+                stats,
+                peers: Vec::new(),
+            });
+        }
+        let swarm = swarms.entry(request.info_hash).or_default();
+        swarm.expire(now, self.opts.peer_expiry);
+        swarm.peers.insert(
+            request.addr,
+            SwarmPeer {
+                left: request.left,
+                last_announce: now,
+            },
+        );
+        if request.event == Some(TrackerRequestEvent::Completed) {
+            swarm.downloaded += 1;
+        }
+
+        // Seeders have nothing to get from each other.
+        let seeding = request.left == 0;
+        let numwant = request
+            .numwant
+            .unwrap_or(self.opts.max_peers)
+            .min(self.opts.max_peers);
+        let peers = swarm
+            .peers
+            .iter()
+            .filter(|(addr, p)| **addr != request.addr && !(seeding && p.left == 0))
+            .map(|(addr, _)| *addr)
+            .choose_multiple(&mut rand::thread_rng(), numwant);
+        Ok(AnnounceReply {
+            interval: self.opts.interval,
+            stats: swarm.stats(),
+            peers,
+        })
+    }
+
+    /// Torrents that aren't tracked, or that no peer announced, are missing (BEP 48).
+    pub fn scrape(&self, info_hashes: &[Id20]) -> HashMap<Id20, ScrapeStats> {
+        let now = Instant::now();
+        let mut swarms = self.swarms.lock();
+        info_hashes
+            .iter()
+            .filter(|h| self.check_allowed(h).is_ok())
+            .filter_map(|h| {
+                let swarm = swarms.get_mut(h)?;
+                swarm.expire(now, self.opts.peer_expiry);
+                Some((*h, swarm.stats()))
+            })
+            .collect()
+    }
+
+    /// Forget the peers that stopped announcing, and the torrents that have no peers left.
+    pub fn expire(&self) {
+        let now = Instant::now();
+        self.swarms.lock().retain(|_, swarm| {
+            swarm.expire(now, self.opts.peer_expiry);
+            !swarm.peers.is_empty()
+        });
+    }
+
+    /// Handle an HTTP announce, given its query string. Returns the bencoded response, which is a
+    /// failure reason on errors.
+    pub fn http_announce(&self, query: &str, remote_ip: IpAddr) -> Vec<u8> {
+        let result = TrackerRequest::from_querystring(query).and_then(|r| {
+            // The "ip" parameter is ignored, it's too easy to announce someone else.
+            self.announce(AnnounceRequest {
+                info_hash: r.info_hash,
+                addr: canonical(SocketAddr::new(remote_ip, r.port)),
+                left: r.left,
+                event: r.event,
+                numwant: r.numwant,
+            })
+        });
+        match result {
+            Ok(reply) => bencoded(TrackerResponse::new(
+                reply.interval.as_secs(),
+                reply.stats.complete,
+                reply.stats.incomplete,
+                &reply.peers,
+            )),
+            Err(e) => failure(&e),
+        }
+    }
+
+    /// Handle an HTTP scrape, given its query string.
+    pub fn http_scrape(&self, query: &str) -> Vec<u8> {
+        let info_hashes = match parse_scrape_querystring(query) {
+            Ok(h) => h,
+            Err(e) => return failure(&e),
+        };
+        let files = self
+            .scrape(&info_hashes)
+            .into_iter()
+            .map(|(h, s)| {
+                (
+                    h,
+                    ScrapeFile {
+                        complete: s.complete,
+                        downloaded: s.downloaded,
+                        incomplete: s.incomplete,
+                    },
+                )
+            })
+            .collect();
+        bencoded(ScrapeResponse { files })
+    }
+
+    fn connection_id(&self, addr: SocketAddr, epoch: u64) -> ConnectionId {
+        self.connection_id_key.hash_one((addr, epoch))
+    }
+
+    fn connection_epoch(&self) -> u64 {
+        self.created.elapsed().as_secs() / CONNECTION_ID_LIFETIME.as_secs()
+    }
+
+    // Ids from the previous epoch are still valid, so that they last at least a minute.
+    fn is_valid_connection_id(&self, id: ConnectionId, addr: SocketAddr) -> bool {
+        let epoch = self.connection_epoch();
+        id == self.connection_id(addr, epoch)
+            || (epoch > 0 && id == self.connection_id(addr, epoch - 1))
+    }
+
+    /// Handle a UDP tracker request. Returns None if there's nothing to respond, e.g. when the
+    /// request can't be parsed.
+    pub fn handle_udp(&self, buf: &[u8], addr: SocketAddr) -> Option<Vec<u8>> {
+        let addr = canonical(addr);
+        let (tid, request) = match Request::parse(buf) {
+            Ok(r) => r,
+            Err(e) => {
+                trace!(%addr, "error parsing UDP tracker request: {e:#}");
+                return None;
+            }
+        };
+        let response = match request {
+            Request::Connect => {
+                Response::Connect(self.connection_id(addr, self.connection_epoch()))
+            }
+            Request::Announce(id, _) | Request::Scrape(id, _)
+                if !self.is_valid_connection_id(id, addr) =>
+            {
+                Response::Error("invalid connection id".to_owned())
+            }
+            Request::Announce(_, fields) => {
+                let result = self.announce(AnnounceRequest {
+                    info_hash: fields.info_hash,
+                    addr: SocketAddr::new(addr.ip(), fields.port),
+                    left: fields.left,
+                    event: match fields.event {
+                        EVENT_STARTED => Some(TrackerRequestEvent::Started),
+                        EVENT_COMPLETED => Some(TrackerRequestEvent::Completed),
+                        EVENT_STOPPED => Some(TrackerRequestEvent::Stopped),
+                        _ => None,
+                    },
+                    numwant: None,
+                });
+                match result {
+                    Ok(reply) => Response::Announce(AnnounceResponse {
+                        interval: reply.interval.as_secs() as u32,
+                        leechers: reply.stats.incomplete as u32,
+                        seeders: reply.stats.complete as u32,
+                        // The response format depends on the address family of the request.
+                        addrs: reply
+                            .peers
+                            .into_iter()
+                            .filter(|a| a.is_ipv4() == addr.is_ipv4())
+                            .collect(),
+                    }),
+                    Err(e) => Response::Error(format!("{e:#}")),
+                }
+            }
+            Request::Scrape(_, info_hashes) => {
+                // UDP responses have an entry for each requested hash, in order, so missing ones
+                // are all zeros.
+                let stats = self.scrape(&info_hashes);
+                Response::Scrape(
+                    info_hashes
+                        .iter()
+                        .map(|h| {
+                            let s = stats.get(h).copied().unwrap_or_default();
+                            ScrapeResponseEntry {
+                                seeders: s.complete as u32,
+                                completed: s.downloaded as u32,
+                                leechers: s.incomplete as u32,
+                            }
+                        })
+                        .collect(),
+                )
+            }
+        };
+        let mut out = Vec::new();
+        response.serialize(tid, &mut out);
+        Some(out)
+    }
+
+    /// Serve UDP tracker requests forever.
+    pub async fn run_udp(&self, sock: tokio::net::UdpSocket) -> anyhow::Result<()> {
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let (size, addr) = sock
+                .recv_from(&mut buf)
+                .await
+                .context("error receiving from UDP socket")?;
+            if let Some(response) = self.handle_udp(&buf[..size], addr) {
+                if let Err(e) = sock.send_to(&response, addr).await {
+                    debug!(%addr, "error responding to UDP tracker request: {e:#}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+
+    use librqbit_core::hash_id::Id20;
+
+    use super::{AnnounceRequest, TrackerServer};
+    use crate::{
+        tracker_comms_http::{TrackerError, TrackerRequestEvent, TrackerResponse},
+        ScrapeStats, TrackerComms,
+    };
+
+    fn announce(
+        server: &TrackerServer,
+        port: u16,
+        left: u64,
+        event: Option<TrackerRequestEvent>,
+    ) -> Vec<SocketAddr> {
+        server
+            .announce(AnnounceRequest {
+                info_hash: Id20::new([1; 20]),
+                addr: SocketAddr::from(([127, 0, 0, 1], port)),
+                left,
+                event,
+                numwant: None,
+            })
+            .unwrap()
+            .peers
+    }
+
+    #[test]
+    fn test_announce() {
+        let server = TrackerServer::new(Default::default(), None);
+        assert!(announce(&server, 1, 0, None).is_empty());
+        // Seeders don't get each other.
+        assert!(announce(&server, 2, 0, None).is_empty());
+        assert_eq!(announce(&server, 3, 100, None).len(), 2);
+        assert_eq!(announce(&server, 1, 0, None).len(), 1);
+
+        announce(&server, 3, 0, Some(TrackerRequestEvent::Completed));
+        announce(&server, 2, 0, Some(TrackerRequestEvent::Stopped));
+        let hash = Id20::new([1; 20]);
+        let unknown = Id20::new([2; 20]);
+        let scrape = server.scrape(&[hash, unknown]);
+        assert_eq!(
+            scrape[&hash],
+            ScrapeStats {
+                complete: 2,
+                incomplete: 0,
+                downloaded: 1
+            }
+        );
+        assert!(!scrape.contains_key(&unknown));
+    }
+
+    #[test]
+    fn test_stopped_unknown_swarm() {
+        let server = TrackerServer::new(Default::default(), None);
+        let reply = server
+            .announce(AnnounceRequest {
+                info_hash: Id20::new([2; 20]),
+                addr: SocketAddr::from(([127, 0, 0, 1], 1)),
+                left: 0,
+                event: Some(TrackerRequestEvent::Stopped),
+                numwant: None,
+            })
+            .unwrap();
+        assert!(reply.peers.is_empty());
+        assert_eq!(reply.stats, ScrapeStats::default());
+        assert!(server.swarms.lock().is_empty());
+    }
+
+    #[test]
+    fn test_http() {
+        let filter = Box::new(|h: &Id20| *h == Id20::new([1; 20]));
+        let server = TrackerServer::new(Default::default(), Some(filter));
+        let ip: IpAddr = "::ffff:10.0.0.1".parse().unwrap();
+        let query = |hash: u8| {
+            format!(
+                "info_hash={}&peer_id={}&port=6881&left=1&compact=1",
+                urlencoding::encode_binary(&[hash; 20]),
+                urlencoding::encode_binary(&[2; 20]),
+            )
+        };
+        server.http_announce(&query(1), ip);
+        let response = server.http_announce(&query(1).replace("6881", "6882"), ip);
+        let response = bencode::from_bytes::<TrackerResponse>(&response).unwrap();
+        assert_eq!(
+            response.iter_peers().collect::<Vec<_>>(),
+            vec!["10.0.0.1:6881".parse().unwrap()]
+        );
+
+        let response = server.http_announce(&query(2), ip);
+        let error = bencode::from_bytes::<TrackerError>(&response).unwrap();
+        assert_eq!(
+            error.failure_reason.as_ref(),
+            b"torrent is not tracked here"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_udp() {
+        let server = std::sync::Arc::new(TrackerServer::new(Default::default(), None));
+        let sock = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}", sock.local_addr().unwrap());
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.run_udp(sock).await }
+        });
+        announce(&server, 1, 0, None);
+
+        let hash = Id20::new([1; 20]);
+        let scrapes = TrackerComms::scrape([url], &[hash]).await;
+        assert_eq!(
+            scrapes[0].result.as_ref().unwrap()[&hash],
+            ScrapeStats {
+                complete: 1,
+                incomplete: 0,
+                downloaded: 0
+            }
+        );
+    }
+}
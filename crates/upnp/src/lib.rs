@@ -13,6 +13,9 @@ use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tracing::{debug, error_span, trace, warn, Instrument, Span};
 use url::Url;
 
+pub mod media_server;
+pub mod ssdp_server;
+
 const SERVICE_TYPE_WAN_IP_CONNECTION: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
 const SSDP_MULTICAST_IP: SocketAddr =
     SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900));
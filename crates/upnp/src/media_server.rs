@@ -0,0 +1,488 @@
+// A UPnP AV MediaServer (what DLNA clients like TVs browse): the device description, and the
+// ContentDirectory and ConnectionManager services.
+//
+// This doesn't serve HTTP itself, nor does it know where the content comes from. The HTTP server
+// routes requests under the prefix to these handlers, and the content is listed by a
+// ContentDirectoryBrowser.
+
+use serde::Deserialize;
+use tracing::{debug, trace};
+
+pub const DEVICE_TYPE_MEDIA_SERVER: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+pub const SERVICE_TYPE_CONTENT_DIRECTORY: &str = "urn:schemas-upnp-org:service:ContentDirectory:1";
+pub const SERVICE_TYPE_CONNECTION_MANAGER: &str =
+    "urn:schemas-upnp-org:service:ConnectionManager:1";
+
+/// The id of the root container.
+pub const ROOT_OBJECT_ID: &str = "0";
+
+const CONTENT_DIRECTORY_SCPD: &str = include_str!("resources/templates/content_directory_scpd.xml");
+const CONNECTION_MANAGER_SCPD: &str =
+    include_str!("resources/templates/connection_manager_scpd.xml");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Container {
+    pub id: String,
+    pub parent_id: String,
+    pub title: String,
+    pub child_count: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub id: String,
+    pub parent_id: String,
+    pub title: String,
+    pub mime_type: String,
+    pub url: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BrowseObject {
+    Container(Container),
+    Item(Item),
+}
+
+/// Lists the content of the media server.
+pub trait ContentDirectoryBrowser: Send + Sync {
+    /// The children of the object, or None if there's no such object. Item URLs should be
+    /// relative to base_url, which is how the client reached us, e.g. "http://192.168.1.2:3030".
+    fn browse_direct_children(&self, object_id: &str, base_url: &str) -> Option<Vec<BrowseObject>>;
+
+    /// The object itself, or None if there's no such object.
+    fn browse_metadata(&self, object_id: &str, base_url: &str) -> Option<BrowseObject>;
+}
+
+/// UPnP errors returned as SOAP faults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SoapFault {
+    pub code: u16,
+    pub description: &'static str,
+}
+
+impl SoapFault {
+    pub const INVALID_ACTION: SoapFault = SoapFault {
+        code: 401,
+        description: "Invalid Action",
+    };
+    pub const INVALID_ARGS: SoapFault = SoapFault {
+        code: 402,
+        description: "Invalid Args",
+    };
+    pub const NO_SUCH_OBJECT: SoapFault = SoapFault {
+        code: 701,
+        description: "No such object",
+    };
+
+    /// The body of the response, to be sent with HTTP status 500.
+    pub fn to_xml(&self) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail><UPnPError xmlns="urn:schemas-upnp-org:control-1-0"><errorCode>{}</errorCode><errorDescription>{}</errorDescription></UPnPError></detail></s:Fault></s:Body>
+</s:Envelope>"#,
+            self.code, self.description
+        )
+    }
+}
+
+pub fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn upnp_class(mime_type: &str) -> &'static str {
+    match mime_type.split_once('/').map(|(t, _)| t) {
+        Some("video") => "object.item.videoItem",
+        Some("audio") => "object.item.audioItem.musicTrack",
+        Some("image") => "object.item.imageItem.photo",
+        _ => "object.item",
+    }
+}
+
+pub fn render_didl<'a>(objects: impl IntoIterator<Item = &'a BrowseObject>) -> String {
+    let mut out = String::from(
+        r#"<DIDL-Lite xmlns="urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:upnp="urn:schemas-upnp-org:metadata-1-0/upnp/">"#,
+    );
+    for obj in objects {
+        match obj {
+            BrowseObject::Container(c) => {
+                let child_count = c
+                    .child_count
+                    .map(|c| format!(r#" childCount="{c}""#))
+                    .unwrap_or_default();
+                out.push_str(&format!(
+                    r#"<container id="{}" parentID="{}" restricted="1"{child_count}><dc:title>{}</dc:title><upnp:class>object.container.storageFolder</upnp:class></container>"#,
+                    xml_escape(&c.id),
+                    xml_escape(&c.parent_id),
+                    xml_escape(&c.title),
+                ));
+            }
+            BrowseObject::Item(i) => {
+                out.push_str(&format!(
+                    r#"<item id="{}" parentID="{}" restricted="1"><dc:title>{}</dc:title><upnp:class>{}</upnp:class><res protocolInfo="http-get:*:{}:*" size="{}">{}</res></item>"#,
+                    xml_escape(&i.id),
+                    xml_escape(&i.parent_id),
+                    xml_escape(&i.title),
+                    upnp_class(&i.mime_type),
+                    xml_escape(&i.mime_type),
+                    i.size,
+                    xml_escape(&i.url),
+                ));
+            }
+        }
+    }
+    out.push_str("</DIDL-Lite>");
+    out
+}
+
+fn soap_response(service_type: &str, action: &str, args: &[(&str, &str)]) -> String {
+    let args: String = args
+        .iter()
+        .map(|(k, v)| format!("<{k}>{}</{k}>", xml_escape(v)))
+        .collect();
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body><u:{action}Response xmlns:u="{service_type}">{args}</u:{action}Response></s:Body>
+</s:Envelope>"#
+    )
+}
+
+// The action name from the SOAPACTION header, e.g. "urn:...:ContentDirectory:1#Browse".
+fn parse_soap_action<'a>(header: &'a str, service_type: &str) -> Result<&'a str, SoapFault> {
+    let (st, action) = header
+        .trim()
+        .trim_matches('"')
+        .split_once('#')
+        .ok_or(SoapFault::INVALID_ACTION)?;
+    if st != service_type {
+        return Err(SoapFault::INVALID_ACTION);
+    }
+    Ok(action)
+}
+
+#[derive(Debug, Deserialize)]
+struct BrowseEnvelope {
+    #[serde(rename = "Body")]
+    body: BrowseBody,
+}
+
+#[derive(Debug, Deserialize)]
+struct BrowseBody {
+    #[serde(rename = "Browse")]
+    browse: BrowseRequest,
+}
+
+#[derive(Debug, Deserialize)]
+struct BrowseRequest {
+    #[serde(rename = "ObjectID")]
+    object_id: String,
+    #[serde(rename = "BrowseFlag")]
+    browse_flag: String,
+    #[serde(rename = "StartingIndex", default)]
+    starting_index: usize,
+    #[serde(rename = "RequestedCount", default)]
+    requested_count: usize,
+}
+
+pub struct MediaServerOptions {
+    pub friendly_name: String,
+    /// The unique device name, "uuid:...". Should stay the same across restarts.
+    pub udn: String,
+    /// Where the HTTP server routes the media server requests, e.g. "/upnp".
+    pub http_prefix: String,
+}
+
+pub struct MediaServer<B> {
+    opts: MediaServerOptions,
+    browser: B,
+}
+
+impl<B: ContentDirectoryBrowser> MediaServer<B> {
+    pub fn new(opts: MediaServerOptions, browser: B) -> Self {
+        Self { opts, browser }
+    }
+
+    pub fn udn(&self) -> &str {
+        &self.opts.udn
+    }
+
+    pub fn description_path(&self) -> String {
+        format!("{}/description.xml", self.opts.http_prefix)
+    }
+
+    pub fn content_directory_scpd(&self) -> &'static str {
+        CONTENT_DIRECTORY_SCPD
+    }
+
+    pub fn connection_manager_scpd(&self) -> &'static str {
+        CONNECTION_MANAGER_SCPD
+    }
+
+    pub fn device_description(&self) -> String {
+        let prefix = &self.opts.http_prefix;
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0" xmlns:dlna="urn:schemas-dlna-org:device-1-0">
+  <specVersion>
+    <major>1</major>
+    <minor>0</minor>
+  </specVersion>
+  <device>
+    <deviceType>{DEVICE_TYPE_MEDIA_SERVER}</deviceType>
+    <friendlyName>{}</friendlyName>
+    <manufacturer>rqbit</manufacturer>
+    <manufacturerURL>https://github.com/ikatson/rqbit</manufacturerURL>
+    <modelName>rqbit</modelName>
+    <modelNumber>1</modelNumber>
+    <UDN>{}</UDN>
+    <dlna:X_DLNADOC>DMS-1.50</dlna:X_DLNADOC>
+    <serviceList>
+      <service>
+        <serviceType>{SERVICE_TYPE_CONTENT_DIRECTORY}</serviceType>
+        <serviceId>urn:upnp-org:serviceId:ContentDirectory</serviceId>
+        <SCPDURL>{prefix}/content_directory.xml</SCPDURL>
+        <controlURL>{prefix}/content_directory/control</controlURL>
+        <eventSubURL>{prefix}/content_directory/events</eventSubURL>
+      </service>
+      <service>
+        <serviceType>{SERVICE_TYPE_CONNECTION_MANAGER}</serviceType>
+        <serviceId>urn:upnp-org:serviceId:ConnectionManager</serviceId>
+        <SCPDURL>{prefix}/connection_manager.xml</SCPDURL>
+        <controlURL>{prefix}/connection_manager/control</controlURL>
+        <eventSubURL>{prefix}/connection_manager/events</eventSubURL>
+      </service>
+    </serviceList>
+  </device>
+</root>"#,
+            xml_escape(&self.opts.friendly_name),
+            xml_escape(&self.opts.udn),
+        )
+    }
+
+    /// Handles a POST to the ContentDirectory control URL.
+    pub fn handle_content_directory(
+        &self,
+        soap_action: &str,
+        body: &str,
+        base_url: &str,
+    ) -> Result<String, SoapFault> {
+        let action = parse_soap_action(soap_action, SERVICE_TYPE_CONTENT_DIRECTORY)?;
+        trace!(action, body, "ContentDirectory request");
+        let st = SERVICE_TYPE_CONTENT_DIRECTORY;
+        match action {
+            "Browse" => self.browse(body, base_url),
+            "GetSearchCapabilities" => Ok(soap_response(st, action, &[("SearchCaps", "")])),
+            "GetSortCapabilities" => Ok(soap_response(st, action, &[("SortCaps", "")])),
+            "GetSystemUpdateID" => Ok(soap_response(st, action, &[("Id", "0")])),
+            _ => Err(SoapFault::INVALID_ACTION),
+        }
+    }
+
+    /// Handles a POST to the ConnectionManager control URL.
+    pub fn handle_connection_manager(
+        &self,
+        soap_action: &str,
+        body: &str,
+    ) -> Result<String, SoapFault> {
+        let action = parse_soap_action(soap_action, SERVICE_TYPE_CONNECTION_MANAGER)?;
+        trace!(action, body, "ConnectionManager request");
+        let st = SERVICE_TYPE_CONNECTION_MANAGER;
+        match action {
+            "GetProtocolInfo" => Ok(soap_response(
+                st,
+                action,
+                &[("Source", "http-get:*:*:*"), ("Sink", "")],
+            )),
+            "GetCurrentConnectionIDs" => Ok(soap_response(st, action, &[("ConnectionIDs", "0")])),
+            "GetCurrentConnectionInfo" => Ok(soap_response(
+                st,
+                action,
+                &[
+                    ("RcsID", "-1"),
+                    ("AVTransportID", "-1"),
+                    ("ProtocolInfo", ""),
+                    ("PeerConnectionManager", ""),
+                    ("PeerConnectionID", "-1"),
+                    ("Direction", "Output"),
+                    ("Status", "OK"),
+                ],
+            )),
+            _ => Err(SoapFault::INVALID_ACTION),
+        }
+    }
+
+    fn browse(&self, body: &str, base_url: &str) -> Result<String, SoapFault> {
+        let req = serde_xml_rs::from_str::<BrowseEnvelope>(body)
+            .map_err(|e| {
+                debug!("error parsing Browse request: {e:#}");
+                SoapFault::INVALID_ARGS
+            })?
+            .body
+            .browse;
+
+        let (objects, total) = match req.browse_flag.as_str() {
+            "BrowseMetadata" => {
+                let obj = self
+                    .browser
+                    .browse_metadata(&req.object_id, base_url)
+                    .ok_or(SoapFault::NO_SUCH_OBJECT)?;
+                (vec![obj], 1)
+            }
+            "BrowseDirectChildren" => {
+                let children = self
+                    .browser
+                    .browse_direct_children(&req.object_id, base_url)
+                    .ok_or(SoapFault::NO_SUCH_OBJECT)?;
+                let total = children.len();
+                let count = match req.requested_count {
+                    0 => usize::MAX,
+                    c => c,
+                };
+                let page = children
+                    .into_iter()
+                    .skip(req.starting_index)
+                    .take(count)
+                    .collect();
+                (page, total)
+            }
+            _ => return Err(SoapFault::INVALID_ARGS),
+        };
+
+        Ok(soap_response(
+            SERVICE_TYPE_CONTENT_DIRECTORY,
+            "Browse",
+            &[
+                ("Result", &render_didl(&objects)),
+                ("NumberReturned", &objects.len().to_string()),
+                ("TotalMatches", &total.to_string()),
+                ("UpdateID", "0"),
+            ],
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BrowseObject, Container, ContentDirectoryBrowser, Item, MediaServer, MediaServerOptions,
+        SoapFault,
+    };
+
+    struct TestBrowser;
+
+    fn item(n: usize) -> BrowseObject {
+        BrowseObject::Item(Item {
+            id: format!("0/{n}"),
+            parent_id: "0".into(),
+            title: format!("Episode {n} & more"),
+            mime_type: "video/mp4".into(),
+            url: format!("http://127.0.0.1:3030/torrents/0/stream/{n}"),
+            size: 100,
+        })
+    }
+
+    impl ContentDirectoryBrowser for TestBrowser {
+        fn browse_direct_children(&self, object_id: &str, _: &str) -> Option<Vec<BrowseObject>> {
+            match object_id {
+                "0" => Some((0..3).map(item).collect()),
+                _ => None,
+            }
+        }
+
+        fn browse_metadata(&self, object_id: &str, _: &str) -> Option<BrowseObject> {
+            match object_id {
+                "0" => Some(BrowseObject::Container(Container {
+                    id: "0".into(),
+                    parent_id: "-1".into(),
+                    title: "root".into(),
+                    child_count: Some(3),
+                })),
+                _ => None,
+            }
+        }
+    }
+
+    fn server() -> MediaServer<TestBrowser> {
+        MediaServer::new(
+            MediaServerOptions {
+                friendly_name: "rqbit <test>".into(),
+                udn: "uuid:1234".into(),
+                http_prefix: "/upnp".into(),
+            },
+            TestBrowser,
+        )
+    }
+
+    fn browse_body(object_id: &str, flag: &str, start: usize, count: usize) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body><u:Browse xmlns:u="urn:schemas-upnp-org:service:ContentDirectory:1"><ObjectID>{object_id}</ObjectID><BrowseFlag>{flag}</BrowseFlag><Filter>*</Filter><StartingIndex>{start}</StartingIndex><RequestedCount>{count}</RequestedCount><SortCriteria></SortCriteria></u:Browse></s:Body>
+</s:Envelope>"#
+        )
+    }
+
+    const BROWSE: &str = "\"urn:schemas-upnp-org:service:ContentDirectory:1#Browse\"";
+
+    #[test]
+    fn test_device_description() {
+        let desc = server().device_description();
+        let root: crate::RootDesc = serde_xml_rs::from_str(&desc).unwrap();
+        assert_eq!(root.devices[0].friendly_name, "rqbit <test>");
+        assert_eq!(root.devices[0].service_list.services.len(), 2);
+        assert_eq!(
+            root.devices[0].service_list.services[0].control_url,
+            "/upnp/content_directory/control"
+        );
+    }
+
+    #[test]
+    fn test_browse() {
+        let s = server();
+        let r = s
+            .handle_content_directory(BROWSE, &browse_body("0", "BrowseDirectChildren", 1, 0), "")
+            .unwrap();
+        assert!(r.contains("<NumberReturned>2</NumberReturned>"), "{r}");
+        assert!(r.contains("<TotalMatches>3</TotalMatches>"), "{r}");
+        // The DIDL document is escaped inside the SOAP response.
+        assert!(r.contains("&lt;dc:title&gt;Episode 1 &amp;amp; more&lt;/dc:title&gt;"));
+        assert!(r.contains("http-get:*:video/mp4:*"));
+        assert!(r.contains("object.item.videoItem"));
+        assert!(!r.contains("Episode 0"));
+
+        let r = s
+            .handle_content_directory(BROWSE, &browse_body("0", "BrowseMetadata", 0, 0), "")
+            .unwrap();
+        assert!(r.contains("childCount=&quot;3&quot;"), "{r}");
+
+        assert_eq!(
+            s.handle_content_directory(BROWSE, &browse_body("42", "BrowseMetadata", 0, 0), ""),
+            Err(SoapFault::NO_SUCH_OBJECT)
+        );
+        assert_eq!(
+            s.handle_content_directory(BROWSE, "<garbage", ""),
+            Err(SoapFault::INVALID_ARGS)
+        );
+        assert_eq!(
+            s.handle_content_directory(
+                "urn:schemas-upnp-org:service:ContentDirectory:1#Search",
+                "",
+                ""
+            ),
+            Err(SoapFault::INVALID_ACTION)
+        );
+    }
+}
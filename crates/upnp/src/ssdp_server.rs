@@ -0,0 +1,319 @@
+// Advertising a UPnP device on the local network with SSDP, so that clients can find it.
+//
+// Answers M-SEARCH requests, and periodically multicasts "ssdp:alive" notifications. The
+// description URL in both points to the local address on the same network as the receiver.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use anyhow::Context;
+use network_interface::NetworkInterfaceConfig;
+use tokio::net::UdpSocket;
+use tracing::{debug, trace, warn};
+
+use crate::{get_local_ip_relative_to, SSDP_MULTICAST_IP};
+
+const SSDP_MULTICAST_IPV4: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+
+// How long clients may cache our advertisements.
+const MAX_AGE_SECS: u64 = 1800;
+
+pub struct SsdpServerOptions {
+    /// The unique device name, "uuid:...".
+    pub udn: String,
+    pub device_type: String,
+    pub service_types: Vec<String>,
+    /// The port of the HTTP server serving the device description.
+    pub http_port: u16,
+    pub description_path: String,
+    /// Where to listen for M-SEARCH requests. Multicast is only joined on the standard port.
+    pub listen_addr: SocketAddr,
+    pub notify_interval: Duration,
+}
+
+impl SsdpServerOptions {
+    pub fn new(udn: String, device_type: String, http_port: u16, description_path: String) -> Self {
+        Self {
+            udn,
+            device_type,
+            service_types: Vec::new(),
+            http_port,
+            description_path,
+            listen_addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), SSDP_MULTICAST_IP.port()),
+            notify_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+pub struct SsdpServer {
+    opts: SsdpServerOptions,
+    socket: UdpSocket,
+}
+
+fn bind_reuse(addr: SocketAddr) -> anyhow::Result<UdpSocket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV4,
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )
+    .context("error creating socket")?;
+    // Other media servers on this host may listen on the SSDP port too.
+    socket
+        .set_reuse_address(true)
+        .context("error setting SO_REUSEADDR")?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("error binding to {addr}"))?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+fn local_ipv4_addrs() -> anyhow::Result<Vec<Ipv4Addr>> {
+    let interfaces =
+        network_interface::NetworkInterface::show().context("error listing network interfaces")?;
+    Ok(interfaces
+        .into_iter()
+        .flat_map(|i| i.addr)
+        .filter_map(|a| match a {
+            network_interface::Addr::V4(v4) if !v4.ip.is_loopback() => Some(v4.ip),
+            _ => None,
+        })
+        .collect())
+}
+
+fn parse_headers(msg: &str) -> HashMap<String, &str> {
+    msg.lines()
+        .skip(1)
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_lowercase(), v.trim()))
+        .collect()
+}
+
+impl SsdpServer {
+    pub async fn new(opts: SsdpServerOptions) -> anyhow::Result<Self> {
+        let socket = bind_reuse(opts.listen_addr)?;
+        if opts.listen_addr.port() == SSDP_MULTICAST_IP.port() {
+            if let Err(e) = socket.join_multicast_v4(SSDP_MULTICAST_IPV4, Ipv4Addr::UNSPECIFIED) {
+                warn!("error joining SSDP multicast group, will only answer unicast: {e:#}");
+            }
+        }
+        Ok(Self { opts, socket })
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    // What we advertise: (notification type, unique service name).
+    fn targets(&self) -> Vec<(String, String)> {
+        let udn = &self.opts.udn;
+        let mut targets = vec![
+            (
+                "upnp:rootdevice".to_owned(),
+                format!("{udn}::upnp:rootdevice"),
+            ),
+            (udn.clone(), udn.clone()),
+            (
+                self.opts.device_type.clone(),
+                format!("{udn}::{}", self.opts.device_type),
+            ),
+        ];
+        for st in self.opts.service_types.iter() {
+            targets.push((st.clone(), format!("{udn}::{st}")));
+        }
+        targets
+    }
+
+    fn location(&self, local_ip: Ipv4Addr) -> String {
+        format!(
+            "http://{local_ip}:{}{}",
+            self.opts.http_port, self.opts.description_path
+        )
+    }
+
+    /// The responses to an M-SEARCH request, if it's one and it's looking for us.
+    pub fn search_responses(&self, request: &str, local_ip: Ipv4Addr) -> Vec<String> {
+        if !request.starts_with("M-SEARCH ") {
+            return Vec::new();
+        }
+        let headers = parse_headers(request);
+        if headers.get("man").map(|m| m.trim_matches('"')) != Some("ssdp:discover") {
+            return Vec::new();
+        }
+        let st = match headers.get("st") {
+            Some(st) => *st,
+            None => return Vec::new(),
+        };
+        let location = self.location(local_ip);
+        self.targets()
+            .into_iter()
+            .filter(|(nt, _)| st == "ssdp:all" || st == nt)
+            .map(|(nt, usn)| {
+                format!(
+                    "HTTP/1.1 200 OK\r\n\
+                     CACHE-CONTROL: max-age={MAX_AGE_SECS}\r\n\
+                     EXT:\r\n\
+                     LOCATION: {location}\r\n\
+                     SERVER: {}\r\n\
+                     ST: {nt}\r\n\
+                     USN: {usn}\r\n\
+                     Content-Length: 0\r\n\
+                     \r\n",
+                    server_string()
+                )
+            })
+            .collect()
+    }
+
+    fn notify_messages(&self, local_ip: Ipv4Addr) -> Vec<String> {
+        let location = self.location(local_ip);
+        self.targets()
+            .into_iter()
+            .map(|(nt, usn)| {
+                format!(
+                    "NOTIFY * HTTP/1.1\r\n\
+                     HOST: {SSDP_MULTICAST_IP}\r\n\
+                     CACHE-CONTROL: max-age={MAX_AGE_SECS}\r\n\
+                     LOCATION: {location}\r\n\
+                     NT: {nt}\r\n\
+                     NTS: ssdp:alive\r\n\
+                     SERVER: {}\r\n\
+                     USN: {usn}\r\n\
+                     \r\n",
+                    server_string()
+                )
+            })
+            .collect()
+    }
+
+    async fn notify_alive(&self) -> anyhow::Result<()> {
+        for ip in local_ipv4_addrs()? {
+            let socket = bind_reuse(SocketAddr::V4(SocketAddrV4::new(ip, 0)))?;
+            for msg in self.notify_messages(ip) {
+                socket
+                    .send_to(msg.as_bytes(), SSDP_MULTICAST_IP)
+                    .await
+                    .with_context(|| format!("error sending NOTIFY from {ip}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn on_request(&self, request: &str, from: SocketAddr) -> anyhow::Result<()> {
+        let local_ip = match from.ip() {
+            IpAddr::V4(ip) if ip.is_loopback() => ip,
+            IpAddr::V4(ip) => get_local_ip_relative_to(ip)?,
+            IpAddr::V6(ip) => anyhow::bail!("IPv6 is not supported, but got request from {ip}"),
+        };
+        for response in self.search_responses(request, local_ip) {
+            trace!(%from, response, "sending SSDP response");
+            self.socket.send_to(response.as_bytes(), from).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn run_forever(self) -> anyhow::Result<()> {
+        let mut notify_interval = tokio::time::interval(self.opts.notify_interval);
+        let mut buf = [0u8; 2048];
+        loop {
+            tokio::select! {
+                _ = notify_interval.tick() => {
+                    if let Err(e) = self.notify_alive().await {
+                        debug!("error sending SSDP notifications: {e:#}");
+                    }
+                }
+                r = self.socket.recv_from(&mut buf) => {
+                    let (len, from) = r.context("error receiving from SSDP socket")?;
+                    let request = match std::str::from_utf8(&buf[..len]) {
+                        Ok(r) => r,
+                        Err(_) => {
+                            debug!(%from, "received invalid utf-8");
+                            continue;
+                        }
+                    };
+                    if let Err(e) = self.on_request(request, from).await {
+                        debug!(%from, "error answering SSDP request: {e:#}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn server_string() -> String {
+    format!("{}/1.0 UPnP/1.0 rqbit/1.0", std::env::consts::OS)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use super::{SsdpServer, SsdpServerOptions};
+
+    fn opts() -> SsdpServerOptions {
+        let mut opts = SsdpServerOptions::new(
+            "uuid:1234".into(),
+            "urn:schemas-upnp-org:device:MediaServer:1".into(),
+            3030,
+            "/upnp/description.xml".into(),
+        );
+        opts.service_types = vec!["urn:schemas-upnp-org:service:ContentDirectory:1".into()];
+        opts.listen_addr = "127.0.0.1:0".parse().unwrap();
+        opts
+    }
+
+    fn m_search(st: &str) -> String {
+        format!(
+            "M-SEARCH * HTTP/1.1\r\n\
+             HOST: 239.255.255.250:1900\r\n\
+             MAN: \"ssdp:discover\"\r\n\
+             MX: 1\r\n\
+             ST: {st}\r\n\
+             \r\n"
+        )
+    }
+
+    #[tokio::test]
+    async fn test_search_responses() {
+        let s = SsdpServer::new(opts()).await.unwrap();
+        let ip = Ipv4Addr::new(192, 168, 1, 2);
+
+        assert_eq!(s.search_responses(&m_search("ssdp:all"), ip).len(), 4);
+        assert!(s
+            .search_responses(&m_search("urn:schemas-upnp-org:service:AVTransport:1"), ip)
+            .is_empty());
+        assert!(s
+            .search_responses("NOTIFY * HTTP/1.1\r\nST: ssdp:all\r\n\r\n", ip)
+            .is_empty());
+
+        let r = s.search_responses(&m_search("urn:schemas-upnp-org:device:MediaServer:1"), ip);
+        assert_eq!(r.len(), 1);
+        assert!(r[0].contains("LOCATION: http://192.168.1.2:3030/upnp/description.xml\r\n"));
+        assert!(r[0].contains("USN: uuid:1234::urn:schemas-upnp-org:device:MediaServer:1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_m_search_client() {
+        let server = SsdpServer::new(opts()).await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(server.run_forever());
+
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(m_search("upnp:rootdevice").as_bytes(), server_addr)
+            .await
+            .unwrap();
+        let mut buf = [0u8; 2048];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = std::str::from_utf8(&buf[..len]).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("LOCATION: http://127.0.0.1:3030/upnp/description.xml\r\n"));
+        assert!(response.contains("ST: upnp:rootdevice\r\n"));
+    }
+}
@@ -104,6 +104,7 @@ async fn api_from_config(
             api.clone(),
             Some(librqbit::http_api::HttpApiOptions {
                 read_only: config.http_api.read_only,
+                ..Default::default()
             }),
         )
         .make_http_api_and_run(config.http_api.listen_addr);